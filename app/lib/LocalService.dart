@@ -13,11 +13,18 @@ class LocalService {
   }
 
   static const String _keyBackendToken = 'backend_token';
+  static const String _keyRefreshToken = 'refresh_token';
   static const String _keyUserId = 'user_id';
   static const String _keyManterConectado = 'manter_conectado';
 
   static final _storage = FlutterSecureStorage();
 
+  /// Cliente HTTP para as rotas autenticadas do back-end, que renova o token de acesso
+  /// expirado (ver [_BackendClient]).
+  static final http.Client client = _BackendClient();
+
+  static Future<bool>? _refreshing;
+
   static Future<bool> getManterConectado() async {
     final valor = await _storage.read(key: _keyManterConectado);
     return valor == null ? true : valor == 'true';
@@ -59,7 +66,7 @@ class LocalService {
         Uri.parse('$backendUrl/login'),
         headers: {'Content-Type': 'application/json'},
         body: jsonEncode({
-          'firebase_uid': userCredential.user!.uid,
+          'id_token': await userCredential.user!.getIdToken(),
           'password': senha,
         }),
       );
@@ -68,8 +75,9 @@ class LocalService {
         return {'success': false, 'message': 'Backend login failed'};
       }
 
-      final backendToken = response.body
-          .trim(); // Assume it returns the token as plain text
+      final tokens = jsonDecode(response.body) as Map<String, dynamic>;
+      final backendToken = tokens['access_token'] as String;
+      final refreshToken = tokens['refresh_token'] as String;
 
       // Salvar preferência
       await setManterConectado(manterConectado);
@@ -77,6 +85,7 @@ class LocalService {
       // Se escolheu manter conectado, salva o token
       if (manterConectado) {
         await _storage.write(key: _keyBackendToken, value: backendToken);
+        await _storage.write(key: _keyRefreshToken, value: refreshToken);
         await _storage.write(key: _keyUserId, value: usuario['id']);
       } else {
         // Se não escolheu manter conectado, limpa qualquer token existente
@@ -142,6 +151,51 @@ class LocalService {
     return await _storage.read(key: _keyBackendToken);
   }
 
+  // ==================== RENOVAR TOKENS ====================
+  /// Troca o token de atualização armazenado por um novo par de tokens (`POST /refresh`).
+  /// Se outra requisição já renovou o token recusado, apenas retorna; renovações
+  /// simultâneas compartilham a mesma chamada, pois o back-end revoga a sessão se um
+  /// token de atualização for reutilizado.
+  static Future<bool> refreshTokens(String rejectedToken) async {
+    if (await getBackendToken() != rejectedToken) return true;
+    return _refreshing ??= _refresh().whenComplete(() => _refreshing = null);
+  }
+
+  static Future<bool> _refresh() async {
+    try {
+      final refreshToken = await _storage.read(key: _keyRefreshToken);
+      if (refreshToken == null) return false;
+
+      final response = await http.post(
+        Uri.parse('$backendUrl/refresh'),
+        headers: {'Content-Type': 'application/json'},
+        body: jsonEncode({'refresh_token': refreshToken}),
+      );
+
+      if (response.statusCode == 401) {
+        // Sessão revogada ou expirada: é preciso fazer login novamente
+        await _storage.delete(key: _keyBackendToken);
+        await _storage.delete(key: _keyRefreshToken);
+        return false;
+      }
+      if (response.statusCode != 200) return false;
+
+      final tokens = jsonDecode(response.body) as Map<String, dynamic>;
+      await _storage.write(
+        key: _keyBackendToken,
+        value: tokens['access_token'] as String,
+      );
+      await _storage.write(
+        key: _keyRefreshToken,
+        value: tokens['refresh_token'] as String,
+      );
+      return true;
+    } catch (e) {
+      print('Erro ao renovar token: $e');
+      return false;
+    }
+  }
+
   // ==================== VERIFICAR SE ESTÁ LOGADO ====================
   static Future<bool> estaLogado() async {
     try {
@@ -193,7 +247,7 @@ class LocalService {
       // Call backend logout if token exists
       final token = await _storage.read(key: _keyBackendToken);
       if (token != null) {
-        await http.post(
+        await client.post(
           Uri.parse('$backendUrl/logout'),
           headers: {'Authorization': 'Bearer $token'},
         );
@@ -208,13 +262,36 @@ class LocalService {
     }
   }
 
+  // ==================== CÓDIGO DE PAREAMENTO ====================
+  static Future<String?> getPairingCode() async {
+    try {
+      final token = await _storage.read(key: _keyBackendToken);
+      if (token == null) return null;
+
+      final response = await client.post(
+        Uri.parse('$backendUrl/pairing_code'),
+        headers: {
+          'Content-Type': 'application/json',
+          'Authorization': 'Bearer $token',
+        },
+        body: jsonEncode({}),
+      );
+
+      if (response.statusCode != 200) return null;
+      return jsonDecode(response.body)['code'];
+    } catch (e) {
+      print('Erro ao obter código de pareamento: $e');
+      return null;
+    }
+  }
+
   // ==================== UPDATE PHONE ====================
   static Future<bool> updatePhone(String phoneNumber) async {
     try {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/update_phone'),
         headers: {
           'Content-Type': 'application/json',
@@ -236,7 +313,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/update_password'),
         headers: {
           'Content-Type': 'application/json',
@@ -258,7 +335,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/delete_account'),
         headers: {'Authorization': 'Bearer $token'},
       );
@@ -276,7 +353,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/verify_password'),
         headers: {
           'Content-Type': 'application/json',
@@ -298,7 +375,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.get(
+      final response = await client.get(
         Uri.parse('$backendUrl/devices'),
         headers: {'Authorization': 'Bearer $token'},
       );
@@ -319,7 +396,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/register_voice'),
         headers: {
           'Content-Type': 'application/octet-stream',
@@ -340,7 +417,7 @@ class LocalService {
       final token = await _storage.read(key: _keyBackendToken);
       if (token == null) return false;
 
-      final response = await http.post(
+      final response = await client.post(
         Uri.parse('$backendUrl/delete_voice'),
         headers: {'Authorization': 'Bearer $token'},
       );
@@ -362,7 +439,7 @@ class LocalService {
       }
 
       print('DEBUG: Calling /voice_status endpoint');
-      final response = await http.get(
+      final response = await client.get(
         Uri.parse('$backendUrl/voice_status'),
         headers: {'Authorization': 'Bearer $token'},
       );
@@ -387,3 +464,41 @@ class LocalService {
     }
   }
 }
+
+/// Cliente HTTP que envia o token de acesso armazenado nas requisições ao back-end. Se o
+/// back-end responder `401` (token expirado), renova os tokens com
+/// [LocalService.refreshTokens] e repete a requisição uma vez com o novo token.
+class _BackendClient extends http.BaseClient {
+  final http.Client _inner = http.Client();
+
+  @override
+  Future<http.StreamedResponse> send(http.BaseRequest request) async {
+    if (!request.url.toString().startsWith(LocalService.backendUrl)) {
+      return _inner.send(request);
+    }
+
+    // Only requests with a buffered body can be sent again
+    final retry = request is http.Request ? _copy(request) : null;
+    final token = await LocalService.getBackendToken();
+    if (token != null) request.headers['Authorization'] = 'Bearer $token';
+
+    final response = await _inner.send(request);
+    if (response.statusCode != 401 || retry == null || token == null) {
+      return response;
+    }
+    if (!await LocalService.refreshTokens(token)) return response;
+
+    await response.stream.drain<void>();
+    retry.headers['Authorization'] =
+        'Bearer ${await LocalService.getBackendToken()}';
+    return _inner.send(retry);
+  }
+
+  static http.Request _copy(http.Request request) {
+    return http.Request(request.method, request.url)
+      ..headers.addAll(request.headers)
+      ..followRedirects = request.followRedirects
+      ..maxRedirects = request.maxRedirects
+      ..bodyBytes = request.bodyBytes;
+  }
+}
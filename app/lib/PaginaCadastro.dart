@@ -506,8 +506,9 @@ class _CadastroState extends State<Cadastro> {
                                                     'application/json',
                                               },
                                               body: jsonEncode({
-                                                'firebase_uid':
-                                                    userCredential.user!.uid,
+                                                'id_token': await userCredential
+                                                    .user!
+                                                    .getIdToken(),
                                                 'password':
                                                     _senhaController.text,
                                                 'email': _emailController.text
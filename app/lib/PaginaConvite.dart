@@ -2,7 +2,6 @@ import 'package:flutter/material.dart';
 import 'LocalService.dart';
 import 'dart:ui';
 import 'dart:convert';
-import 'package:http/http.dart' as http;
 import 'package:cloud_firestore/cloud_firestore.dart';
 
 class PaginaConvites extends StatefulWidget {
@@ -44,7 +43,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
         // Call backend to get invites
         final backendToken = await LocalService.getBackendToken();
         if (backendToken != null) {
-          final response = await http.get(
+          final response = await LocalService.client.get(
             Uri.parse('${LocalService.backendUrl}/invites'),
             headers: {'Authorization': 'Bearer $backendToken'},
           );
@@ -597,7 +596,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
           return;
         }
 
-        final response = await http.post(
+        final response = await LocalService.client.post(
           Uri.parse(
             '${LocalService.backendUrl}/cancel_invite/${convite['id']}',
           ),
@@ -771,7 +770,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
           return;
         }
 
-        final response = await http.post(
+        final response = await LocalService.client.post(
           Uri.parse(
             '${LocalService.backendUrl}/update_invite/${convite['id']}',
           ),
@@ -802,7 +801,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
         return;
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse('${LocalService.backendUrl}/accept_invite/${convite['id']}'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -835,7 +834,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
         return;
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse('${LocalService.backendUrl}/reject_invite/${convite['id']}'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -942,7 +941,7 @@ class _PaginaConvitesState extends State<PaginaConvites>
           return;
         }
 
-        final response = await http.post(
+        final response = await LocalService.client.post(
           Uri.parse(
             '${LocalService.backendUrl}/reject_invite/${convite['id']}',
           ),
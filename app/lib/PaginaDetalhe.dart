@@ -6,7 +6,6 @@ import 'dart:async';
 import 'dart:math';
 import 'PaginaDetalhe.dart';
 import 'package:cloud_firestore/cloud_firestore.dart';
-import 'package:http/http.dart' as http;
 import 'package:web_socket_channel/web_socket_channel.dart';
 import 'package:web_socket_channel/status.dart' as status;
 import 'package:firebase_auth/firebase_auth.dart';
@@ -244,7 +243,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
 
     for (int attempt = 0; attempt < 2; attempt++) {
       try {
-        final deviceResponse = await http.get(
+        final deviceResponse = await LocalService.client.get(
           Uri.parse('${LocalService.backendUrl}/device/${widget.fechaduraId}'),
           headers: {'Authorization': 'Bearer $backendToken'},
         );
@@ -285,7 +284,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
     if (backendToken == null) return;
 
     final start = DateTime.now().millisecondsSinceEpoch;
-    final pingResponse = await http.post(
+    final pingResponse = await LocalService.client.post(
       Uri.parse('${LocalService.backendUrl}/ping/${widget.fechaduraId}'),
       headers: {'Authorization': 'Bearer $backendToken'},
     );
@@ -303,7 +302,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
     if (backendToken == null) return;
 
     try {
-      final logsResponse = await http.get(
+      final logsResponse = await LocalService.client.get(
         Uri.parse('${LocalService.backendUrl}/logs/${widget.fechaduraId}'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -362,7 +361,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
       final backendToken = await LocalService.getBackendToken();
       if (backendToken != null) {
         // Fetch device state
-        final deviceResponse = await http.get(
+        final deviceResponse = await LocalService.client.get(
           Uri.parse('${LocalService.backendUrl}/device/${widget.fechaduraId}'),
           headers: {'Authorization': 'Bearer $backendToken'},
         );
@@ -403,7 +402,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
           }
         }
 
-        final logsResponse = await http.get(
+        final logsResponse = await LocalService.client.get(
           Uri.parse('${LocalService.backendUrl}/logs/${widget.fechaduraId}'),
           headers: {'Authorization': 'Bearer $backendToken'},
         );
@@ -467,7 +466,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
       // Call backend for control
       final command = acao == 'Abrir' ? 'UNLOCK' : 'LOCK';
       final url = '${LocalService.backendUrl}/control/${widget.fechaduraId}';
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse(url),
         headers: {
           'Authorization': 'Bearer $backendToken',
@@ -1811,7 +1810,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
         throw Exception('No backend token');
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse('${LocalService.backendUrl}/lockdown/${widget.fechaduraId}'),
         headers: {
           'Authorization': 'Bearer $backendToken',
@@ -2017,7 +2016,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
         return;
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse('${LocalService.backendUrl}/create_invite'),
         headers: {
           'Content-Type': 'application/json',
@@ -2160,7 +2159,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
         return;
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse(
           '${LocalService.backendUrl}/update_config/${widget.fechaduraId}',
         ),
@@ -2211,7 +2210,7 @@ class _LockDetailsState extends State<LockDetails> with WidgetsBindingObserver {
         return;
       }
 
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse('${LocalService.backendUrl}/reboot/${widget.fechaduraId}'),
         headers: {
           'Authorization': 'Bearer $backendToken',
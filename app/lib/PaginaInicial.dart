@@ -196,7 +196,7 @@ class _InicialState extends State<Inicial> {
     final backendToken = await LocalService.getBackendToken();
     if (backendToken == null) return;
     try {
-      final response = await http.get(
+      final response = await LocalService.client.get(
         Uri.parse('${LocalService.backendUrl}/devices'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -564,7 +564,7 @@ class _InicialState extends State<Inicial> {
                                       final backendToken =
                                           await LocalService.getBackendToken();
                                       if (backendToken != null) {
-                                        final response = await http.post(
+                                        final response = await LocalService.client.post(
                                           Uri.parse(
                                             '${LocalService.backendUrl}/unpair/${cartao['id']}',
                                           ),
@@ -658,6 +658,8 @@ class _InicialState extends State<Inicial> {
     IconData iconeSelecionado = Icons.star;
     selectedWifiNetwork = null;
     wifiPassword = '';
+    // Request the pairing code while still connected to the internet
+    final Future<String?> codigoPareamento = LocalService.getPairingCode();
 
     showDialog(
       context: context,
@@ -894,9 +896,18 @@ class _InicialState extends State<Inicial> {
                                                 () => isPairing = true,
                                               );
                                               try {
+                                                // The device presents the pairing code to the backend
+                                                final codigo =
+                                                    await codigoPareamento;
+                                                if (codigo == null) {
+                                                  throw Exception(
+                                                    'Pairing code request failed',
+                                                  );
+                                                }
+
                                                 // First, send configuration to device
                                                 String configData =
-                                                    '${widget.usuarioId}\n$selectedWifiNetwork\n$wifiPassword';
+                                                    '$codigo\n$selectedWifiNetwork\n$wifiPassword';
 
                                                 var response = await http.post(
                                                   Uri.parse(
@@ -1173,7 +1184,7 @@ class _InicialState extends State<Inicial> {
       final backendToken = await LocalService.getBackendToken();
       if (backendToken != null && cartoes.isNotEmpty) {
         try {
-          final response = await http.get(
+          final response = await LocalService.client.get(
             Uri.parse('${LocalService.backendUrl}/devices'),
             headers: {'Authorization': 'Bearer $backendToken'},
           );
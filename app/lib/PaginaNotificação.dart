@@ -3,7 +3,6 @@ import 'LocalService.dart';
 import 'dart:ui';
 import 'main.dart';
 import 'package:cloud_firestore/cloud_firestore.dart';
-import 'package:http/http.dart' as http;
 import 'dart:convert';
 import 'dart:async';
 import 'package:web_socket_channel/web_socket_channel.dart';
@@ -174,7 +173,7 @@ class _NotificacaoState extends State<Notificacao>
 
       // Fetch notifications from backend, filtered by enabled devices
       final queryParams = '?devices=${enabledDeviceIds.join(',')}';
-      final notificationsResponse = await http.get(
+      final notificationsResponse = await LocalService.client.get(
         Uri.parse('${LocalService.backendUrl}/notifications$queryParams'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -299,7 +298,7 @@ class _NotificacaoState extends State<Notificacao>
 
       // Fetch notifications from backend, filtered by enabled devices
       final queryParams = '?devices=${enabledDeviceIds.join(',')}';
-      final notificationsResponse = await http.get(
+      final notificationsResponse = await LocalService.client.get(
         Uri.parse('${LocalService.backendUrl}/notifications$queryParams'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -5,7 +5,6 @@ import 'dart:convert';
 import 'dart:async';
 import 'PaginaDetalhe.dart';
 import 'package:cloud_firestore/cloud_firestore.dart';
-import 'package:http/http.dart' as http;
 import 'package:web_socket_channel/web_socket_channel.dart';
 import 'package:web_socket_channel/status.dart' as status;
 
@@ -108,7 +107,7 @@ class _TemporariaState extends State<Temporaria> with WidgetsBindingObserver {
       if (_usuario != null) {
         final backendToken = await LocalService.getBackendToken();
         if (backendToken != null) {
-          final response = await http.get(
+          final response = await LocalService.client.get(
             Uri.parse('${LocalService.backendUrl}/accessible_devices'),
             headers: {'Authorization': 'Bearer $backendToken'},
           );
@@ -192,7 +191,7 @@ class _TemporariaState extends State<Temporaria> with WidgetsBindingObserver {
     final backendToken = await LocalService.getBackendToken();
     if (backendToken == null) return;
     try {
-      final response = await http.get(
+      final response = await LocalService.client.get(
         Uri.parse('${LocalService.backendUrl}/temp_devices_status'),
         headers: {'Authorization': 'Bearer $backendToken'},
       );
@@ -272,7 +271,7 @@ class _TemporariaState extends State<Temporaria> with WidgetsBindingObserver {
       if (_usuario != null) {
         final backendToken = await LocalService.getBackendToken();
         if (backendToken != null) {
-          final response = await http.get(
+          final response = await LocalService.client.get(
             Uri.parse('${LocalService.backendUrl}/accessible_devices'),
             headers: {'Authorization': 'Bearer $backendToken'},
           );
@@ -918,7 +917,7 @@ class _TemporaryDeviceDialogState extends State<_TemporaryDeviceDialog>
     if (backendToken == null) return;
 
     final start = DateTime.now().millisecondsSinceEpoch;
-    final pingResponse = await http.post(
+    final pingResponse = await LocalService.client.post(
       Uri.parse('${LocalService.backendUrl}/temp_ping/${widget.deviceId}'),
       headers: {'Authorization': 'Bearer $backendToken'},
     );
@@ -940,7 +939,7 @@ class _TemporaryDeviceDialogState extends State<_TemporaryDeviceDialog>
     try {
       final backendToken = await LocalService.getBackendToken();
       if (backendToken != null) {
-        final deviceResponse = await http.get(
+        final deviceResponse = await LocalService.client.get(
           Uri.parse(
             '${LocalService.backendUrl}/temp_device/${widget.deviceId}',
           ),
@@ -1036,7 +1035,7 @@ class _TemporaryDeviceDialogState extends State<_TemporaryDeviceDialog>
 
       final command = acao == 'Abrir' ? 'UNLOCK' : 'LOCK';
       final url = '${LocalService.backendUrl}/temp_control/${widget.deviceId}';
-      final response = await http.post(
+      final response = await LocalService.client.post(
         Uri.parse(url),
         headers: {
           'Authorization': 'Bearer $backendToken',
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS invites;
+DROP TABLE IF EXISTS logs;
+DROP TABLE IF EXISTS users;
+DROP TABLE IF EXISTS devices;
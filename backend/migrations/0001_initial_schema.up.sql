@@ -0,0 +1,65 @@
+-- Esquema inicial do LockWise.
+--
+-- Escrito de forma idempotente para que bancos criados antes do sistema de
+-- migrações (que criava as tabelas diretamente na inicialização) possam
+-- adotá-lo sem perda de dados.
+
+CREATE TABLE IF NOT EXISTS devices (
+    uuid uuid PRIMARY KEY,
+    user_id VARCHAR(255),
+    last_heard timestamptz NOT NULL,
+    uptime_ms bigint NOT NULL,
+    hashed_passphrase VARCHAR(255),
+    locked_down_at timestamptz
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    firebase_uid VARCHAR(255) PRIMARY KEY,
+    hashed_password VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    phone_number VARCHAR(255),
+    name VARCHAR(255) NOT NULL,
+    current_token VARCHAR(255),
+    voice_embeddings BYTEA,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    last_login timestamptz
+);
+
+CREATE TABLE IF NOT EXISTS logs (
+    id SERIAL PRIMARY KEY,
+    device_id VARCHAR(255) NOT NULL,
+    timestamp timestamptz NOT NULL DEFAULT NOW(),
+    event_type VARCHAR(10) NOT NULL,
+    reason VARCHAR(20) NOT NULL,
+    user_id VARCHAR(255),
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS invites (
+    id SERIAL PRIMARY KEY,
+    device_id UUID NOT NULL,
+    sender_id VARCHAR(255) NOT NULL,
+    receiver_id VARCHAR(255) NOT NULL,
+    status INTEGER NOT NULL DEFAULT 0,
+    expiry_timestamp BIGINT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    FOREIGN KEY (device_id) REFERENCES devices(uuid) ON DELETE CASCADE
+);
+
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS user_id VARCHAR(255);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS hashed_passphrase VARCHAR(255);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS wifi_ssid VARCHAR(255);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS backend_url VARCHAR(255);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_broker_url VARCHAR(255);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_heartbeat_enable BOOLEAN;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_heartbeat_interval_sec INTEGER;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS audio_record_timeout_sec INTEGER;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS lock_timeout_ms INTEGER;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS lock_state VARCHAR(10);
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS pairing_timeout_sec INTEGER;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS locked_down_at timestamptz;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_detection_enable BOOLEAN DEFAULT true;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_invite_enable BOOLEAN DEFAULT true;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_threshold FLOAT8 DEFAULT 0.60;
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS vad_rms_threshold INTEGER DEFAULT 1000;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS voice_embeddings BYTEA;
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS current_token VARCHAR(255);
+
+DROP TABLE IF EXISTS sessions;
@@ -0,0 +1,19 @@
+-- Sessões de usuário com tokens de atualização rotativos, substituindo o token
+-- único em users.current_token (que impedia sessões em mais de um aparelho).
+
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    refresh_token_hash VARCHAR(64) NOT NULL UNIQUE,
+    previous_refresh_token_hash VARCHAR(64),
+    device_name VARCHAR(255),
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    last_used_at timestamptz NOT NULL DEFAULT NOW(),
+    expires_at timestamptz NOT NULL,
+    revoked_at timestamptz
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+CREATE INDEX sessions_previous_refresh_token_hash_idx ON sessions (previous_refresh_token_hash);
+
+ALTER TABLE users DROP COLUMN IF EXISTS current_token;
@@ -0,0 +1,15 @@
+-- Membros de dispositivos com papéis (admin, resident, guest), substituindo as
+-- verificações ad hoc de proprietário/convite. O proprietário continua sendo
+-- devices.user_id (informado também pelo heartbeat do dispositivo) e não é
+-- armazenado aqui; convites aceitos e não expirados equivalem ao papel guest.
+
+CREATE TABLE device_members (
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    user_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    role VARCHAR(16) NOT NULL CHECK (role IN ('admin', 'resident', 'guest')),
+    added_by VARCHAR(255),
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, user_id)
+);
+
+CREATE INDEX device_members_user_id_idx ON device_members (user_id);
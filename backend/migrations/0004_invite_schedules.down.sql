@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS invite_windows;
+
+ALTER TABLE invites DROP COLUMN IF EXISTS timezone;
+ALTER TABLE invites DROP COLUMN IF EXISTS starts_at;
@@ -0,0 +1,19 @@
+-- Janelas semanais recorrentes para convites (ex.: dias úteis das 08:00 às 18:00),
+-- com fuso horário e data de início opcional. Convites sem janelas continuam
+-- válidos a qualquer hora até expiry_timestamp.
+
+ALTER TABLE invites ADD COLUMN starts_at BIGINT;
+ALTER TABLE invites ADD COLUMN timezone VARCHAR(64);
+
+CREATE TABLE invite_windows (
+    id SERIAL PRIMARY KEY,
+    invite_id INTEGER NOT NULL REFERENCES invites(id) ON DELETE CASCADE,
+    -- Dias da semana como máscara de bits (bit 0 = segunda-feira ... bit 6 = domingo)
+    days SMALLINT NOT NULL CHECK (days > 0 AND days < 128),
+    -- Minutos desde a meia-noite no fuso do convite; end_minute <= start_minute
+    -- indica uma janela que atravessa a meia-noite
+    start_minute SMALLINT NOT NULL CHECK (start_minute >= 0 AND start_minute < 1440),
+    end_minute SMALLINT NOT NULL CHECK (end_minute >= 0 AND end_minute <= 1440)
+);
+
+CREATE INDEX invite_windows_invite_id_idx ON invite_windows (invite_id);
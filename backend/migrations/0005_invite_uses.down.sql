@@ -0,0 +1,5 @@
+DELETE FROM invites WHERE status = 2;
+
+ALTER TABLE invites DROP COLUMN IF EXISTS exhausted_at;
+ALTER TABLE invites DROP COLUMN IF EXISTS remaining_uses;
+ALTER TABLE invites DROP COLUMN IF EXISTS max_uses;
@@ -0,0 +1,7 @@
+-- Convites de uso limitado (ex.: entregador que pode abrir a porta uma vez).
+-- remaining_uses é decrementado a cada destrancamento; ao chegar a zero, o
+-- convite passa para o status 2 (esgotado). NULL indica usos ilimitados.
+
+ALTER TABLE invites ADD COLUMN max_uses INTEGER CHECK (max_uses > 0);
+ALTER TABLE invites ADD COLUMN remaining_uses INTEGER CHECK (remaining_uses >= 0);
+ALTER TABLE invites ADD COLUMN exhausted_at timestamptz;
@@ -0,0 +1,7 @@
+DELETE FROM invites WHERE receiver_id IS NULL;
+
+ALTER TABLE invites DROP COLUMN IF EXISTS code_revoked_at;
+ALTER TABLE invites DROP COLUMN IF EXISTS code_redeemed_at;
+ALTER TABLE invites DROP COLUMN IF EXISTS code_expires_at;
+ALTER TABLE invites DROP COLUMN IF EXISTS code_hash;
+ALTER TABLE invites ALTER COLUMN receiver_id SET NOT NULL;
@@ -0,0 +1,9 @@
+-- Convites resgatáveis por código assinado, para destinatários que ainda não têm
+-- conta. O convite é criado sem destinatário e recebe o receiver_id ao ser
+-- resgatado; o código é de uso único, com expiração e revogação próprias.
+
+ALTER TABLE invites ALTER COLUMN receiver_id DROP NOT NULL;
+ALTER TABLE invites ADD COLUMN code_hash VARCHAR(64) UNIQUE;
+ALTER TABLE invites ADD COLUMN code_expires_at BIGINT;
+ALTER TABLE invites ADD COLUMN code_redeemed_at timestamptz;
+ALTER TABLE invites ADD COLUMN code_revoked_at timestamptz;
@@ -0,0 +1,14 @@
+-- Códigos de pareamento de uso único. O dispositivo apresenta o código no
+-- heartbeat e passa a pertencer ao usuário que o solicitou; o heartbeat deixa de
+-- definir devices.user_id diretamente.
+
+CREATE TABLE pairing_codes (
+    id SERIAL PRIMARY KEY,
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    user_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    device_id UUID,
+    hashed_passphrase VARCHAR(255),
+    expires_at timestamptz NOT NULL,
+    claimed_at timestamptz,
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS archived_logs;
+DROP TABLE IF EXISTS device_transfers;
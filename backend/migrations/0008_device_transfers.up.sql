@@ -0,0 +1,32 @@
+-- Transferências de propriedade de dispositivos entre usuários, e logs arquivados
+-- sob o proprietário anterior quando a transferência pede o arquivamento do
+-- histórico.
+
+CREATE TABLE device_transfers (
+    id SERIAL PRIMARY KEY,
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    from_user_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    to_user_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    keep_invites BOOLEAN NOT NULL DEFAULT FALSE,
+    history VARCHAR(16) NOT NULL DEFAULT 'keep' CHECK (history IN ('keep', 'archive')),
+    -- 0: pendente, 1: aceita, 2: rejeitada, 3: cancelada
+    status SMALLINT NOT NULL DEFAULT 0,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    resolved_at timestamptz
+);
+
+CREATE UNIQUE INDEX device_transfers_pending_idx ON device_transfers (device_id) WHERE status = 0;
+CREATE INDEX device_transfers_to_user_id_idx ON device_transfers (to_user_id);
+
+CREATE TABLE archived_logs (
+    id INTEGER PRIMARY KEY,
+    device_id VARCHAR(255) NOT NULL,
+    owner_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    timestamp timestamptz NOT NULL,
+    event_type VARCHAR(10) NOT NULL,
+    reason VARCHAR(20) NOT NULL,
+    user_id VARCHAR(255),
+    archived_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX archived_logs_owner_device_idx ON archived_logs (owner_id, device_id);
@@ -0,0 +1,19 @@
+-- Comandos LOCK/UNLOCK com ID, ecoado pelo dispositivo no evento de fechadura,
+-- para atribuição confiável e confirmação. A chave de idempotência é única por
+-- dispositivo e usuário.
+
+CREATE TABLE device_commands (
+    id UUID PRIMARY KEY,
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    user_id VARCHAR(255) REFERENCES users(firebase_uid) ON DELETE SET NULL,
+    command VARCHAR(32) NOT NULL,
+    idempotency_key VARCHAR(255),
+    status VARCHAR(16) NOT NULL DEFAULT 'SENT' CHECK (status IN ('SENT', 'APPLIED', 'TIMEOUT')),
+    lock_state VARCHAR(16),
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    acked_at timestamptz
+);
+
+CREATE UNIQUE INDEX device_commands_idempotency_idx
+    ON device_commands (device_id, user_id, idempotency_key)
+    WHERE idempotency_key IS NOT NULL;
@@ -0,0 +1,18 @@
+-- Histórico unificado de eventos: toda mensagem recebida dos dispositivos e toda
+-- ação do back-end sobre eles, com um tipo e um payload JSON. Sem chave estrangeira
+-- para devices: mensagens chegam antes do primeiro pareamento.
+
+CREATE TABLE device_events (
+    id BIGSERIAL PRIMARY KEY,
+    device_id UUID NOT NULL,
+    kind VARCHAR(64) NOT NULL,
+    source VARCHAR(16) NOT NULL CHECK (source IN ('DEVICE', 'BACKEND')),
+    payload JSONB NOT NULL DEFAULT '{}',
+    user_id VARCHAR(255) REFERENCES users(firebase_uid) ON DELETE SET NULL,
+    device_timestamp BIGINT,
+    uptime_ms BIGINT,
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX device_events_device_idx ON device_events (device_id, id DESC);
+CREATE INDEX device_events_created_at_idx ON device_events (created_at);
@@ -0,0 +1,9 @@
+DROP INDEX IF EXISTS device_commands_pending_idx;
+ALTER TABLE device_commands DROP CONSTRAINT device_commands_status_check;
+UPDATE device_commands SET status = 'TIMEOUT' WHERE status IN ('QUEUED', 'EXPIRED');
+ALTER TABLE device_commands
+    ALTER COLUMN status SET DEFAULT 'SENT',
+    ADD CONSTRAINT device_commands_status_check CHECK (status IN ('SENT', 'APPLIED', 'TIMEOUT')),
+    DROP COLUMN attempts,
+    DROP COLUMN last_attempt_at,
+    DROP COLUMN expires_at;
@@ -0,0 +1,17 @@
+-- Fila de saída de comandos: cada comando tem validade e é reenviado até ser
+-- confirmado pelo dispositivo ou expirar. QUEUED: ainda não enviado ao broker;
+-- SENT: enviado, aguardando confirmação; APPLIED: confirmado; EXPIRED: validade
+-- esgotada sem confirmação (substitui TIMEOUT).
+
+ALTER TABLE device_commands DROP CONSTRAINT device_commands_status_check;
+UPDATE device_commands SET status = 'EXPIRED' WHERE status = 'TIMEOUT';
+ALTER TABLE device_commands
+    ALTER COLUMN status SET DEFAULT 'QUEUED',
+    ADD CONSTRAINT device_commands_status_check CHECK (status IN ('QUEUED', 'SENT', 'APPLIED', 'EXPIRED')),
+    ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN last_attempt_at timestamptz,
+    ADD COLUMN expires_at timestamptz NOT NULL DEFAULT NOW();
+
+CREATE INDEX device_commands_pending_idx
+    ON device_commands (device_id, created_at)
+    WHERE status IN ('QUEUED', 'SENT');
@@ -0,0 +1,2 @@
+ALTER TABLE pairing_codes DROP COLUMN IF EXISTS device_key;
+ALTER TABLE devices DROP COLUMN IF EXISTS device_key;
@@ -0,0 +1,5 @@
+-- Chave de cada dispositivo para o canal autenticado, derivada do código de
+-- pareamento. NULL para dispositivos pareados com firmware anterior.
+
+ALTER TABLE devices ADD COLUMN device_key BYTEA;
+ALTER TABLE pairing_codes ADD COLUMN device_key BYTEA;
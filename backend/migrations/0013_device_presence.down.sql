@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS device_outages;
+ALTER TABLE devices DROP COLUMN IF EXISTS online;
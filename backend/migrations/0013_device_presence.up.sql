@@ -0,0 +1,16 @@
+-- Presença dos dispositivos: estado online/offline mantido pelo watchdog de
+-- heartbeats e pela mensagem Last Will, e os intervalos em que cada dispositivo
+-- ficou offline. ended_at é NULL enquanto a queda está em andamento.
+
+ALTER TABLE devices ADD COLUMN online BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE device_outages (
+    id BIGSERIAL PRIMARY KEY,
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    started_at timestamptz NOT NULL,
+    ended_at timestamptz,
+    reason VARCHAR(32) NOT NULL CHECK (reason IN ('HEARTBEAT_TIMEOUT', 'LAST_WILL'))
+);
+
+CREATE INDEX device_outages_device_idx ON device_outages (device_id, started_at DESC);
+CREATE UNIQUE INDEX device_outages_open_idx ON device_outages (device_id) WHERE ended_at IS NULL;
@@ -0,0 +1,8 @@
+-- Credenciais MQTT individuais dos dispositivos, verificadas pelo broker embutido
+-- (feature embedded-broker). O usuário é o UUID do dispositivo.
+
+CREATE TABLE mqtt_credentials (
+    username VARCHAR(255) PRIMARY KEY,
+    password_hash VARCHAR(255) NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
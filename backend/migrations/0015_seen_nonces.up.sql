@@ -0,0 +1,11 @@
+-- Nonces das mensagens assinadas dos dispositivos, compartilhados entre as instâncias do
+-- back-end (COORDINATION=postgres) contra repetição de mensagens.
+
+CREATE TABLE seen_nonces (
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    nonce BIGINT NOT NULL,
+    sent_at timestamptz NOT NULL,
+    PRIMARY KEY (device_id, nonce)
+);
+
+CREATE INDEX seen_nonces_sent_at_idx ON seen_nonces (sent_at);
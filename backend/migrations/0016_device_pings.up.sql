@@ -0,0 +1,12 @@
+-- Histórico de pings dos dispositivos, para diagnóstico de conectividade. Pings sem
+-- resposta dentro do prazo têm rtt_ms nulo.
+
+CREATE TABLE device_pings (
+    id UUID PRIMARY KEY,
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    user_id VARCHAR(255),
+    sent_at timestamptz NOT NULL,
+    rtt_ms INTEGER
+);
+
+CREATE INDEX device_pings_device_sent_at_idx ON device_pings (device_id, sent_at DESC);
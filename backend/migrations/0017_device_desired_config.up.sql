@@ -0,0 +1,21 @@
+-- Configuração desejada dos dispositivos (device twin). O estado reportado vem do
+-- heartbeat, armazenado em devices; o reconciliador reenvia as chaves pendentes até que
+-- os dois convirjam. Valores sensíveis são apagados assim que aplicados.
+
+CREATE TABLE device_desired_config (
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    key VARCHAR(64) NOT NULL,
+    value TEXT,
+    status VARCHAR(16) NOT NULL DEFAULT 'PENDING',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    command_id UUID,
+    error VARCHAR(64),
+    requested_by VARCHAR(255),
+    updated_at timestamptz NOT NULL DEFAULT NOW(),
+    last_sent_at timestamptz,
+    applied_at timestamptz,
+    PRIMARY KEY (device_id, key)
+);
+
+CREATE INDEX device_desired_config_pending_idx ON device_desired_config (status, last_sent_at) WHERE status = 'PENDING';
+CREATE UNIQUE INDEX device_desired_config_command_idx ON device_desired_config (command_id);
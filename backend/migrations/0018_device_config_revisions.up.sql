@@ -0,0 +1,14 @@
+-- Histórico versionado da configuração dos dispositivos. Cada revisão guarda a
+-- configuração completa (exceto valores sensíveis), quem a alterou e a origem da
+-- alteração: o usuário, um heartbeat ou a reversão para uma revisão anterior.
+
+CREATE TABLE device_config_revisions (
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    config JSONB NOT NULL,
+    source VARCHAR(16) NOT NULL,
+    actor VARCHAR(255),
+    rollback_of INTEGER,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, revision)
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS device_config_profiles;
+DROP TABLE IF EXISTS config_profiles;
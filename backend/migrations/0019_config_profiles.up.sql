@@ -0,0 +1,25 @@
+-- Perfis de configuração: conjuntos nomeados de chaves de um usuário, atribuídos a
+-- vários dispositivos. Cada atribuição guarda as sobreposições do dispositivo e o
+-- resultado da última aplicação do perfil (rollout_error nulo se foi aplicado).
+
+CREATE TABLE config_profiles (
+    id UUID PRIMARY KEY,
+    owner_id VARCHAR(255) NOT NULL REFERENCES users(firebase_uid) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    config JSONB NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    updated_at timestamptz NOT NULL DEFAULT NOW(),
+    UNIQUE (owner_id, name)
+);
+
+CREATE TABLE device_config_profiles (
+    device_id UUID PRIMARY KEY REFERENCES devices(uuid) ON DELETE CASCADE,
+    profile_id UUID NOT NULL REFERENCES config_profiles(id) ON DELETE CASCADE,
+    overrides JSONB NOT NULL DEFAULT '{}',
+    assigned_by VARCHAR(255),
+    assigned_at timestamptz NOT NULL DEFAULT NOW(),
+    rollout_at timestamptz,
+    rollout_error VARCHAR(64)
+);
+
+CREATE INDEX device_config_profiles_profile_idx ON device_config_profiles (profile_id);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS ota_updates;
+DROP TABLE IF EXISTS ota_rollouts;
+DROP TABLE IF EXISTS firmware_releases;
+ALTER TABLE devices DROP COLUMN IF EXISTS firmware_version;
@@ -0,0 +1,49 @@
+-- Atualização de firmware pelo ar (OTA): versões assinadas de firmware, rollouts para
+-- um dispositivo, um perfil ou uma porcentagem da frota, e o progresso da atualização
+-- em cada dispositivo. devices.firmware_version é a versão informada no heartbeat.
+
+ALTER TABLE devices ADD COLUMN firmware_version VARCHAR(32);
+
+CREATE TABLE firmware_releases (
+    id UUID PRIMARY KEY,
+    version VARCHAR(32) NOT NULL UNIQUE,
+    image BYTEA NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 BYTEA NOT NULL,
+    signature BYTEA NOT NULL,
+    uploaded_by VARCHAR(255),
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE ota_rollouts (
+    id UUID PRIMARY KEY,
+    release_id UUID NOT NULL REFERENCES firmware_releases(id) ON DELETE CASCADE,
+    target VARCHAR(16) NOT NULL,
+    device_id UUID,
+    profile_id UUID,
+    percentage SMALLINT,
+    max_failure_percent SMALLINT NOT NULL DEFAULT 10,
+    status VARCHAR(16) NOT NULL DEFAULT 'RUNNING',
+    halted_reason VARCHAR(64),
+    created_by VARCHAR(255) NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    updated_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ota_rollouts_status_idx ON ota_rollouts (status);
+
+CREATE TABLE ota_updates (
+    rollout_id UUID NOT NULL REFERENCES ota_rollouts(id) ON DELETE CASCADE,
+    device_id UUID NOT NULL REFERENCES devices(uuid) ON DELETE CASCADE,
+    status VARCHAR(16) NOT NULL DEFAULT 'QUEUED',
+    progress SMALLINT NOT NULL DEFAULT 0,
+    command_id UUID UNIQUE,
+    error VARCHAR(64),
+    previous_version VARCHAR(32),
+    sent_at timestamptz,
+    installed_at timestamptz,
+    updated_at timestamptz NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (rollout_id, device_id)
+);
+
+CREATE INDEX ota_updates_device_idx ON ota_updates (device_id);
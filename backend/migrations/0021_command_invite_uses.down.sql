@@ -0,0 +1 @@
+ALTER TABLE device_commands DROP COLUMN IF EXISTS invite_id;
@@ -0,0 +1,4 @@
+-- Convite cujo uso foi consumido por um comando UNLOCK. O uso é consumido na mesma
+-- transação que registra o comando e devolvido se o comando expirar sem confirmação.
+
+ALTER TABLE device_commands ADD COLUMN invite_id INTEGER REFERENCES invites(id) ON DELETE SET NULL;
@@ -0,0 +1,6 @@
+-- Número de sequência das mensagens de controle assinadas, enviado no campo `n` do
+-- envelope. O dispositivo guarda o último aceito e descarta os iguais ou menores, o que
+-- impede a repetição de comandos capturados mesmo após reinicializações. Recomeça em
+-- zero a cada nova chave.
+
+ALTER TABLE devices ADD COLUMN control_seq BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS ota_updates_active_device_idx;
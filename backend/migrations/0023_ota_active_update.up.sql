@@ -0,0 +1,15 @@
+-- Garante no máximo uma atualização não concluída por dispositivo, mesmo com rollouts
+-- criados ao mesmo tempo em réplicas diferentes. Duplicatas já existentes são
+-- canceladas, mantendo a mais antiga de cada dispositivo.
+
+UPDATE ota_updates u SET status = 'CANCELLED', updated_at = NOW()
+WHERE u.status IN ('QUEUED', 'SENT', 'DOWNLOADING', 'INSTALLED')
+AND EXISTS (
+    SELECT 1 FROM ota_updates o
+    WHERE o.device_id = u.device_id
+    AND o.status IN ('QUEUED', 'SENT', 'DOWNLOADING', 'INSTALLED')
+    AND (o.updated_at, o.rollout_id) < (u.updated_at, u.rollout_id)
+);
+
+CREATE UNIQUE INDEX ota_updates_active_device_idx ON ota_updates (device_id)
+WHERE status IN ('QUEUED', 'SENT', 'DOWNLOADING', 'INSTALLED');
@@ -0,0 +1,4 @@
+ALTER TABLE pairing_codes ADD COLUMN IF NOT EXISTS device_key BYTEA;
+
+ALTER TABLE devices DROP COLUMN IF EXISTS rekey_requested_at;
+ALTER TABLE devices DROP COLUMN IF EXISTS pending_device_key;
@@ -0,0 +1,12 @@
+-- A chave do dispositivo passa a ser acordada por ECDH P-256 entre uma chave gerada pelo
+-- dispositivo e a chave do back-end (DEVICE_KEY_EXCHANGE_PRIVATE_KEY), e não mais derivada
+-- apenas do código de pareamento. Uma troca de chave pedida ao dispositivo (ex.: após uma
+-- transferência) fica em `rekey_requested_at` até ele oferecer a nova chave, que fica em
+-- `pending_device_key` até ser usada pela primeira vez. Chaves derivadas de códigos são
+-- trocadas na próxima conexão de cada dispositivo.
+
+ALTER TABLE devices ADD COLUMN pending_device_key BYTEA;
+ALTER TABLE devices ADD COLUMN rekey_requested_at timestamptz;
+UPDATE devices SET rekey_requested_at = NOW() WHERE device_key IS NOT NULL;
+
+ALTER TABLE pairing_codes DROP COLUMN device_key;
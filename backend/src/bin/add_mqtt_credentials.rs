@@ -0,0 +1,69 @@
+/// Ferramenta para criar ou renovar as credenciais MQTT de um dispositivo no broker embutido.
+/// Esta ferramenta conecta ao banco de dados, gera uma senha aleatória para o UUID informado e armazena seu hash.
+use anyhow::Result;
+use argon2::password_hash::rand_core::RngCore;
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use argon2::{Argon2, PasswordHasher};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use sqlx::ConnectOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+use std::env;
+use std::io::{self, Write};
+use url::Url;
+use uuid::Uuid;
+
+/// Função principal do utilitário add_mqtt_credentials.
+/// Carrega variáveis de ambiente, conecta ao banco de dados e solicita o UUID do dispositivo.
+/// Gera uma senha, armazena seu hash e a exibe para ser gravada no dispositivo.
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+
+    // Load DB URL
+    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    // Setup DB
+    let url = Url::parse(&db_url)?;
+    let options = PgConnectOptions::from_url(&url)?.ssl_mode(PgSslMode::Require);
+    let db_pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect_with(options)
+        .await?;
+
+    // Prompt for the device
+    print!("Enter the UUID of the device: ");
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let uuid = Uuid::parse_str(input.trim()).map_err(|_| anyhow::anyhow!("Invalid UUID"))?;
+
+    // Generate the password
+    let mut secret = [0u8; 96];
+    OsRng.fill_bytes(&mut secret);
+    let password = URL_SAFE_NO_PAD.encode(secret);
+
+    // Hash the password
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::default();
+    let password_hash = argon2
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string();
+
+    // Update DB
+    sqlx::query(
+        "INSERT INTO mqtt_credentials (username, password_hash) VALUES ($1, $2)
+         ON CONFLICT (username) DO UPDATE SET password_hash = $2, created_at = NOW()",
+    )
+    .bind(uuid.to_string())
+    .bind(password_hash)
+    .execute(&db_pool)
+    .await?;
+
+    println!("MQTT credentials set for device {}.", uuid);
+    println!("Username: {}", uuid);
+    println!("Password: {}", password);
+
+    Ok(())
+}
@@ -0,0 +1,1039 @@
+//! Módulo do broker MQTT embutido (feature `embedded-broker`).
+//!
+//! Para instalações pequenas, o back-end pode executar o próprio broker MQTT 3.1.1 em vez
+//! de depender de um broker externo: o cliente do back-end se conecta a ele pela
+//! interface local e os dispositivos se conectam diretamente, com o UUID como usuário e
+//! credenciais individuais guardadas em `mqtt_credentials` (ver o utilitário
+//! `add_mqtt_credentials`). Cada dispositivo só pode publicar em
+//! `lockwise/<id>/status` e `lockwise/<id>/presence` e se inscrever em
+//! `lockwise/<id>/control`; o back-end acessa todos os tópicos `lockwise/#`.
+//!
+//! O broker suporta QoS 0 e 1, mensagens retidas, Last Will e sessões persistentes
+//! (`clean_session = false`), cujas mensagens QoS 1 são guardadas em memória enquanto o
+//! cliente está desconectado. Não há retransmissão de mensagens não confirmadas dentro
+//! de uma conexão nem QoS 2, e o estado não sobrevive a um reinício do back-end.
+//!
+//! As mensagens a enviar a cada conexão passam por uma fila limitada: um cliente que não
+//! as consome a tempo é desconectado, e as mensagens QoS 1 seguintes de uma sessão
+//! persistente ficam guardadas até a reconexão.
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use bytes::BytesMut;
+use rumqttc::mqttbytes::v4::{self, Packet};
+use rumqttc::mqttbytes::{self, matches, valid_filter, valid_topic};
+use rumqttc::tokio_rustls::TlsAcceptor;
+use rumqttc::tokio_rustls::rustls::ServerConfig;
+use rumqttc::{
+    ConnAck, ConnectReturnCode, LastWill, PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+    UnsubAck,
+};
+use sqlx::PgPool;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+/// Usuário reservado ao cliente MQTT do próprio back-end.
+pub const BACKEND_USERNAME: &str = "backend";
+/// Tamanho máximo de um pacote MQTT aceito, em bytes.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+/// Número máximo de mensagens guardadas para uma sessão persistente desconectada.
+const MAX_QUEUED_MESSAGES: usize = 1000;
+/// Número máximo de mensagens aguardando envio em uma conexão.
+const MAX_PENDING_MESSAGES: usize = 256;
+/// Prazo para o cliente enviar o CONNECT após abrir a conexão.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Menor de dois níveis de QoS.
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) <= (b as u8) { a } else { b }
+}
+
+/// Configuração do broker embutido, lida das variáveis de ambiente.
+pub struct BrokerConfig {
+    /// Endereço do listener TCP (`MQTT_BROKER_BIND`, padrão `0.0.0.0:1883`).
+    bind: SocketAddr,
+    /// Endereço do listener TLS (`MQTT_BROKER_TLS_BIND`), se habilitado.
+    tls_bind: Option<SocketAddr>,
+    /// Certificado (`MQTT_BROKER_TLS_CERT`) e chave privada (`MQTT_BROKER_TLS_KEY`) em PEM.
+    tls_files: Option<(String, String)>,
+    /// Senha do cliente do back-end, gerada a cada inicialização.
+    backend_password: String,
+}
+
+impl BrokerConfig {
+    /// Lê a configuração das variáveis de ambiente.
+    pub fn from_env() -> Result<Self> {
+        let bind = env::var("MQTT_BROKER_BIND")
+            .unwrap_or("0.0.0.0:1883".to_string())
+            .parse()
+            .context("invalid MQTT_BROKER_BIND")?;
+        let tls_bind = env::var("MQTT_BROKER_TLS_BIND")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("invalid MQTT_BROKER_TLS_BIND")?;
+        let tls_files = match (
+            env::var("MQTT_BROKER_TLS_CERT"),
+            env::var("MQTT_BROKER_TLS_KEY"),
+        ) {
+            (Ok(cert), Ok(key)) => Some((cert, key)),
+            _ if tls_bind.is_some() => {
+                bail!("MQTT_BROKER_TLS_BIND requires MQTT_BROKER_TLS_CERT and MQTT_BROKER_TLS_KEY")
+            }
+            _ => None,
+        };
+        let mut secret = [0u8; 32];
+        argon2::password_hash::rand_core::RngCore::fill_bytes(
+            &mut argon2::password_hash::rand_core::OsRng,
+            &mut secret,
+        );
+        Ok(BrokerConfig {
+            bind,
+            tls_bind,
+            tls_files,
+            backend_password: hex::encode(secret),
+        })
+    }
+
+    /// Endereço ao qual o cliente do back-end se conecta: o listener TCP, pela interface
+    /// local se ele escutar em todas as interfaces.
+    pub fn client_addr(&self) -> SocketAddr {
+        if self.bind.ip().is_unspecified() {
+            SocketAddr::from(([127, 0, 0, 1], self.bind.port()))
+        } else {
+            self.bind
+        }
+    }
+
+    /// Senha do cliente do back-end.
+    pub fn backend_password(&self) -> &str {
+        &self.backend_password
+    }
+}
+
+/// Papel de um cliente conectado, que define os tópicos que ele pode acessar.
+#[derive(Clone)]
+enum Role {
+    /// Cliente do back-end: todos os tópicos `lockwise/#`.
+    Backend,
+    /// Dispositivo: apenas os próprios tópicos.
+    Device(Uuid),
+}
+
+impl Role {
+    /// Indica se o cliente pode publicar no tópico.
+    fn can_publish(&self, topic: &str) -> bool {
+        match self {
+            Role::Backend => topic.starts_with("lockwise/"),
+            Role::Device(uuid) => {
+                topic == format!("lockwise/{}/status", uuid)
+                    || topic == format!("lockwise/{}/presence", uuid)
+            }
+        }
+    }
+
+    /// Indica se o cliente pode se inscrever no filtro.
+    fn can_subscribe(&self, filter: &str) -> bool {
+        match self {
+            Role::Backend => filter.starts_with("lockwise/"),
+            Role::Device(uuid) => filter == format!("lockwise/{}/control", uuid),
+        }
+    }
+}
+
+/// Sessão de um cliente, identificada pelo ID de cliente.
+struct Session {
+    /// Filtros inscritos, com o QoS concedido.
+    subscriptions: Vec<(String, QoS)>,
+    /// Canal da conexão ativa, ou `None` se o cliente está desconectado.
+    tx: Option<mpsc::Sender<Publish>>,
+    /// ID da conexão ativa, para que uma conexão substituída não altere a sessão.
+    connection_id: u64,
+    /// Se a sessão é descartada ao desconectar.
+    clean: bool,
+    /// Mensagens QoS 1 recebidas enquanto o cliente estava desconectado.
+    queue: VecDeque<Publish>,
+}
+
+/// Estado compartilhado do broker.
+struct Broker {
+    /// Pool do banco, para verificar as credenciais dos dispositivos.
+    db_pool: PgPool,
+    /// Senha do cliente do back-end.
+    backend_password: String,
+    /// Sessões por ID de cliente.
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Mensagens retidas por tópico.
+    retained: Mutex<HashMap<String, Publish>>,
+    /// Próximo ID de conexão.
+    next_connection_id: AtomicU64,
+}
+
+/// Inicia o broker embutido, retornando após abrir os listeners.
+pub async fn start(db_pool: PgPool, config: &BrokerConfig) -> Result<()> {
+    let broker = Arc::new(Broker {
+        db_pool,
+        backend_password: config.backend_password.clone(),
+        sessions: Mutex::new(HashMap::new()),
+        retained: Mutex::new(HashMap::new()),
+        next_connection_id: AtomicU64::new(1),
+    });
+
+    let listener = TcpListener::bind(config.bind)
+        .await
+        .with_context(|| format!("failed to bind MQTT broker to {}", config.bind))?;
+    println!("DEBUG: Embedded MQTT broker listening on {}", config.bind);
+    let tcp_broker = broker.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let broker = tcp_broker.clone();
+                    tokio::spawn(async move { serve(broker, stream, addr).await });
+                }
+                Err(e) => println!("DEBUG: MQTT broker accept error: {}", e),
+            }
+        }
+    });
+
+    if let (Some(tls_bind), Some((cert, key))) = (config.tls_bind, &config.tls_files) {
+        let acceptor = tls_acceptor(cert, key)?;
+        let listener = TcpListener::bind(tls_bind)
+            .await
+            .with_context(|| format!("failed to bind MQTT broker to {}", tls_bind))?;
+        println!(
+            "DEBUG: Embedded MQTT broker listening on {} (TLS)",
+            tls_bind
+        );
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let broker = broker.clone();
+                        let acceptor = acceptor.clone();
+                        tokio::spawn(async move {
+                            match acceptor.accept(stream).await {
+                                Ok(stream) => serve(broker, stream, addr).await,
+                                Err(e) => {
+                                    println!("DEBUG: TLS handshake with {} failed: {}", addr, e)
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => println!("DEBUG: MQTT broker accept error: {}", e),
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Carrega o certificado e a chave privada do listener TLS.
+fn tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let mut cert_reader = std::io::BufReader::new(
+        std::fs::File::open(cert_path).with_context(|| format!("failed to open {}", cert_path))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    let mut key_reader = std::io::BufReader::new(
+        std::fs::File::open(key_path).with_context(|| format!("failed to open {}", key_path))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Atende uma conexão até que ela seja encerrada.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    broker: Arc<Broker>,
+    stream: S,
+    addr: SocketAddr,
+) {
+    if let Err(e) = handle_connection(&broker, stream).await {
+        println!("DEBUG: MQTT connection from {} closed: {}", addr, e);
+    }
+}
+
+/// Lê o próximo pacote da conexão, aguardando mais bytes se necessário.
+async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut BytesMut,
+) -> Result<Packet> {
+    loop {
+        match v4::read(buffer, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(packet),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                if reader.read_buf(buffer).await? == 0 {
+                    bail!("connection closed");
+                }
+            }
+            Err(e) => bail!("malformed packet: {}", e),
+        }
+    }
+}
+
+/// Escreve um pacote na conexão.
+async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: Packet) -> Result<()> {
+    let mut buffer = BytesMut::new();
+    match packet {
+        Packet::ConnAck(p) => p.write(&mut buffer),
+        Packet::Publish(p) => p.write(&mut buffer),
+        Packet::PubAck(p) => p.write(&mut buffer),
+        Packet::SubAck(p) => p.write(&mut buffer),
+        Packet::UnsubAck(p) => p.write(&mut buffer),
+        Packet::PingResp => v4::PingResp.write(&mut buffer),
+        _ => return Ok(()),
+    }
+    .map_err(|e| anyhow!("failed to encode packet: {}", e))?;
+    writer.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Autentica o cliente, retornando seu papel, ou `None` se as credenciais forem inválidas.
+async fn authenticate(broker: &Broker, client_id: &str, login: Option<&v4::Login>) -> Option<Role> {
+    let login = login?;
+    if login.username == BACKEND_USERNAME {
+        let valid = login.password.len() == broker.backend_password.len()
+            && login
+                .password
+                .bytes()
+                .zip(broker.backend_password.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        return valid.then_some(Role::Backend);
+    }
+
+    // Devices authenticate as themselves, with their UUID as client ID and username
+    let uuid = Uuid::parse_str(&login.username).ok()?;
+    if client_id != login.username {
+        return None;
+    }
+    let (hash,): (String,) =
+        sqlx::query_as("SELECT password_hash FROM mqtt_credentials WHERE username = $1")
+            .bind(&login.username)
+            .fetch_optional(&broker.db_pool)
+            .await
+            .ok()??;
+    let password = login.password.clone();
+    let valid = tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .map(|parsed| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false);
+    valid.then_some(Role::Device(uuid))
+}
+
+/// Trata uma conexão: CONNECT, autenticação e troca de pacotes até a desconexão.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    broker: &Broker,
+    stream: S,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    let connect =
+        match tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buffer)).await {
+            Ok(Ok(Packet::Connect(connect))) => connect,
+            Ok(Ok(_)) => bail!("expected CONNECT"),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => bail!("timed out waiting for CONNECT"),
+        };
+
+    let Some(role) = authenticate(broker, &connect.client_id, connect.login.as_ref()).await else {
+        write_packet(
+            &mut writer,
+            Packet::ConnAck(ConnAck::new(ConnectReturnCode::BadUserNamePassword, false)),
+        )
+        .await?;
+        bail!("bad credentials for client {}", connect.client_id);
+    };
+    run_session(broker, reader, writer, buffer, connect, role).await
+}
+
+/// Atende a sessão de um cliente autenticado, do CONNACK até a desconexão.
+async fn run_session<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    broker: &Broker,
+    mut reader: R,
+    mut writer: W,
+    mut buffer: BytesMut,
+    connect: v4::Connect,
+    role: Role,
+) -> Result<()> {
+    if connect
+        .last_will
+        .as_ref()
+        .is_some_and(|will| !role.can_publish(&will.topic) || !valid_topic(&will.topic))
+    {
+        write_packet(
+            &mut writer,
+            Packet::ConnAck(ConnAck::new(ConnectReturnCode::NotAuthorized, false)),
+        )
+        .await?;
+        bail!(
+            "last will topic not allowed for client {}",
+            connect.client_id
+        );
+    }
+
+    // Register the connection, taking over any previous one with the same client ID
+    let client_id = connect.client_id.clone();
+    let connection_id = broker.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel(MAX_PENDING_MESSAGES);
+    let (session_present, queued) = {
+        let mut sessions = broker.sessions.lock().unwrap();
+        if connect.clean_session {
+            sessions.remove(&client_id);
+        }
+        let session_present = sessions.contains_key(&client_id);
+        let session = sessions
+            .entry(client_id.clone())
+            .or_insert_with(|| Session {
+                subscriptions: Vec::new(),
+                tx: None,
+                connection_id,
+                clean: connect.clean_session,
+                queue: VecDeque::new(),
+            });
+        session.tx = Some(tx);
+        session.connection_id = connection_id;
+        session.clean = connect.clean_session;
+        (session_present, std::mem::take(&mut session.queue))
+    };
+    write_packet(
+        &mut writer,
+        Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, session_present)),
+    )
+    .await?;
+
+    let mut outgoing = Outgoing::default();
+    for publish in queued {
+        write_packet(&mut writer, outgoing.prepare(publish)).await?;
+    }
+
+    // Clients are disconnected after 1.5 keep-alive periods without any packet
+    let keep_alive = match connect.keep_alive {
+        0 => Duration::MAX,
+        secs => Duration::from_millis(secs as u64 * 1500),
+    };
+    let mut last_will = connect.last_will;
+    let result: Result<()> = async {
+        loop {
+            tokio::select! {
+                packet = tokio::time::timeout(keep_alive, read_packet(&mut reader, &mut buffer)) => {
+                    let packet = packet.map_err(|_| anyhow!("keep-alive timeout"))??;
+                    match packet {
+                        Packet::Publish(publish) => {
+                            if publish.qos == QoS::ExactlyOnce {
+                                bail!("QoS 2 is not supported");
+                            }
+                            if !role.can_publish(&publish.topic) || !valid_topic(&publish.topic) {
+                                bail!("publish to {} not allowed", publish.topic);
+                            }
+                            if publish.qos == QoS::AtLeastOnce {
+                                write_packet(&mut writer, Packet::PubAck(PubAck::new(publish.pkid))).await?;
+                            }
+                            broker.publish(publish);
+                        }
+                        Packet::Subscribe(subscribe) => {
+                            let mut granted = Vec::new();
+                            let mut retained = Vec::new();
+                            for filter in subscribe.filters {
+                                if !valid_filter(&filter.path) || !role.can_subscribe(&filter.path) {
+                                    granted.push(SubscribeReasonCode::Failure);
+                                    continue;
+                                }
+                                let qos = min_qos(filter.qos, QoS::AtLeastOnce);
+                                granted.push(SubscribeReasonCode::Success(qos));
+                                retained.extend(broker.subscribe(&client_id, filter.path, qos));
+                            }
+                            write_packet(&mut writer, Packet::SubAck(SubAck::new(subscribe.pkid, granted))).await?;
+                            for publish in retained {
+                                write_packet(&mut writer, outgoing.prepare(publish)).await?;
+                            }
+                        }
+                        Packet::Unsubscribe(unsubscribe) => {
+                            broker.unsubscribe(&client_id, &unsubscribe.topics);
+                            write_packet(&mut writer, Packet::UnsubAck(UnsubAck::new(unsubscribe.pkid))).await?;
+                        }
+                        Packet::PingReq => write_packet(&mut writer, Packet::PingResp).await?,
+                        Packet::Disconnect => {
+                            last_will = None;
+                            return Ok(());
+                        }
+                        // Unacknowledged messages are not retransmitted, so acks need no tracking
+                        Packet::PubAck(_) => {}
+                        Packet::Connect(_) => bail!("unexpected CONNECT"),
+                        _ => bail!("unsupported packet"),
+                    }
+                }
+                publish = rx.recv() => {
+                    // The sender is dropped when another connection takes over the session,
+                    // or when this one falls too far behind
+                    let Some(publish) = publish else {
+                        if broker.is_current(&client_id, connection_id) {
+                            bail!("outgoing queue full");
+                        }
+                        last_will = None;
+                        bail!("session taken over by a new connection");
+                    };
+                    write_packet(&mut writer, outgoing.prepare(publish)).await?;
+                }
+            }
+        }
+    }
+    .await;
+
+    if let Some(will) = last_will {
+        broker.publish_will(will);
+    }
+    broker.disconnect(&client_id, connection_id);
+    result
+}
+
+/// Numeração das mensagens QoS 1 enviadas a um cliente.
+#[derive(Default)]
+struct Outgoing {
+    /// Último ID de pacote usado.
+    last_pkid: u16,
+}
+
+impl Outgoing {
+    /// Atribui um ID de pacote à mensagem, se ela tiver QoS 1.
+    fn prepare(&mut self, mut publish: Publish) -> Packet {
+        if publish.qos == QoS::AtLeastOnce {
+            self.last_pkid = self.last_pkid.checked_add(1).unwrap_or(1);
+            publish.pkid = self.last_pkid;
+        } else {
+            publish.pkid = 0;
+        }
+        Packet::Publish(publish)
+    }
+}
+
+impl Broker {
+    /// Encaminha uma mensagem publicada aos inscritos, guardando-a se for retida.
+    fn publish(&self, publish: Publish) {
+        if publish.retain {
+            let mut retained = self.retained.lock().unwrap();
+            if publish.payload.is_empty() {
+                retained.remove(&publish.topic);
+            } else {
+                retained.insert(publish.topic.clone(), publish.clone());
+            }
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        for (client_id, session) in sessions.iter_mut() {
+            let Some(granted) = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| matches(&publish.topic, filter))
+                .map(|(_, qos)| *qos)
+                .reduce(|a, b| if min_qos(a, b) == a { b } else { a })
+            else {
+                continue;
+            };
+            let mut message = Publish::from_bytes(
+                publish.topic.clone(),
+                min_qos(publish.qos, granted),
+                publish.payload.clone(),
+            );
+            message.retain = false;
+
+            if let Some(tx) = &session.tx {
+                match tx.try_send(message) {
+                    // A client that does not keep up is disconnected instead of buffering
+                    // without bound; its connection ends once it sends what is pending
+                    Err(TrySendError::Full(rejected)) => {
+                        println!(
+                            "DEBUG: Disconnecting MQTT client {}: outgoing queue full",
+                            client_id
+                        );
+                        session.tx = None;
+                        message = rejected;
+                    }
+                    _ => continue,
+                }
+            }
+            if !session.clean && message.qos == QoS::AtLeastOnce {
+                if session.queue.len() >= MAX_QUEUED_MESSAGES {
+                    session.queue.pop_front();
+                }
+                session.queue.push_back(message);
+            }
+        }
+    }
+
+    /// Publica a mensagem Last Will de um cliente desconectado sem aviso.
+    fn publish_will(&self, will: LastWill) {
+        let mut publish = Publish::from_bytes(
+            will.topic,
+            min_qos(will.qos, QoS::AtLeastOnce),
+            will.message,
+        );
+        publish.retain = will.retain;
+        self.publish(publish);
+    }
+
+    /// Inscreve o cliente no filtro, retornando as mensagens retidas correspondentes.
+    fn subscribe(&self, client_id: &str, filter: String, qos: QoS) -> Vec<Publish> {
+        let retained: Vec<Publish> = self
+            .retained
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|publish| matches(&publish.topic, &filter))
+            .map(|publish| {
+                let mut message = publish.clone();
+                message.qos = min_qos(message.qos, qos);
+                message
+            })
+            .collect();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(client_id) {
+            session
+                .subscriptions
+                .retain(|(existing, _)| *existing != filter);
+            session.subscriptions.push((filter, qos));
+        }
+        retained
+    }
+
+    /// Cancela as inscrições do cliente nos filtros.
+    fn unsubscribe(&self, client_id: &str, filters: &[String]) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(client_id) {
+            session
+                .subscriptions
+                .retain(|(existing, _)| !filters.contains(existing));
+        }
+    }
+
+    /// Indica se a conexão ainda é a ativa na sessão do cliente.
+    fn is_current(&self, client_id: &str, connection_id: u64) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .is_some_and(|session| session.connection_id == connection_id)
+    }
+
+    /// Encerra a conexão na sessão, descartando-a se não for persistente.
+    fn disconnect(&self, client_id: &str, connection_id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(client_id) else {
+            return;
+        };
+        if session.connection_id != connection_id {
+            return;
+        }
+        if session.clean {
+            sessions.remove(client_id);
+        } else {
+            session.tx = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgPoolOptions;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn test_broker() -> Arc<Broker> {
+        // Nothing listens here, so device credential lookups fail fast
+        let db_pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://lockwise@127.0.0.1:1/lockwise")
+            .unwrap();
+        Arc::new(Broker {
+            db_pool,
+            backend_password: "secret".to_string(),
+            sessions: Mutex::new(HashMap::new()),
+            retained: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Lado do cliente de uma conexão com o broker.
+    struct Client {
+        stream: DuplexStream,
+        buffer: BytesMut,
+    }
+
+    impl Client {
+        async fn send(&mut self, packet: Packet) {
+            let mut buffer = BytesMut::new();
+            match packet {
+                Packet::Connect(p) => p.write(&mut buffer),
+                Packet::Publish(p) => p.write(&mut buffer),
+                Packet::Subscribe(p) => p.write(&mut buffer),
+                Packet::Unsubscribe(p) => p.write(&mut buffer),
+                Packet::Disconnect => v4::Disconnect.write(&mut buffer),
+                _ => unimplemented!(),
+            }
+            .unwrap();
+            self.stream.write_all(&buffer).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Option<Packet> {
+            tokio::time::timeout(
+                RECV_TIMEOUT,
+                read_packet(&mut self.stream, &mut self.buffer),
+            )
+            .await
+            .ok()?
+            .ok()
+        }
+
+        async fn recv_publish(&mut self) -> Publish {
+            match self.recv().await {
+                Some(Packet::Publish(publish)) => publish,
+                other => panic!("expected PUBLISH, got {:?}", other),
+            }
+        }
+
+        async fn subscribe(&mut self, filter: &str) -> SubscribeReasonCode {
+            self.send(Packet::Subscribe(v4::Subscribe::new(
+                filter,
+                QoS::AtLeastOnce,
+            )))
+            .await;
+            match self.recv().await {
+                Some(Packet::SubAck(suback)) => suback.return_codes[0],
+                other => panic!("expected SUBACK, got {:?}", other),
+            }
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str, retain: bool) {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+            publish.pkid = 1;
+            publish.retain = retain;
+            self.send(Packet::Publish(publish)).await;
+        }
+
+        /// Verifica que a conexão foi encerrada pelo broker.
+        async fn assert_closed(&mut self) {
+            let result = tokio::time::timeout(
+                RECV_TIMEOUT,
+                read_packet(&mut self.stream, &mut self.buffer),
+            )
+            .await
+            .expect("connection still open");
+            assert!(result.is_err(), "unexpected packet {:?}", result);
+        }
+    }
+
+    /// Conecta um cliente já autenticado com o papel informado.
+    async fn connect_as(
+        broker: &Arc<Broker>,
+        role: Role,
+        connect: v4::Connect,
+    ) -> (Client, ConnAck, JoinHandle<Result<()>>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let broker = broker.clone();
+        let task = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(server);
+            run_session(&broker, reader, writer, BytesMut::new(), connect, role).await
+        });
+        let mut client = Client {
+            stream: client,
+            buffer: BytesMut::new(),
+        };
+        let connack = match client.recv().await {
+            Some(Packet::ConnAck(connack)) => connack,
+            other => panic!("expected CONNACK, got {:?}", other),
+        };
+        (client, connack, task)
+    }
+
+    async fn connect_backend(broker: &Arc<Broker>) -> Client {
+        connect_as(broker, Role::Backend, v4::Connect::new("backend"))
+            .await
+            .0
+    }
+
+    async fn connect_device(broker: &Arc<Broker>, uuid: Uuid, clean_session: bool) -> Client {
+        let mut connect = v4::Connect::new(uuid.to_string());
+        connect.clean_session = clean_session;
+        connect_as(broker, Role::Device(uuid), connect).await.0
+    }
+
+    /// Envia o CONNECT pela conexão e retorna o código do CONNACK, passando pela autenticação.
+    async fn login(
+        broker: &Arc<Broker>,
+        client_id: &str,
+        username: &str,
+        password: &str,
+    ) -> ConnectReturnCode {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let broker = broker.clone();
+        tokio::spawn(async move { handle_connection(&broker, server).await });
+        let mut client = Client {
+            stream: client,
+            buffer: BytesMut::new(),
+        };
+        let mut connect = v4::Connect::new(client_id);
+        connect.set_login(username, password);
+        client.send(Packet::Connect(connect)).await;
+        match client.recv().await {
+            Some(Packet::ConnAck(connack)) => connack.code,
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn backend_login_requires_its_password() {
+        let broker = test_broker();
+        assert_eq!(
+            login(&broker, "backend", BACKEND_USERNAME, "secret").await,
+            ConnectReturnCode::Success
+        );
+        assert_eq!(
+            login(&broker, "backend", BACKEND_USERNAME, "wrong").await,
+            ConnectReturnCode::BadUserNamePassword
+        );
+    }
+
+    #[tokio::test]
+    async fn device_login_requires_its_uuid_as_client_id() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4().to_string();
+        assert_eq!(
+            login(&broker, "another-client", &uuid, "password").await,
+            ConnectReturnCode::BadUserNamePassword
+        );
+        // Unknown credentials are refused as well
+        assert_eq!(
+            login(&broker, &uuid, &uuid, "password").await,
+            ConnectReturnCode::BadUserNamePassword
+        );
+    }
+
+    #[tokio::test]
+    async fn device_only_subscribes_to_its_control_topic() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let mut device = connect_device(&broker, uuid, true).await;
+
+        assert_eq!(
+            device
+                .subscribe(&format!("lockwise/{}/control", uuid))
+                .await,
+            SubscribeReasonCode::Success(QoS::AtLeastOnce)
+        );
+        let other = Uuid::new_v4();
+        assert_eq!(
+            device
+                .subscribe(&format!("lockwise/{}/control", other))
+                .await,
+            SubscribeReasonCode::Failure
+        );
+        assert_eq!(
+            device.subscribe("lockwise/#").await,
+            SubscribeReasonCode::Failure
+        );
+    }
+
+    #[tokio::test]
+    async fn device_publishes_only_to_its_own_topics() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let mut backend = connect_backend(&broker).await;
+        backend.subscribe("lockwise/+/status").await;
+
+        let mut device = connect_device(&broker, uuid, true).await;
+        device
+            .publish(&format!("lockwise/{}/status", uuid), "online", false)
+            .await;
+        assert!(matches!(device.recv().await, Some(Packet::PubAck(_))));
+        assert_eq!(&backend.recv_publish().await.payload[..], b"online");
+
+        device
+            .publish(
+                &format!("lockwise/{}/status", Uuid::new_v4()),
+                "spoofed",
+                false,
+            )
+            .await;
+        device.assert_closed().await;
+        assert!(backend.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn backend_is_limited_to_lockwise_topics() {
+        let broker = test_broker();
+        let mut backend = connect_backend(&broker).await;
+        assert_eq!(
+            backend.subscribe("$SYS/#").await,
+            SubscribeReasonCode::Failure
+        );
+        backend.publish("other/topic", "payload", false).await;
+        backend.assert_closed().await;
+    }
+
+    #[tokio::test]
+    async fn last_will_topic_must_be_allowed() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let mut connect = v4::Connect::new(uuid.to_string());
+        connect.last_will = Some(LastWill::new(
+            format!("lockwise/{}/presence", Uuid::new_v4()),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (_, connack, _) = connect_as(&broker, Role::Device(uuid), connect).await;
+        assert_eq!(connack.code, ConnectReturnCode::NotAuthorized);
+    }
+
+    #[tokio::test]
+    async fn last_will_is_published_only_on_unexpected_disconnect() {
+        let broker = test_broker();
+        let mut backend = connect_backend(&broker).await;
+        backend.subscribe("lockwise/+/presence").await;
+
+        let uuid = Uuid::new_v4();
+        let will_connect = || {
+            let mut connect = v4::Connect::new(uuid.to_string());
+            connect.last_will = Some(LastWill::new(
+                format!("lockwise/{}/presence", uuid),
+                "offline",
+                QoS::AtLeastOnce,
+                false,
+            ));
+            connect
+        };
+
+        // A clean DISCONNECT discards the will
+        let (mut device, _, task) = connect_as(&broker, Role::Device(uuid), will_connect()).await;
+        device.send(Packet::Disconnect).await;
+        task.await.unwrap().unwrap();
+        assert!(backend.recv().await.is_none());
+
+        // Dropping the connection publishes it
+        let (device, _, task) = connect_as(&broker, Role::Device(uuid), will_connect()).await;
+        drop(device);
+        let _ = task.await.unwrap();
+        let will = backend.recv_publish().await;
+        assert_eq!(will.topic, format!("lockwise/{}/presence", uuid));
+        assert_eq!(&will.payload[..], b"offline");
+    }
+
+    #[tokio::test]
+    async fn retained_messages_are_delivered_on_subscribe_until_cleared() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let topic = format!("lockwise/{}/control", uuid);
+        let mut backend = connect_backend(&broker).await;
+        backend.publish(&topic, "LOCK", true).await;
+        assert!(matches!(backend.recv().await, Some(Packet::PubAck(_))));
+
+        let mut device = connect_device(&broker, uuid, true).await;
+        device.subscribe(&topic).await;
+        let retained = device.recv_publish().await;
+        assert!(retained.retain);
+        assert_eq!(&retained.payload[..], b"LOCK");
+
+        // An empty retained message clears it
+        backend.publish(&topic, "", true).await;
+        assert!(matches!(backend.recv().await, Some(Packet::PubAck(_))));
+        device.recv_publish().await;
+        let mut late = connect_as(&broker, Role::Device(uuid), v4::Connect::new("late"))
+            .await
+            .0;
+        late.subscribe(&topic).await;
+        assert!(late.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn new_connection_takes_over_the_session() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let topic = format!("lockwise/{}/control", uuid);
+
+        let mut first = connect_device(&broker, uuid, false).await;
+        first.subscribe(&topic).await;
+
+        let mut connect = v4::Connect::new(uuid.to_string());
+        connect.clean_session = false;
+        let (mut second, connack, _) = connect_as(&broker, Role::Device(uuid), connect).await;
+        assert!(connack.session_present);
+        first.assert_closed().await;
+
+        // The subscription carries over to the new connection
+        broker.publish(Publish::new(&topic, QoS::AtLeastOnce, "UNLOCK"));
+        assert_eq!(&second.recv_publish().await.payload[..], b"UNLOCK");
+    }
+
+    #[tokio::test]
+    async fn persistent_session_keeps_messages_while_offline() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let topic = format!("lockwise/{}/control", uuid);
+
+        let mut device = connect_device(&broker, uuid, false).await;
+        device.subscribe(&topic).await;
+        device.send(Packet::Disconnect).await;
+        device.assert_closed().await;
+
+        broker.publish(Publish::new(&topic, QoS::AtLeastOnce, "UNLOCK"));
+        let mut device = connect_device(&broker, uuid, false).await;
+        assert_eq!(&device.recv_publish().await.payload[..], b"UNLOCK");
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_disconnected_without_losing_messages() {
+        let broker = test_broker();
+        let uuid = Uuid::new_v4();
+        let topic = format!("lockwise/{}/control", uuid);
+
+        let mut device = connect_device(&broker, uuid, false).await;
+        device.subscribe(&topic).await;
+
+        // The device stops reading while far more messages than fit in its queue arrive
+        let total = MAX_PENDING_MESSAGES * 4;
+        for _ in 0..total {
+            broker.publish(Publish::new(&topic, QoS::AtLeastOnce, vec![b'x'; 1024]));
+            tokio::task::yield_now().await;
+        }
+        assert!(
+            broker.sessions.lock().unwrap()[&uuid.to_string()]
+                .tx
+                .is_none()
+        );
+
+        // The connection ends after what was already pending, and the rest waits in the session
+        let mut received = 0;
+        while device.recv().await.is_some() {
+            received += 1;
+        }
+        assert!(received < total);
+        let mut device = connect_device(&broker, uuid, false).await;
+        while device.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, total);
+    }
+}
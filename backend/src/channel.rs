@@ -0,0 +1,732 @@
+//! Módulo do canal autenticado entre o back-end e os dispositivos.
+//!
+//! Cada dispositivo tem uma chave própria, acordada por ECDH P-256: no pareamento, o
+//! dispositivo gera um par de chaves, combina a chave privada com a chave pública do
+//! back-end (gravada no firmware) e deriva a chave com HKDF-SHA256; o back-end faz o mesmo
+//! com a sua chave privada (`DEVICE_KEY_EXCHANGE_PRIVATE_KEY`) e a chave pública que o
+//! dispositivo apresenta no heartbeat, junto com o hash do código de pareamento. Quem
+//! conhece o código (ou o recupera do hash) não obtém a chave. Ao trocar de dono, o
+//! back-end pede uma nova chave ao dispositivo ([`request_rekey`]). As mensagens de
+//! controle e de status são envelopadas em um mapa CBOR assinado:
+//!
+//! - `p`: mensagem original (bytes CBOR)
+//! - `t`: timestamp do remetente (segundos desde a época Unix)
+//! - `n`: nonce aleatório nas mensagens de status; nas de controle, número de sequência
+//!   crescente por dispositivo (`devices.control_seq`), que o dispositivo persiste para
+//!   recusar repetições mesmo após reinicializar
+//! - `s`: HMAC-SHA256 de `"LW1"`, direção (`C` para controle, `S` para status), `t` e
+//!   `n` (big-endian, 8 bytes cada) e `p`
+//!
+//! Mensagens fora da janela de tempo, com nonce repetido ou com assinatura inválida são
+//! rejeitadas e contadas. Valores sensíveis de configuração (ex.: `wifi_pass`) são
+//! cifrados com AES-256-GCM e enviados em `value_enc` (nonce de 12 bytes, texto cifrado
+//! e tag).
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::Result;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use p256::{PublicKey, SecretKey};
+use rumqttc::{AsyncClient, QoS};
+use serde_cbor::Value;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+use super::config;
+
+/// Sal usado na derivação da chave do dispositivo a partir do segredo ECDH.
+const DEVICE_KEY_SALT: &[u8] = b"lockwise-pairing-v2";
+/// Diferença máxima aceita entre o timestamp de uma mensagem e o relógio local, em segundos.
+pub const MAX_CLOCK_SKEW_SEC: u64 = 300;
+
+/// Total de mensagens de dispositivos rejeitadas por falta de autenticação.
+static REJECTED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Chave secreta de 32 bytes de um dispositivo.
+pub type DeviceKey = [u8; 32];
+
+/// Direção de uma mensagem, incluída na assinatura para impedir que uma mensagem seja
+/// refletida no sentido oposto.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    /// Do back-end para o dispositivo (`lockwise/<id>/control`).
+    Control,
+    /// Do dispositivo para o back-end (`lockwise/<id>/status`).
+    Status,
+}
+
+/// Mensagem envelopada recebida, ainda não verificada.
+pub struct SealedFrame {
+    /// Mensagem original (bytes CBOR).
+    pub payload: Vec<u8>,
+    /// Timestamp do remetente.
+    timestamp: u64,
+    /// Nonce aleatório.
+    nonce: u64,
+    /// Assinatura HMAC-SHA256.
+    signature: Vec<u8>,
+}
+
+/// Motivo da rejeição de uma mensagem de dispositivo.
+pub enum ChannelError {
+    /// A mensagem não está assinada, mas o dispositivo tem chave.
+    Unsigned,
+    /// Não há chave conhecida para o dispositivo.
+    UnknownKey,
+    /// A assinatura não confere.
+    BadSignature,
+    /// O timestamp está fora da janela aceita.
+    Stale(u64),
+    /// O nonce já foi usado dentro da janela.
+    Replayed(u64),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Unsigned => write!(f, "unsigned message"),
+            ChannelError::UnknownKey => write!(f, "no key for device"),
+            ChannelError::BadSignature => write!(f, "bad signature"),
+            ChannelError::Stale(t) => write!(f, "timestamp {} outside the accepted window", t),
+            ChannelError::Replayed(n) => write!(f, "replayed nonce {}", n),
+        }
+    }
+}
+
+/// Lê do ambiente a chave privada P-256 do back-end para a troca de chaves com os
+/// dispositivos (`DEVICE_KEY_EXCHANGE_PRIVATE_KEY`, escalar de 32 bytes em hexadecimal).
+/// Sem ela, dispositivos com canal autenticado não podem ser pareados.
+pub fn key_exchange_secret_from_env() -> Result<Option<SecretKey>> {
+    env::var("DEVICE_KEY_EXCHANGE_PRIVATE_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+        .map(|key| {
+            let bytes = hex::decode(key.trim()).map_err(|_| {
+                anyhow::anyhow!("DEVICE_KEY_EXCHANGE_PRIVATE_KEY is not hexadecimal")
+            })?;
+            SecretKey::from_slice(&bytes)
+                .map_err(|_| anyhow::anyhow!("invalid DEVICE_KEY_EXCHANGE_PRIVATE_KEY"))
+        })
+        .transpose()
+}
+
+/// Deriva a chave do dispositivo por ECDH entre a chave privada informada e a chave
+/// pública do dispositivo (ponto SEC1 em hexadecimal).
+fn derive_device_key(secret: &SecretKey, public_key: &str) -> Option<DeviceKey> {
+    let public_key = PublicKey::from_sec1_bytes(&hex::decode(public_key).ok()?).ok()?;
+    let shared = p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), public_key.as_affine());
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(DEVICE_KEY_SALT), shared.raw_secret_bytes())
+        .expand(b"device-key", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Some(key)
+}
+
+/// Deriva a chave do dispositivo a partir da chave pública apresentada por ele, com a
+/// chave privada do back-end. Retorna `None` se a chave pública for inválida ou se o
+/// back-end não tiver chave privada configurada.
+pub fn exchange_key(public_key: &str) -> Option<DeviceKey> {
+    let secret = super::DEVICE_KEY_EXCHANGE.get()?.as_ref()?;
+    derive_device_key(secret, public_key)
+}
+
+/// Deriva uma subchave da chave do dispositivo para um propósito específico.
+fn subkey(key: &DeviceKey, purpose: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(purpose, &mut out)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    out
+}
+
+/// Calcula a assinatura de uma mensagem.
+fn sign(key: &DeviceKey, direction: Direction, timestamp: u64, nonce: u64) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&subkey(key, b"mac"))
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"LW1");
+    mac.update(match direction {
+        Direction::Control => b"C",
+        Direction::Status => b"S",
+    });
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(&nonce.to_be_bytes());
+    mac
+}
+
+/// Envelopa e assina uma mensagem CBOR para envio ao dispositivo, com o número de
+/// sequência informado.
+pub fn seal(key: &DeviceKey, sequence: u64, payload: Vec<u8>) -> Result<Vec<u8>> {
+    let timestamp = chrono::Utc::now().timestamp() as u64;
+    let mut mac = sign(key, Direction::Control, timestamp, sequence);
+    mac.update(&payload);
+    let signature = mac.finalize().into_bytes().to_vec();
+
+    let mut map = BTreeMap::new();
+    map.insert(Value::Text("p".to_string()), Value::Bytes(payload));
+    map.insert(
+        Value::Text("t".to_string()),
+        Value::Integer(timestamp.into()),
+    );
+    map.insert(
+        Value::Text("n".to_string()),
+        Value::Integer(sequence.into()),
+    );
+    map.insert(Value::Text("s".to_string()), Value::Bytes(signature));
+    Ok(serde_cbor::to_vec(&Value::Map(map))?)
+}
+
+/// Interpreta uma mensagem envelopada. Retorna `None` se a mensagem não estiver
+/// envelopada (mensagem CBOR simples).
+pub fn parse(frame: &[u8]) -> Option<SealedFrame> {
+    let Ok(Value::Map(map)) = serde_cbor::from_slice::<Value>(frame) else {
+        return None;
+    };
+    let field = |name: &str| map.get(&Value::Text(name.to_string()));
+    let unsigned = |name: &str| match field(name) {
+        Some(Value::Integer(n)) => u64::try_from(*n).ok(),
+        _ => None,
+    };
+    let (Some(Value::Bytes(payload)), Some(Value::Bytes(signature))) = (field("p"), field("s"))
+    else {
+        return None;
+    };
+    Some(SealedFrame {
+        payload: payload.clone(),
+        timestamp: unsigned("t")?,
+        nonce: unsigned("n")?,
+        signature: signature.clone(),
+    })
+}
+
+/// Verifica a assinatura e o timestamp de uma mensagem de status.
+pub fn verify(key: &DeviceKey, frame: &SealedFrame) -> Result<(), ChannelError> {
+    let mut mac = sign(key, Direction::Status, frame.timestamp, frame.nonce);
+    mac.update(&frame.payload);
+    mac.verify_slice(&frame.signature)
+        .map_err(|_| ChannelError::BadSignature)?;
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    if now.abs_diff(frame.timestamp) > MAX_CLOCK_SKEW_SEC {
+        return Err(ChannelError::Stale(frame.timestamp));
+    }
+    Ok(())
+}
+
+/// Registra o nonce de uma mensagem verificada, rejeitando-o se já tiver sido usado.
+/// Com várias instâncias ([`super::cluster`]), os nonces ficam na tabela `seen_nonces`.
+pub async fn claim_nonce(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    frame: &SealedFrame,
+) -> Result<(), ChannelError> {
+    if super::cluster::is_shared() {
+        let result = sqlx::query(
+            "INSERT INTO seen_nonces (device_id, nonce, sent_at) VALUES ($1, $2, to_timestamp($3)) ON CONFLICT DO NOTHING",
+        )
+        .bind(device_id)
+        .bind(frame.nonce as i64)
+        .bind(frame.timestamp as f64)
+        .execute(db_pool)
+        .await;
+        return match result {
+            Ok(result) if result.rows_affected() == 0 => Err(ChannelError::Replayed(frame.nonce)),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Fail closed: a nonce that cannot be recorded could be replayed
+                println!(
+                    "DEBUG: Failed to record nonce of device {}: {}",
+                    device_id, e
+                );
+                Err(ChannelError::Replayed(frame.nonce))
+            }
+        };
+    }
+
+    // Remember nonces for as long as their timestamps stay in the window
+    let now = chrono::Utc::now().timestamp() as u64;
+    let nonces_mutex = super::SEEN_NONCES.get().unwrap();
+    let mut nonces = nonces_mutex.lock().unwrap();
+    let seen = nonces.entry(device_id).or_default();
+    while seen
+        .front()
+        .is_some_and(|(t, _)| now.saturating_sub(*t) > 2 * MAX_CLOCK_SKEW_SEC)
+    {
+        seen.pop_front();
+    }
+    if seen.iter().any(|(_, n)| *n == frame.nonce) {
+        return Err(ChannelError::Replayed(frame.nonce));
+    }
+    seen.push_back((frame.timestamp, frame.nonce));
+    Ok(())
+}
+
+/// Cifra um valor sensível de configuração com AES-256-GCM.
+/// Retorna o nonce de 12 bytes seguido do texto cifrado e da tag.
+pub fn encrypt_value(key: &DeviceKey, value: &str) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(&subkey(key, b"config-enc"))?;
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+        .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Converte uma chave lida do banco de dados.
+fn stored_key(key: Option<Vec<u8>>) -> Option<DeviceKey> {
+    key.and_then(|key| DeviceKey::try_from(key.as_slice()).ok())
+}
+
+/// Recupera a chave do dispositivo, se ele tiver sido pareado com o canal autenticado.
+pub async fn device_key(db_pool: &PgPool, device_id: Uuid) -> Result<Option<DeviceKey>> {
+    let row: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT device_key FROM devices WHERE uuid = $1")
+            .bind(device_id)
+            .fetch_optional(db_pool)
+            .await?;
+    Ok(row.and_then(|(key,)| stored_key(key)))
+}
+
+/// Recupera a chave que assina as mensagens de controle do dispositivo: a chave pendente
+/// de uma troca, se houver, que o dispositivo adota ao receber a primeira mensagem
+/// assinada com ela; caso contrário, a chave atual.
+pub async fn control_key(db_pool: &PgPool, device_id: Uuid) -> Result<Option<DeviceKey>> {
+    let row: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+        "SELECT COALESCE(pending_device_key, device_key) FROM devices WHERE uuid = $1",
+    )
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.and_then(|(key,)| stored_key(key)))
+}
+
+/// Verifica uma mensagem com a chave pendente de uma troca. Se ela conferir, o
+/// dispositivo já adotou a nova chave, que passa a ser a atual.
+async fn verify_pending(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    sealed: &SealedFrame,
+) -> Option<Result<(), ChannelError>> {
+    let row: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT pending_device_key FROM devices WHERE uuid = $1")
+            .bind(device_id)
+            .fetch_optional(db_pool)
+            .await
+            .ok()?;
+    let pending = row.and_then(|(key,)| stored_key(key))?;
+    let result = verify(&pending, sealed);
+    if result.is_ok() {
+        let promoted = sqlx::query(
+            "UPDATE devices SET device_key = pending_device_key, pending_device_key = NULL
+             WHERE uuid = $1 AND pending_device_key = $2",
+        )
+        .bind(device_id)
+        .bind(pending.to_vec())
+        .execute(db_pool)
+        .await;
+        if let Err(e) = promoted {
+            println!(
+                "DEBUG: Failed to replace key of device {}: {}",
+                device_id, e
+            );
+        } else {
+            println!("DEBUG: Device {} switched to its new key", device_id);
+        }
+    }
+    Some(result)
+}
+
+/// Indica se mensagens não assinadas de dispositivos sem chave são aceitas
+/// (`ALLOW_UNSIGNED_DEVICES`), para dispositivos com firmware anterior ao canal autenticado.
+pub fn allow_unsigned() -> bool {
+    *super::ALLOW_UNSIGNED_DEVICES.get().unwrap_or(&false)
+}
+
+/// Mensagem de status autenticada.
+pub struct Authenticated {
+    /// Mensagem original.
+    pub payload: Vec<u8>,
+    /// Se a mensagem foi assinada com a chave do código de pareamento apresentado, e não
+    /// com a do dispositivo. Nesse caso, ela só vale depois que o código reivindicar o
+    /// dispositivo ([`super::pairing::claim_presented`]).
+    pub pairing: bool,
+}
+
+/// Autentica uma mensagem de status e retorna a mensagem original.
+/// Mensagens assinadas são verificadas com a chave do dispositivo ou, durante o
+/// pareamento, com a chave do código de pareamento apresentado no heartbeat: qualquer
+/// código, se o dispositivo ainda não tiver chave, ou apenas um código emitido para
+/// reparear esse dispositivo.
+pub async fn authenticate(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    frame: &[u8],
+) -> Result<Authenticated, ChannelError> {
+    let key = device_key(db_pool, device_id).await.unwrap_or_else(|e| {
+        println!("DEBUG: Failed to load key of device {}: {}", device_id, e);
+        None
+    });
+
+    let Some(sealed) = parse(frame) else {
+        return if key.is_none() && allow_unsigned() {
+            Ok(Authenticated {
+                payload: frame.to_vec(),
+                pairing: false,
+            })
+        } else {
+            Err(ChannelError::Unsigned)
+        };
+    };
+
+    let mut result = Err(ChannelError::UnknownKey);
+    if let Some(key) = key {
+        result = verify(&key, &sealed);
+        // After a key exchange, the device signs with the new key once it adopts it
+        if matches!(result, Err(ChannelError::BadSignature))
+            && let Some(pending) = verify_pending(db_pool, device_id, &sealed).await
+        {
+            result = pending;
+        }
+    }
+    let mut pairing = false;
+    if matches!(
+        result,
+        Err(ChannelError::UnknownKey | ChannelError::BadSignature)
+    ) {
+        // A device being (re)paired signs with the key of the code it presents
+        let any_code = key.is_none();
+        if let Some(code_key) =
+            super::pairing::presented_key(db_pool, device_id, any_code, &sealed.payload).await
+        {
+            result = verify(&code_key, &sealed);
+            pairing = true;
+        }
+    }
+    result?;
+    claim_nonce(db_pool, device_id, &sealed).await?;
+    Ok(Authenticated {
+        payload: sealed.payload,
+        pairing,
+    })
+}
+
+/// Reserva o próximo número de sequência de controle do dispositivo. O incremento no
+/// banco mantém a sequência crescente entre instâncias e reinicializações do back-end.
+async fn next_sequence(db_pool: &PgPool, device_id: Uuid) -> Result<u64> {
+    let sequence: i64 = sqlx::query_scalar(
+        "UPDATE devices SET control_seq = control_seq + 1 WHERE uuid = $1 RETURNING control_seq",
+    )
+    .bind(device_id)
+    .fetch_one(db_pool)
+    .await?;
+    Ok(sequence as u64)
+}
+
+/// Envelopa e assina uma mensagem de controle se houver chave, ou a serializa como CBOR
+/// simples caso contrário.
+pub async fn wrap(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    key: Option<&DeviceKey>,
+    message: &impl serde::Serialize,
+) -> Result<Vec<u8>> {
+    let payload = serde_cbor::to_vec(message)?;
+    match key {
+        Some(key) => seal(key, next_sequence(db_pool, device_id).await?, payload),
+        None => Ok(payload),
+    }
+}
+
+/// Prepara uma mensagem de controle para o dispositivo, assinada com a chave dele.
+pub async fn control_payload(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    message: &impl serde::Serialize,
+) -> Result<Vec<u8>> {
+    let key = control_key(db_pool, device_id).await?;
+    wrap(db_pool, device_id, key.as_ref(), message).await
+}
+
+/// Pede ao dispositivo uma nova chave, ex.: após uma transferência de propriedade. O
+/// pedido fica registrado e é repetido a cada `CONNECTED` ([`resume_rekey`]) até o
+/// dispositivo oferecer a nova chave ([`handle_key_offer`]).
+pub async fn request_rekey(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE devices SET rekey_requested_at = NOW() WHERE uuid = $1 AND device_key IS NOT NULL",
+    )
+    .bind(device_id)
+    .execute(db_pool)
+    .await?;
+    resume_rekey(db_pool, mqtt_client, device_id).await
+}
+
+/// Envia o comando `REKEY` ao dispositivo se houver uma troca de chave pedida e ainda
+/// não respondida.
+pub async fn resume_rekey(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+) -> Result<()> {
+    let requested: Option<bool> =
+        sqlx::query_scalar("SELECT rekey_requested_at IS NOT NULL FROM devices WHERE uuid = $1")
+            .bind(device_id)
+            .fetch_optional(db_pool)
+            .await?;
+    if requested != Some(true) {
+        return Ok(());
+    }
+    let payload = control_payload(
+        db_pool,
+        device_id,
+        &serde_json::json!({ "command": "REKEY" }),
+    )
+    .await?;
+    mqtt_client.try_publish(
+        format!("lockwise/{}/control", device_id),
+        QoS::AtLeastOnce,
+        false,
+        payload,
+    )?;
+    println!("DEBUG: Requested a new key from device {}", device_id);
+    Ok(())
+}
+
+/// Trata a chave pública oferecida pelo dispositivo em `KEY_OFFER`, em resposta ao
+/// comando `REKEY` e assinada com a chave atual. A chave derivada fica pendente e passa
+/// a assinar as mensagens de controle; o comando `REKEYED` faz o dispositivo adotá-la, e
+/// a primeira mensagem dele assinada com ela a torna a chave atual.
+pub async fn handle_key_offer(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+    public_key: &str,
+) -> Result<()> {
+    let key = exchange_key(public_key).ok_or_else(|| anyhow::anyhow!("invalid public key"))?;
+    let result = sqlx::query(
+        "UPDATE devices SET pending_device_key = $2, rekey_requested_at = NULL
+         WHERE uuid = $1 AND device_key IS NOT NULL",
+    )
+    .bind(device_id)
+    .bind(key.to_vec())
+    .execute(db_pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    // The event loop is not polled while this runs, so never wait on the request queue
+    let payload = control_payload(
+        db_pool,
+        device_id,
+        &serde_json::json!({ "command": "REKEYED" }),
+    )
+    .await?;
+    mqtt_client.try_publish(
+        format!("lockwise/{}/control", device_id),
+        QoS::AtLeastOnce,
+        false,
+        payload,
+    )?;
+    Ok(())
+}
+
+/// Monta a mensagem `update_config` de uma chave de configuração. Valores sensíveis
+/// são cifrados em `value_enc`; sem chave do dispositivo, só são enviados em claro se
+/// `ALLOW_UNSIGNED_DEVICES` estiver habilitado.
+pub fn config_message(
+    key: Option<&DeviceKey>,
+    config_key: &str,
+    value: &str,
+    command_id: Uuid,
+) -> Result<Value> {
+    let mut map = BTreeMap::new();
+    map.insert(
+        Value::Text("command".to_string()),
+        Value::Text("update_config".to_string()),
+    );
+    map.insert(
+        Value::Text("command_id".to_string()),
+        Value::Text(command_id.to_string()),
+    );
+    map.insert(
+        Value::Text("key".to_string()),
+        Value::Text(config_key.to_string()),
+    );
+    if config::find(config_key).is_some_and(|k| k.sensitive) {
+        match key {
+            Some(key) => {
+                map.insert(
+                    Value::Text("value_enc".to_string()),
+                    Value::Bytes(encrypt_value(key, value)?),
+                );
+                return Ok(Value::Map(map));
+            }
+            None if !allow_unsigned() => {
+                anyhow::bail!("refusing to send {} in clear", config_key)
+            }
+            None => {}
+        }
+    }
+    map.insert(
+        Value::Text("value".to_string()),
+        Value::Text(value.to_string()),
+    );
+    Ok(Value::Map(map))
+}
+
+/// Registra uma mensagem de dispositivo rejeitada por falta de autenticação.
+pub fn record_rejection(device_id: Uuid, error: &ChannelError) {
+    let rejected = REJECTED_MESSAGES.fetch_add(1, Ordering::Relaxed) + 1;
+    println!(
+        "DEBUG: Rejected message from device {}: {} ({} rejected so far)",
+        device_id, error, rejected
+    );
+}
+
+/// Total de mensagens rejeitadas desde a inicialização.
+pub fn rejected_messages() -> u64 {
+    REJECTED_MESSAGES.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    const KEY: DeviceKey = [7; 32];
+
+    /// Envelopa uma mensagem de status como o firmware.
+    fn seal_status(key: &DeviceKey, timestamp: u64, nonce: u64, payload: &[u8]) -> Vec<u8> {
+        let mut mac = sign(key, Direction::Status, timestamp, nonce);
+        mac.update(payload);
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("p".to_string()), Value::Bytes(payload.to_vec()));
+        map.insert(
+            Value::Text("t".to_string()),
+            Value::Integer(timestamp.into()),
+        );
+        map.insert(Value::Text("n".to_string()), Value::Integer(nonce.into()));
+        map.insert(
+            Value::Text("s".to_string()),
+            Value::Bytes(mac.finalize().into_bytes().to_vec()),
+        );
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    fn now() -> u64 {
+        chrono::Utc::now().timestamp() as u64
+    }
+
+    fn test_pool() -> PgPool {
+        sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://lockwise@127.0.0.1:1/lockwise")
+            .unwrap()
+    }
+
+    #[test]
+    fn verifies_status_signed_with_the_device_key() {
+        let frame = parse(&seal_status(&KEY, now(), 42, b"payload")).unwrap();
+        assert!(verify(&KEY, &frame).is_ok());
+        assert_eq!(frame.payload, b"payload");
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_tampered_payload() {
+        let frame = parse(&seal_status(&KEY, now(), 42, b"payload")).unwrap();
+        assert!(matches!(
+            verify(&[8; 32], &frame),
+            Err(ChannelError::BadSignature)
+        ));
+
+        let mut tampered = parse(&seal_status(&KEY, now(), 42, b"payload")).unwrap();
+        tampered.payload = b"PAYLOAD".to_vec();
+        assert!(matches!(
+            verify(&KEY, &tampered),
+            Err(ChannelError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_control_frames_reflected_as_status() {
+        let sealed = seal(&KEY, 1, b"payload".to_vec()).unwrap();
+        let frame = parse(&sealed).unwrap();
+        assert_eq!(frame.nonce, 1);
+        assert!(matches!(
+            verify(&KEY, &frame),
+            Err(ChannelError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_window() {
+        let old = now() - MAX_CLOCK_SKEW_SEC - 10;
+        let frame = parse(&seal_status(&KEY, old, 42, b"payload")).unwrap();
+        assert!(matches!(verify(&KEY, &frame), Err(ChannelError::Stale(t)) if t == old));
+    }
+
+    #[test]
+    fn plain_cbor_is_not_an_envelope() {
+        let plain = serde_cbor::to_vec(&serde_json::json!({ "event": "PONG" })).unwrap();
+        assert!(parse(&plain).is_none());
+        assert!(parse(b"not cbor").is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_nonces() {
+        let _ = crate::SEEN_NONCES.set(Mutex::new(HashMap::new()));
+        let db_pool = test_pool();
+        let device_id = Uuid::new_v4();
+        let frame = parse(&seal_status(&KEY, now(), 42, b"payload")).unwrap();
+
+        assert!(claim_nonce(&db_pool, device_id, &frame).await.is_ok());
+        assert!(matches!(
+            claim_nonce(&db_pool, device_id, &frame).await,
+            Err(ChannelError::Replayed(42))
+        ));
+        // Nonces are tracked per device
+        assert!(claim_nonce(&db_pool, Uuid::new_v4(), &frame).await.is_ok());
+    }
+
+    #[test]
+    fn device_and_backend_agree_on_the_exchanged_key() {
+        let backend = SecretKey::random(&mut OsRng);
+        let device = SecretKey::random(&mut OsRng);
+        let device_public = hex::encode(device.public_key().to_sec1_bytes());
+
+        // The firmware side: ECDH with the backend public key, then the same HKDF
+        let shared = p256::ecdh::diffie_hellman(
+            device.to_nonzero_scalar(),
+            backend.public_key().as_affine(),
+        );
+        let mut expected = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(DEVICE_KEY_SALT), shared.raw_secret_bytes())
+            .expand(b"device-key", &mut expected)
+            .unwrap();
+
+        assert_eq!(derive_device_key(&backend, &device_public), Some(expected));
+        assert!(derive_device_key(&backend, "04deadbeef").is_none());
+        assert!(derive_device_key(&backend, "not hex").is_none());
+    }
+
+    #[test]
+    fn encrypted_values_decrypt_with_the_config_subkey() {
+        let encrypted = encrypt_value(&KEY, "hunter2").unwrap();
+        let cipher = Aes256Gcm::new_from_slice(&subkey(&KEY, b"config-enc")).unwrap();
+        let (nonce, ciphertext) = encrypted.split_at(12);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .unwrap();
+        assert_eq!(plaintext, b"hunter2");
+    }
+}
@@ -0,0 +1,235 @@
+//! Módulo de coordenação entre instâncias do back-end.
+//!
+//! As esperas por respostas dos dispositivos (PONG e confirmações de comando), as
+//! verificações de voz recentes e os broadcasts WebSocket vivem na memória de cada
+//! instância. Com várias instâncias atrás de um balanceador de carga, a mensagem
+//! MQTT que resolve uma espera pode ser processada por uma instância diferente daquela
+//! que atende a requisição. Por isso, esses eventos são publicados como [`Signal`] e
+//! entregues a todas as instâncias pelo coordenador escolhido em `COORDINATION`:
+//!
+//! - `local` (padrão): entrega direta na própria instância, para uma única instância
+//! - `postgres`: `NOTIFY` no canal [`SIGNAL_CHANNEL`], recebido com `LISTEN` por todas as
+//!   instâncias
+//!
+//! No modo `postgres`, os nonces do canal autenticado são registrados no banco e as
+//! tarefas periódicas (fila de saída de comandos e watchdog de presença) rodam em uma
+//! instância por vez, com um advisory lock ([`run_exclusive`]).
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use std::future::Future;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Canal do Postgres em que os sinais são publicados.
+pub const SIGNAL_CHANNEL: &str = "lockwise_signals";
+/// Tamanho máximo de um sinal publicado via `NOTIFY` (o limite do Postgres é 8000 bytes).
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+/// Espera antes de reconectar o `LISTEN` após uma falha, em segundos.
+const LISTEN_RETRY_SEC: u64 = 5;
+
+/// Evento entregue a todas as instâncias.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Signal {
+    /// O dispositivo respondeu a um PING, ecoando seu ID se o firmware o suportar.
+    Pong {
+        device_id: Uuid,
+        ping_id: Option<Uuid>,
+    },
+    /// O dispositivo confirmou um comando, com o estado resultante da fechadura.
+    CommandAcknowledged {
+        command_id: Uuid,
+        lock_state: String,
+    },
+    /// Um usuário foi reconhecido pela voz no dispositivo, no timestamp informado, e se
+    /// o acesso dele vem de um convite.
+    VoiceMatch {
+        device_id: Uuid,
+        user_id: String,
+        at: i64,
+        via_invite: bool,
+    },
+    /// A verificação de voz recente do dispositivo foi atribuída a um evento.
+    VoiceMatchConsumed { device_id: Uuid },
+    /// Atualização WebSocket para os usuários informados.
+    UserUpdate {
+        recipients: Vec<String>,
+        update: String,
+    },
+}
+
+/// Coordenador entre instâncias, escolhido em `COORDINATION`.
+pub enum Coordinator {
+    /// Uma única instância; os sinais são entregues diretamente.
+    Local,
+    /// Várias instâncias coordenadas por `LISTEN`/`NOTIFY`; os sinais seguem pela tarefa
+    /// que os publica, na ordem em que foram emitidos.
+    Postgres { tx: mpsc::UnboundedSender<String> },
+}
+
+impl Coordinator {
+    /// Indica se o estado é compartilhado entre várias instâncias.
+    pub fn is_shared(&self) -> bool {
+        matches!(self, Coordinator::Postgres { .. })
+    }
+}
+
+/// Cria o coordenador do modo informado (`local` ou `postgres`). No modo `postgres`,
+/// inicia as tarefas que publicam e recebem os sinais.
+pub async fn start(db_pool: &PgPool, mode: &str) -> Result<Coordinator> {
+    match mode {
+        "local" => Ok(Coordinator::Local),
+        "postgres" => {
+            let mut listener = PgListener::connect_with(db_pool).await?;
+            listener.listen(SIGNAL_CHANNEL).await?;
+            tokio::spawn(listen(listener));
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(notify(db_pool.clone(), rx));
+            println!("DEBUG: Coordinating instances through Postgres");
+            Ok(Coordinator::Postgres { tx })
+        }
+        _ => bail!("Unknown COORDINATION mode: {}", mode),
+    }
+}
+
+/// Recebe os sinais das instâncias e os entrega localmente.
+async fn listen(mut listener: PgListener) {
+    loop {
+        match listener.recv().await {
+            Ok(notification) => match serde_json::from_str(notification.payload()) {
+                Ok(signal) => deliver(signal),
+                Err(e) => println!("DEBUG: Ignoring malformed signal: {}", e),
+            },
+            Err(e) => {
+                // The listener reconnects on the next call, but signals sent
+                // in the meantime are lost
+                println!("DEBUG: Lost signal listener connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(LISTEN_RETRY_SEC)).await;
+            }
+        }
+    }
+}
+
+/// Publica os sinais emitidos por esta instância.
+async fn notify(db_pool: PgPool, mut rx: mpsc::UnboundedReceiver<String>) {
+    while let Some(payload) = rx.recv().await {
+        if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(SIGNAL_CHANNEL)
+            .bind(&payload)
+            .execute(&db_pool)
+            .await
+        {
+            println!("DEBUG: Failed to publish signal: {}", e);
+        }
+    }
+}
+
+/// Publica um sinal para todas as instâncias, incluindo esta.
+pub fn publish(signal: Signal) {
+    let Some(Coordinator::Postgres { tx }) = super::COORDINATOR.get() else {
+        deliver(signal);
+        return;
+    };
+
+    let payload = serde_json::to_string(&signal).unwrap();
+    if payload.len() > MAX_NOTIFY_PAYLOAD {
+        // Too large for NOTIFY; only clients of this instance get it
+        println!(
+            "DEBUG: Signal of {} bytes is too large to share, delivering locally",
+            payload.len()
+        );
+        deliver(signal);
+    } else if tx.send(payload).is_err() {
+        println!("DEBUG: Signal publisher is gone, dropping signal");
+    }
+}
+
+/// Entrega um sinal às esperas e aos clientes WebSocket desta instância.
+fn deliver(signal: Signal) {
+    match signal {
+        Signal::Pong { device_id, ping_id } => {
+            let pings_mutex = super::PENDING_PINGS.get().unwrap();
+            let mut pings = pings_mutex.lock().unwrap();
+            let answered: Vec<Uuid> = pings
+                .iter()
+                .filter(|(id, (device, _))| {
+                    *device == device_id && ping_id.is_none_or(|ping_id| **id == ping_id)
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            for id in answered {
+                if let Some((_, tx)) = pings.remove(&id) {
+                    tx.send(()).ok();
+                }
+            }
+        }
+        Signal::CommandAcknowledged {
+            command_id,
+            lock_state,
+        } => {
+            let pending_mutex = super::PENDING_COMMANDS.get().unwrap();
+            if let Some(tx) = pending_mutex.lock().unwrap().remove(&command_id) {
+                tx.send(lock_state).ok();
+            }
+        }
+        Signal::VoiceMatch {
+            device_id,
+            user_id,
+            at,
+            via_invite,
+        } => {
+            let matches_mutex = super::RECENT_VOICE_MATCHES.get().unwrap();
+            let mut matches = matches_mutex.lock().unwrap();
+            matches.insert(device_id.to_string(), (user_id, at, via_invite));
+        }
+        Signal::VoiceMatchConsumed { device_id } => {
+            let matches_mutex = super::RECENT_VOICE_MATCHES.get().unwrap();
+            let mut matches = matches_mutex.lock().unwrap();
+            matches.remove(&device_id.to_string());
+        }
+        Signal::UserUpdate { recipients, update } => {
+            let broadcasts = super::USER_BROADCASTS.get().unwrap().lock().unwrap();
+            for recipient in recipients {
+                if let Some(tx) = broadcasts.get(&recipient) {
+                    let _ = tx.send(update.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Indica se o estado é compartilhado entre várias instâncias.
+pub fn is_shared() -> bool {
+    super::COORDINATOR.get().is_some_and(Coordinator::is_shared)
+}
+
+/// Executa uma tarefa periódica em uma única instância por vez. Com estado compartilhado,
+/// a tarefa é pulada se outra instância já a estiver executando.
+pub async fn run_exclusive<F, Fut>(db_pool: &PgPool, job: &str, task: F) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if !is_shared() {
+        return task().await;
+    }
+
+    // Session-level lock, held on a dedicated connection while the task runs
+    let mut conn = db_pool.acquire().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock(hashtext($1))")
+        .bind(job)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !locked {
+        return Ok(());
+    }
+    let result = task().await;
+    sqlx::query("SELECT pg_advisory_unlock(hashtext($1))")
+        .bind(job)
+        .execute(&mut *conn)
+        .await?;
+    result
+}
@@ -0,0 +1,575 @@
+//! Módulo para comandos com confirmação e fila de saída.
+//!
+//! Cada comando recebe um ID, enviado ao dispositivo junto do comando. Nos comandos
+//! LOCK/UNLOCK, o ID é ecoado no evento de fechadura resultante: o evento é atribuído ao
+//! usuário que enviou o comando, sem depender de janelas de tempo, e a requisição HTTP
+//! pode aguardar a mudança de estado. LOCKDOWN e REBOOT são confirmados pelos eventos
+//! `LOCKING_DOWN` e `RESTARTING`. Uma chave de idempotência opcional evita que repetições
+//! da mesma requisição reenviem o comando.
+//!
+//! Os comandos ficam em uma fila de saída (`device_commands`) até serem confirmados ou
+//! expirarem: enquanto o dispositivo não confirma, o comando é reenviado periodicamente
+//! e sempre que o dispositivo se reconecta ao broker, desde que quem o enviou ainda tenha
+//! permissão para executá-lo. A validade depende do comando e pode ser ajustada por
+//! requisição, exceto em UNLOCK.
+use anyhow::Result;
+use rocket::http::Status;
+use rocket::{State, get};
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::cluster::{self, Signal};
+use super::event::{BackendEvent, record_backend_event};
+use super::invite::{self, InviteUse};
+use super::member::{Action, authorize, authorize_access};
+use super::mqtt::{ensure_connected, is_connected, publish_command};
+use super::session::AuthUser;
+
+/// Tempo máximo de espera pela confirmação de um comando, em segundos.
+const COMMAND_ACK_TIMEOUT_SEC: u64 = 10;
+/// Intervalo mínimo entre reenvios de um comando não confirmado, em segundos.
+const COMMAND_RETRY_INTERVAL_SEC: i64 = 10;
+/// Validade mínima de um comando na fila, em segundos.
+const MIN_COMMAND_TTL_SEC: i64 = 5;
+/// Validade máxima de um comando na fila, em segundos.
+const MAX_COMMAND_TTL_SEC: i64 = 24 * 3600;
+/// Colunas de `device_commands` retornadas pela API.
+const COMMAND_COLUMNS: &str = "id, device_id, user_id, command, status, lock_state, attempts, created_at, last_attempt_at, expires_at, acked_at";
+
+/// Opções de entrega de um comando, aceitas junto do comando nas rotas de controle.
+#[derive(Deserialize)]
+pub struct CommandOptions {
+    /// Chave de idempotência escolhida pelo cliente.
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// Se a requisição deve aguardar a confirmação do dispositivo.
+    #[serde(default)]
+    wait: bool,
+    /// Validade do comando na fila, em segundos (padrão conforme o comando). Ignorada em
+    /// UNLOCK, que sempre usa a validade padrão.
+    #[serde(default)]
+    ttl_sec: Option<i64>,
+}
+
+/// Comando registrado, como retornado pela API.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct CommandInfo {
+    /// ID do comando.
+    id: Uuid,
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// ID do usuário que enviou o comando.
+    user_id: Option<String>,
+    /// Comando enviado (LOCK, UNLOCK, LOCKDOWN ou REBOOT).
+    command: String,
+    /// Estado do comando (QUEUED, SENT, APPLIED ou EXPIRED).
+    status: String,
+    /// Estado da fechadura informado na confirmação.
+    lock_state: Option<String>,
+    /// Número de envios ao broker.
+    attempts: i32,
+    /// Momento em que o comando foi registrado.
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Momento do último envio.
+    last_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Momento a partir do qual o comando deixa de ser reenviado.
+    expires_at: chrono::DateTime<chrono::Utc>,
+    /// Momento da confirmação.
+    acked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Comando pendente na fila de saída.
+#[derive(sqlx::FromRow)]
+struct PendingCommandRow {
+    /// ID do comando.
+    id: Uuid,
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// ID do usuário que enviou o comando.
+    user_id: Option<String>,
+    /// Comando a enviar.
+    command: String,
+}
+
+/// Validade padrão de um comando na fila, em segundos. Destrancar é sensível ao
+/// momento, então só é reenviado por pouco tempo e não pode ser estendido pelo cliente;
+/// bloquear deve chegar mesmo tarde.
+fn default_ttl_sec(command: &str) -> i64 {
+    match command {
+        "UNLOCK" => 30,
+        "LOCK" | "REBOOT" => 600,
+        "LOCKDOWN" => 3600,
+        _ => 60,
+    }
+}
+
+/// Envia um comando LOCK/UNLOCK e, se `wait` for verdadeiro, aguarda a confirmação do
+/// dispositivo. Com `idempotency_key`, uma repetição retorna o comando original sem
+/// reenviá-lo nem consumir outro uso de convite.
+pub async fn dispatch(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+    user_id: &str,
+    command: &str,
+    options: &CommandOptions,
+    via_invite: bool,
+) -> Result<String, Status> {
+    let idempotency_key = options.idempotency_key.as_deref();
+
+    if command != "LOCK" && command != "UNLOCK" {
+        return Err(Status::BadRequest);
+    }
+
+    ensure_connected()?;
+
+    // Replay of a request already handled
+    if let Some(key) = idempotency_key {
+        let existing: Option<CommandInfo> = sqlx::query_as(&format!(
+            "SELECT {} FROM device_commands WHERE device_id = $1 AND user_id = $2 AND idempotency_key = $3",
+            COMMAND_COLUMNS
+        ))
+        .bind(device_id)
+        .bind(user_id)
+        .bind(key)
+        .fetch_optional(db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+        if let Some(existing) = existing {
+            if existing.command != command {
+                return Err(Status::Conflict);
+            }
+            return Ok(serde_json::to_string(&existing).unwrap());
+        }
+    }
+
+    let ttl_sec = match options.ttl_sec {
+        // A late unlock could open the door after the request stopped making sense
+        Some(ttl_sec) if command != "UNLOCK" => {
+            ttl_sec.clamp(MIN_COMMAND_TTL_SEC, MAX_COMMAND_TTL_SEC)
+        }
+        _ => default_ttl_sec(command),
+    };
+    let command_id = Uuid::new_v4();
+
+    // Register the waiter before publishing so a fast ack is not missed
+    let rx = if options.wait {
+        let (tx, rx) = tokio::sync::oneshot::channel::<String>();
+        let pending_mutex = super::PENDING_COMMANDS.get().unwrap();
+        pending_mutex.lock().unwrap().insert(command_id, tx);
+        Some(rx)
+    } else {
+        None
+    };
+
+    let inserted = enqueue(
+        db_pool,
+        mqtt_client,
+        QueuedCommand {
+            id: command_id,
+            device_id,
+            user_id,
+            command,
+            ttl_sec,
+            idempotency_key,
+            // Unlocking through a limited invite uses it up
+            uses_invite: via_invite && command == "UNLOCK",
+        },
+    )
+    .await;
+    if !matches!(inserted, Ok(true)) {
+        forget_waiter(command_id);
+        // A concurrent request with the same key won the race
+        return Err(match inserted {
+            Ok(_) => Status::Conflict,
+            Err(status) => status,
+        });
+    }
+
+    let Some(rx) = rx else {
+        return get_command_info(db_pool, command_id).await;
+    };
+
+    match tokio::time::timeout(std::time::Duration::from_secs(COMMAND_ACK_TIMEOUT_SEC), rx).await {
+        Ok(Ok(_)) => get_command_info(db_pool, command_id).await,
+        _ => {
+            forget_waiter(command_id);
+            // The command stays queued and may still be applied before it expires
+            println!(
+                "DEBUG: Command {} to device {} not acknowledged within {} s",
+                command_id, device_id, COMMAND_ACK_TIMEOUT_SEC
+            );
+            Err(Status::RequestTimeout)
+        }
+    }
+}
+
+/// Envia um comando sem confirmação por ID (LOCKDOWN ou REBOOT) pela fila de saída,
+/// com a validade padrão. Retorna o comando registrado.
+pub async fn send(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+    user_id: &str,
+    command: &str,
+) -> Result<String, Status> {
+    ensure_connected()?;
+
+    let command_id = Uuid::new_v4();
+    enqueue(
+        db_pool,
+        mqtt_client,
+        QueuedCommand {
+            id: command_id,
+            device_id,
+            user_id,
+            command,
+            ttl_sec: default_ttl_sec(command),
+            idempotency_key: None,
+            uses_invite: false,
+        },
+    )
+    .await?;
+
+    get_command_info(db_pool, command_id).await
+}
+
+/// Comando a registrar na fila de saída.
+struct QueuedCommand<'a> {
+    /// ID do comando.
+    id: Uuid,
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// ID do usuário que enviou o comando.
+    user_id: &'a str,
+    /// Comando a enviar.
+    command: &'a str,
+    /// Validade do comando na fila, em segundos.
+    ttl_sec: i64,
+    /// Chave de idempotência escolhida pelo cliente.
+    idempotency_key: Option<&'a str>,
+    /// Se o comando consome um uso do convite do usuário.
+    uses_invite: bool,
+}
+
+/// Registra um comando na fila de saída e faz o primeiro envio. Retorna `false` se
+/// outra requisição com a mesma chave de idempotência registrou o comando antes.
+/// O uso de convite é consumido na mesma transação, só quando o comando é registrado,
+/// e devolvido se ele expirar sem confirmação; sem usos restantes, retorna `Forbidden`.
+/// Uma falha no envio não é um erro: o comando continua na fila e será reenviado.
+async fn enqueue(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    queued: QueuedCommand<'_>,
+) -> Result<bool, Status> {
+    let mut tx = db_pool
+        .begin()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let inserted = sqlx::query(
+        "INSERT INTO device_commands (id, device_id, user_id, command, idempotency_key, expires_at)
+         VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))
+         ON CONFLICT (device_id, user_id, idempotency_key) WHERE idempotency_key IS NOT NULL DO NOTHING",
+    )
+    .bind(queued.id)
+    .bind(queued.device_id)
+    .bind(queued.user_id)
+    .bind(queued.command)
+    .bind(queued.idempotency_key)
+    .bind(queued.ttl_sec as f64)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .rows_affected();
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    let invite_use = if queued.uses_invite {
+        let used = invite::take_use(db_pool, &mut tx, queued.device_id, queued.user_id)
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::Forbidden)?;
+        if let InviteUse::Limited { invite_id, .. } = used {
+            sqlx::query("UPDATE device_commands SET invite_id = $1 WHERE id = $2")
+                .bind(invite_id)
+                .bind(queued.id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| Status::InternalServerError)?;
+        }
+        Some(used)
+    } else {
+        None
+    };
+    tx.commit().await.map_err(|_| Status::InternalServerError)?;
+    if let Some(used) = invite_use {
+        invite::notify_use(db_pool, queued.device_id, queued.user_id, &used).await;
+    }
+
+    record_backend_event(
+        db_pool,
+        queued.device_id,
+        BackendEvent::CommandSent,
+        Some(queued.user_id),
+        serde_json::json!({ "command": queued.command, "command_id": queued.id }),
+    )
+    .await;
+
+    deliver(
+        db_pool,
+        mqtt_client,
+        &PendingCommandRow {
+            id: queued.id,
+            device_id: queued.device_id,
+            user_id: Some(queued.user_id.to_string()),
+            command: queued.command.to_string(),
+        },
+    )
+    .await;
+    Ok(true)
+}
+
+/// Publica um comando da fila e registra a tentativa.
+async fn deliver(db_pool: &PgPool, mqtt_client: &AsyncClient, pending: &PendingCommandRow) {
+    if let Err(e) = publish_command(
+        db_pool,
+        mqtt_client,
+        pending.device_id,
+        pending.command.clone(),
+        Some(pending.id.to_string()),
+    )
+    .await
+    {
+        println!("DEBUG: Failed to send command {}: {}", pending.id, e);
+        return;
+    }
+    let _ = sqlx::query(
+        "UPDATE device_commands SET status = CASE WHEN status = 'QUEUED' THEN 'SENT' ELSE status END,
+         attempts = attempts + 1, last_attempt_at = NOW() WHERE id = $1",
+    )
+    .bind(pending.id)
+    .execute(db_pool)
+    .await;
+}
+
+/// Reenvia os comandos não confirmados e marca como `EXPIRED` os que passaram da
+/// validade. Com `device_id`, reenvia imediatamente todos os comandos pendentes do
+/// dispositivo (ex.: ao se reconectar); sem ele, apenas os que aguardam há mais de
+/// [`COMMAND_RETRY_INTERVAL_SEC`] segundos.
+pub async fn retry_pending(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Option<Uuid>,
+) -> Result<()> {
+    // Unlocks that never reached the device give their invite use back
+    let mut tx = db_pool.begin().await?;
+    let expired: Vec<(Option<i32>,)> = sqlx::query_as(
+        "UPDATE device_commands SET status = 'EXPIRED' WHERE status IN ('QUEUED', 'SENT') AND expires_at <= NOW() RETURNING invite_id",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for invite_id in expired.iter().filter_map(|(invite_id,)| *invite_id) {
+        invite::refund_use(&mut tx, invite_id).await?;
+    }
+    tx.commit().await?;
+    if !expired.is_empty() {
+        println!("DEBUG: {} queued command(s) expired", expired.len());
+    }
+
+    if !is_connected() {
+        return Ok(());
+    }
+
+    let pending: Vec<PendingCommandRow> = sqlx::query_as(
+        "SELECT id, device_id, user_id, command FROM device_commands
+         WHERE status IN ('QUEUED', 'SENT') AND expires_at > NOW()
+         AND ($1::uuid IS NULL OR device_id = $1)
+         AND ($1::uuid IS NOT NULL OR last_attempt_at IS NULL OR last_attempt_at < NOW() - make_interval(secs => $2))
+         ORDER BY created_at",
+    )
+    .bind(device_id)
+    .bind(COMMAND_RETRY_INTERVAL_SEC as f64)
+    .fetch_all(db_pool)
+    .await?;
+
+    for command in &pending {
+        if !still_authorized(db_pool, command).await {
+            println!(
+                "DEBUG: Sender of command {} lost access to device {}, dropping it",
+                command.id, command.device_id
+            );
+            expire(db_pool, command.id).await?;
+            continue;
+        }
+        deliver(db_pool, mqtt_client, command).await;
+    }
+    Ok(())
+}
+
+/// Verifica se quem enviou o comando ainda pode executá-lo (ex.: o convite pode ter
+/// expirado ou saído da agenda desde o envio).
+async fn still_authorized(db_pool: &PgPool, pending: &PendingCommandRow) -> bool {
+    let Some(user_id) = &pending.user_id else {
+        return true;
+    };
+    let action = match pending.command.as_str() {
+        "REBOOT" => Action::Reboot,
+        "LOCKDOWN" => Action::Lockdown,
+        _ => Action::Control,
+    };
+    authorize_access(db_pool, pending.device_id, user_id, action)
+        .await
+        .is_ok()
+}
+
+/// Marca um comando pendente como `EXPIRED`, devolvendo o uso de convite que ele
+/// tenha consumido.
+async fn expire(db_pool: &PgPool, command_id: Uuid) -> Result<()> {
+    let mut tx = db_pool.begin().await?;
+    let row: Option<(Option<i32>,)> = sqlx::query_as(
+        "UPDATE device_commands SET status = 'EXPIRED' WHERE id = $1 AND status IN ('QUEUED', 'SENT') RETURNING invite_id",
+    )
+    .bind(command_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some((Some(invite_id),)) = row {
+        invite::refund_use(&mut tx, invite_id).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Recupera um comando pelo ID, serializado para a API.
+async fn get_command_info(db_pool: &PgPool, command_id: Uuid) -> Result<String, Status> {
+    let command: CommandInfo = sqlx::query_as(&format!(
+        "SELECT {} FROM device_commands WHERE id = $1",
+        COMMAND_COLUMNS
+    ))
+    .bind(command_id)
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)?;
+    Ok(serde_json::to_string(&command).unwrap())
+}
+
+/// Remove o canal de espera pela confirmação de um comando.
+fn forget_waiter(command_id: Uuid) {
+    super::PENDING_COMMANDS
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .remove(&command_id);
+}
+
+/// Registra a confirmação de um comando ecoada em um evento de fechadura.
+/// Retorna o ID do usuário que enviou o comando, se o comando pertencer ao dispositivo.
+pub async fn acknowledge(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    command_id: &str,
+    lock_state: &str,
+) -> Result<Option<String>> {
+    let Ok(command_id) = Uuid::parse_str(command_id) else {
+        return Ok(None);
+    };
+
+    let row: Option<(Option<String>,)> = sqlx::query_as(
+        "UPDATE device_commands SET status = 'APPLIED', lock_state = $1, acked_at = NOW() WHERE id = $2 AND device_id = $3 RETURNING user_id",
+    )
+    .bind(lock_state)
+    .bind(command_id)
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await?;
+    let Some((user_id,)) = row else {
+        return Ok(None);
+    };
+
+    // The request waiting for the ack may be served by another instance
+    cluster::publish(Signal::CommandAcknowledged {
+        command_id,
+        lock_state: lock_state.to_string(),
+    });
+
+    Ok(user_id)
+}
+
+/// Registra a confirmação de um comando sem ID ecoado (LOCKDOWN ou REBOOT), atribuída
+/// ao comando pendente mais antigo do mesmo tipo para o dispositivo.
+pub async fn acknowledge_oldest(db_pool: &PgPool, device_id: Uuid, command: &str) -> Result<()> {
+    sqlx::query(
+        "UPDATE device_commands SET status = 'APPLIED', acked_at = NOW() WHERE id = (
+             SELECT id FROM device_commands WHERE device_id = $1 AND command = $2 AND status IN ('QUEUED', 'SENT')
+             ORDER BY created_at LIMIT 1
+         )",
+    )
+    .bind(device_id)
+    .bind(command)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Recupera o estado de um comando enviado pelo usuário.
+#[get("/command/<command_id>")]
+pub async fn get_command(
+    user: AuthUser,
+    command_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let command_id = Uuid::parse_str(command_id).map_err(|_| Status::BadRequest)?;
+
+    let command: CommandInfo = sqlx::query_as(&format!(
+        "SELECT {} FROM device_commands WHERE id = $1",
+        COMMAND_COLUMNS
+    ))
+    .bind(command_id)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)?;
+
+    // Senders see their own commands; others need access to the device logs
+    if command.user_id.as_deref() != Some(&user.firebase_uid) {
+        authorize(
+            db_pool,
+            command.device_id,
+            &user.firebase_uid,
+            Action::ViewLogs,
+        )
+        .await?;
+    }
+
+    Ok(serde_json::to_string(&command).unwrap())
+}
+
+/// Lista os comandos recentes de um dispositivo, do mais recente ao mais antigo,
+/// opcionalmente filtrados pelo estado (ex.: `SENT` para os ainda pendentes).
+#[get("/commands/<uuid>?<status>")]
+pub async fn get_commands(
+    user: AuthUser,
+    uuid: &str,
+    status: Option<String>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::ViewLogs).await?;
+
+    let commands: Vec<CommandInfo> = sqlx::query_as(&format!(
+        "SELECT {} FROM device_commands WHERE device_id = $1 AND ($2::text IS NULL OR status = $2) ORDER BY created_at DESC LIMIT 100",
+        COMMAND_COLUMNS
+    ))
+    .bind(uuid)
+    .bind(&status)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::to_string(&commands).unwrap())
+}
@@ -0,0 +1,395 @@
+//! Módulo do registro de configurações dos dispositivos.
+//!
+//! Cada chave aceita por `POST /update_config/<uuid>` é descrita uma única vez em
+//! [`CONFIG_KEYS`]: tipo, faixa de valores, unidade, valor padrão, onde é aplicada (no
+//! back-end, em uma coluna de `devices`, ou no dispositivo, via device twin) e a versão
+//! mínima de firmware que a suporta. A validação das requisições e o device twin usam
+//! este registro, e `GET /config_schema` o serve para que o aplicativo monte as telas de
+//! configuração sem duplicar as regras.
+use anyhow::Result;
+use rocket::get;
+use rocket::http::Status;
+use sqlx::{PgPool, Row};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use super::session::AuthUser;
+
+/// Versão do esquema servido em `GET /config_schema`, incrementada quando o formato muda.
+const SCHEMA_VERSION: u32 = 1;
+/// Versão de firmware em que as chaves originais do dispositivo foram introduzidas.
+const BASELINE_FIRMWARE: &str = "1.0.0";
+
+/// Tipo e faixa de valores de uma chave de configuração.
+pub enum ConfigType {
+    /// Inteiro na faixa `min..=max`.
+    Integer { min: i64, max: i64 },
+    /// Número real na faixa `min..=max`.
+    Float { min: f64, max: f64 },
+    /// Booleano, enviado como `"0"` ou `"1"`.
+    Boolean,
+    /// Texto com pelo menos `min_length` caracteres.
+    Text { min_length: usize },
+}
+
+/// Onde uma chave de configuração é aplicada.
+pub enum Location {
+    /// No back-end, na coluna informada de `devices`.
+    Backend { column: &'static str },
+    /// No dispositivo, via device twin. `reported` é a expressão SQL do valor reportado
+    /// no heartbeat, ou `None` se o valor não for reportado.
+    Device { reported: Option<&'static str> },
+}
+
+/// Descrição de uma chave de configuração.
+pub struct ConfigKey {
+    /// Nome da chave, como recebido na API e enviado ao dispositivo.
+    pub key: &'static str,
+    /// Descrição da chave, exibida pelo aplicativo.
+    pub description: &'static str,
+    /// Tipo e faixa de valores.
+    pub kind: ConfigType,
+    /// Unidade do valor (ex.: `s`, `ms`), se houver.
+    pub unit: Option<&'static str>,
+    /// Valor padrão, se houver.
+    pub default: Option<&'static str>,
+    /// Onde a chave é aplicada.
+    pub location: Location,
+    /// Se o valor é secreto: enviado cifrado, nunca reportado nem retornado pela API.
+    pub sensitive: bool,
+    /// Versão mínima de firmware que suporta a chave (apenas chaves do dispositivo).
+    pub min_firmware: Option<&'static str>,
+}
+
+/// Chaves de configuração aceitas.
+pub const CONFIG_KEYS: &[ConfigKey] = &[
+    ConfigKey {
+        key: "wifi_ssid",
+        description: "Nome da rede Wi-Fi",
+        kind: ConfigType::Text { min_length: 1 },
+        unit: None,
+        default: None,
+        location: Location::Device {
+            reported: Some("wifi_ssid"),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "wifi_pass",
+        description: "Senha da rede Wi-Fi",
+        kind: ConfigType::Text { min_length: 0 },
+        unit: None,
+        default: None,
+        location: Location::Device { reported: None },
+        sensitive: true,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "audio_timeout",
+        description: "Duração máxima da gravação de voz",
+        kind: ConfigType::Integer { min: 3, max: 10 },
+        unit: Some("s"),
+        default: Some("5"),
+        location: Location::Device {
+            reported: Some("audio_record_timeout_sec::text"),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "lock_timeout",
+        description: "Tempo até trancar automaticamente após destrancar",
+        kind: ConfigType::Integer {
+            min: 5000,
+            max: 300000,
+        },
+        unit: Some("ms"),
+        default: Some("20000"),
+        location: Location::Device {
+            reported: Some("lock_timeout_ms::text"),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "pairing_timeout",
+        description: "Tempo máximo no modo de pareamento",
+        kind: ConfigType::Integer { min: 60, max: 600 },
+        unit: Some("s"),
+        default: Some("120"),
+        location: Location::Device {
+            reported: Some("pairing_timeout_sec::text"),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "voice_detection_enable",
+        description: "Detecção de voz no dispositivo",
+        kind: ConfigType::Boolean,
+        unit: None,
+        default: Some("1"),
+        location: Location::Device {
+            reported: Some(
+                "CASE WHEN voice_detection_enable THEN '1' WHEN NOT voice_detection_enable THEN '0' END",
+            ),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "vad_rms_threshold",
+        description: "Limiar de energia (RMS) para detectar voz",
+        kind: ConfigType::Integer {
+            min: 500,
+            max: 25000,
+        },
+        unit: None,
+        default: Some("1000"),
+        location: Location::Device {
+            reported: Some("vad_rms_threshold::text"),
+        },
+        sensitive: false,
+        min_firmware: Some(BASELINE_FIRMWARE),
+    },
+    ConfigKey {
+        key: "voice_invite_enable",
+        description: "Aceitar convites por voz",
+        kind: ConfigType::Boolean,
+        unit: None,
+        default: Some("1"),
+        location: Location::Backend {
+            column: "voice_invite_enable",
+        },
+        sensitive: false,
+        min_firmware: None,
+    },
+    ConfigKey {
+        key: "voice_threshold",
+        description: "Confiança mínima para reconhecer um usuário pela voz",
+        kind: ConfigType::Float {
+            min: 0.20,
+            max: 0.90,
+        },
+        unit: None,
+        default: Some("0.6"),
+        location: Location::Backend {
+            column: "voice_threshold",
+        },
+        sensitive: false,
+        min_firmware: None,
+    },
+];
+
+/// Busca a descrição de uma chave de configuração.
+pub fn find(key: &str) -> Option<&'static ConfigKey> {
+    CONFIG_KEYS.iter().find(|k| k.key == key)
+}
+
+/// Chaves cujo valor é secreto.
+pub fn sensitive_keys() -> Vec<&'static str> {
+    CONFIG_KEYS
+        .iter()
+        .filter(|k| k.sensitive)
+        .map(|k| k.key)
+        .collect()
+}
+
+/// Chaves aplicadas pelo dispositivo.
+pub fn device_keys() -> impl Iterator<Item = &'static ConfigKey> {
+    CONFIG_KEYS.iter().filter(|k| k.is_device())
+}
+
+impl ConfigKey {
+    /// Indica se a chave é aplicada pelo dispositivo.
+    pub fn is_device(&self) -> bool {
+        matches!(self.location, Location::Device { .. })
+    }
+
+    /// Valida um valor e o retorna normalizado (ex.: `"0100"` → `"100"`), ou `None` se
+    /// estiver fora do tipo ou da faixa da chave.
+    pub fn validate(&self, value: &str) -> Option<String> {
+        match self.kind {
+            ConfigType::Integer { min, max } => {
+                let val: i64 = value.trim().parse().ok()?;
+                (min..=max).contains(&val).then(|| val.to_string())
+            }
+            ConfigType::Float { min, max } => {
+                let val: f64 = value.trim().parse().ok()?;
+                (min..=max).contains(&val).then(|| val.to_string())
+            }
+            ConfigType::Boolean => match value.trim() {
+                "0" | "1" => Some(value.trim().to_string()),
+                _ => None,
+            },
+            ConfigType::Text { min_length } => {
+                (value.chars().count() >= min_length).then(|| value.to_string())
+            }
+        }
+    }
+
+    /// Descrição da chave em JSON, como servida em `GET /config_schema`.
+    fn schema(&self) -> serde_json::Value {
+        let mut schema = serde_json::json!({
+            "key": self.key,
+            "description": self.description,
+            "unit": self.unit,
+            "default": self.default,
+            "sensitive": self.sensitive,
+            "min_firmware": self.min_firmware,
+        });
+        let fields = match self.kind {
+            ConfigType::Integer { min, max } => {
+                serde_json::json!({ "type": "integer", "min": min, "max": max })
+            }
+            ConfigType::Float { min, max } => {
+                serde_json::json!({ "type": "float", "min": min, "max": max })
+            }
+            ConfigType::Boolean => serde_json::json!({ "type": "boolean" }),
+            ConfigType::Text { min_length } => {
+                serde_json::json!({ "type": "string", "min_length": min_length })
+            }
+        };
+        let location = match self.location {
+            Location::Backend { .. } => "backend",
+            Location::Device { reported } => {
+                schema["reported"] = reported.is_some().into();
+                "device"
+            }
+        };
+        schema["location"] = location.into();
+        for (name, value) in fields.as_object().unwrap() {
+            schema[name] = value.clone();
+        }
+        schema
+    }
+}
+
+/// Recupera o valor atual das chaves do back-end, normalizado como em
+/// [`ConfigKey::validate`].
+pub async fn backend_state(
+    db_pool: &PgPool,
+    device_id: Uuid,
+) -> Result<BTreeMap<&'static str, Option<String>>> {
+    let columns: Vec<(&'static str, String)> = CONFIG_KEYS
+        .iter()
+        .filter_map(|k| match (&k.location, &k.kind) {
+            (Location::Backend { column }, ConfigType::Boolean) => Some((
+                k.key,
+                format!(
+                    "CASE WHEN {c} THEN '1' WHEN NOT {c} THEN '0' END",
+                    c = column
+                ),
+            )),
+            (Location::Backend { column }, _) => Some((k.key, format!("{}::text", column))),
+            _ => None,
+        })
+        .collect();
+    let select: Vec<String> = columns
+        .iter()
+        .map(|(key, expr)| format!("{} AS {}", expr, key))
+        .collect();
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM devices WHERE uuid = $1",
+        select.join(", ")
+    ))
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await?;
+
+    let mut state = BTreeMap::new();
+    if let Some(row) = row {
+        for (key, _) in columns {
+            state.insert(key, row.try_get::<Option<String>, _>(key)?);
+        }
+    }
+    Ok(state)
+}
+
+/// Grava o valor validado de uma chave do back-end em `devices`.
+pub async fn store_backend(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    config: &ConfigKey,
+    value: &str,
+) -> Result<()> {
+    let Location::Backend { column } = config.location else {
+        anyhow::bail!("{} is not a backend config key", config.key);
+    };
+    let sql = format!("UPDATE devices SET {} = $1 WHERE uuid = $2", column);
+    let query = sqlx::query(&sql);
+    let query = match config.kind {
+        ConfigType::Integer { .. } => query.bind(value.parse::<i64>()?),
+        ConfigType::Float { .. } => query.bind(value.parse::<f64>()?),
+        ConfigType::Boolean => query.bind(value == "1"),
+        ConfigType::Text { .. } => query.bind(value.to_string()),
+    };
+    query.bind(device_id).execute(db_pool).await?;
+    Ok(())
+}
+
+/// Recupera o esquema das chaves de configuração: tipo, faixa, unidade, valor padrão,
+/// onde cada chave é aplicada e a versão mínima de firmware que a suporta.
+#[get("/config_schema")]
+pub async fn get_config_schema(_user: AuthUser) -> Result<String, Status> {
+    let keys: Vec<serde_json::Value> = CONFIG_KEYS.iter().map(ConfigKey::schema).collect();
+    Ok(serde_json::json!({ "version": SCHEMA_VERSION, "keys": keys }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(key: &str, value: &str) -> Option<String> {
+        find(key).unwrap().validate(value)
+    }
+
+    #[test]
+    fn integers_are_trimmed_normalised_and_range_checked() {
+        assert_eq!(
+            validate("pairing_timeout", " 0100 "),
+            Some("100".to_string())
+        );
+        assert_eq!(validate("pairing_timeout", "60"), Some("60".to_string()));
+        assert_eq!(validate("pairing_timeout", "600"), Some("600".to_string()));
+        assert_eq!(validate("pairing_timeout", "59"), None);
+        assert_eq!(validate("pairing_timeout", "601"), None);
+        assert_eq!(validate("pairing_timeout", "1.5"), None);
+        assert_eq!(validate("pairing_timeout", ""), None);
+    }
+
+    #[test]
+    fn floats_are_range_checked() {
+        assert_eq!(validate("voice_threshold", "0.5"), Some("0.5".to_string()));
+        assert_eq!(validate("voice_threshold", "0.20"), Some("0.2".to_string()));
+        assert_eq!(validate("voice_threshold", "0.95"), None);
+        assert_eq!(validate("voice_threshold", "NaN"), None);
+        assert_eq!(validate("voice_threshold", "inf"), None);
+    }
+
+    #[test]
+    fn booleans_are_zero_or_one() {
+        assert_eq!(validate("voice_invite_enable", " 1"), Some("1".to_string()));
+        assert_eq!(validate("voice_invite_enable", "0"), Some("0".to_string()));
+        assert_eq!(validate("voice_invite_enable", "true"), None);
+        assert_eq!(validate("voice_invite_enable", "2"), None);
+    }
+
+    #[test]
+    fn text_respects_the_minimum_length() {
+        assert_eq!(validate("wifi_ssid", "casa"), Some("casa".to_string()));
+        assert_eq!(validate("wifi_ssid", ""), None);
+        assert_eq!(validate("wifi_pass", ""), Some(String::new()));
+    }
+
+    #[test]
+    fn defaults_are_valid_values() {
+        for key in CONFIG_KEYS {
+            if let Some(default) = key.default {
+                assert!(key.validate(default).is_some(), "{}", key.key);
+            }
+        }
+        assert!(find("unknown_key").is_none());
+    }
+}
@@ -3,22 +3,32 @@
 //! Este módulo contém funções e estruturas para registro, controle e monitoramento
 //! de dispositivos LockWise via API REST e comunicação MQTT.
 use anyhow::Result;
-use argon2::{Argon2, PasswordHasher, PasswordVerifier, password_hash::PasswordHash};
+use argon2::{Argon2, PasswordVerifier, password_hash::PasswordHash};
 use base64::Engine;
 use chrono::Utc;
 use reqwest::Client;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::{Request, State, get, post};
-use rumqttc::{AsyncClient, QoS};
+use rumqttc::AsyncClient;
 use serde::{Deserialize, Serialize};
 use sqlx::{PgPool, Row};
 use tokio::io::AsyncReadExt;
 use uuid::Uuid;
 
 use super::SpeechbrainUrl;
-use super::Token;
-use super::mqtt::publish_control_message;
+use super::channel;
+use super::cluster::{self, Signal};
+use super::command::{self, CommandOptions};
+use super::config;
+use super::event::{BackendEvent, record_backend_event};
+use super::invite;
+use super::member::{self, Action, Role, authorize, authorize_access};
+use super::ping;
+use super::profile;
+use super::revision::{self, Source};
+use super::session::AuthUser;
+use super::twin;
 
 /// Invólucro para token de dispositivo extraído do cabeçalho Authorization.
 #[derive(Clone)]
@@ -53,20 +63,9 @@ pub struct ControlRequest {
     command: String,
     /// ID do usuário fazendo a requisição
     user_id: String,
-}
-
-/// Estrutura de mensagem para atualizações de status de bloqueio dos dispositivos
-#[derive(Deserialize)]
-pub struct LockStatusMessage {
-    /// Estado de bloqueio (LOCKED/UNLOCKED)
-    pub lock: String,
-    /// Motivo da mudança de bloqueio
-    pub reason: String,
-    /// Tempo de atividade do dispositivo em milissegundos
-    #[allow(dead_code)]
-    pub uptime_ms: u64,
-    /// Timestamp do evento
-    pub timestamp: u64,
+    /// Idempotência e espera pela confirmação
+    #[serde(flatten)]
+    options: CommandOptions,
 }
 
 /// Estrutura para entradas de log retornadas pela API
@@ -104,17 +103,6 @@ pub struct ConfigItem {
     value: String,
 }
 
-/// Estrutura de requisição para registrar um dispositivo com uma senha.
-#[derive(Deserialize)]
-pub struct RegisterDeviceRequest {
-    /// UUID do dispositivo.
-    device_id: String,
-    /// Senha para o dispositivo.
-    user_key: String,
-    /// ID do usuário proprietário do dispositivo.
-    user_id: String,
-}
-
 /// Estrutura para representar uma linha de dispositivo para verificação de voz.
 #[derive(sqlx::FromRow)]
 struct DeviceVoiceRow {
@@ -128,379 +116,201 @@ struct DeviceVoiceRow {
     hashed_passphrase: Option<String>,
 }
 
-/// Atualiza configuração do dispositivo via MQTT.
-/// Valida a requisição, envia configuração ao dispositivo e aguarda confirmação.
+/// Atualiza configuração do dispositivo.
+/// Valida a requisição com o registro de chaves (ver [`super::config`]), aplica as
+/// configurações do back-end e registra as do dispositivo como configuração desejada
+/// (ver [`super::twin`]), retornando o estado de cada chave.
 #[post("/update_config/<uuid>", data = "<request>")]
 pub async fn update_config(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     request: rocket::serde::json::Json<UpdateConfigRequest>,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
-
-    // Check ownership
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid_parsed)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((Some(owner_id),)) = row {
-        if firebase_uid != owner_id {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::NotFound);
+    authorize(
+        db_pool,
+        uuid_parsed,
+        &user.firebase_uid,
+        Action::UpdateConfig,
+    )
+    .await?;
+
+    // Validate configs against the registry, normalizing their values
+    let mut configs = Vec::new();
+    for item in &request.configs {
+        let key = config::find(&item.key).ok_or(Status::BadRequest)?;
+        let value = key.validate(&item.value).ok_or(Status::BadRequest)?;
+        configs.push((key, value));
     }
 
-    // Validate configs
-    for config in &request.configs {
-        match config.key.as_str() {
-            "wifi_ssid" => {
-                if config.value.is_empty() {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "wifi_pass" => {} // Allow empty to not change
-            "audio_timeout" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(3..=10).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "lock_timeout" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(5000..=300000).contains(&val) {
-                    // ms
-                    return Err(Status::BadRequest);
-                }
-            }
-            "pairing_timeout" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(60..=600).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "voice_detection_enable" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(0..=1).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "voice_invite_enable" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(0..=1).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "voice_threshold" => {
-                let val: f64 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(0.20..=0.90).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            "vad_rms_threshold" => {
-                let val: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                if !(500..=25000).contains(&val) {
-                    return Err(Status::BadRequest);
-                }
-            }
-            _ => {
-                return Err(Status::BadRequest);
-            }
-        }
+    // Only keys are recorded so secrets such as wifi_pass stay out of the history
+    let keys: Vec<&str> = request.configs.iter().map(|c| c.key.as_str()).collect();
+    record_backend_event(
+        db_pool,
+        uuid_parsed,
+        BackendEvent::ConfigUpdateRequested,
+        Some(&user.firebase_uid),
+        serde_json::json!({ "keys": keys }),
+    )
+    .await;
+
+    // Profile keys changed here stay with the device on later profile edits
+    if let Err(e) = profile::record_overrides(db_pool, uuid_parsed, &configs).await {
+        println!(
+            "DEBUG: Failed to record config overrides of device {}: {}",
+            uuid_parsed, e
+        );
     }
 
+    apply_config(
+        db_pool,
+        mqtt_client,
+        uuid_parsed,
+        &user.firebase_uid,
+        &configs,
+        Source::User,
+    )
+    .await
+}
+
+/// Aplica configurações já validadas: grava as do back-end, registra as do dispositivo
+/// como configuração desejada e as envia, e registra a revisão resultante (ver
+/// [`super::revision`]). Retorna o documento do device twin.
+pub async fn apply_config(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+    user_id: &str,
+    configs: &[(&'static config::ConfigKey, String)],
+    source: Source,
+) -> Result<String, Status> {
     // Separate backend-only configs from device configs
-    let mut backend_configs = Vec::new();
-    let mut device_configs = Vec::new();
+    let (device_configs, backend_configs): (Vec<_>, Vec<_>) =
+        configs.iter().partition(|(key, _)| key.is_device());
 
-    for config in &request.configs {
-        match config.key.as_str() {
-            "voice_threshold" | "voice_invite_enable" => {
-                backend_configs.push(config);
-            }
-            _ => {
-                device_configs.push(config);
-            }
-        }
+    // Sensitive values are encrypted with the device key and never sent in clear
+    let device_key = channel::device_key(db_pool, device_id)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if device_key.is_none()
+        && !channel::allow_unsigned()
+        && device_configs.iter().any(|(key, _)| key.sensitive)
+    {
+        return Err(Status::Conflict);
     }
 
     // Update backend-only configs directly in database
-    for config in backend_configs {
-        match config.key.as_str() {
-            "voice_threshold" => {
-                let threshold: f64 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                sqlx::query("UPDATE devices SET voice_threshold = $1 WHERE uuid = $2")
-                    .bind(threshold)
-                    .bind(uuid_parsed)
-                    .execute(&**db_pool)
-                    .await
-                    .map_err(|_| Status::InternalServerError)?;
-            }
-            "voice_invite_enable" => {
-                let enable: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                let enable_bool = enable == 1;
-                sqlx::query("UPDATE devices SET voice_invite_enable = $1 WHERE uuid = $2")
-                    .bind(enable_bool)
-                    .bind(uuid_parsed)
-                    .execute(&**db_pool)
-                    .await
-                    .map_err(|_| Status::InternalServerError)?;
-            }
-            "vad_rms_threshold" => {
-                let threshold: i32 = config.value.parse().map_err(|_| Status::BadRequest)?;
-                sqlx::query("UPDATE devices SET vad_rms_threshold = $1 WHERE uuid = $2")
-                    .bind(threshold)
-                    .bind(uuid_parsed)
-                    .execute(&**db_pool)
-                    .await
-                    .map_err(|_| Status::InternalServerError)?;
-            }
-            _ => {} // Should not happen
-        }
+    for (key, value) in backend_configs {
+        config::store_backend(db_pool, device_id, key, value)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
     }
 
-    // Send device configs to the device
-    for config in device_configs {
-        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-        {
-            let updates_mutex = super::PENDING_CONFIG_UPDATES.get().unwrap();
-            let mut updates = updates_mutex.lock().unwrap();
-            updates.insert(uuid.to_string(), tx);
-        }
-
-        // Send update_config
-        let topic = format!("lockwise/{}/control", uuid);
-        let msg = serde_cbor::to_vec(&serde_json::json!({
-            "command": "update_config",
-            "key": config.key,
-            "value": config.value
-        }))
+    // Device configs become the desired state, sent until the device converges
+    let device_configs: Vec<(&str, &str)> = device_configs
+        .iter()
+        .map(|(key, value)| (key.key, value.as_str()))
+        .collect();
+    twin::set_desired(db_pool, device_id, user_id, &device_configs)
+        .await
         .map_err(|_| Status::InternalServerError)?;
-        mqtt_client
-            .publish(topic, QoS::AtMostOnce, false, msg)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
 
-        // Wait for CONFIG_UPDATED, timeout 10s
-        match tokio::time::timeout(std::time::Duration::from_secs(10), rx).await {
-            Ok(Ok(())) => {}
-            Ok(Err(_)) => {
-                return Err(Status::InternalServerError);
-            }
-            Err(_) => {
-                return Err(Status::RequestTimeout);
-            }
-        }
+    let changes: Vec<(&str, &str)> = configs
+        .iter()
+        .map(|(key, value)| (key.key, value.as_str()))
+        .collect();
+    if let Err(e) = revision::record(db_pool, device_id, &changes, source, Some(user_id)).await {
+        println!(
+            "DEBUG: Failed to record config revision of device {}: {}",
+            device_id, e
+        );
     }
 
-    Ok(())
+    if let Err(e) = twin::reconcile(db_pool, mqtt_client, Some(device_id)).await {
+        println!(
+            "DEBUG: Failed to send config to device {}: {}",
+            device_id, e
+        );
+    }
+
+    twin::document(db_pool, device_id)
+        .await
+        .map_err(|_| Status::InternalServerError)
 }
 
-/// Reinicializa um dispositivo remotamente.
+/// Reinicializa um dispositivo remotamente. O comando fica na fila até ser confirmado.
 #[post("/reboot/<uuid>")]
 pub async fn reboot_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
-
-    // Check ownership
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid_parsed)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((Some(owner_id),)) = row {
-        if firebase_uid != owner_id {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::NotFound);
-    }
-
-    // Send REBOOT
-    publish_control_message(mqtt_client, uuid_parsed, "REBOOT".to_string())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
+    authorize(db_pool, uuid_parsed, &user.firebase_uid, Action::Reboot).await?;
 
-    Ok(())
+    // Queue REBOOT until the device acknowledges it
+    command::send(
+        db_pool,
+        mqtt_client,
+        uuid_parsed,
+        &user.firebase_uid,
+        "REBOOT",
+    )
+    .await
 }
 
-/// Bloqueia um dispositivo, impedindo controle adicional.
+/// Bloqueia um dispositivo, impedindo controle adicional. O comando fica na fila até ser
+/// confirmado.
 #[post("/lockdown/<uuid>")]
 pub async fn lockdown_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    authorize(db_pool, uuid_parsed, &user.firebase_uid, Action::Lockdown).await?;
 
-    // Check ownership
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid_parsed)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((Some(owner_id),)) = row {
-        if firebase_uid != owner_id {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::NotFound);
-    }
-
-    // Send LOCKDOWN
-    publish_control_message(mqtt_client, uuid_parsed, "LOCKDOWN".to_string())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    Ok(())
+    // Queue LOCKDOWN until the device acknowledges it
+    command::send(
+        db_pool,
+        mqtt_client,
+        uuid_parsed,
+        &user.firebase_uid,
+        "LOCKDOWN",
+    )
+    .await
 }
 
 /// Faz ping em um dispositivo para verificar conectividade.
+/// Retorna o tempo de ida e volta medido (ver [`super::ping`]).
 #[post("/ping/<uuid>")]
 pub async fn ping_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
-
-    // Check ownership or accepted invite
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid_parsed)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((db_user_id_opt,)) = row {
-        let has_access = if let Some(db_user_id) = db_user_id_opt {
-            // User owns the device
-            if firebase_uid == db_user_id {
-                true
-            } else {
-                // Check for accepted, non-expired invite
-                let now = Utc::now().timestamp_millis();
-                let invite_row: Option<(i32,)> = sqlx::query_as(
-                    "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
-                )
-                .bind(uuid_parsed)
-                .bind(&firebase_uid)
-                .bind(now)
-                .fetch_optional(&**db_pool)
-                .await
-                .map_err(|_| Status::InternalServerError)?;
-                invite_row.is_some()
-            }
-        } else {
-            false // Unpaired device
-        };
-
-        if !has_access {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::NotFound);
-    }
-
-    // Send PING
-    publish_control_message(mqtt_client, uuid_parsed, "PING".to_string())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    // Wait for PONG
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    let start = chrono::Utc::now().timestamp_millis();
-    {
-        let pings_mutex = super::PENDING_PINGS.get().unwrap();
-        let mut pings = pings_mutex.lock().unwrap();
-        pings.insert(uuid.to_string(), (start, tx));
-    }
+    authorize(db_pool, uuid_parsed, &user.firebase_uid, Action::Ping).await?;
 
-    // Timeout after 10 seconds
-    tokio::time::timeout(std::time::Duration::from_secs(10), rx)
-        .await
-        .map_err(|_| Status::RequestTimeout)?
-        .map_err(|_| Status::InternalServerError)?;
-
-    Ok(())
+    ping::ping(db_pool, mqtt_client, uuid_parsed, &user.firebase_uid).await
 }
 
-/// Recupera lista de dispositivos pertencentes ao usuário autenticado.
+/// Recupera lista de dispositivos dos quais o usuário autenticado é proprietário ou membro.
 #[get("/devices")]
-pub async fn get_devices(token: Token, db_pool: &State<PgPool>) -> Result<String, Status> {
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+pub async fn get_devices(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let firebase_uid = user.firebase_uid;
 
     let rows = sqlx::query(
-        "SELECT uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, locked_down_at, voice_detection_enable, voice_invite_enable, voice_threshold, vad_rms_threshold FROM devices WHERE user_id = $1",
+        "SELECT d.uuid, d.user_id, d.last_heard, d.uptime_ms, d.wifi_ssid, d.backend_url, d.mqtt_broker_url, d.mqtt_heartbeat_enable, d.mqtt_heartbeat_interval_sec, d.audio_record_timeout_sec, d.lock_timeout_ms, d.pairing_timeout_sec, d.lock_state, d.locked_down_at, d.voice_detection_enable, d.voice_invite_enable, d.voice_threshold, d.vad_rms_threshold, CASE WHEN d.user_id = $1 THEN 'owner' ELSE m.role END AS role, d.online FROM devices d LEFT JOIN device_members m ON m.device_id = d.uuid AND m.user_id = $1 WHERE d.user_id = $1 OR m.user_id = $1",
     )
     .bind(&firebase_uid)
     .fetch_all(&**db_pool)
@@ -528,10 +338,14 @@ pub async fn get_devices(token: Token, db_pool: &State<PgPool>) -> Result<String
             let voice_invite_enable: Option<bool> = row.get(15);
             let voice_threshold: Option<f64> = row.get(16);
             let vad_rms_threshold: Option<i32> = row.get(17);
+            let role: String = row.get(18);
+            let online: bool = row.get(19);
             serde_json::json!({
                 "uuid": db_uuid.to_string(),
                 "user_id": firebase_uid,
+                "role": role,
                 "last_heard": last_heard.timestamp_millis(),
+                "online": online,
                 "uptime_ms": uptime_ms,
                 "wifi_ssid": wifi_ssid,
                 "backend_url": backend_url,
@@ -554,166 +368,52 @@ pub async fn get_devices(token: Token, db_pool: &State<PgPool>) -> Result<String
     Ok(serde_json::to_string(&devices).unwrap())
 }
 
-/// Registra um dispositivo com uma senha.
-#[post("/register_device", data = "<request>")]
-pub async fn register_device(
-    request: rocket::serde::json::Json<RegisterDeviceRequest>,
-    db_pool: &State<PgPool>,
-) -> Result<(), Status> {
-    let device_uuid = Uuid::parse_str(&request.device_id).map_err(|_| Status::BadRequest)?;
-
-    // Hash the user key
-    let salt =
-        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
-    let argon2 = Argon2::default();
-    let hashed_key = argon2
-        .hash_password(request.user_key.as_bytes(), &salt)
-        .map_err(|_| Status::InternalServerError)?
-        .to_string();
-
-    // Insert or update device
-    sqlx::query(
-        "INSERT INTO devices (uuid, user_id, hashed_passphrase, last_heard, uptime_ms) VALUES ($1, $2, $3, NOW(), 0)
-         ON CONFLICT (uuid) DO UPDATE SET user_id = $2, hashed_passphrase = $3, last_heard = NOW()"
-    )
-    .bind(device_uuid)
-    .bind(&request.user_id)
-    .bind(&hashed_key)
-    .execute(&**db_pool)
-    .await
-    .map_err(|_| Status::InternalServerError)?;
-
-    // Remove any logs from previous owners
-    sqlx::query("DELETE FROM logs WHERE device_id = $1")
-        .bind(device_uuid.to_string())
-        .execute(&**db_pool)
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    // Remove any invites from previous owners
-    sqlx::query("DELETE FROM invites WHERE device_id = $1")
-        .bind(device_uuid)
-        .execute(&**db_pool)
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    Ok(())
-}
-
 /// Envia um comando de controle a um dispositivo (LOCK/UNLOCK).
+/// Retorna o comando registrado; com `wait`, aguarda a confirmação do dispositivo.
 #[post("/control/<uuid>", data = "<request>")]
 pub async fn control_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     request: rocket::serde::json::Json<ControlRequest>,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Check Firebase UID matches request user_id
     if firebase_uid != request.user_id {
         return Err(Status::Unauthorized);
     }
 
-    // Check if user owns the device OR has an accepted, non-expired invite
-    let device_row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-
-    let has_access = if let Some((Some(owner_id),)) = device_row {
-        // User owns the device
-        if request.user_id == owner_id {
-            true
-        } else {
-            // Check for accepted, non-expired invite
-            let now = Utc::now().timestamp_millis();
-            let invite_row: Option<(i32,)> = sqlx::query_as(
-                "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
-            )
-            .bind(uuid)
-            .bind(&request.user_id)
-            .bind(now)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-            invite_row.is_some()
-        }
-    } else {
-        false // Device not found
-    };
+    let (_, via_invite) = authorize_access(db_pool, uuid, &firebase_uid, Action::Control).await?;
 
-    if !has_access {
-        return Err(Status::Unauthorized);
-    }
-
-    // Store recent command
-    let now = chrono::Utc::now().timestamp();
-    {
-        let commands_mutex = super::RECENT_COMMANDS.get().unwrap();
-        let mut commands = commands_mutex.lock().unwrap();
-        commands.insert(uuid.to_string(), (firebase_uid.clone(), now));
-    }
-
-    publish_control_message(mqtt_client, uuid, request.command.clone())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    Ok(())
+    command::dispatch(
+        db_pool,
+        mqtt_client,
+        uuid,
+        &firebase_uid,
+        &request.command,
+        &request.options,
+        via_invite,
+    )
+    .await
 }
 
 /// Despareia um dispositivo do usuário.
 #[post("/unpair/<uuid>")]
 pub async fn unpair_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
     let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
-
-    // Check that the device belongs to this user
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((Some(db_user_id),)) = row {
-        if firebase_uid != db_user_id {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::Unauthorized); // Device not found
-    }
+    authorize(db_pool, uuid, &user.firebase_uid, Action::Unpair).await?;
 
-    // Unpair: set user_id to NULL
-    sqlx::query("UPDATE devices SET user_id = NULL WHERE uuid = $1")
+    // Unpair: set user_id to NULL and drop the key, so the next pairing may use any code
+    sqlx::query("UPDATE devices SET user_id = NULL, device_key = NULL, pending_device_key = NULL, rekey_requested_at = NULL WHERE uuid = $1")
         .bind(uuid)
         .execute(&**db_pool)
         .await
@@ -733,39 +433,83 @@ pub async fn unpair_device(
         .await
         .map_err(|_| Status::InternalServerError)?;
 
+    // Remove all members of this device
+    sqlx::query("DELETE FROM device_members WHERE device_id = $1")
+        .bind(uuid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // Drop pending settings requested by this owner, including a plaintext Wi-Fi password
+    sqlx::query("DELETE FROM device_desired_config WHERE device_id = $1")
+        .bind(uuid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // Drop this owner's configuration history
+    sqlx::query("DELETE FROM device_config_revisions WHERE device_id = $1")
+        .bind(uuid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // Detach the device from this owner's configuration profile
+    sqlx::query("DELETE FROM device_config_profiles WHERE device_id = $1")
+        .bind(uuid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // Cancel firmware updates not yet sent, created with this owner's permission
+    sqlx::query(
+        "UPDATE ota_updates SET status = 'CANCELLED', updated_at = NOW() WHERE device_id = $1 AND status = 'QUEUED'",
+    )
+    .bind(uuid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    // Cancel any pending ownership transfer
+    sqlx::query(
+        "UPDATE device_transfers SET status = 3, resolved_at = NOW() WHERE device_id = $1 AND status = 0",
+    )
+    .bind(uuid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    record_backend_event(
+        db_pool,
+        uuid,
+        BackendEvent::Unpaired,
+        Some(&user.firebase_uid),
+        serde_json::json!({}),
+    )
+    .await;
+
     Ok(())
 }
 
 /// Recupera detalhes de um dispositivo específico.
 #[get("/device/<uuid>")]
 pub async fn get_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
     let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
-    // Check that the device belongs to this user or has accepted invite
-    let row = sqlx::query("SELECT uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, locked_down_at, voice_detection_enable, voice_invite_enable, voice_threshold, vad_rms_threshold FROM devices WHERE uuid = $1")
+    // Access is checked once the device is known to exist
+    let row = sqlx::query("SELECT uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, locked_down_at, voice_detection_enable, voice_invite_enable, voice_threshold, vad_rms_threshold, online FROM devices WHERE uuid = $1")
             .bind(uuid)
             .fetch_optional(&**db_pool)
             .await
             .map_err(|_| Status::InternalServerError)?;
     if let Some(row) = row {
         let db_uuid: Uuid = row.get(0);
-        let db_user_id_opt: Option<String> = row.get(1);
         let last_heard: chrono::DateTime<chrono::Utc> = row.get(2);
         let uptime_ms: Option<i64> = row.get(3);
         let wifi_ssid: Option<String> = row.get(4);
@@ -782,36 +526,16 @@ pub async fn get_device(
         let voice_invite_enable: Option<bool> = row.get(15);
         let voice_threshold: Option<f64> = row.get(16);
         let vad_rms_threshold: Option<i32> = row.get(17);
+        let online: bool = row.get(18);
 
-        let has_access = if let Some(db_user_id) = db_user_id_opt {
-            // User owns the device
-            if firebase_uid == db_user_id {
-                true
-            } else {
-                // Check for accepted, non-expired invite
-                let now = Utc::now().timestamp_millis();
-                let invite_row: Option<(i32,)> = sqlx::query_as(
-                    "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
-                )
-                .bind(uuid)
-                .bind(&firebase_uid)
-                .bind(now)
-                .fetch_optional(&**db_pool)
-                .await
-                .map_err(|_| Status::InternalServerError)?;
-                invite_row.is_some()
-            }
-        } else {
-            false // Unpaired device
-        };
+        let role = authorize(db_pool, uuid, &firebase_uid, Action::View).await?;
 
-        if !has_access {
-            return Err(Status::Unauthorized);
-        }
         let device = serde_json::json!({
             "uuid": db_uuid.to_string(),
             "user_id": firebase_uid,
+            "role": role.as_str(),
             "last_heard": last_heard.timestamp_millis(),
+            "online": online,
             "uptime_ms": uptime_ms,
             "wifi_ssid": wifi_ssid,
             "backend_url": backend_url,
@@ -837,41 +561,18 @@ pub async fn get_device(
 /// Recupera detalhes de um dispositivo acessível temporariamente.
 #[get("/temp_device/<uuid>")]
 pub async fn get_temp_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
-    // Check for accepted, non-expired invite
-    let now = Utc::now().timestamp_millis();
-    let invite_row: Option<(i32,)> = sqlx::query_as(
-        "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
-    )
-    .bind(uuid_parsed)
-    .bind(&firebase_uid)
-    .bind(now)
-    .fetch_optional(&**db_pool)
-    .await
-    .map_err(|_| Status::InternalServerError)?;
-    if invite_row.is_none() {
-        return Err(Status::Unauthorized);
-    }
+    authorize(db_pool, uuid_parsed, &firebase_uid, Action::View).await?;
 
     // Get device data
-    let row = sqlx::query("SELECT uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, locked_down_at, voice_detection_enable, voice_invite_enable, voice_threshold, vad_rms_threshold FROM devices WHERE uuid = $1")
+    let row = sqlx::query("SELECT uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, locked_down_at, voice_detection_enable, voice_invite_enable, voice_threshold, vad_rms_threshold, online FROM devices WHERE uuid = $1")
             .bind(uuid_parsed)
             .fetch_optional(&**db_pool)
             .await
@@ -894,11 +595,13 @@ pub async fn get_temp_device(
         let voice_invite_enable: Option<bool> = row.get(15);
         let voice_threshold: Option<f64> = row.get(16);
         let vad_rms_threshold: Option<i32> = row.get(17);
+        let online: bool = row.get(18);
 
         let device = serde_json::json!({
             "uuid": db_uuid.to_string(),
             "user_id": firebase_uid,
             "last_heard": last_heard.timestamp_millis(),
+            "online": online,
             "uptime_ms": uptime_ms,
             "wifi_ssid": wifi_ssid,
             "backend_url": backend_url,
@@ -924,139 +627,61 @@ pub async fn get_temp_device(
 /// Envia comando de controle a um dispositivo acessível temporariamente.
 #[post("/temp_control/<uuid>", data = "<request>")]
 pub async fn control_temp_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     request: rocket::serde::json::Json<ControlRequest>,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Check Firebase UID matches request user_id
     if firebase_uid != request.user_id {
         return Err(Status::Unauthorized);
     }
 
-    // Check for accepted, non-expired invite
-    let now = Utc::now().timestamp_millis();
-    let invite_row: Option<(i32,)> = sqlx::query_as(
-        "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
+    let (_, via_invite) =
+        authorize_access(db_pool, uuid_parsed, &firebase_uid, Action::Control).await?;
+
+    command::dispatch(
+        db_pool,
+        mqtt_client,
+        uuid_parsed,
+        &firebase_uid,
+        &request.command,
+        &request.options,
+        via_invite,
     )
-    .bind(uuid_parsed)
-    .bind(&request.user_id)
-    .bind(now)
-    .fetch_optional(&**db_pool)
     .await
-    .map_err(|_| Status::InternalServerError)?;
-    if invite_row.is_none() {
-        return Err(Status::Unauthorized);
-    }
-
-    // Store recent command
-    let now_ts = chrono::Utc::now().timestamp();
-    {
-        let commands_mutex = super::RECENT_COMMANDS.get().unwrap();
-        let mut commands = commands_mutex.lock().unwrap();
-        commands.insert(uuid.to_string(), (firebase_uid.clone(), now_ts));
-    }
-
-    publish_control_message(mqtt_client, uuid_parsed, request.command.clone())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    Ok(())
 }
 
 /// Faz ping em um dispositivo acessível temporariamente.
 #[post("/temp_ping/<uuid>")]
 pub async fn ping_temp_device(
-    token: Token,
+    user: AuthUser,
     uuid: &str,
     db_pool: &State<PgPool>,
     mqtt_client: &State<AsyncClient>,
-) -> Result<(), Status> {
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
-
-    // Check for accepted, non-expired invite
-    let now = Utc::now().timestamp_millis();
-    let invite_row: Option<(i32,)> = sqlx::query_as(
-        "SELECT id FROM invites WHERE device_id = $1 AND receiver_id = $2 AND status = 1 AND expiry_timestamp > $3"
-    )
-    .bind(uuid_parsed)
-    .bind(&firebase_uid)
-    .bind(now)
-    .fetch_optional(&**db_pool)
-    .await
-    .map_err(|_| Status::InternalServerError)?;
-    if invite_row.is_none() {
-        return Err(Status::Unauthorized);
-    }
-
-    // Send PING
-    publish_control_message(mqtt_client, uuid_parsed, "PING".to_string())
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    // Wait for PONG
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    let start = chrono::Utc::now().timestamp_millis();
-    {
-        let pings_mutex = super::PENDING_PINGS.get().unwrap();
-        let mut pings = pings_mutex.lock().unwrap();
-        pings.insert(uuid.to_string(), (start, tx));
-    }
+    authorize(db_pool, uuid_parsed, &user.firebase_uid, Action::Ping).await?;
 
-    // Timeout after 10 seconds
-    tokio::time::timeout(std::time::Duration::from_secs(10), rx)
-        .await
-        .map_err(|_| Status::RequestTimeout)?
-        .map_err(|_| Status::InternalServerError)?;
-    Ok(())
+    ping::ping(db_pool, mqtt_client, uuid_parsed, &user.firebase_uid).await
 }
 
 /// Lista dispositivos com acesso temporário.
 #[get("/temp_devices_status")]
 pub async fn get_temp_devices_status(
-    token: Token,
+    user: AuthUser,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     let rows = sqlx::query(
-        "SELECT d.uuid, d.user_id, d.last_heard, d.uptime_ms, d.wifi_ssid, d.backend_url, d.mqtt_broker_url, d.mqtt_heartbeat_enable, d.mqtt_heartbeat_interval_sec, d.audio_record_timeout_sec, d.lock_timeout_ms, d.pairing_timeout_sec, d.lock_state, d.locked_down_at, d.voice_detection_enable, d.voice_invite_enable, d.voice_threshold, d.vad_rms_threshold FROM devices d JOIN invites i ON d.uuid = i.device_id WHERE i.receiver_id = $1 AND i.status = 1 AND i.expiry_timestamp > $2"
+        "SELECT d.uuid, d.user_id, d.last_heard, d.uptime_ms, d.wifi_ssid, d.backend_url, d.mqtt_broker_url, d.mqtt_heartbeat_enable, d.mqtt_heartbeat_interval_sec, d.audio_record_timeout_sec, d.lock_timeout_ms, d.pairing_timeout_sec, d.lock_state, d.locked_down_at, d.voice_detection_enable, d.voice_invite_enable, d.voice_threshold, d.vad_rms_threshold, d.online FROM devices d JOIN invites i ON d.uuid = i.device_id WHERE i.receiver_id = $1 AND i.status = 1 AND i.expiry_timestamp > $2"
     )
     .bind(&firebase_uid)
     .bind(Utc::now().timestamp_millis())
@@ -1084,10 +709,12 @@ pub async fn get_temp_devices_status(
             let voice_invite_enable: Option<bool> = row.get(15);
             let voice_threshold: Option<f64> = row.get(16);
             let vad_rms_threshold: Option<i32> = row.get(17);
+            let online: bool = row.get(18);
             serde_json::json!({
                 "uuid": db_uuid.to_string(),
                 "user_id": firebase_uid,
                 "last_heard": last_heard.timestamp_millis(),
+                "online": online,
                 "uptime_ms": uptime_ms,
                 "wifi_ssid": wifi_ssid,
                 "backend_url": backend_url,
@@ -1112,37 +739,14 @@ pub async fn get_temp_devices_status(
 
 /// Recupera logs de acesso de um dispositivo.
 #[get("/logs/<uuid>")]
-pub async fn get_logs(token: Token, uuid: &str, db_pool: &State<PgPool>) -> Result<String, Status> {
+pub async fn get_logs(
+    user: AuthUser,
+    uuid: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
     let uuid_parsed = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
 
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
-
-    // Check that the device belongs to this user (logs only for owners)
-    let row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(uuid_parsed)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    if let Some((Some(db_user_id),)) = row {
-        if firebase_uid != db_user_id {
-            return Err(Status::Unauthorized);
-        }
-    } else {
-        return Err(Status::Unauthorized); // Device not found or not owned
-    }
+    authorize(db_pool, uuid_parsed, &user.firebase_uid, Action::ViewLogs).await?;
 
     // Get logs, limit to 1000
     let rows = sqlx::query("SELECT l.id, l.device_id, l.timestamp, l.event_type, l.reason, l.user_id, u.name as user_name FROM logs l LEFT JOIN users u ON l.user_id = u.firebase_uid WHERE l.device_id = $1 ORDER BY l.timestamp DESC LIMIT 1000")
@@ -1168,94 +772,51 @@ pub async fn get_logs(token: Token, uuid: &str, db_pool: &State<PgPool>) -> Resu
     Ok(serde_json::to_string(&logs).unwrap())
 }
 
-/// Recupera notificações de dispositivos próprios.
+/// Recupera notificações dos dispositivos cujos logs o usuário pode ler.
 #[get("/notifications?<devices>")]
 pub async fn get_notifications(
-    token: Token,
+    user: AuthUser,
     devices: Option<String>,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
-    // Validate token: get firebase_uid from current_token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
+    let firebase_uid = user.firebase_uid;
+
+    let mut device_uuids =
+        member::devices_with_permission(db_pool, &firebase_uid, Action::ViewLogs)
             .await
             .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
 
-    // Get logs for owned devices, optionally filtered by devices list
-    let logs: Vec<LogEntry> = if let Some(devices_str) = devices {
-        let device_uuids: Vec<Uuid> = devices_str
+    // Optionally filter by devices list
+    if let Some(devices_str) = devices {
+        let requested: Vec<Uuid> = devices_str
             .split(',')
             .filter_map(|s| Uuid::parse_str(s.trim()).ok())
             .collect();
-        if device_uuids.is_empty() {
-            let rows = sqlx::query("SELECT l.id, l.device_id, l.timestamp, l.event_type, l.reason, l.user_id, u.name as user_name FROM logs l LEFT JOIN users u ON l.user_id = u.firebase_uid WHERE l.device_id IN (SELECT uuid::text FROM devices WHERE user_id = $1) ORDER BY l.timestamp DESC LIMIT 1000")
-                .bind(firebase_uid)
-                .fetch_all(&**db_pool)
-                .await
-                .map_err(|_| {
-                    Status::InternalServerError
-                })?;
-            rows.into_iter()
-                .map(|row| LogEntry {
-                    id: row.get(0),
-                    device_id: row.get(1),
-                    timestamp: row.get(2),
-                    event_type: row.get(3),
-                    reason: row.get(4),
-                    user_id: row.get(5),
-                    user_name: row.get(6),
-                })
-                .collect()
-        } else {
-            let device_strings: Vec<String> = device_uuids.iter().map(|u| u.to_string()).collect();
-            let rows = sqlx::query("SELECT l.id, l.device_id, l.timestamp, l.event_type, l.reason, l.user_id, u.name as user_name FROM logs l LEFT JOIN users u ON l.user_id = u.firebase_uid WHERE l.device_id IN (SELECT uuid::text FROM devices WHERE user_id = $1) AND l.device_id = ANY($2) ORDER BY l.timestamp DESC LIMIT 1000")
-                .bind(firebase_uid)
-                .bind(&device_strings)
-                .fetch_all(&**db_pool)
-                .await
-                .map_err(|_| {
-                    Status::InternalServerError
-                })?;
-            rows.into_iter()
-                .map(|row| LogEntry {
-                    id: row.get(0),
-                    device_id: row.get(1),
-                    timestamp: row.get(2),
-                    event_type: row.get(3),
-                    reason: row.get(4),
-                    user_id: row.get(5),
-                    user_name: row.get(6),
-                })
-                .collect()
+        if !requested.is_empty() {
+            device_uuids.retain(|uuid| requested.contains(uuid));
         }
-    } else {
-        let rows = sqlx::query("SELECT l.id, l.device_id, l.timestamp, l.event_type, l.reason, l.user_id, u.name as user_name FROM logs l LEFT JOIN users u ON l.user_id = u.firebase_uid WHERE l.device_id IN (SELECT uuid::text FROM devices WHERE user_id = $1) ORDER BY l.timestamp DESC LIMIT 1000")
-            .bind(firebase_uid)
-            .fetch_all(&**db_pool)
-            .await
-            .map_err(|_| {
-                Status::InternalServerError
-            })?;
-        rows.into_iter()
-            .map(|row| LogEntry {
-                id: row.get(0),
-                device_id: row.get(1),
-                timestamp: row.get(2),
-                event_type: row.get(3),
-                reason: row.get(4),
-                user_id: row.get(5),
-                user_name: row.get(6),
-            })
-            .collect()
-    };
+    }
+
+    let device_strings: Vec<String> = device_uuids.iter().map(|u| u.to_string()).collect();
+    let rows = sqlx::query("SELECT l.id, l.device_id, l.timestamp, l.event_type, l.reason, l.user_id, u.name as user_name FROM logs l LEFT JOIN users u ON l.user_id = u.firebase_uid WHERE l.device_id = ANY($1) ORDER BY l.timestamp DESC LIMIT 1000")
+        .bind(&device_strings)
+        .fetch_all(&**db_pool)
+        .await
+        .map_err(|_| {
+            Status::InternalServerError
+        })?;
+    let logs: Vec<LogEntry> = rows
+        .into_iter()
+        .map(|row| LogEntry {
+            id: row.get(0),
+            device_id: row.get(1),
+            timestamp: row.get(2),
+            event_type: row.get(3),
+            reason: row.get(4),
+            user_id: row.get(5),
+            user_name: row.get(6),
+        })
+        .collect();
 
     Ok(serde_json::to_string(&logs).unwrap())
 }
@@ -1319,6 +880,7 @@ pub async fn verify_voice(
     // Collect embeddings
     let mut user_embeddings = Vec::new();
     let mut user_ids = Vec::new();
+    let mut invited_ids = Vec::new();
 
     // Always include owner
     let owner_row: Option<(Option<Vec<u8>>,)> =
@@ -1340,6 +902,32 @@ pub async fn verify_voice(
         return Err(Status::BadRequest); // Owner must have voice registered
     }
 
+    // Include members allowed to unlock; guests only if voice_invite_enable
+    let voice_roles: Vec<&str> = Action::Control
+        .allowed_roles()
+        .iter()
+        .filter(|role| voice_invite_enable || **role != Role::Guest)
+        .map(|role| role.as_str())
+        .collect();
+    let member_rows: Vec<(String, Vec<u8>)> = sqlx::query_as(
+        "SELECT u.firebase_uid, u.voice_embeddings FROM users u JOIN device_members m ON u.firebase_uid = m.user_id WHERE m.device_id = $1 AND m.role = ANY($2) AND u.voice_embeddings IS NOT NULL",
+    )
+    .bind(device_uuid)
+    .bind(&voice_roles)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    for (member_id, emb) in member_rows {
+        println!(
+            "DEBUG: Found voice embeddings for member {} ({} bytes)",
+            member_id,
+            emb.len()
+        );
+        user_embeddings.push(base64::engine::general_purpose::STANDARD.encode(&emb));
+        user_ids.push(member_id);
+    }
+
     // If voice_invite_enable, include invited users
     if voice_invite_enable {
         let now = Utc::now().timestamp_millis();
@@ -1354,14 +942,30 @@ pub async fn verify_voice(
             Status::InternalServerError
         })?;
 
+        // Only invites currently within their schedule may unlock
+        let active = invite::active_receivers(db_pool, device_uuid)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+
         for (invite_user_id, emb) in invite_rows {
+            if user_ids.contains(&invite_user_id) {
+                continue; // Already a candidate as owner or member
+            }
+            if !active.contains(&invite_user_id) {
+                println!(
+                    "DEBUG: Skipping invited user {} outside invite schedule",
+                    invite_user_id
+                );
+                continue;
+            }
             println!(
                 "DEBUG: Found voice embeddings for invited user {} ({} bytes)",
                 invite_user_id,
                 emb.len()
             );
             user_embeddings.push(base64::engine::general_purpose::STANDARD.encode(&emb));
-            user_ids.push(invite_user_id);
+            user_ids.push(invite_user_id.clone());
+            invited_ids.push(invite_user_id);
         }
     }
 
@@ -1444,14 +1048,29 @@ pub async fn verify_voice(
 
         let matched_user_id = &user_ids[best_index];
 
-        // Store recent voice verification
-        let now = chrono::Utc::now().timestamp();
+        // The invite use is consumed once the device reports the unlock
+        let via_invite = invited_ids.contains(matched_user_id);
+        if via_invite
+            && !invite::has_use(db_pool, device_uuid, matched_user_id)
+                .await
+                .map_err(|_| Status::InternalServerError)?
         {
-            let commands_mutex = super::RECENT_COMMANDS.get().unwrap();
-            let mut commands = commands_mutex.lock().unwrap();
-            commands.insert(device_id.to_string(), (matched_user_id.clone(), now));
+            println!(
+                "DEBUG: Invite of user {} has no uses left, denying unlock",
+                matched_user_id
+            );
+            return Err(Status::Forbidden);
         }
 
+        // Store recent voice verification on every instance, since the
+        // lock event may be processed by another one
+        cluster::publish(Signal::VoiceMatch {
+            device_id: device_uuid,
+            user_id: matched_user_id.clone(),
+            at: chrono::Utc::now().timestamp(),
+            via_invite,
+        });
+
         println!(
             "DEBUG: Stored recent voice verification for user {}",
             matched_user_id
@@ -1471,20 +1090,10 @@ pub async fn verify_voice(
 /// Lista dispositivos acessíveis ao usuário (próprios ou convidados).
 #[get("/accessible_devices")]
 pub async fn get_accessible_devices(
-    token: Token,
+    user: AuthUser,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let user_id = user.firebase_uid;
 
     // Get accepted invites that haven't expired
     let invites: Vec<(i32, uuid::Uuid, String, i64)> =
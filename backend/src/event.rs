@@ -0,0 +1,250 @@
+//! Módulo para o histórico unificado de eventos dos dispositivos.
+//!
+//! Toda mensagem recebida de um dispositivo (inclusive heartbeats e mensagens que não
+//! puderam ser decodificadas) e toda ação do back-end sobre ele (comandos enviados,
+//! alterações de configuração, pareamento e transferência) é registrada em
+//! `device_events`, com um tipo e um payload JSON, para diagnóstico posterior.
+use anyhow::Result;
+use rocket::http::Status;
+use rocket::{State, get};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::member::{Action, authorize};
+use super::pairing::PAIRING_CODE_PREFIX;
+use super::protocol::{DeviceMessage, Envelope};
+use super::session::AuthUser;
+
+/// Número máximo de eventos retornados por consulta.
+const MAX_EVENTS_PER_PAGE: i64 = 500;
+
+/// Origem de um evento.
+#[derive(Clone, Copy)]
+pub enum EventSource {
+    /// Mensagem publicada pelo dispositivo.
+    Device,
+    /// Ação executada pelo back-end.
+    Backend,
+}
+
+impl EventSource {
+    /// Nome da origem, como armazenado no banco.
+    fn as_str(self) -> &'static str {
+        match self {
+            EventSource::Device => "DEVICE",
+            EventSource::Backend => "BACKEND",
+        }
+    }
+}
+
+/// Ações do back-end registradas no histórico.
+#[derive(Clone, Copy)]
+pub enum BackendEvent {
+    /// Comando publicado para o dispositivo.
+    CommandSent,
+    /// Alteração de configuração solicitada.
+    ConfigUpdateRequested,
+    /// Dispositivo pareado a um usuário.
+    Paired,
+    /// Dispositivo despareado.
+    Unpaired,
+    /// Propriedade transferida a outro usuário.
+    OwnershipTransferred,
+    /// Dispositivo considerado offline.
+    WentOffline,
+    /// Dispositivo de volta online.
+    CameOnline,
+}
+
+impl BackendEvent {
+    /// Tipo do evento, como armazenado no banco.
+    fn kind(self) -> &'static str {
+        match self {
+            BackendEvent::CommandSent => "COMMAND_SENT",
+            BackendEvent::ConfigUpdateRequested => "CONFIG_UPDATE_REQUESTED",
+            BackendEvent::Paired => "PAIRED",
+            BackendEvent::Unpaired => "UNPAIRED",
+            BackendEvent::OwnershipTransferred => "OWNERSHIP_TRANSFERRED",
+            BackendEvent::WentOffline => "DEVICE_OFFLINE",
+            BackendEvent::CameOnline => "DEVICE_ONLINE",
+        }
+    }
+}
+
+/// Evento do histórico, como retornado pela API.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct DeviceEventInfo {
+    /// ID do evento.
+    id: i64,
+    /// Tipo do evento (ex.: POWER_ON, HEARTBEAT, COMMAND_SENT).
+    kind: String,
+    /// Origem do evento (DEVICE ou BACKEND).
+    source: String,
+    /// Dados do evento em JSON.
+    #[sqlx(try_from = "String")]
+    payload: JsonText,
+    /// Usuário associado ao evento, se houver.
+    user_id: Option<String>,
+    /// Timestamp informado pelo dispositivo (segundos desde a época Unix).
+    device_timestamp: Option<i64>,
+    /// Tempo de atividade informado pelo dispositivo em milissegundos.
+    uptime_ms: Option<i64>,
+    /// Momento em que o evento foi registrado.
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Payload JSON lido como texto do banco e serializado como objeto na API.
+#[derive(Serialize)]
+#[serde(transparent)]
+struct JsonText(serde_json::Value);
+
+impl TryFrom<String> for JsonText {
+    type Error = serde_json::Error;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        serde_json::from_str(&text).map(JsonText)
+    }
+}
+
+/// Registra uma mensagem recebida de um dispositivo.
+pub async fn record_device_message(db_pool: &PgPool, device_id: Uuid, envelope: &Envelope) {
+    let mut payload = match &envelope.message {
+        DeviceMessage::Heartbeat(heartbeat) => serde_json::to_value(heartbeat),
+        DeviceMessage::Lock(lock) => serde_json::to_value(lock),
+        DeviceMessage::OtaProgress(progress) => Ok(serde_json::json!({ "progress": progress })),
+        DeviceMessage::OtaFailed(error) => Ok(serde_json::json!({ "error": error })),
+        DeviceMessage::Unknown(event) => Ok(serde_json::json!({ "event": event })),
+        _ => Ok(serde_json::json!({})),
+    }
+    .unwrap_or_default();
+
+    // Pairing codes presented in heartbeats are secrets; keep only the prefix
+    if let Some(user_id) = payload.get_mut("user_id")
+        && user_id
+            .as_str()
+            .is_some_and(|id| id.starts_with(PAIRING_CODE_PREFIX))
+    {
+        *user_id = serde_json::json!(PAIRING_CODE_PREFIX);
+    }
+
+    let result = sqlx::query(
+        "INSERT INTO device_events (device_id, kind, source, payload, device_timestamp, uptime_ms) VALUES ($1, $2, $3, $4::jsonb, $5, $6)",
+    )
+    .bind(device_id)
+    .bind(envelope.message.name())
+    .bind(EventSource::Device.as_str())
+    .bind(payload.to_string())
+    .bind(envelope.timestamp.map(|t| t as i64))
+    .bind(envelope.uptime_ms.map(|u| u as i64))
+    .execute(db_pool)
+    .await;
+    if let Err(e) = result {
+        println!(
+            "DEBUG: Failed to record event of device {}: {}",
+            device_id, e
+        );
+    }
+}
+
+/// Registra uma mensagem de dispositivo rejeitada: `DECODE_FAILED` se não pôde ser
+/// decodificada ou `UNAUTHENTICATED` se não passou pela verificação de assinatura.
+pub async fn record_rejected_message(db_pool: &PgPool, device_id: Uuid, kind: &str, error: &str) {
+    if let Err(e) = insert(
+        db_pool,
+        device_id,
+        kind,
+        EventSource::Device,
+        None,
+        serde_json::json!({ "error": error }),
+    )
+    .await
+    {
+        println!(
+            "DEBUG: Failed to record event of device {}: {}",
+            device_id, e
+        );
+    }
+}
+
+/// Registra uma ação do back-end sobre um dispositivo.
+pub async fn record_backend_event(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    event: BackendEvent,
+    user_id: Option<&str>,
+    payload: serde_json::Value,
+) {
+    if let Err(e) = insert(
+        db_pool,
+        device_id,
+        event.kind(),
+        EventSource::Backend,
+        user_id,
+        payload,
+    )
+    .await
+    {
+        println!(
+            "DEBUG: Failed to record event of device {}: {}",
+            device_id, e
+        );
+    }
+}
+
+/// Insere um evento sem dados informados pelo dispositivo.
+async fn insert(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    kind: &str,
+    source: EventSource,
+    user_id: Option<&str>,
+    payload: serde_json::Value,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO device_events (device_id, kind, source, payload, user_id) VALUES ($1, $2, $3, $4::jsonb, $5)",
+    )
+    .bind(device_id)
+    .bind(kind)
+    .bind(source.as_str())
+    .bind(payload.to_string())
+    .bind(user_id)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Recupera o histórico de eventos de um dispositivo, do mais recente ao mais antigo.
+/// Filtros opcionais: `kind` (ex.: POWER_ON), `source` (DEVICE ou BACKEND), `before`
+/// (ID do evento, para paginação) e `limit` (até 500, padrão 100).
+#[get("/events/<uuid>?<kind>&<source>&<before>&<limit>")]
+pub async fn get_events(
+    user: AuthUser,
+    uuid: &str,
+    kind: Option<String>,
+    source: Option<String>,
+    before: Option<i64>,
+    limit: Option<i64>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::ViewEvents).await?;
+
+    let limit = limit.unwrap_or(100).clamp(1, MAX_EVENTS_PER_PAGE);
+    let events: Vec<DeviceEventInfo> = sqlx::query_as(
+        "SELECT id, kind, source, payload::text AS payload, user_id, device_timestamp, uptime_ms, created_at FROM device_events
+         WHERE device_id = $1 AND ($2::text IS NULL OR kind = $2) AND ($3::text IS NULL OR source = $3) AND ($4::bigint IS NULL OR id < $4)
+         ORDER BY id DESC LIMIT $5",
+    )
+    .bind(uuid)
+    .bind(&kind)
+    .bind(&source)
+    .bind(before)
+    .bind(limit)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::to_string(&events).unwrap())
+}
@@ -0,0 +1,335 @@
+//! Módulo para verificação de ID tokens do Firebase Authentication.
+//!
+//! Em produção, os tokens são verificados pelo crate `firebase-auth` contra as chaves
+//! públicas do Google, que são atualizadas automaticamente conforme o `Cache-Control`.
+//! Para testes de integração e ambientes locais, `FIREBASE_JWKS_URL` aponta para um
+//! JWKS substituto, permitindo emitir tokens assinados com chaves próprias.
+use anyhow::Result;
+use firebase_auth::{FirebaseAuth, FirebaseUser};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// Intervalo mínimo entre buscas do JWKS motivadas por um `kid` desconhecido. Dentro dele,
+/// tokens com `kid` desconhecido são recusados sem nova busca.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Verificador de ID tokens do Firebase, gerenciado como estado do Rocket.
+pub enum FirebaseVerifier {
+    /// Chaves públicas do Google, via `firebase-auth`.
+    Google(FirebaseAuth),
+    /// JWKS configurável localmente, usado em testes e desenvolvimento.
+    Local(LocalJwks),
+}
+
+/// Verificador baseado em um JWKS obtido de uma URL arbitrária.
+pub struct LocalJwks {
+    /// URL do JWKS.
+    url: String,
+    /// ID do projeto Firebase, usado como audiência.
+    project_id: String,
+    /// Emissor esperado dos tokens.
+    issuer: String,
+    /// Chaves obtidas na última busca.
+    keys: RwLock<JwkSet>,
+    /// Momento da última busca, bem-sucedida ou não.
+    last_refresh: Mutex<Instant>,
+}
+
+impl FirebaseVerifier {
+    /// Cria o verificador para o projeto informado. Se `jwks_url` for informado,
+    /// as chaves são buscadas dessa URL em vez das chaves públicas do Google.
+    pub async fn new(project_id: &str, jwks_url: Option<String>) -> Result<Self> {
+        match jwks_url {
+            Some(url) => {
+                let local = LocalJwks {
+                    url,
+                    project_id: project_id.to_string(),
+                    issuer: format!("https://securetoken.google.com/{}", project_id),
+                    keys: RwLock::new(JwkSet { keys: Vec::new() }),
+                    last_refresh: Mutex::new(Instant::now()),
+                };
+                local.refresh().await?;
+                println!("DEBUG: Verifying Firebase ID tokens against {}", local.url);
+                Ok(FirebaseVerifier::Local(local))
+            }
+            None => Ok(FirebaseVerifier::Google(
+                FirebaseAuth::new(project_id).await,
+            )),
+        }
+    }
+
+    /// Verifica a assinatura, emissor, audiência e validade de um ID token,
+    /// retornando as claims do usuário em caso de sucesso.
+    pub async fn verify(&self, id_token: &str) -> Option<FirebaseUser> {
+        match self {
+            FirebaseVerifier::Google(auth) => auth.verify(id_token).ok(),
+            FirebaseVerifier::Local(local) => local.verify(id_token).await,
+        }
+    }
+}
+
+impl LocalJwks {
+    /// Busca novamente o JWKS configurado.
+    async fn refresh(&self) -> Result<()> {
+        let set: JwkSet = reqwest::get(&self.url).await?.json().await?;
+        *self.keys.write().await = set;
+        Ok(())
+    }
+
+    /// Busca o JWKS novamente se a última busca tiver mais de [`MIN_REFRESH_INTERVAL`].
+    /// Requisições simultâneas esperam pela mesma busca em vez de repeti-la.
+    async fn refresh_throttled(&self) {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < MIN_REFRESH_INTERVAL {
+            return;
+        }
+        // Failed fetches count too, so an unreachable JWKS is not hammered either
+        *last_refresh = Instant::now();
+        if let Err(e) = self.refresh().await {
+            println!("DEBUG: Failed to refresh JWKS from {}: {}", self.url, e);
+        }
+    }
+
+    /// Verifica um ID token, buscando o JWKS novamente (com limite de frequência) se o
+    /// `kid` for desconhecido.
+    async fn verify(&self, id_token: &str) -> Option<FirebaseUser> {
+        let header = decode_header(id_token).ok()?;
+        if header.alg != Algorithm::RS256 {
+            return None;
+        }
+        let kid = header.kid?;
+
+        if self.keys.read().await.find(&kid).is_none() {
+            // Keys may have been rotated since the last fetch
+            self.refresh_throttled().await;
+        }
+        let keys = self.keys.read().await;
+        let key = DecodingKey::from_jwk(keys.find(&kid)?).ok()?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.project_id]);
+        validation.set_issuer(&[&self.issuer]);
+
+        decode::<FirebaseUser>(id_token, &key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const PROJECT_ID: &str = "lockwise-test";
+    const KEY_1: &str = include_str!("../testdata/firebase_key_1.pem");
+    const KEY_2: &str = include_str!("../testdata/firebase_key_2.pem");
+    /// Módulos (base64url) das chaves de teste, como publicados no JWKS.
+    const MODULUS_1: &str = "112K7hSFklEHpU18VLk8uCorT6jCiwS0M9ELOVRgIGa8NGL5quaqKon_LfZ_6k881xmqUScwRLf470KjdGqLSI-mQw5B808kqn0EHKZjozl5SPG9PiobgFyUPvp5Uzn8rcMquWWKaF6-e-wz9C5F0BVMtrN0Gyd6SsOp9wF_-wsUy1L_lzA_4d-9jBKc2YtZoKSOC-3Bb4_tJflOZI7tgIqAKC-Oig1kMLMB3_OqAuKytyaopRtdp2jNxcX-qlI5CZROKgJ8FRcyqWfczASwQlw9lhu-qF3_OUPOpwpO1Xd0kL_fqKdEpznFyFBq46VpXv7GEzjotOBp4FuEJNOOZQ";
+    const MODULUS_2: &str = "nX6WeF1CU7g8Ds4z7BF5dSydsaZXZA7wbZTzZbkgaO0kVoKMf6TPT1XswVYp07o1N10_srzetb26UGIymNGHcb2y6qWQFYhG5BkUy3mR8tfTT5HjLFOL7kkkAGsCtxlHOaaazRHQVi2bJ76dbtF0-4q0_gLBzdIXZqDO1QoJO_aSDOyBGQHqP8jaNz-dSbikSbFAt7JCmW9lBipzsyqRySKqhnQoApSWyZuIR7p6u5huDxESA5Ir1PnZodwqNw3m2vsbBDUYy4nYJcCTLLt9OMF2QNwDV490DYg2m9CJ-443S3LR-opNWJ5PyfxQDy1pt5dAsXkM4WoUcEwmiAnDyw";
+
+    /// Servidor HTTP mínimo que serve o JWKS atual e conta as buscas.
+    struct JwksServer {
+        url: String,
+        body: Arc<std::sync::Mutex<String>>,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl JwksServer {
+        async fn start(keys: &[(&str, &str)]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+            let body = Arc::new(std::sync::Mutex::new(jwks(keys)));
+            let fetches = Arc::new(AtomicUsize::new(0));
+
+            let (served, counter) = (body.clone(), fetches.clone());
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let body = served.lock().unwrap().clone();
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                }
+            });
+
+            JwksServer { url, body, fetches }
+        }
+
+        fn publish(&self, keys: &[(&str, &str)]) {
+            *self.body.lock().unwrap() = jwks(keys);
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    /// JWKS com as chaves RSA informadas (`kid`, módulo).
+    fn jwks(keys: &[(&str, &str)]) -> String {
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(kid, n)| {
+                serde_json::json!({
+                    "kty": "RSA", "alg": "RS256", "use": "sig", "kid": kid, "n": n, "e": "AQAB",
+                })
+            })
+            .collect();
+        serde_json::json!({ "keys": keys }).to_string()
+    }
+
+    fn claims(audience: &str, issuer: &str, expires_in: i64) -> serde_json::Value {
+        let now = chrono::Utc::now().timestamp();
+        serde_json::json!({
+            "iss": issuer,
+            "aud": audience,
+            "sub": "user-1",
+            "user_id": "user-1",
+            "iat": now - 10,
+            "auth_time": now - 10,
+            "exp": now + expires_in,
+            "firebase": { "sign_in_provider": "password", "identities": {} },
+        })
+    }
+
+    fn valid_claims() -> serde_json::Value {
+        claims(
+            PROJECT_ID,
+            &format!("https://securetoken.google.com/{}", PROJECT_ID),
+            3600,
+        )
+    }
+
+    fn token(kid: &str, pem: &str, claims: &serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        encode(
+            &header,
+            claims,
+            &EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn verifier(server: &JwksServer) -> FirebaseVerifier {
+        FirebaseVerifier::new(PROJECT_ID, Some(server.url.clone()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_tokens_signed_with_a_published_key() {
+        let server = JwksServer::start(&[("key-1", MODULUS_1)]).await;
+        let verifier = verifier(&server).await;
+        assert_eq!(server.fetches(), 1);
+
+        let user = verifier
+            .verify(&token("key-1", KEY_1, &valid_claims()))
+            .await
+            .unwrap();
+        assert_eq!(user.user_id, "user-1");
+        assert_eq!(user.aud, PROJECT_ID);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_claims_and_signatures() {
+        let server = JwksServer::start(&[("key-1", MODULUS_1)]).await;
+        let verifier = verifier(&server).await;
+        let issuer = format!("https://securetoken.google.com/{}", PROJECT_ID);
+
+        let wrong_audience = claims("another-project", &issuer, 3600);
+        assert!(
+            verifier
+                .verify(&token("key-1", KEY_1, &wrong_audience))
+                .await
+                .is_none()
+        );
+
+        let wrong_issuer = claims(PROJECT_ID, "https://securetoken.google.com/other", 3600);
+        assert!(
+            verifier
+                .verify(&token("key-1", KEY_1, &wrong_issuer))
+                .await
+                .is_none()
+        );
+
+        let expired = claims(PROJECT_ID, &issuer, -3600);
+        assert!(
+            verifier
+                .verify(&token("key-1", KEY_1, &expired))
+                .await
+                .is_none()
+        );
+
+        // Signed with a key other than the one published under this kid
+        let forged = token("key-1", KEY_2, &valid_claims());
+        assert!(verifier.verify(&forged).await.is_none());
+
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("key-1".to_string());
+        let symmetric = encode(
+            &header,
+            &valid_claims(),
+            &EncodingKey::from_secret(MODULUS_1.as_bytes()),
+        )
+        .unwrap();
+        assert!(verifier.verify(&symmetric).await.is_none());
+
+        let without_kid = encode(
+            &Header::new(Algorithm::RS256),
+            &valid_claims(),
+            &EncodingKey::from_rsa_pem(KEY_1.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        assert!(verifier.verify(&without_kid).await.is_none());
+        assert!(verifier.verify("not a token").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fetches_rotated_keys_at_most_once_per_interval() {
+        let server = JwksServer::start(&[("key-1", MODULUS_1)]).await;
+        let verifier = verifier(&server).await;
+        let FirebaseVerifier::Local(local) = &verifier else {
+            panic!("expected a local verifier");
+        };
+        let rotated = token("key-2", KEY_2, &valid_claims());
+
+        // The key is published right after the initial fetch, within the interval
+        server.publish(&[("key-1", MODULUS_1), ("key-2", MODULUS_2)]);
+        assert!(verifier.verify(&rotated).await.is_none());
+        assert!(verifier.verify(&rotated).await.is_none());
+        assert_eq!(server.fetches(), 1);
+
+        *local.last_refresh.lock().await -= MIN_REFRESH_INTERVAL;
+        assert!(verifier.verify(&rotated).await.is_some());
+        assert_eq!(server.fetches(), 2);
+
+        // Known keys never trigger a fetch
+        *local.last_refresh.lock().await -= MIN_REFRESH_INTERVAL;
+        assert!(verifier.verify(&rotated).await.is_some());
+        assert!(
+            verifier
+                .verify(&token("key-1", KEY_1, &valid_claims()))
+                .await
+                .is_some()
+        );
+        assert_eq!(server.fetches(), 2);
+    }
+}
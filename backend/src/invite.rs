@@ -3,14 +3,21 @@
 //! Este módulo implementa a funcionalidade de convites temporários para compartilhamento
 //! de acesso a dispositivos LockWise entre usuários.
 use anyhow::Result;
-use chrono::Utc;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use chrono::{Datelike, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
 use rocket::http::Status;
 use rocket::{State, get, post};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
 use uuid::Uuid;
 
-use super::Token;
+use super::member::{self, Action};
+use super::session::AuthUser;
+use super::{HomepageUrl, JWT_KEYS, notify_users};
 
 /// Informações sobre convites enviados.
 #[derive(sqlx::FromRow, Serialize)]
@@ -21,18 +28,36 @@ pub struct SentInviteInfo {
     device_id: uuid::Uuid,
     /// ID do usuário remetente.
     sender_id: String,
-    /// ID do usuário destinatário.
-    receiver_id: String,
+    /// ID do usuário destinatário (ausente em códigos ainda não resgatados).
+    receiver_id: Option<String>,
     /// Nome do destinatário.
-    receiver_name: String,
+    receiver_name: Option<String>,
     /// Email do destinatário.
-    receiver_email: String,
-    /// Status do convite (0: pendente, 1: aceito).
+    receiver_email: Option<String>,
+    /// Expiração (ms) do código de convite, se o convite foi criado por código.
+    code_expires_at: Option<i64>,
+    /// Momento em que o código foi resgatado.
+    code_redeemed_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Momento em que o código foi revogado.
+    code_revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Status do convite (0: pendente, 1: aceito, 2: esgotado).
     status: i32,
     /// Timestamp de expiração.
     expiry_timestamp: i64,
     /// Timestamp de criação.
     created_at: chrono::DateTime<chrono::Utc>,
+    /// Timestamp a partir do qual o convite é válido.
+    starts_at: Option<i64>,
+    /// Número máximo de destrancamentos (ausente para ilimitado).
+    max_uses: Option<i32>,
+    /// Destrancamentos restantes.
+    remaining_uses: Option<i32>,
+    /// Fuso horário das janelas do convite.
+    #[serde(skip)]
+    timezone: Option<String>,
+    /// Agenda recorrente do convite, se houver.
+    #[sqlx(skip)]
+    schedule: Option<InviteSchedule>,
 }
 
 /// Informações sobre convites recebidos.
@@ -50,12 +75,60 @@ pub struct ReceivedInviteInfo {
     sender_name: String,
     /// Email do remetente.
     sender_email: String,
-    /// Status do convite.
+    /// Status do convite (0: pendente, 1: aceito, 2: esgotado).
     status: i32,
     /// Timestamp de expiração.
     expiry_timestamp: i64,
     /// Timestamp de criação.
     created_at: chrono::DateTime<chrono::Utc>,
+    /// Timestamp a partir do qual o convite é válido.
+    starts_at: Option<i64>,
+    /// Número máximo de destrancamentos (ausente para ilimitado).
+    max_uses: Option<i32>,
+    /// Destrancamentos restantes.
+    remaining_uses: Option<i32>,
+    /// Fuso horário das janelas do convite.
+    #[serde(skip)]
+    timezone: Option<String>,
+    /// Agenda recorrente do convite, se houver.
+    #[sqlx(skip)]
+    schedule: Option<InviteSchedule>,
+}
+
+/// Agenda recorrente de um convite: o acesso só é válido dentro das janelas semanais.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct InviteSchedule {
+    /// Fuso horário IANA das janelas (ex.: "America/Sao_Paulo").
+    timezone: String,
+    /// Janelas semanais em que o convite é válido.
+    windows: Vec<ScheduleWindow>,
+}
+
+/// Janela semanal de uma agenda de convite.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ScheduleWindow {
+    /// Dias da semana ("mon", "tue", ..., "sun").
+    days: Vec<String>,
+    /// Horário de início (HH:MM).
+    start: String,
+    /// Horário de término (HH:MM); se não for posterior ao início, a janela atravessa a meia-noite.
+    end: String,
+}
+
+/// Termos de acesso comuns a convites diretos e por código.
+#[derive(Deserialize)]
+pub struct InviteTerms {
+    /// String de duração de expiração (ex.: "2_dias", "1_semana").
+    expiry_duration: String,
+    /// Timestamp (ms) a partir do qual o convite é válido.
+    #[serde(default)]
+    starts_at: Option<i64>,
+    /// Agenda recorrente; sem agenda, o convite vale a qualquer hora.
+    #[serde(default)]
+    schedule: Option<InviteSchedule>,
+    /// Número máximo de destrancamentos; sem limite se ausente.
+    #[serde(default)]
+    max_uses: Option<i32>,
 }
 
 /// Estrutura de requisição para criar um convite.
@@ -65,8 +138,42 @@ pub struct CreateInviteRequest {
     receiver_email: String,
     /// UUID do dispositivo a convidar.
     device_id: String,
-    /// String de duração de expiração (ex.: "2_dias", "1_semana").
-    expiry_duration: String,
+    /// Termos de acesso do convite.
+    #[serde(flatten)]
+    terms: InviteTerms,
+}
+
+/// Estrutura de requisição para criar um convite resgatável por código.
+#[derive(Deserialize)]
+pub struct CreateInviteCodeRequest {
+    /// UUID do dispositivo a convidar.
+    device_id: String,
+    /// Duração de validade do código (mesmos valores de `expiry_duration`; padrão "2_dias").
+    #[serde(default)]
+    code_expiry_duration: Option<String>,
+    /// Termos de acesso do convite.
+    #[serde(flatten)]
+    terms: InviteTerms,
+}
+
+/// Estrutura de requisição para resgatar um código de convite.
+#[derive(Deserialize)]
+pub struct RedeemInviteRequest {
+    /// Código de convite recebido.
+    code: String,
+}
+
+/// Claims assinadas de um código de convite.
+#[derive(Serialize, Deserialize)]
+struct InviteCodeClaims {
+    /// Audiência fixa ("invite"), para que o código não seja aceito como token de acesso.
+    aud: String,
+    /// ID do convite.
+    inv: i32,
+    /// Valor aleatório que torna cada código único.
+    jti: String,
+    /// Momento de expiração do código (segundos desde a época Unix).
+    exp: i64,
 }
 
 /// Estrutura de requisição para atualizar um convite.
@@ -76,10 +183,92 @@ pub struct UpdateInviteRequest {
     expiry_duration: String,
 }
 
+/// Verifica se o remetente pode convidar para o dispositivo.
+async fn authorize_sender(
+    db_pool: &PgPool,
+    device_uuid: Uuid,
+    sender_id: &str,
+) -> Result<(), Status> {
+    if let Err(status) = member::authorize(db_pool, device_uuid, sender_id, Action::Invite).await {
+        if status == Status::Unauthorized {
+            println!(
+                "DEBUG: User {} may not invite to device {}",
+                sender_id, device_uuid
+            );
+            return Err(Status::Forbidden);
+        }
+        return Err(status);
+    }
+    Ok(())
+}
+
+/// Valida os termos e insere o convite com suas janelas em uma transação.
+/// Convites por código são criados sem destinatário, com o hash e a expiração do código.
+async fn insert_invite(
+    db_pool: &PgPool,
+    device_uuid: Uuid,
+    sender_id: &str,
+    receiver_id: Option<&str>,
+    terms: &InviteTerms,
+    code: Option<(&str, i64)>,
+) -> Result<i32, Status> {
+    // Calculate expiry timestamp
+    let now = Utc::now();
+    let expiry_timestamp = calculate_expiry_timestamp(now, &terms.expiry_duration);
+
+    // Validate schedule
+    let windows = match &terms.schedule {
+        Some(schedule) => parse_schedule(schedule).ok_or(Status::BadRequest)?,
+        None => Vec::new(),
+    };
+    let timezone = terms.schedule.as_ref().map(|s| s.timezone.clone());
+    if terms.max_uses.is_some_and(|uses| uses <= 0) {
+        return Err(Status::BadRequest);
+    }
+
+    // Create invite and its windows atomically
+    let mut tx = db_pool
+        .begin()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let invite_id: i32 = sqlx::query_scalar(
+        "INSERT INTO invites (device_id, sender_id, receiver_id, expiry_timestamp, starts_at, timezone, max_uses, remaining_uses, code_hash, code_expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $7, $8, $9) RETURNING id"
+    )
+    .bind(device_uuid) // UUID for invites table
+    .bind(sender_id)
+    .bind(receiver_id)
+    .bind(expiry_timestamp)
+    .bind(terms.starts_at)
+    .bind(&timezone)
+    .bind(terms.max_uses)
+    .bind(code.map(|(hash, _)| hash))
+    .bind(code.map(|(_, expires_at)| expires_at))
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| {
+        Status::InternalServerError
+    })?;
+    for (days, start_minute, end_minute) in windows {
+        sqlx::query(
+            "INSERT INTO invite_windows (invite_id, days, start_minute, end_minute) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(invite_id)
+        .bind(days)
+        .bind(start_minute)
+        .bind(end_minute)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    }
+    tx.commit().await.map_err(|_| Status::InternalServerError)?;
+
+    Ok(invite_id)
+}
+
 /// Cria convite para acesso temporário ao dispositivo.
 #[post("/create_invite", data = "<request>")]
 pub async fn create_invite(
-    token: Token,
+    user: AuthUser,
     request: rocket::serde::json::Json<CreateInviteRequest>,
     db_pool: &State<PgPool>,
 ) -> Result<String, Status> {
@@ -91,39 +280,10 @@ pub async fn create_invite(
         }
     };
 
-    // Validate token and get sender
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let sender_id = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+    let sender_id = user.firebase_uid;
 
-    // Check if sender owns the device
-    let device_row: Option<(Option<String>,)> =
-        sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-            .bind(device_uuid)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let owner_id = if let Some((Some(owner),)) = device_row {
-        owner
-    } else {
-        return Err(Status::NotFound);
-    };
-    if owner_id != sender_id {
-        println!(
-            "DEBUG: User {} does not own device {} (owned by {})",
-            sender_id, request.device_id, owner_id
-        );
-        return Err(Status::Forbidden);
-    }
+    // Check if sender may invite to the device
+    authorize_sender(db_pool, device_uuid, &sender_id).await?;
 
     // Find receiver by email
     let receiver_row: Option<(String,)> =
@@ -155,23 +315,15 @@ pub async fn create_invite(
         return Err(Status::Conflict);
     }
 
-    // Calculate expiry timestamp
-    let now = Utc::now();
-    let expiry_timestamp = calculate_expiry_timestamp(now, &request.expiry_duration);
-
-    // Create invite
-    let invite_id: i32 = sqlx::query_scalar(
-        "INSERT INTO invites (device_id, sender_id, receiver_id, expiry_timestamp) VALUES ($1, $2, $3, $4) RETURNING id"
+    let invite_id = insert_invite(
+        db_pool,
+        device_uuid,
+        &sender_id,
+        Some(&receiver_id),
+        &request.terms,
+        None,
     )
-    .bind(device_uuid) // UUID for invites table
-    .bind(&sender_id)
-    .bind(&receiver_id)
-    .bind(expiry_timestamp)
-    .fetch_one(&**db_pool)
-    .await
-    .map_err(|_| {
-        Status::InternalServerError
-    })?;
+    .await?;
 
     Ok(serde_json::json!({
         "invite_id": invite_id,
@@ -180,26 +332,143 @@ pub async fn create_invite(
     .to_string())
 }
 
+/// Cria um convite sem destinatário e retorna um código assinado de uso único
+/// (e um link com o código), para quem ainda não tem conta. O código é resgatado
+/// após o cadastro em `POST /redeem_invite`.
+#[post("/create_invite_code", data = "<request>")]
+pub async fn create_invite_code(
+    user: AuthUser,
+    request: rocket::serde::json::Json<CreateInviteCodeRequest>,
+    db_pool: &State<PgPool>,
+    homepage_url: &State<HomepageUrl>,
+) -> Result<String, Status> {
+    let device_uuid = Uuid::parse_str(&request.device_id).map_err(|_| Status::BadRequest)?;
+
+    let sender_id = user.firebase_uid;
+
+    authorize_sender(db_pool, device_uuid, &sender_id).await?;
+
+    // Reserve the invite first so the code can carry its ID
+    let code_expires_at = calculate_expiry_timestamp(
+        Utc::now(),
+        request.code_expiry_duration.as_deref().unwrap_or("2_dias"),
+    );
+    let mut jti = [0u8; 32];
+    OsRng.fill_bytes(&mut jti);
+    let jti = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(jti);
+    let invite_id = insert_invite(
+        db_pool,
+        device_uuid,
+        &sender_id,
+        None,
+        &request.terms,
+        Some((&hash_code(&jti), code_expires_at)),
+    )
+    .await?;
+
+    let claims = InviteCodeClaims {
+        aud: "invite".to_string(),
+        inv: invite_id,
+        jti: jti.clone(),
+        exp: code_expires_at / 1000,
+    };
+    let code = JWT_KEYS
+        .get()
+        .unwrap()
+        .sign(&claims)
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::json!({
+        "invite_id": invite_id,
+        "code": code,
+        "url": format!("{}/invite?code={}", homepage_url.0.trim_end_matches('/'), code),
+        "code_expires_at": code_expires_at
+    })
+    .to_string())
+}
+
+/// Resgata um código de convite: associa o convite à conta autenticada e o aceita.
+/// O código é de uso único e deixa de valer se expirado ou revogado.
+#[post("/redeem_invite", data = "<request>")]
+pub async fn redeem_invite(
+    user: AuthUser,
+    request: rocket::serde::json::Json<RedeemInviteRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let user_id = user.firebase_uid;
+
+    let claims: InviteCodeClaims = JWT_KEYS
+        .get()
+        .unwrap()
+        .verify(&request.code, "invite")
+        .ok_or(Status::Unauthorized)?;
+
+    // Bind the invite to this account; fails if already redeemed, revoked or
+    // if the user would be inviting themselves
+    let rows_affected = sqlx::query(
+        "UPDATE invites SET receiver_id = $1, code_redeemed_at = NOW() WHERE id = $2 AND code_hash = $3 AND receiver_id IS NULL AND code_redeemed_at IS NULL AND code_revoked_at IS NULL AND code_expires_at > $4 AND sender_id <> $1",
+    )
+    .bind(&user_id)
+    .bind(claims.inv)
+    .bind(hash_code(&claims.jti))
+    .bind(Utc::now().timestamp_millis())
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Err(Status::NotFound);
+    }
+
+    // Continue through the regular acceptance flow
+    if !accept(db_pool, claims.inv, &user_id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+    {
+        return Err(Status::NotFound);
+    }
+
+    Ok(serde_json::json!({ "invite_id": claims.inv }).to_string())
+}
+
+/// Revoga o código de um convite ainda não resgatado.
+#[post("/revoke_invite_code/<invite_id>")]
+pub async fn revoke_invite_code(
+    user: AuthUser,
+    invite_id: i32,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let rows_affected = sqlx::query(
+        "UPDATE invites SET code_revoked_at = NOW() WHERE id = $1 AND sender_id = $2 AND code_hash IS NOT NULL AND code_redeemed_at IS NULL AND code_revoked_at IS NULL",
+    )
+    .bind(invite_id)
+    .bind(&user.firebase_uid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Err(Status::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Calcula o hash SHA-256 (hexadecimal) do identificador de um código de convite.
+fn hash_code(jti: &str) -> String {
+    hex::encode(Sha256::digest(jti.as_bytes()))
+}
+
 /// Recupera convites enviados e recebidos do usuário.
 #[get("/invites")]
-pub async fn get_invites(token: Token, db_pool: &State<PgPool>) -> Result<String, Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+pub async fn get_invites(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let user_id = user.firebase_uid;
 
     // Get sent invites with receiver info
-    let sent_invites: Vec<SentInviteInfo> =
-        sqlx::query_as("SELECT i.id, i.device_id, i.sender_id, i.receiver_id, ru.name as receiver_name, ru.email as receiver_email, i.status, i.expiry_timestamp, i.created_at FROM invites i LEFT JOIN users ru ON i.receiver_id = ru.firebase_uid WHERE i.sender_id = $1")
+    let mut sent_invites: Vec<SentInviteInfo> =
+        sqlx::query_as("SELECT i.id, i.device_id, i.sender_id, i.receiver_id, ru.name as receiver_name, ru.email as receiver_email, i.code_expires_at, i.code_redeemed_at, i.code_revoked_at, i.status, i.expiry_timestamp, i.created_at, i.starts_at, i.max_uses, i.remaining_uses, i.timezone FROM invites i LEFT JOIN users ru ON i.receiver_id = ru.firebase_uid WHERE i.sender_id = $1")
             .bind(&user_id)
             .fetch_all(&**db_pool)
             .await
@@ -208,8 +477,8 @@ pub async fn get_invites(token: Token, db_pool: &State<PgPool>) -> Result<String
             })?;
 
     // Get received invites with sender info
-    let received_invites: Vec<ReceivedInviteInfo> =
-        sqlx::query_as("SELECT i.id, i.device_id, i.sender_id, i.receiver_id, su.name as sender_name, su.email as sender_email, i.status, i.expiry_timestamp, i.created_at FROM invites i LEFT JOIN users su ON i.sender_id = su.firebase_uid WHERE i.receiver_id = $1")
+    let mut received_invites: Vec<ReceivedInviteInfo> =
+        sqlx::query_as("SELECT i.id, i.device_id, i.sender_id, i.receiver_id, su.name as sender_name, su.email as sender_email, i.status, i.expiry_timestamp, i.created_at, i.starts_at, i.max_uses, i.remaining_uses, i.timezone FROM invites i LEFT JOIN users su ON i.sender_id = su.firebase_uid WHERE i.receiver_id = $1")
             .bind(&user_id)
             .fetch_all(&**db_pool)
             .await
@@ -217,6 +486,22 @@ pub async fn get_invites(token: Token, db_pool: &State<PgPool>) -> Result<String
                 Status::InternalServerError
             })?;
 
+    // Attach schedules
+    let invite_ids: Vec<i32> = sent_invites
+        .iter()
+        .map(|i| i.id)
+        .chain(received_invites.iter().map(|i| i.id))
+        .collect();
+    let windows = load_windows(db_pool, &invite_ids)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    for invite in &mut sent_invites {
+        invite.schedule = build_schedule(invite.timezone.as_deref(), windows.get(&invite.id));
+    }
+    for invite in &mut received_invites {
+        invite.schedule = build_schedule(invite.timezone.as_deref(), windows.get(&invite.id));
+    }
+
     let sent: Vec<serde_json::Value> = sent_invites
         .into_iter()
         .map(|invite| serde_json::to_value(invite).unwrap())
@@ -237,60 +522,45 @@ pub async fn get_invites(token: Token, db_pool: &State<PgPool>) -> Result<String
 /// Aceita convite para acesso ao dispositivo.
 #[post("/accept_invite/<invite_id>")]
 pub async fn accept_invite(
-    token: Token,
+    user: AuthUser,
     invite_id: i32,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let user_id = user.firebase_uid;
 
+    if accept(db_pool, invite_id, &user_id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+    {
+        Ok(())
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+/// Marca como aceito um convite pendente do usuário. Retorna `false` se não houver.
+async fn accept(db_pool: &PgPool, invite_id: i32, user_id: &str) -> Result<bool> {
     // Update invite status to accepted (1)
     let rows_affected = sqlx::query(
         "UPDATE invites SET status = 1 WHERE id = $1 AND receiver_id = $2 AND status = 0",
     )
     .bind(invite_id)
-    .bind(&user_id)
-    .execute(&**db_pool)
-    .await
-    .map_err(|_| Status::InternalServerError)?
+    .bind(user_id)
+    .execute(db_pool)
+    .await?
     .rows_affected();
 
-    if rows_affected == 0 {
-        Err(Status::NotFound)
-    } else {
-        Ok(())
-    }
+    Ok(rows_affected > 0)
 }
 
 /// Rejeita convite.
 #[post("/reject_invite/<invite_id>")]
 pub async fn reject_invite(
-    token: Token,
+    user: AuthUser,
     invite_id: i32,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+    let user_id = user.firebase_uid;
 
     // Delete the invite
     let rows_affected = sqlx::query("DELETE FROM invites WHERE id = $1 AND receiver_id = $2")
@@ -311,21 +581,11 @@ pub async fn reject_invite(
 /// Cancela convite enviado.
 #[post("/cancel_invite/<invite_id>")]
 pub async fn cancel_invite(
-    token: Token,
+    user: AuthUser,
     invite_id: i32,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let user_id = user.firebase_uid;
 
     // Delete the invite
     let rows_affected = sqlx::query("DELETE FROM invites WHERE id = $1 AND sender_id = $2")
@@ -345,22 +605,12 @@ pub async fn cancel_invite(
 /// Atualiza um convite (ex.: estender expiração).
 #[post("/update_invite/<invite_id>", data = "<request>")]
 pub async fn update_invite(
-    token: Token,
+    user: AuthUser,
     invite_id: i32,
     request: rocket::serde::json::Json<UpdateInviteRequest>,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let user_id = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let user_id = user.firebase_uid;
 
     // Calculate new expiry timestamp
     let now = Utc::now();
@@ -395,3 +645,406 @@ fn calculate_expiry_timestamp(base_time: chrono::DateTime<chrono::Utc>, duration
     };
     (base_time + duration).timestamp_millis()
 }
+
+/// Converte um horário no formato HH:MM em minutos desde a meia-noite (até 24:00).
+fn parse_minute(time: &str) -> Option<i16> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: i16 = hours.parse().ok()?;
+    let minutes: i16 = minutes.parse().ok()?;
+    let total = hours * 60 + minutes;
+    if !(0..60).contains(&minutes) || !(0..=1440).contains(&total) {
+        return None;
+    }
+    Some(total)
+}
+
+/// Formata minutos desde a meia-noite como HH:MM.
+fn format_minute(minute: i16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// Valida uma agenda e a converte em linhas de `invite_windows`
+/// (máscara de dias, minuto inicial, minuto final).
+fn parse_schedule(schedule: &InviteSchedule) -> Option<Vec<(i16, i16, i16)>> {
+    schedule.timezone.parse::<Tz>().ok()?;
+    if schedule.windows.is_empty() {
+        return None;
+    }
+
+    schedule
+        .windows
+        .iter()
+        .map(|window| {
+            let mut days = 0i16;
+            for day in &window.days {
+                let weekday: Weekday = day.parse().ok()?;
+                days |= 1 << weekday.num_days_from_monday();
+            }
+            let start = parse_minute(&window.start)?;
+            let end = parse_minute(&window.end)?;
+            if days == 0 || start == 1440 || start == end {
+                return None;
+            }
+            Some((days, start, end))
+        })
+        .collect()
+}
+
+/// Recupera as janelas dos convites informados, agrupadas por convite.
+async fn load_windows(
+    db_pool: &PgPool,
+    invite_ids: &[i32],
+) -> Result<HashMap<i32, Vec<(i16, i16, i16)>>> {
+    let rows: Vec<(i32, i16, i16, i16)> = sqlx::query_as(
+        "SELECT invite_id, days, start_minute, end_minute FROM invite_windows WHERE invite_id = ANY($1) ORDER BY id",
+    )
+    .bind(invite_ids)
+    .fetch_all(db_pool)
+    .await?;
+
+    let mut windows: HashMap<i32, Vec<(i16, i16, i16)>> = HashMap::new();
+    for (invite_id, days, start, end) in rows {
+        windows
+            .entry(invite_id)
+            .or_default()
+            .push((days, start, end));
+    }
+    Ok(windows)
+}
+
+/// Reconstrói a agenda de um convite a partir de suas janelas.
+fn build_schedule(
+    timezone: Option<&str>,
+    windows: Option<&Vec<(i16, i16, i16)>>,
+) -> Option<InviteSchedule> {
+    let windows = windows?;
+    Some(InviteSchedule {
+        timezone: timezone.unwrap_or("UTC").to_string(),
+        windows: windows
+            .iter()
+            .map(|&(days, start, end)| ScheduleWindow {
+                days: (0..7)
+                    .filter(|d| days & (1 << d) != 0)
+                    .map(|d| {
+                        ["mon", "tue", "wed", "thu", "fri", "sat", "sun"][d as usize].to_string()
+                    })
+                    .collect(),
+                start: format_minute(start),
+                end: format_minute(end),
+            })
+            .collect(),
+    })
+}
+
+/// Verifica se um convite está válido no instante `now`, considerando a data de
+/// início e, se houver, as janelas semanais no fuso do convite.
+fn is_within_schedule(
+    starts_at: Option<i64>,
+    timezone: Option<&str>,
+    windows: &[(i16, i16, i16)],
+    now: chrono::DateTime<Utc>,
+) -> bool {
+    if starts_at.is_some_and(|start| now.timestamp_millis() < start) {
+        return false;
+    }
+    if windows.is_empty() {
+        return true;
+    }
+
+    let tz: Tz = timezone
+        .and_then(|tz| tz.parse().ok())
+        .unwrap_or(chrono_tz::UTC);
+    let local = now.with_timezone(&tz);
+    let weekday = local.weekday().num_days_from_monday();
+    let minute = (local.hour() * 60 + local.minute()) as i16;
+    let on = |day: u32, days: i16| days & (1 << day) != 0;
+
+    windows.iter().any(|&(days, start, end)| {
+        if start < end {
+            on(weekday, days) && start <= minute && minute < end
+        } else {
+            // Window crosses midnight into the following day
+            (on(weekday, days) && minute >= start) || (on((weekday + 6) % 7, days) && minute < end)
+        }
+    })
+}
+
+/// Linha de convite aceito usada para verificar validade e usos.
+#[derive(sqlx::FromRow)]
+struct AcceptedInviteRow {
+    /// ID do convite.
+    id: i32,
+    /// ID do usuário destinatário.
+    receiver_id: String,
+    /// Timestamp a partir do qual o convite é válido.
+    starts_at: Option<i64>,
+    /// Fuso horário das janelas do convite.
+    timezone: Option<String>,
+    /// Destrancamentos restantes.
+    remaining_uses: Option<i32>,
+}
+
+/// Convite aceito e válido neste momento.
+pub struct ActiveInvite {
+    /// ID do convite.
+    pub id: i32,
+    /// ID do usuário destinatário.
+    pub receiver_id: String,
+    /// Destrancamentos restantes (ausente para ilimitado).
+    pub remaining_uses: Option<i32>,
+}
+
+/// Lista os convites aceitos, não expirados e não esgotados do dispositivo que
+/// estão dentro da data de início e da agenda neste momento.
+pub async fn active_invites(db_pool: &PgPool, device_id: Uuid) -> Result<Vec<ActiveInvite>> {
+    let now = Utc::now();
+    let invites: Vec<AcceptedInviteRow> = sqlx::query_as(
+        "SELECT id, receiver_id, starts_at, timezone, remaining_uses FROM invites WHERE device_id = $1 AND status = 1 AND expiry_timestamp > $2",
+    )
+    .bind(device_id)
+    .bind(now.timestamp_millis())
+    .fetch_all(db_pool)
+    .await?;
+
+    let invite_ids: Vec<i32> = invites.iter().map(|invite| invite.id).collect();
+    let windows = load_windows(db_pool, &invite_ids).await?;
+
+    Ok(invites
+        .into_iter()
+        .filter(|invite| {
+            let invite_windows = windows.get(&invite.id).map(Vec::as_slice).unwrap_or(&[]);
+            is_within_schedule(
+                invite.starts_at,
+                invite.timezone.as_deref(),
+                invite_windows,
+                now,
+            )
+        })
+        .map(|invite| ActiveInvite {
+            id: invite.id,
+            receiver_id: invite.receiver_id,
+            remaining_uses: invite.remaining_uses,
+        })
+        .collect())
+}
+
+/// Lista os destinatários de convites válidos neste momento (ver [`active_invites`]).
+pub async fn active_receivers(db_pool: &PgPool, device_id: Uuid) -> Result<Vec<String>> {
+    Ok(active_invites(db_pool, device_id)
+        .await?
+        .into_iter()
+        .map(|invite| invite.receiver_id)
+        .collect())
+}
+
+/// Uso de convite consumido por um destrancamento.
+pub enum InviteUse {
+    /// O usuário tem um convite ilimitado válido; nada foi consumido.
+    Unlimited,
+    /// Um uso do convite foi consumido.
+    Limited {
+        /// ID do convite.
+        invite_id: i32,
+        /// Destrancamentos restantes.
+        remaining: i32,
+    },
+}
+
+/// Indica se o usuário ainda pode destrancar o dispositivo por convite neste momento,
+/// sem consumir um uso.
+pub async fn has_use(db_pool: &PgPool, device_id: Uuid, user_id: &str) -> Result<bool> {
+    Ok(active_invites(db_pool, device_id)
+        .await?
+        .iter()
+        .any(|invite| {
+            invite.receiver_id == user_id && invite.remaining_uses.is_none_or(|uses| uses > 0)
+        }))
+}
+
+/// Consome um uso de convite para um destrancamento, na conexão ou transação informada.
+/// Se o usuário tiver um convite ilimitado válido, nada é consumido; caso contrário, um
+/// uso de um convite limitado é decrementado atomicamente e, ao chegar a zero, o convite
+/// é marcado como esgotado. Retorna `None` se não houver usos restantes. Depois de
+/// confirmar a transação, chame [`notify_use`].
+pub async fn take_use(
+    db_pool: &PgPool,
+    conn: &mut PgConnection,
+    device_id: Uuid,
+    user_id: &str,
+) -> Result<Option<InviteUse>> {
+    let invites: Vec<ActiveInvite> = active_invites(db_pool, device_id)
+        .await?
+        .into_iter()
+        .filter(|invite| invite.receiver_id == user_id)
+        .collect();
+
+    if invites.iter().any(|invite| invite.remaining_uses.is_none()) {
+        return Ok(Some(InviteUse::Unlimited));
+    }
+
+    for invite in invites {
+        let row: Option<(i32,)> = sqlx::query_as(
+            "UPDATE invites SET remaining_uses = remaining_uses - 1, status = CASE WHEN remaining_uses <= 1 THEN 2 ELSE status END, exhausted_at = CASE WHEN remaining_uses <= 1 THEN NOW() ELSE exhausted_at END WHERE id = $1 AND status = 1 AND remaining_uses > 0 RETURNING remaining_uses",
+        )
+        .bind(invite.id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        let Some((remaining,)) = row else {
+            // Used up concurrently; try the next one
+            continue;
+        };
+        return Ok(Some(InviteUse::Limited {
+            invite_id: invite.id,
+            remaining,
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Registra um uso consumido por [`take_use`] e, se o convite se esgotou, avisa via
+/// WebSocket quem pode ler os logs do dispositivo.
+pub async fn notify_use(db_pool: &PgPool, device_id: Uuid, user_id: &str, used: &InviteUse) {
+    let InviteUse::Limited {
+        invite_id,
+        remaining,
+    } = *used
+    else {
+        return;
+    };
+    println!(
+        "DEBUG: Invite {} of user {} used, {} use(s) remaining",
+        invite_id, user_id, remaining
+    );
+    if remaining == 0 {
+        notify_exhausted(db_pool, device_id, user_id).await;
+    }
+}
+
+/// Consome um uso de convite fora de uma transação (ver [`take_use`]).
+/// Retorna `false` se não houver usos restantes.
+pub async fn consume_use(db_pool: &PgPool, device_id: Uuid, user_id: &str) -> Result<bool> {
+    let mut conn = db_pool.acquire().await?;
+    let Some(used) = take_use(db_pool, &mut conn, device_id, user_id).await? else {
+        return Ok(false);
+    };
+    notify_use(db_pool, device_id, user_id, &used).await;
+    Ok(true)
+}
+
+/// Devolve um uso consumido por um destrancamento que não chegou ao dispositivo,
+/// reativando o convite se ele havia se esgotado.
+pub async fn refund_use(conn: &mut PgConnection, invite_id: i32) -> Result<()> {
+    sqlx::query(
+        "UPDATE invites SET remaining_uses = remaining_uses + 1, status = 1, exhausted_at = NULL WHERE id = $1 AND status IN (1, 2) AND remaining_uses IS NOT NULL",
+    )
+    .bind(invite_id)
+    .execute(&mut *conn)
+    .await?;
+    println!("DEBUG: Unused unlock refunded to invite {}", invite_id);
+    Ok(())
+}
+
+/// Envia um `log_update` informando que o convite de um usuário se esgotou.
+async fn notify_exhausted(db_pool: &PgPool, device_id: Uuid, user_id: &str) {
+    let user_name: Option<String> =
+        sqlx::query_scalar("SELECT name FROM users WHERE firebase_uid = $1")
+            .bind(user_id)
+            .fetch_optional(db_pool)
+            .await
+            .unwrap_or(None);
+
+    let log_update = serde_json::json!({
+        "type": "log_update",
+        "device_id": device_id.to_string(),
+        "timestamp": Utc::now().timestamp_millis(),
+        "event_type": "INVITE_EXHAUSTED",
+        "reason": "INVITE",
+        "user_id": user_id,
+        "user_name": user_name
+    })
+    .to_string();
+
+    let recipients = member::users_with_permission(db_pool, device_id, Action::ViewLogs)
+        .await
+        .unwrap_or_default();
+
+    notify_users(&recipients, &log_update);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const FRIDAY: i16 = 1 << 4;
+    const SATURDAY: i16 = 1 << 5;
+
+    /// Instante em UTC; 2026-10-16 é uma sexta-feira.
+    fn at(day: u32, hour: u32, minute: u32) -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn no_windows_means_always() {
+        assert!(is_within_schedule(None, None, &[], at(16, 3, 0)));
+    }
+
+    #[test]
+    fn matches_windows_within_a_day() {
+        let windows = [(FRIDAY, 9 * 60, 17 * 60)];
+        assert!(is_within_schedule(None, None, &windows, at(16, 9, 0)));
+        assert!(is_within_schedule(None, None, &windows, at(16, 16, 59)));
+        assert!(!is_within_schedule(None, None, &windows, at(16, 17, 0)));
+        assert!(!is_within_schedule(None, None, &windows, at(17, 12, 0)));
+    }
+
+    #[test]
+    fn windows_crossing_midnight_continue_into_the_next_day() {
+        // Friday 22:00 until Saturday 06:00
+        let windows = [(FRIDAY, 22 * 60, 6 * 60)];
+        assert!(is_within_schedule(None, None, &windows, at(16, 23, 30)));
+        assert!(is_within_schedule(None, None, &windows, at(17, 3, 0)));
+        assert!(!is_within_schedule(None, None, &windows, at(17, 6, 0)));
+        assert!(!is_within_schedule(None, None, &windows, at(16, 3, 0)));
+        assert!(!is_within_schedule(None, None, &windows, at(17, 23, 0)));
+    }
+
+    #[test]
+    fn crossing_midnight_wraps_from_sunday_to_monday() {
+        let windows = [(1 << 6, 22 * 60, 6 * 60)];
+        // 2026-10-19 is a Monday
+        assert!(is_within_schedule(None, None, &windows, at(19, 5, 0)));
+        assert!(!is_within_schedule(None, None, &windows, at(20, 5, 0)));
+    }
+
+    #[test]
+    fn windows_use_the_invite_timezone() {
+        let windows = [(SATURDAY, 0, 2 * 60)];
+        // Saturday 01:00 in São Paulo (UTC-3) is Saturday 04:00 UTC
+        assert!(is_within_schedule(
+            None,
+            Some("America/Sao_Paulo"),
+            &windows,
+            at(17, 4, 0)
+        ));
+        assert!(!is_within_schedule(None, None, &windows, at(17, 4, 0)));
+        // Unknown timezones fall back to UTC
+        assert!(is_within_schedule(
+            None,
+            Some("Nowhere/City"),
+            &windows,
+            at(17, 1, 0)
+        ));
+    }
+
+    #[test]
+    fn invites_are_not_valid_before_they_start() {
+        let now = at(16, 12, 0);
+        let starts_at = now.timestamp_millis() + 1;
+        assert!(!is_within_schedule(Some(starts_at), None, &[], now));
+        assert!(is_within_schedule(Some(starts_at - 1), None, &[], now));
+    }
+}
@@ -7,13 +7,30 @@
 //! ## Funcionalidades
 //!
 //! - **Gerenciamento de Usuários**: Ver [`user`] para autenticação e contas de usuário
+//! - **Sessões**: Ver [`session`] para tokens de acesso JWT e sessões por aparelho
+//! - **Firebase Authentication**: Ver [`firebase`] para verificação de ID tokens
 //! - **Gerenciamento de Dispositivos**: Ver [`device`] para registro e controle remoto
+//! - **Histórico de Eventos**: Ver [`event`] para o registro unificado de eventos dos dispositivos
+//! - **Comandos Confirmados**: Ver [`command`] para IDs de comando, idempotência, confirmação e fila de saída
 //! - **Convites Temporários**: Ver [`invite`] para compartilhamento de acesso
+//! - **Membros e Papéis**: Ver [`member`] para controle de acesso por papéis
+//! - **Pareamento**: Ver [`pairing`] para reivindicação de dispositivos com código de uso único
+//! - **Ping**: Ver [`ping`] para pings concorrentes com tempo de ida e volta e histórico
+//! - **Transferência de Propriedade**: Ver [`transfer`] para passar um dispositivo a outro usuário
+//! - **Canal Autenticado**: Ver [`channel`] para assinatura e cifragem das mensagens MQTT
+//! - **Coordenação**: Ver [`cluster`] para execução de várias instâncias atrás de um balanceador de carga
 //! - **Comunicação MQTT**: Ver [`mqtt`] para mensagens e heartbeats
+//! - **Broker Embutido**: Ver `broker` (feature `embedded-broker`) para dispensar um broker MQTT externo
+//! - **Presença**: Ver [`presence`] para detecção de dispositivos offline e histórico de quedas
+//! - **Protocolo dos Dispositivos**: Ver [`protocol`] para decodificação das mensagens CBOR
+//! - **Migrações de Esquema**: Ver [`migrate`] para versionamento do banco de dados
 //! - **WebSockets**: Atualizações em tempo real via WebSocket para dispositivos
 //! - **Autenticação por Voz**: Registro e verificação usando SpeechBrain (serviço Python)
 //! - **Logs de Acesso**: Histórico de operações em dispositivos
-//! - **Configuração Remota**: Atualização de parâmetros via MQTT
+//! - **Configuração Remota**: Ver [`twin`] para configuração desejada e reportada, reconciliada via MQTT,
+//!   [`config`] para o registro das chaves aceitas, [`revision`] para o histórico de alterações
+//!   e [`profile`] para perfis aplicados a vários dispositivos
+//! - **Atualização de Firmware**: Ver [`ota`] para versões assinadas e rollouts OTA com interrupção automática
 //!
 //! ## Arquitetura
 //!
@@ -35,9 +52,8 @@ use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::{Request, State, get, routes};
 use rocket_ws::{Message, WebSocket};
-use rumqttc::{AsyncClient, MqttOptions, QoS, Transport};
+use rumqttc::{AsyncClient, MqttOptions, Transport};
 use sqlx::ConnectOptions;
-use sqlx::PgPool;
 use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
 use std::collections::HashMap;
 use std::env;
@@ -45,9 +61,29 @@ use std::sync::{Mutex, OnceLock};
 use tokio::sync::broadcast;
 use url::Url;
 
+#[cfg(feature = "embedded-broker")]
+mod broker;
+mod channel;
+mod cluster;
+mod command;
+mod config;
 mod device;
+mod event;
+mod firebase;
 mod invite;
+mod member;
+mod migrate;
 mod mqtt;
+mod ota;
+mod pairing;
+mod ping;
+mod presence;
+mod profile;
+mod protocol;
+mod revision;
+mod session;
+mod transfer;
+mod twin;
 mod user;
 
 /// Invólucro para a URL do serviço SpeechBrain
@@ -88,12 +124,16 @@ impl<'r> FromRequest<'r> for Token {
     }
 }
 
-/// Armazena comandos recentes enviados aos dispositivos com timestamp para desduplicação
-type RecentCommands = Mutex<HashMap<String, (String, i64)>>;
-/// Rastreia solicitações de ping pendentes com timestamp e canal de resposta
-type PendingPings = Mutex<HashMap<String, (i64, tokio::sync::oneshot::Sender<()>)>>;
-/// Rastreia solicitações de atualização de configuração pendentes com canal de resposta
-type PendingConfigUpdates = Mutex<HashMap<String, tokio::sync::oneshot::Sender<()>>>;
+/// Armazena a última verificação de voz bem-sucedida por dispositivo, com timestamp e se o
+/// acesso vem de um convite
+type RecentVoiceMatches = Mutex<HashMap<String, (String, i64, bool)>>;
+/// Rastreia comandos aguardando confirmação, com canal que recebe o estado da fechadura
+type PendingCommands = Mutex<HashMap<uuid::Uuid, tokio::sync::oneshot::Sender<String>>>;
+/// Rastreia pings aguardando `PONG` por ID do ping, com o dispositivo e o canal de resposta
+type PendingPings = Mutex<HashMap<uuid::Uuid, (uuid::Uuid, tokio::sync::oneshot::Sender<()>)>>;
+
+/// Nonces recentes de mensagens assinadas por dispositivo, com o timestamp da mensagem
+type SeenNonces = Mutex<HashMap<uuid::Uuid, std::collections::VecDeque<(u64, u64)>>>;
 
 /// Canal de broadcast para atualizações de dispositivos via WebSocket
 type DeviceUpdateSender = broadcast::Sender<String>;
@@ -102,33 +142,98 @@ type UserBroadcast = broadcast::Sender<String>;
 /// Mapa de broadcasts por usuário
 type UserBroadcasts = Mutex<HashMap<String, UserBroadcast>>;
 
-/// Armazenamento global para comandos recentes
-pub static RECENT_COMMANDS: OnceLock<RecentCommands> = OnceLock::new();
+/// Armazenamento global para verificações de voz recentes
+pub static RECENT_VOICE_MATCHES: OnceLock<RecentVoiceMatches> = OnceLock::new();
+/// Armazenamento global para comandos aguardando confirmação
+pub static PENDING_COMMANDS: OnceLock<PendingCommands> = OnceLock::new();
 /// Armazenamento global para pings pendentes
 pub static PENDING_PINGS: OnceLock<PendingPings> = OnceLock::new();
-/// Armazenamento global para atualizações de configuração pendentes
-pub static PENDING_CONFIG_UPDATES: OnceLock<PendingConfigUpdates> = OnceLock::new();
+/// Armazenamento global para nonces recentes, contra repetição de mensagens
+pub static SEEN_NONCES: OnceLock<SeenNonces> = OnceLock::new();
+/// Se mensagens não assinadas de dispositivos sem chave são aceitas
+pub static ALLOW_UNSIGNED_DEVICES: OnceLock<bool> = OnceLock::new();
+/// Chave privada da troca de chaves com os dispositivos, se configurada
+pub static DEVICE_KEY_EXCHANGE: OnceLock<Option<p256::SecretKey>> = OnceLock::new();
+/// Administradores e chave de assinatura das atualizações de firmware
+pub static FIRMWARE: OnceLock<ota::FirmwareConfig> = OnceLock::new();
 /// Canal de broadcast para WebSocket
 pub static DEVICE_UPDATE_TX: OnceLock<DeviceUpdateSender> = OnceLock::new();
 /// Broadcasts por usuário
 pub static USER_BROADCASTS: OnceLock<UserBroadcasts> = OnceLock::new();
+/// Coordenação entre instâncias do back-end
+pub static COORDINATOR: OnceLock<cluster::Coordinator> = OnceLock::new();
+/// Chaves de assinatura dos tokens de sessão
+pub static JWT_KEYS: OnceLock<session::JwtKeys> = OnceLock::new();
+
+/// Envia uma mensagem via WebSocket aos usuários informados que estiverem conectados.
+/// Com várias instâncias, a mensagem chega aos usuários conectados a qualquer uma delas.
+pub fn notify_users(recipients: &[String], update: &str) {
+    if recipients.is_empty() {
+        return;
+    }
+    cluster::publish(cluster::Signal::UserUpdate {
+        recipients: recipients.to_vec(),
+        update: update.to_string(),
+    });
+}
+
+/// Inicia o broker MQTT embutido e retorna as opções de conexão do back-end a ele.
+#[cfg(feature = "embedded-broker")]
+async fn embedded_broker_options(db_pool: &sqlx::PgPool, client_id: &str) -> Result<MqttOptions> {
+    let config = broker::BrokerConfig::from_env()?;
+    broker::start(db_pool.clone(), &config).await?;
+    let addr = config.client_addr();
+    let mut options = MqttOptions::new(client_id, addr.ip().to_string(), addr.port());
+    options.set_credentials(broker::BACKEND_USERNAME, config.backend_password());
+    Ok(options)
+}
+
+/// Sem a feature `embedded-broker`, o broker embutido não está disponível.
+#[cfg(not(feature = "embedded-broker"))]
+async fn embedded_broker_options(_db_pool: &sqlx::PgPool, _client_id: &str) -> Result<MqttOptions> {
+    anyhow::bail!("MQTT_EMBEDDED_BROKER requires building with the embedded-broker feature")
+}
 
 /// Ponto de entrada principal do serviço de back-end LockWise.
-/// Inicializa banco de dados, cliente MQTT, configura tabelas, inicia manipulador de eventos MQTT,
+/// Inicializa banco de dados, aplica migrações pendentes, cliente MQTT, inicia manipulador de eventos MQTT,
 /// tarefa de limpeza de logs e lança o servidor HTTP Rocket.
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
 
-    // Load env vars
+    // Load DB URL
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let mqtt_host = env::var("MQTT_HOST").expect("MQTT_HOST must be set");
+
+    // Setup DB
+    let url = Url::parse(&db_url)?;
+    let options = PgConnectOptions::from_url(&url)?.ssl_mode(PgSslMode::Require);
+    let db_pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect_with(options)
+        .await?;
+
+    // Command-line subcommands
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        return migrate::run_cli(&db_pool, &args[1..]).await;
+    }
+
+    // Bring the schema up to date, refusing to run against a newer database
+    migrate::run_pending(&db_pool).await?;
+
+    // Load remaining env vars
+    let mqtt_embedded_broker: bool = env::var("MQTT_EMBEDDED_BROKER")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(false);
+    let mqtt_host = env::var("MQTT_HOST").ok();
     let mqtt_port: u16 = env::var("MQTT_PORT")
         .map(|s| s.parse().unwrap())
         .unwrap_or(1883);
     let mqtt_tls: bool = env::var("MQTT_TLS")
         .map(|s| s.parse().unwrap())
         .unwrap_or(false);
+    let mqtt_client_id = env::var("MQTT_CLIENT_ID").unwrap_or("backend".to_string());
+    let mqtt_shared_group = env::var("MQTT_SHARED_GROUP").ok();
     let mqtt_username = env::var("MQTT_USERNAME").ok();
     let mqtt_password = env::var("MQTT_PASSWORD").ok();
     let port: u16 = env::var("PORT")
@@ -139,122 +244,156 @@ async fn main() -> Result<()> {
         SpeechbrainUrl(env::var("SPEECHBRAIN_URL").unwrap_or("http://localhost:5008".to_string()));
     let homepage_url =
         HomepageUrl(env::var("HOMEPAGE_URL").unwrap_or("https://example.com".to_string()));
-    RECENT_COMMANDS.set(Mutex::new(HashMap::new())).unwrap();
-    PENDING_PINGS.set(Mutex::new(HashMap::new())).unwrap();
-    PENDING_CONFIG_UPDATES
+    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let jwt_access_ttl_sec: i64 = env::var("JWT_ACCESS_TTL_SEC")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(3600);
+    let jwt_refresh_ttl_sec: i64 = env::var("JWT_REFRESH_TTL_SEC")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(30 * 24 * 3600);
+    let allow_unsigned_devices: bool = env::var("ALLOW_UNSIGNED_DEVICES")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(false);
+    let firebase_project_id =
+        env::var("FIREBASE_PROJECT_ID").expect("FIREBASE_PROJECT_ID must be set");
+    let firebase_jwks_url = env::var("FIREBASE_JWKS_URL").ok();
+    let coordination = env::var("COORDINATION").unwrap_or("local".to_string());
+    if mqtt_embedded_broker && mqtt_shared_group.is_some() {
+        anyhow::bail!("MQTT_SHARED_GROUP cannot be used with MQTT_EMBEDDED_BROKER");
+    }
+    RECENT_VOICE_MATCHES
         .set(Mutex::new(HashMap::new()))
         .unwrap();
+    PENDING_COMMANDS.set(Mutex::new(HashMap::new())).unwrap();
+    PENDING_PINGS.set(Mutex::new(HashMap::new())).unwrap();
+    SEEN_NONCES.set(Mutex::new(HashMap::new())).unwrap();
+    ALLOW_UNSIGNED_DEVICES.set(allow_unsigned_devices).unwrap();
+    let device_key_exchange = channel::key_exchange_secret_from_env()?;
+    if device_key_exchange.is_none() {
+        println!(
+            "DEBUG: DEVICE_KEY_EXCHANGE_PRIVATE_KEY not set; devices with the authenticated channel cannot pair"
+        );
+    }
+    DEVICE_KEY_EXCHANGE
+        .set(device_key_exchange)
+        .unwrap_or_else(|_| panic!("DEVICE_KEY_EXCHANGE already set"));
+    FIRMWARE
+        .set(ota::FirmwareConfig::from_env()?)
+        .unwrap_or_else(|_| panic!("FIRMWARE already set"));
     let (tx, _rx) = broadcast::channel(100);
     DEVICE_UPDATE_TX.set(tx).unwrap();
     USER_BROADCASTS.set(Mutex::new(HashMap::new())).unwrap();
+    JWT_KEYS
+        .set(session::JwtKeys::new(
+            jwt_secret.as_bytes(),
+            jwt_access_ttl_sec,
+            jwt_refresh_ttl_sec,
+        ))
+        .unwrap_or_else(|_| panic!("JWT_KEYS already set"));
+
+    // Setup coordination between instances
+    COORDINATOR
+        .set(cluster::start(&db_pool, &coordination).await?)
+        .unwrap_or_else(|_| panic!("COORDINATOR already set"));
+
+    // Setup Firebase ID token verification
+    let firebase_verifier =
+        firebase::FirebaseVerifier::new(&firebase_project_id, firebase_jwks_url).await?;
+
+    // Setup MQTT, against the embedded broker or an external one
+    let mut mqtt_options = if mqtt_embedded_broker {
+        embedded_broker_options(&db_pool, &mqtt_client_id).await?
+    } else {
+        let mqtt_host = mqtt_host.expect("MQTT_HOST must be set");
+        let mut mqtt_options = MqttOptions::new(mqtt_client_id, mqtt_host, mqtt_port);
+        if let Some(user) = mqtt_username {
+            mqtt_options.set_credentials(user, mqtt_password.unwrap_or_default());
+        }
+        if mqtt_tls {
+            mqtt_options.set_transport(Transport::tls_with_default_config());
+        }
+        mqtt_options
+    };
+    // Keep subscriptions and QoS 1 messages across reconnections
+    mqtt_options.set_clean_session(false);
 
-    // Setup DB
-    let url = Url::parse(&db_url)?;
-    let options = PgConnectOptions::from_url(&url)?.ssl_mode(PgSslMode::Require);
-    let db_pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect_with(options)
-        .await?;
-
-    // Create devices table if not exists
-    sqlx::query("CREATE TABLE IF NOT EXISTS devices ( uuid uuid PRIMARY KEY, user_id VARCHAR(255), last_heard timestamptz NOT NULL, uptime_ms bigint NOT NULL, hashed_passphrase VARCHAR(255), locked_down_at timestamptz)")
-    .execute(&db_pool)
-    .await?;
-
-    // Create users table if not exists
-    sqlx::query("CREATE TABLE IF NOT EXISTS users ( firebase_uid VARCHAR(255) PRIMARY KEY, hashed_password VARCHAR(255) NOT NULL, email VARCHAR(255) NOT NULL, phone_number VARCHAR(255), name VARCHAR(255) NOT NULL, current_token VARCHAR(255), voice_embeddings BYTEA, created_at timestamptz NOT NULL DEFAULT NOW(), last_login timestamptz)")
-    .execute(&db_pool)
-    .await?;
-
-    // Create logs table if not exists
-    sqlx::query("CREATE TABLE IF NOT EXISTS logs ( id SERIAL PRIMARY KEY, device_id VARCHAR(255) NOT NULL, timestamp timestamptz NOT NULL DEFAULT NOW(), event_type VARCHAR(10) NOT NULL, reason VARCHAR(20) NOT NULL, user_id VARCHAR(255), created_at timestamptz NOT NULL DEFAULT NOW())")
-        .execute(&db_pool)
-        .await?;
-
-    // Create invites table if not exists
-    sqlx::query("CREATE TABLE IF NOT EXISTS invites ( id SERIAL PRIMARY KEY, device_id UUID NOT NULL, sender_id VARCHAR(255) NOT NULL, receiver_id VARCHAR(255) NOT NULL, status INTEGER NOT NULL DEFAULT 0, expiry_timestamp BIGINT NOT NULL, created_at timestamptz NOT NULL DEFAULT NOW(), FOREIGN KEY (device_id) REFERENCES devices(uuid) ON DELETE CASCADE)")
-        .execute(&db_pool)
-        .await?;
+    // The event loop subscribes to the status topics on every (re)connection
+    let (mqtt_client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
 
-    // Add columns if not exists (for migration)
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS user_id VARCHAR(255)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS hashed_passphrase VARCHAR(255)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS wifi_ssid VARCHAR(255)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS backend_url VARCHAR(255)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_broker_url VARCHAR(255)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_heartbeat_enable BOOLEAN")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS mqtt_heartbeat_interval_sec INTEGER")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS audio_record_timeout_sec INTEGER")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS lock_timeout_ms INTEGER")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS lock_state VARCHAR(10)")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS pairing_timeout_sec INTEGER")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS locked_down_at timestamptz")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query(
-        "ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_detection_enable BOOLEAN DEFAULT true",
-    )
-    .execute(&db_pool)
-    .await?;
-    sqlx::query(
-        "ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_invite_enable BOOLEAN DEFAULT true",
-    )
-    .execute(&db_pool)
-    .await?;
-    sqlx::query("ALTER TABLE devices ADD COLUMN IF NOT EXISTS voice_threshold FLOAT8 DEFAULT 0.60")
-        .execute(&db_pool)
-        .await?;
-    sqlx::query(
-        "ALTER TABLE devices ADD COLUMN IF NOT EXISTS vad_rms_threshold INTEGER DEFAULT 1000",
-    )
-    .execute(&db_pool)
-    .await?;
-    sqlx::query("ALTER TABLE users ADD COLUMN IF NOT EXISTS voice_embeddings BYTEA")
-        .execute(&db_pool)
-        .await?;
+    // Spawn MQTT event handler
+    let db_pool_clone = db_pool.clone();
+    let mqtt_client_clone = mqtt_client.clone();
+    tokio::spawn(async move {
+        mqtt::handle_mqtt_events(
+            &db_pool_clone,
+            &mqtt_client_clone,
+            &mut eventloop,
+            mqtt_shared_group.as_deref(),
+        )
+        .await;
+    });
 
-    // Setup MQTT
-    let mut mqtt_options = MqttOptions::new("backend", mqtt_host, mqtt_port);
-    if let Some(user) = mqtt_username {
-        mqtt_options.set_credentials(user, mqtt_password.unwrap_or_default());
-    }
-    if mqtt_tls {
-        mqtt_options.set_transport(Transport::tls_with_default_config());
-    }
+    // Spawn command outbox task; periodic tasks run on one instance at a time
+    let db_pool_outbox = db_pool.clone();
+    let mqtt_client_outbox = mqtt_client.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            if let Err(e) = cluster::run_exclusive(&db_pool_outbox, "command_outbox", || {
+                command::retry_pending(&db_pool_outbox, &mqtt_client_outbox, None)
+            })
+            .await
+            {
+                println!("DEBUG: Failed to process command outbox: {}", e);
+            }
+        }
+    });
 
-    let (mqtt_client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+    // Spawn config reconciler task
+    let db_pool_twin = db_pool.clone();
+    let mqtt_client_twin = mqtt_client.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            if let Err(e) = cluster::run_exclusive(&db_pool_twin, "config_reconciler", || {
+                twin::reconcile(&db_pool_twin, &mqtt_client_twin, None)
+            })
+            .await
+            {
+                println!("DEBUG: Failed to reconcile device config: {}", e);
+            }
+        }
+    });
 
-    // Subscribe to status topics
-    mqtt_client
-        .subscribe("lockwise/+/status", QoS::AtMostOnce)
-        .await?;
+    // Spawn firmware rollout orchestrator task
+    let db_pool_ota = db_pool.clone();
+    let mqtt_client_ota = mqtt_client.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            if let Err(e) = cluster::run_exclusive(&db_pool_ota, "ota_orchestrator", || {
+                ota::orchestrate(&db_pool_ota, &mqtt_client_ota)
+            })
+            .await
+            {
+                println!("DEBUG: Failed to orchestrate firmware rollouts: {}", e);
+            }
+        }
+    });
 
-    // Spawn MQTT event handler
-    let db_pool_clone = db_pool.clone();
+    // Spawn presence watchdog task
+    let db_pool_presence = db_pool.clone();
     tokio::spawn(async move {
-        mqtt::handle_mqtt_events(&db_pool_clone, &mut eventloop).await;
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+            if let Err(e) = cluster::run_exclusive(&db_pool_presence, "presence_watchdog", || {
+                presence::check_timeouts(&db_pool_presence)
+            })
+            .await
+            {
+                println!("DEBUG: Failed to check device presence: {}", e);
+            }
+        }
     });
 
     // Spawn log cleanup task
@@ -267,6 +406,27 @@ async fn main() -> Result<()> {
                 .bind(one_month_ago)
                 .execute(&db_pool_cleanup)
                 .await;
+            let _ = sqlx::query("DELETE FROM device_commands WHERE created_at < $1")
+                .bind(one_month_ago)
+                .execute(&db_pool_cleanup)
+                .await;
+            let _ = sqlx::query("DELETE FROM device_events WHERE created_at < $1")
+                .bind(one_month_ago)
+                .execute(&db_pool_cleanup)
+                .await;
+            let _ = sqlx::query("DELETE FROM device_outages WHERE ended_at < $1")
+                .bind(one_month_ago)
+                .execute(&db_pool_cleanup)
+                .await;
+            let _ = sqlx::query("DELETE FROM device_pings WHERE sent_at < $1")
+                .bind(one_month_ago)
+                .execute(&db_pool_cleanup)
+                .await;
+            // Nonces outside the clock skew window are rejected anyway
+            let _ =
+                sqlx::query("DELETE FROM seen_nonces WHERE sent_at < NOW() - INTERVAL '1 hour'")
+                    .execute(&db_pool_cleanup)
+                    .await;
         }
     });
 
@@ -283,12 +443,17 @@ async fn main() -> Result<()> {
             .manage(mqtt_client)
             .manage(speechbrain_url)
             .manage(homepage_url)
+            .manage(firebase_verifier)
             .mount(
                 "/",
                 routes![
                     index,
                     health,
+                    mqtt_health,
                     websocket_updates,
+                    command::get_command,
+                    command::get_commands,
+                    config::get_config_schema,
                     device::control_device,
                     device::control_temp_device,
                     device::get_accessible_devices,
@@ -302,16 +467,53 @@ async fn main() -> Result<()> {
                     device::ping_device,
                     device::ping_temp_device,
                     device::reboot_device,
-                    device::register_device,
                     device::unpair_device,
                     device::update_config,
                     device::verify_voice,
+                    event::get_events,
                     invite::accept_invite,
                     invite::cancel_invite,
                     invite::create_invite,
+                    invite::create_invite_code,
                     invite::get_invites,
+                    invite::redeem_invite,
                     invite::reject_invite,
+                    invite::revoke_invite_code,
                     invite::update_invite,
+                    member::add_member,
+                    member::get_members,
+                    member::remove_member,
+                    ota::cancel_rollout,
+                    ota::create_release,
+                    ota::create_rollout,
+                    ota::download_firmware,
+                    ota::get_releases,
+                    ota::get_rollout,
+                    ota::get_rollouts,
+                    ota::resume_rollout,
+                    pairing::create_pairing_code,
+                    ping::get_pings,
+                    presence::get_presence,
+                    profile::assign_profile,
+                    profile::create_profile,
+                    profile::delete_profile,
+                    profile::get_profile,
+                    profile::get_profiles,
+                    profile::unassign_profile,
+                    profile::update_profile,
+                    revision::diff_revisions,
+                    revision::get_revisions,
+                    revision::rollback,
+                    transfer::accept_transfer,
+                    transfer::cancel_transfer,
+                    transfer::get_archived_logs,
+                    transfer::get_transfers,
+                    transfer::reject_transfer,
+                    transfer::request_transfer,
+                    twin::get_config,
+                    session::get_sessions,
+                    session::refresh_session,
+                    session::revoke_session,
                     user::delete_account,
                     user::delete_voice,
                     user::login_user,
@@ -346,32 +548,25 @@ fn health() -> &'static str {
     "OK"
 }
 
+/// Endpoint de verificação da conexão com o broker MQTT.
+/// Retorna o estado da conexão, com `503` enquanto o broker estiver inacessível.
+#[get("/health/mqtt")]
+fn mqtt_health() -> (Status, String) {
+    let status = mqtt::connection_status();
+    let code = if mqtt::is_connected() {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (code, serde_json::to_string(&status).unwrap())
+}
+
 /// WebSocket endpoint para atualizações em tempo real de dispositivos
 #[get("/ws/updates")]
-fn websocket_updates(
-    ws: WebSocket,
-    token: Token,
-    db_pool: &State<PgPool>,
-) -> rocket_ws::Channel<'static> {
-    let pool = (**db_pool).clone();
+fn websocket_updates(ws: WebSocket, user: session::AuthUser) -> rocket_ws::Channel<'static> {
+    let user_id = user.firebase_uid;
     ws.channel(move |mut stream| {
         Box::pin(async move {
-            // Validate token and get user_id
-            let user_row: Option<(String,)> =
-                sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-                    .bind(&token.0)
-                    .fetch_optional(&pool)
-                    .await
-                    .unwrap_or(None);
-            let user_id = match user_row {
-                Some((uid,)) => uid,
-                None => {
-                    // Invalid token, close connection
-                    let _ = stream.close(None).await;
-                    return Ok(());
-                }
-            };
-
             // Get or create user broadcast
             let user_broadcasts = USER_BROADCASTS.get().unwrap();
             let tx = {
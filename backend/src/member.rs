@@ -0,0 +1,389 @@
+//! Módulo para membros de dispositivos e controle de acesso por papéis.
+//!
+//! Cada usuário com acesso a um dispositivo possui um papel ([`Role`]): o proprietário
+//! (`devices.user_id`), membros cadastrados em `device_members` (admin, resident, guest)
+//! ou destinatários de convites aceitos e não expirados, tratados como guest. A matriz de
+//! permissões em [`Action::allowed_roles`] define quais papéis podem executar cada ação.
+use anyhow::Result;
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::{State, get, post};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::invite;
+use super::session::AuthUser;
+
+/// Papel de um usuário em um dispositivo, em ordem crescente de privilégio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Acesso temporário (membro guest ou convite aceito).
+    Guest,
+    /// Morador: uso diário e leitura de logs.
+    Resident,
+    /// Coadministrador: configura o dispositivo e gerencia membros de papel inferior.
+    Admin,
+    /// Proprietário do dispositivo.
+    Owner,
+}
+
+impl Role {
+    /// Nome do papel, como armazenado no banco e retornado pela API.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Resident => "resident",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Converte o nome de um papel.
+    pub fn parse(name: &str) -> Option<Role> {
+        match name {
+            "guest" => Some(Role::Guest),
+            "resident" => Some(Role::Resident),
+            "admin" => Some(Role::Admin),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+/// Ações sujeitas a controle de acesso em um dispositivo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Ver detalhes e estado do dispositivo.
+    View,
+    /// Trancar e destrancar (inclusive por voz).
+    Control,
+    /// Verificar conectividade.
+    Ping,
+    /// Ler logs de acesso e notificações.
+    ViewLogs,
+    /// Ler o histórico de eventos do dispositivo.
+    ViewEvents,
+    /// Alterar configuração.
+    UpdateConfig,
+    /// Atualizar o firmware.
+    UpdateFirmware,
+    /// Reiniciar remotamente.
+    Reboot,
+    /// Bloquear o dispositivo.
+    Lockdown,
+    /// Criar convites temporários.
+    Invite,
+    /// Listar membros.
+    ViewMembers,
+    /// Adicionar, alterar ou remover membros.
+    ManageMembers,
+    /// Despareiar o dispositivo.
+    Unpair,
+}
+
+impl Action {
+    /// Matriz de permissões: papéis autorizados a executar cada ação.
+    pub fn allowed_roles(self) -> &'static [Role] {
+        match self {
+            Action::View | Action::Control | Action::Ping => {
+                &[Role::Owner, Role::Admin, Role::Resident, Role::Guest]
+            }
+            Action::ViewLogs | Action::ViewMembers => &[Role::Owner, Role::Admin, Role::Resident],
+            Action::UpdateConfig
+            | Action::UpdateFirmware
+            | Action::Reboot
+            | Action::Lockdown
+            | Action::Invite
+            | Action::ViewEvents
+            | Action::ManageMembers => &[Role::Owner, Role::Admin],
+            Action::Unpair => &[Role::Owner],
+        }
+    }
+
+    /// Verifica se o papel pode executar a ação.
+    pub fn allows(self, role: Role) -> bool {
+        self.allowed_roles().contains(&role)
+    }
+
+    /// Nomes dos papéis autorizados, para uso em consultas SQL.
+    pub fn allowed_role_names(self) -> Vec<&'static str> {
+        self.allowed_roles()
+            .iter()
+            .map(|role| role.as_str())
+            .collect()
+    }
+}
+
+/// Informações de um membro do dispositivo retornadas pela API.
+#[derive(sqlx::FromRow, Serialize)]
+pub struct MemberInfo {
+    /// ID do usuário.
+    user_id: String,
+    /// Nome do usuário.
+    name: String,
+    /// Email do usuário.
+    email: String,
+    /// Papel no dispositivo.
+    role: String,
+    /// ID do usuário que adicionou o membro (ausente para o proprietário).
+    added_by: Option<String>,
+    /// Momento em que o membro foi adicionado (ausente para o proprietário).
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Estrutura de requisição para adicionar ou alterar um membro.
+#[derive(Deserialize)]
+pub struct AddMemberRequest {
+    /// Email do usuário a adicionar.
+    email: String,
+    /// Papel a atribuir (admin, resident ou guest).
+    role: String,
+}
+
+/// Resolve o papel do usuário no dispositivo, ou `None` se ele não tiver acesso.
+/// Retorna `NotFound` se o dispositivo não existir ou não estiver pareado.
+pub async fn role_of(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    user_id: &str,
+) -> Result<Option<Role>, Status> {
+    Ok(resolve(db_pool, device_id, user_id)
+        .await?
+        .map(|(role, _)| role))
+}
+
+/// Resolve o papel do usuário e se o acesso vem apenas de um convite.
+async fn resolve(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    user_id: &str,
+) -> Result<Option<(Role, bool)>, Status> {
+    let row: Option<(Option<String>, Option<String>, bool)> = sqlx::query_as(
+        "SELECT d.user_id, m.role, EXISTS (SELECT 1 FROM invites i WHERE i.device_id = d.uuid AND i.receiver_id = $2 AND i.status = 1 AND i.expiry_timestamp > $3) FROM devices d LEFT JOIN device_members m ON m.device_id = d.uuid AND m.user_id = $2 WHERE d.uuid = $1",
+    )
+    .bind(device_id)
+    .bind(user_id)
+    .bind(Utc::now().timestamp_millis())
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let Some((Some(owner_id), member_role, has_invite)) = row else {
+        return Err(Status::NotFound);
+    };
+
+    if owner_id == user_id {
+        return Ok(Some((Role::Owner, false)));
+    }
+    if let Some(role) = member_role.as_deref().and_then(Role::parse) {
+        return Ok(Some((role, false)));
+    }
+    Ok(has_invite.then_some((Role::Guest, true)))
+}
+
+/// Verifica se o usuário pode executar a ação no dispositivo, retornando seu papel.
+/// Retorna `NotFound` se o dispositivo não existir e `Unauthorized` se a ação for negada.
+/// Quem tem acesso apenas por convite só controla a fechadura dentro da agenda do convite.
+pub async fn authorize(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    user_id: &str,
+    action: Action,
+) -> Result<Role, Status> {
+    authorize_access(db_pool, device_id, user_id, action)
+        .await
+        .map(|(role, _)| role)
+}
+
+/// Como [`authorize`], mas também informa se o acesso vem apenas de um convite.
+pub async fn authorize_access(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    user_id: &str,
+    action: Action,
+) -> Result<(Role, bool), Status> {
+    match resolve(db_pool, device_id, user_id).await? {
+        Some((role, via_invite)) if action.allows(role) => {
+            if via_invite && action == Action::Control {
+                let active = invite::active_receivers(db_pool, device_id)
+                    .await
+                    .map_err(|_| Status::InternalServerError)?;
+                if !active.iter().any(|receiver| receiver == user_id) {
+                    println!(
+                        "DEBUG: Invite of user {} to device {} is outside its schedule",
+                        user_id, device_id
+                    );
+                    return Err(Status::Forbidden);
+                }
+            }
+            Ok((role, via_invite))
+        }
+        _ => Err(Status::Unauthorized),
+    }
+}
+
+/// Lista os usuários que podem executar a ação no dispositivo.
+pub async fn users_with_permission(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    action: Action,
+) -> Result<Vec<String>> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        "SELECT user_id FROM devices WHERE uuid = $1 AND user_id IS NOT NULL AND 'owner' = ANY($2)
+         UNION SELECT user_id FROM device_members WHERE device_id = $1 AND role = ANY($2)
+         UNION SELECT receiver_id FROM invites WHERE device_id = $1 AND status = 1 AND expiry_timestamp > $3 AND 'guest' = ANY($2)",
+    )
+    .bind(device_id)
+    .bind(action.allowed_role_names())
+    .bind(Utc::now().timestamp_millis())
+    .fetch_all(db_pool)
+    .await?;
+    Ok(rows.into_iter().map(|(user_id,)| user_id).collect())
+}
+
+/// Lista os dispositivos nos quais o usuário pode executar a ação.
+pub async fn devices_with_permission(
+    db_pool: &PgPool,
+    user_id: &str,
+    action: Action,
+) -> Result<Vec<Uuid>> {
+    let rows: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT uuid FROM devices WHERE user_id = $1 AND 'owner' = ANY($2)
+         UNION SELECT device_id FROM device_members WHERE user_id = $1 AND role = ANY($2)
+         UNION SELECT device_id FROM invites WHERE receiver_id = $1 AND status = 1 AND expiry_timestamp > $3 AND 'guest' = ANY($2)",
+    )
+    .bind(user_id)
+    .bind(action.allowed_role_names())
+    .bind(Utc::now().timestamp_millis())
+    .fetch_all(db_pool)
+    .await?;
+    Ok(rows.into_iter().map(|(device_id,)| device_id).collect())
+}
+
+/// Lista o proprietário e os membros de um dispositivo.
+#[get("/members/<uuid>")]
+pub async fn get_members(
+    user: AuthUser,
+    uuid: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let device_id = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, device_id, &user.firebase_uid, Action::ViewMembers).await?;
+
+    let members: Vec<MemberInfo> = sqlx::query_as(
+        "SELECT u.firebase_uid AS user_id, u.name, u.email, 'owner' AS role, NULL AS added_by, NULL::timestamptz AS created_at FROM devices d JOIN users u ON u.firebase_uid = d.user_id WHERE d.uuid = $1
+         UNION ALL
+         SELECT u.firebase_uid, u.name, u.email, m.role, m.added_by, m.created_at FROM device_members m JOIN users u ON u.firebase_uid = m.user_id WHERE m.device_id = $1",
+    )
+    .bind(device_id)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::to_string(&members).unwrap())
+}
+
+/// Adiciona um membro ao dispositivo ou altera seu papel.
+/// Só é possível atribuir papéis inferiores ao do usuário autenticado.
+#[post("/add_member/<uuid>", data = "<request>")]
+pub async fn add_member(
+    user: AuthUser,
+    uuid: &str,
+    request: rocket::serde::json::Json<AddMemberRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let device_id = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    let actor_role = authorize(
+        db_pool,
+        device_id,
+        &user.firebase_uid,
+        Action::ManageMembers,
+    )
+    .await?;
+
+    // Ownership is not granted through membership
+    let role = match Role::parse(&request.role) {
+        Some(Role::Owner) | None => return Err(Status::BadRequest),
+        Some(role) => role,
+    };
+    if role >= actor_role {
+        return Err(Status::Unauthorized);
+    }
+
+    // Find member by email
+    let member_row: Option<(String,)> =
+        sqlx::query_as("SELECT firebase_uid FROM users WHERE email = $1")
+            .bind(&request.email)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    let Some((member_id,)) = member_row else {
+        println!("DEBUG: Member not found for email: {}", request.email);
+        return Err(Status::NotFound);
+    };
+
+    // Cannot change the role of someone with equal or higher privilege
+    if let Some(current) = role_of(db_pool, device_id, &member_id).await?
+        && current >= actor_role
+    {
+        return Err(Status::Unauthorized);
+    }
+
+    sqlx::query(
+        "INSERT INTO device_members (device_id, user_id, role, added_by) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (device_id, user_id) DO UPDATE SET role = $3, added_by = $4",
+    )
+    .bind(device_id)
+    .bind(&member_id)
+    .bind(role.as_str())
+    .bind(&user.firebase_uid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(())
+}
+
+/// Remove um membro do dispositivo. Qualquer membro pode remover a si mesmo.
+#[post("/remove_member/<uuid>/<member_id>")]
+pub async fn remove_member(
+    user: AuthUser,
+    uuid: &str,
+    member_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let device_id = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    if member_id != user.firebase_uid {
+        let actor_role = authorize(
+            db_pool,
+            device_id,
+            &user.firebase_uid,
+            Action::ManageMembers,
+        )
+        .await?;
+        if let Some(current) = role_of(db_pool, device_id, member_id).await?
+            && current >= actor_role
+        {
+            return Err(Status::Unauthorized);
+        }
+    }
+
+    let rows_affected =
+        sqlx::query("DELETE FROM device_members WHERE device_id = $1 AND user_id = $2")
+            .bind(device_id)
+            .bind(member_id)
+            .execute(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .rows_affected();
+
+    if rows_affected == 0 {
+        return Err(Status::NotFound);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! Módulo para migrações versionadas do esquema do banco de dados.
+//!
+//! Cada migração possui um número de versão, um script `up` e um script `down`,
+//! armazenados em `migrations/` e embutidos no binário em tempo de compilação.
+//! As migrações aplicadas são registradas na tabela `schema_migrations` junto com
+//! o checksum do script `up`, permitindo detectar scripts alterados após a aplicação
+//! e bancos de dados mais novos que o binário em execução.
+//!
+//! Como todas as instâncias migram o banco ao iniciar, a verificação e a aplicação são
+//! serializadas por um advisory lock: uma instância que chega depois espera a anterior
+//! terminar e só então lê as migrações aplicadas.
+use anyhow::{Result, anyhow, bail};
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, PgConnection, PgPool};
+
+/// Chave do advisory lock que serializa as migrações entre instâncias.
+const MIGRATION_LOCK: &str = "schema_migrations";
+
+/// Uma migração de esquema embutida no binário.
+pub struct Migration {
+    /// Número de versão, estritamente crescente.
+    pub version: i64,
+    /// Nome descritivo da migração.
+    pub name: &'static str,
+    /// Script SQL que aplica a migração.
+    pub up: &'static str,
+    /// Script SQL que reverte a migração.
+    pub down: &'static str,
+}
+
+impl Migration {
+    /// Calcula o checksum SHA-256 (hexadecimal) do script `up`.
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+/// Declara uma migração a partir dos arquivos `migrations/<arquivo>.up.sql` e `.down.sql`.
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $name, ".down.sql")),
+        }
+    };
+}
+
+/// Todas as migrações conhecidas por este binário, em ordem de versão.
+pub static MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial_schema"),
+    migration!(2, "0002_sessions"),
+    migration!(3, "0003_device_members"),
+    migration!(4, "0004_invite_schedules"),
+    migration!(5, "0005_invite_uses"),
+    migration!(6, "0006_invite_codes"),
+    migration!(7, "0007_pairing_codes"),
+    migration!(8, "0008_device_transfers"),
+    migration!(9, "0009_device_commands"),
+    migration!(10, "0010_device_events"),
+    migration!(11, "0011_command_outbox"),
+    migration!(12, "0012_device_keys"),
+    migration!(13, "0013_device_presence"),
+    migration!(14, "0014_mqtt_credentials"),
+    migration!(15, "0015_seen_nonces"),
+    migration!(16, "0016_device_pings"),
+    migration!(17, "0017_device_desired_config"),
+    migration!(18, "0018_device_config_revisions"),
+    migration!(19, "0019_config_profiles"),
+    migration!(20, "0020_firmware_ota"),
+    migration!(21, "0021_command_invite_uses"),
+    migration!(22, "0022_control_sequence"),
+    migration!(23, "0023_ota_active_update"),
+    migration!(24, "0024_device_key_exchange"),
+];
+
+/// Linha da tabela `schema_migrations`.
+#[derive(sqlx::FromRow)]
+struct AppliedMigration {
+    /// Versão aplicada.
+    version: i64,
+    /// Nome da migração no momento da aplicação.
+    name: String,
+    /// Checksum do script `up` no momento da aplicação.
+    checksum: String,
+    /// Momento da aplicação.
+    applied_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Obtém uma conexão exclusiva com o advisory lock das migrações, esperando que outra
+/// instância termine as suas. A conexão é retirada do pool, e o lock é liberado quando
+/// ela é fechada, mesmo que a migração falhe no meio.
+async fn lock(db_pool: &PgPool) -> Result<PgConnection> {
+    let mut conn = db_pool.acquire().await?.detach();
+    sqlx::query("SELECT pg_advisory_lock(hashtext($1))")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut conn)
+        .await?;
+    Ok(conn)
+}
+
+/// Cria a tabela de controle `schema_migrations`, se necessário.
+async fn ensure_migrations_table(conn: &mut PgConnection) -> Result<()> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_migrations ( version BIGINT PRIMARY KEY, name VARCHAR(255) NOT NULL, checksum VARCHAR(64) NOT NULL, applied_at timestamptz NOT NULL DEFAULT NOW())")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Recupera as migrações já aplicadas, em ordem de versão.
+async fn applied_migrations(conn: &mut PgConnection) -> Result<Vec<AppliedMigration>> {
+    ensure_migrations_table(conn).await?;
+    let rows = sqlx::query_as(
+        "SELECT version, name, checksum, applied_at FROM schema_migrations ORDER BY version",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(rows)
+}
+
+/// Verifica se o banco de dados é compatível com as migrações deste binário.
+/// Falha se o banco possuir uma versão desconhecida (mais nova que o binário)
+/// ou se o checksum de alguma migração aplicada divergir do script embutido.
+/// Retorna as migrações aplicadas.
+async fn verify(conn: &mut PgConnection) -> Result<Vec<AppliedMigration>> {
+    let applied = applied_migrations(conn).await?;
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+
+    for row in &applied {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.version == row.version) else {
+            if row.version > latest {
+                bail!(
+                    "database schema is at version {} but this binary only knows up to {}; refusing to start",
+                    row.version,
+                    latest
+                );
+            }
+            bail!(
+                "database has unknown migration {} ({}) applied",
+                row.version,
+                row.name
+            );
+        };
+        if migration.checksum() != row.checksum {
+            bail!(
+                "checksum mismatch for migration {} ({}): script was modified after being applied",
+                row.version,
+                row.name
+            );
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Aplica todas as migrações pendentes, cada uma em sua própria transação.
+/// Retorna o número de migrações aplicadas.
+pub async fn run_pending(db_pool: &PgPool) -> Result<usize> {
+    let mut conn = lock(db_pool).await?;
+    let result = apply_pending(&mut conn).await;
+    conn.close().await?;
+    result
+}
+
+/// Aplica as migrações pendentes em uma conexão que já detém o lock.
+async fn apply_pending(conn: &mut PgConnection) -> Result<usize> {
+    // Read under the lock, so migrations applied by another instance are seen
+    let applied = verify(conn).await?;
+
+    let mut count = 0;
+    for migration in MIGRATIONS {
+        if applied.iter().any(|row| row.version == migration.version) {
+            continue;
+        }
+
+        println!(
+            "Applying migration {} ({})",
+            migration.version, migration.name
+        );
+        let mut tx = conn.begin().await?;
+        sqlx::raw_sql(migration.up).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Reverte as `steps` migrações mais recentes, da mais nova para a mais antiga.
+/// Retorna o número de migrações revertidas.
+pub async fn rollback(db_pool: &PgPool, steps: usize) -> Result<usize> {
+    let mut conn = lock(db_pool).await?;
+    let result = revert(&mut conn, steps).await;
+    conn.close().await?;
+    result
+}
+
+/// Reverte as `steps` migrações mais recentes em uma conexão que já detém o lock.
+async fn revert(conn: &mut PgConnection, steps: usize) -> Result<usize> {
+    let applied = verify(conn).await?;
+
+    let mut count = 0;
+    for row in applied.iter().rev().take(steps) {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.version == row.version)
+            .ok_or_else(|| anyhow!("no down script for migration {}", row.version))?;
+
+        println!(
+            "Reverting migration {} ({})",
+            migration.version, migration.name
+        );
+        let mut tx = conn.begin().await?;
+        sqlx::raw_sql(migration.down).execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+            .bind(migration.version)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Imprime o estado de cada migração (aplicada ou pendente).
+pub async fn print_status(db_pool: &PgPool) -> Result<()> {
+    let mut conn = db_pool.acquire().await?;
+    let applied = applied_migrations(&mut conn).await?;
+
+    for migration in MIGRATIONS {
+        match applied.iter().find(|row| row.version == migration.version) {
+            Some(row) => {
+                let marker = if row.checksum == migration.checksum() {
+                    ""
+                } else {
+                    " (CHECKSUM MISMATCH)"
+                };
+                println!(
+                    "{:>4} {:<40} applied {}{}",
+                    migration.version, migration.name, row.applied_at, marker
+                );
+            }
+            None => println!("{:>4} {:<40} pending", migration.version, migration.name),
+        }
+    }
+
+    for row in &applied {
+        if !MIGRATIONS.iter().any(|m| m.version == row.version) {
+            println!(
+                "{:>4} {:<40} applied {} (UNKNOWN TO THIS BINARY)",
+                row.version, row.name, row.applied_at
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Executa o subcomando `migrate` da linha de comando.
+///
+/// Uso: `lockwise-backend migrate [up | down [N] | status]` (padrão: `up`).
+pub async fn run_cli(db_pool: &PgPool, args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        None | Some("up") => {
+            let count = run_pending(db_pool).await?;
+            println!("{} migration(s) applied", count);
+        }
+        Some("down") => {
+            let steps = match args.get(1) {
+                Some(n) => n.parse().map_err(|_| anyhow!("Invalid number"))?,
+                None => 1,
+            };
+            let count = rollback(db_pool, steps).await?;
+            println!("{} migration(s) reverted", count);
+        }
+        Some("status") => print_status(db_pool).await?,
+        Some(other) => bail!(
+            "unknown migrate command '{}' (expected up, down [N] or status)",
+            other
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checksums registrados em `schema_migrations` pelos bancos já migrados. Alterar uma
+    /// migração aplicada impede que esses bancos iniciem; crie uma nova migração.
+    const APPLIED_CHECKSUMS: &[&str] = &[
+        "b62c4f9f88b6f93114efe38ddccb2f4edbdc8fce45f8bd15687b5c4d5100982b",
+        "6320435dd3d6e47227d4ff606c92ead6c5fbd172efe9b4175fe19b8326ef04f1",
+        "495ff2fc93354616c17fdd0c878e1f692883a6c283eb6d2f1cb51971679d21e3",
+        "22098e68e82879b4a834d1c54af33c724b70cb34e1009c5dcc9bf502e89b251a",
+        "300e1ba0708cb186f2cbfb41b7b4449bda1d06d631889d76186016588329c2e0",
+        "d58de4ce1f2e4b2ff01217cee046e2d10bf3aca2f30380ac6c3a1150039f9de5",
+        "fd7ec1d7fa3fdcad2db151b87b7a2cd25fa02780f7abe333989994503d0b3fa5",
+        "806cfde44f7e45fe82de2db67960b8808fd7c66d8d6c17c6d4d510c0afc25dfe",
+        "fa222fea2366d82dbbb07db214053c828ab8fd081579d0be461ac179dd355701",
+        "1d14c7e5dbcd3a5d34ec42f40f813ddeec5e85520d4079e757fb3d05632f4445",
+        "4e1600efb0c9405a9679588ad9ce1a87faff0707e4728d5f7fb61b1fba4a2b55",
+        "80ff6e77622b8308b4b50a217dc30f98a35fd4a912addbf7c1f6cab0940e4022",
+        "efce7478f139db7d0f2b14e17493ac8f71c1a053cab35975bb90f235907fda76",
+        "011470b8d935027a6f2a7f236c1b8ebb0a0b15f106f212ed629b34f9f2db98a1",
+        "7444efecfc9f0fe95051350edd2008d2ac1442f93be0f9f0c63cc2f6c35fde97",
+        "a677b25c0b73d9d9d7103133e9d9af92faddbd40486caa63a0090422c38d22f2",
+        "8fc104afdc31895af5cf8cc513d5310cdf7cc8c0ade8998b2506952bfbbe3ffb",
+        "1711c0ed7d0185ec91e09f5a8e5a422bcbb518bee75b9bbdcd60aea353bece49",
+        "5b3f0444a73b27bfdac301c4848aef7492793a5682675460d34cf84444a07789",
+        "f3fdc66cca5797cdfc2dcb69e9a45b98cc13d3434b81ae2fae26875879cf6d83",
+        "ef7e6fd1cb6a00105ea29867220e579a18f3cd5af75317ad5324eb9dd816bd0c",
+        "b10f11a3b7e82917dce38e0951ce0025c07a8a00a34d9edee571b90c0b4c3058",
+        "509011c01438016e554d2e1aeeef550ed779c54f51033fb58131395384fc9ee9",
+        "a65c511f5cb530152e07d9f09d01c97c447c37fc089553a5e6c0c0d573463372",
+    ];
+
+    #[test]
+    fn versions_are_contiguous_and_match_file_names() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+            assert!(
+                migration
+                    .name
+                    .starts_with(&format!("{:04}_", migration.version)),
+                "{}",
+                migration.name
+            );
+            assert!(!migration.up.trim().is_empty(), "{}", migration.name);
+            assert!(!migration.down.trim().is_empty(), "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn every_migration_file_is_registered() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_suffix(".up.sql").map(str::to_string)
+            })
+            .collect();
+        names.sort();
+        let registered: Vec<&str> = MIGRATIONS.iter().map(|m| m.name).collect();
+        assert_eq!(names, registered);
+    }
+
+    #[test]
+    fn applied_migrations_are_unchanged() {
+        for (migration, expected) in MIGRATIONS.iter().zip(APPLIED_CHECKSUMS) {
+            assert_eq!(&migration.checksum(), expected, "{}", migration.name);
+        }
+        assert!(MIGRATIONS.len() >= APPLIED_CHECKSUMS.len());
+    }
+}
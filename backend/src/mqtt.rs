@@ -1,62 +1,121 @@
 //! Módulo para comunicação MQTT.
 //!
 //! Este módulo gerencia a conexão MQTT com dispositivos, incluindo publicação de comandos,
-//! recebimento de mensagens de status e processamento de heartbeats.
-use anyhow::Result;
-use chrono::{TimeZone, Utc};
+//! recebimento de mensagens de status e processamento de heartbeats. As mensagens
+//! recebidas são decodificadas por [`super::protocol`] e registradas no histórico de
+//! eventos ([`super::event`]).
+//!
+//! A conexão é supervisionada: após uma falha, o laço de eventos aguarda com backoff
+//! exponencial antes de reconectar e refaz a inscrição nos tópicos de status a cada
+//! reconexão. O estado da conexão pode ser consultado pela camada HTTP, e as rotas que
+//! publicam comandos falham imediatamente com `503` enquanto o broker está inacessível.
+//! Cada mensagem autenticada e cada Last Will atualizam a presença do dispositivo
+//! ([`super::presence`]).
+//!
+//! Com várias instâncias, as inscrições podem ser compartilhadas (`MQTT_SHARED_GROUP`)
+//! para que cada mensagem seja processada uma só vez; as respostas que resolvem esperas
+//! de outras instâncias seguem por [`super::cluster`].
+use anyhow::{Result, bail};
+use chrono::{DateTime, TimeZone, Utc};
+use rocket::http::Status;
 use rumqttc::{AsyncClient, Event, Incoming, QoS};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use sqlx::PgPool;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use uuid::Uuid;
 
-use super::device::LockStatusMessage;
-
-/// Estrutura de mensagem para relatórios de heartbeat de dispositivos via MQTT
-#[derive(Deserialize)]
-struct HeartbeatMessage {
-    /// Tipo de mensagem de heartbeat
-    heartbeat: String,
-    /// Tempo de atividade do dispositivo em milissegundos
-    uptime_ms: u64,
-    /// Timestamp da mensagem
-    #[allow(dead_code)]
-    timestamp: u64,
-    /// SSID WiFi ao qual o dispositivo está conectado
-    wifi_ssid: String,
-    /// URL do back-end configurada no dispositivo
-    backend_url: String,
-    /// URL do broker MQTT configurada no dispositivo
-    mqtt_broker_url: String,
-    /// Se o heartbeat MQTT está habilitado
-    mqtt_heartbeat_enable: bool,
-    /// Intervalo de heartbeat em segundos
-    mqtt_heartbeat_interval_sec: i32,
-    /// Tempo limite de gravação de áudio em segundos
-    audio_record_timeout_sec: i32,
-    /// Tempo limite de bloqueio em milissegundos
-    lock_timeout_ms: i32,
-    /// Tempo limite de pareamento em segundos
-    pairing_timeout_sec: i32,
-    /// ID do usuário associado ao dispositivo
-    user_id: String,
-    /// Estado atual de bloqueio
-    lock_state: Option<String>,
-    /// Se a detecção de voz está habilitada
-    voice_detection_enable: bool,
-    /// Limiar RMS para detecção de atividade de voz
-    vad_rms_threshold: i32,
+use super::channel;
+use super::cluster::{self, Signal};
+use super::command;
+use super::event;
+use super::member::Action;
+use super::notify_users;
+use super::ota;
+use super::pairing::PAIRING_CODE_PREFIX;
+use super::presence::{self, LAST_WILL_PAYLOAD, OfflineReason, PRESENCE_TOPIC};
+use super::protocol::{self, DeviceMessage, Envelope, Heartbeat, LockEvent};
+use super::revision;
+use super::twin;
+
+/// Tópico de status publicado pelos dispositivos.
+pub const STATUS_TOPIC: &str = "lockwise/+/status";
+/// Espera inicial antes de reconectar ao broker, em milissegundos.
+const RECONNECT_INITIAL_BACKOFF_MS: u64 = 500;
+/// Espera máxima antes de reconectar ao broker, em milissegundos.
+const RECONNECT_MAX_BACKOFF_MS: u64 = 60_000;
+
+/// Estado da conexão com o broker MQTT.
+static CONNECTION: ConnectionState = ConnectionState {
+    connected: AtomicBool::new(false),
+    changed_at: AtomicI64::new(0),
+    reconnects: AtomicU64::new(0),
+    last_error: Mutex::new(None),
+};
+
+/// Estado da conexão com o broker, atualizado pelo laço de eventos.
+struct ConnectionState {
+    /// Se a conexão está estabelecida (CONNACK recebido).
+    connected: AtomicBool,
+    /// Momento da última mudança de estado, em milissegundos desde a época Unix.
+    changed_at: AtomicI64,
+    /// Número de reconexões bem-sucedidas desde a inicialização.
+    reconnects: AtomicU64,
+    /// Último erro de conexão.
+    last_error: Mutex<Option<String>>,
+}
+
+/// Estado da conexão com o broker, como retornado pela API.
+#[derive(Serialize)]
+pub struct ConnectionStatus {
+    /// Se a conexão está estabelecida.
+    connected: bool,
+    /// Momento da última mudança de estado, em milissegundos desde a época Unix.
+    since: i64,
+    /// Número de reconexões bem-sucedidas desde a inicialização.
+    reconnects: u64,
+    /// Último erro de conexão.
+    last_error: Option<String>,
+    /// Mensagens de dispositivos rejeitadas por falta de autenticação.
+    rejected_messages: u64,
+    /// Mensagens de dispositivos que não puderam ser decodificadas.
+    decode_failures: u64,
+}
+
+/// Indica se a conexão com o broker está estabelecida.
+pub fn is_connected() -> bool {
+    CONNECTION.connected.load(Ordering::Relaxed)
 }
 
-/// Estrutura de mensagem para relatórios de eventos de dispositivos via MQTT
-#[derive(Deserialize)]
-struct EventMessage {
-    /// Tipo de evento (ex.: PONG, CONFIG_UPDATED, LOCKING_DOWN)
-    event: String,
-    /// Tempo de atividade do dispositivo em milissegundos
-    #[allow(dead_code)]
-    uptime_ms: u64,
-    /// Timestamp do evento
-    timestamp: u64,
+/// Falha com `503 Service Unavailable` se o broker estiver inacessível, para que as
+/// rotas não enfileirem comandos que não podem ser entregues.
+pub fn ensure_connected() -> Result<(), Status> {
+    if is_connected() {
+        Ok(())
+    } else {
+        Err(Status::ServiceUnavailable)
+    }
+}
+
+/// Recupera o estado atual da conexão com o broker.
+pub fn connection_status() -> ConnectionStatus {
+    ConnectionStatus {
+        connected: is_connected(),
+        since: CONNECTION.changed_at.load(Ordering::Relaxed),
+        reconnects: CONNECTION.reconnects.load(Ordering::Relaxed),
+        last_error: CONNECTION.last_error.lock().unwrap().clone(),
+        rejected_messages: channel::rejected_messages(),
+        decode_failures: protocol::decode_failures(),
+    }
+}
+
+/// Atualiza o estado da conexão, registrando a mudança.
+fn set_connected(connected: bool) {
+    if CONNECTION.connected.swap(connected, Ordering::Relaxed) != connected {
+        CONNECTION
+            .changed_at
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
 }
 
 /// Estrutura de mensagem para enviar comandos de controle aos dispositivos via MQTT
@@ -64,380 +123,543 @@ struct EventMessage {
 struct ControlMessage {
     /// O comando a enviar (ex.: LOCK, UNLOCK, PING)
     command: String,
+    /// ID do comando, ecoado pelo dispositivo no evento resultante
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_id: Option<String>,
 }
 
 /// Manipula eventos MQTT recebidos dos dispositivos.
-/// Processa mensagens de heartbeat, eventos (PONG, CONFIG_UPDATED, LOCKING_DOWN)
-/// e atualizações de status de bloqueio, atualizando o banco de dados conforme necessário.
+/// Processa mensagens de heartbeat, eventos (PONG, CONFIG_UPDATED, LOCKING_DOWN,
+/// ENTERING_PAIRING_MODE), códigos de pareamento apresentados e atualizações de status de
+/// bloqueio, atualizando o banco de dados conforme necessário.
 /// Também envia atualizações em tempo real via WebSocket para usuários conectados.
-pub async fn handle_mqtt_events(db_pool: &PgPool, eventloop: &mut rumqttc::EventLoop) {
+/// Supervisiona a conexão: a cada CONNACK refaz a inscrição em [`STATUS_TOPIC`] e, após
+/// uma falha, aguarda com backoff exponencial antes de reconectar.
+/// Com `shared_group`, as inscrições são compartilhadas (`$share/<grupo>/...`) e o broker
+/// entrega cada mensagem a uma só das instâncias do grupo.
+pub async fn handle_mqtt_events(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    eventloop: &mut rumqttc::EventLoop,
+    shared_group: Option<&str>,
+) {
+    let mut backoff_ms = RECONNECT_INITIAL_BACKOFF_MS;
+    let mut ever_connected = false;
     loop {
         match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                if ever_connected {
+                    CONNECTION.reconnects.fetch_add(1, Ordering::Relaxed);
+                }
+                ever_connected = true;
+                backoff_ms = RECONNECT_INITIAL_BACKOFF_MS;
+                set_connected(true);
+                println!("DEBUG: Connected to MQTT broker");
+
+                // Subscriptions do not survive a clean session; never wait on the request
+                // queue here, since only this loop drains it
+                for topic in [STATUS_TOPIC, PRESENCE_TOPIC] {
+                    let topic = match shared_group {
+                        Some(group) => format!("$share/{}/{}", group, topic),
+                        None => topic.to_string(),
+                    };
+                    if let Err(e) = mqtt_client.try_subscribe(&topic, QoS::AtLeastOnce) {
+                        println!("DEBUG: Failed to subscribe to {}: {}", topic, e);
+                    }
+                }
+            }
             Ok(Event::Incoming(Incoming::Publish(publish))) => {
                 let topic = publish.topic;
+                // The broker publishes the Last Will on the device's behalf, so it
+                // cannot be signed; it can only ever mark the device offline
+                if let Some(uuid_str) = topic
+                    .strip_prefix("lockwise/")
+                    .and_then(|t| t.strip_suffix("/presence"))
+                {
+                    if let Ok(uuid) = Uuid::parse_str(uuid_str)
+                        && publish.payload.as_ref() == LAST_WILL_PAYLOAD
+                        && let Err(e) =
+                            presence::mark_offline(db_pool, uuid, OfflineReason::LastWill, None)
+                                .await
+                    {
+                        println!("DEBUG: Failed to mark device {} offline: {}", uuid, e);
+                    }
+                    continue;
+                }
                 if topic.starts_with("lockwise/") && topic.ends_with("/status") {
                     let uuid_str = &topic[9..topic.len() - 7]; // extract UUID
                     if let Ok(uuid) = Uuid::parse_str(uuid_str) {
-                        // Try to parse as HeartbeatMessage first (has heartbeat field)
-                        if let Ok(heartbeat_msg) =
-                            serde_cbor::from_slice::<HeartbeatMessage>(&publish.payload)
-                        {
-                            if heartbeat_msg.heartbeat == "HEARTBEAT" {
-                                // Handle HEARTBEAT
-                                let now = Utc::now();
-                                let lock_state =
-                                    heartbeat_msg.lock_state.as_deref().unwrap_or("UNKNOWN");
-
-                                // Check if device is in lockdown and heartbeat is at least 10 seconds after lockdown
-                                let should_clear_lockdown = {
-                                    let row: Option<(Option<chrono::DateTime<chrono::Utc>>,)> =
-                                        sqlx::query_as(
-                                            "SELECT locked_down_at FROM devices WHERE uuid = $1",
-                                        )
-                                        .bind(uuid)
-                                        .fetch_optional(db_pool)
-                                        .await
-                                        .unwrap_or(None);
-
-                                    if let Some((Some(locked_down_at),)) = row {
-                                        let duration_since_lockdown = now - locked_down_at;
-                                        duration_since_lockdown.num_seconds() >= 10
-                                    } else {
-                                        false
-                                    }
-                                };
-
-                                let update_query = if should_clear_lockdown {
-                                    "INSERT INTO devices (uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, voice_detection_enable, vad_rms_threshold, hashed_passphrase, locked_down_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, NULL, NULL)
-                                          ON CONFLICT (uuid) DO UPDATE SET user_id = $2, last_heard = $3, uptime_ms = $4, wifi_ssid = $5, backend_url = $6, mqtt_broker_url = $7, mqtt_heartbeat_enable = $8, mqtt_heartbeat_interval_sec = $9, audio_record_timeout_sec = $10, lock_timeout_ms = $11, pairing_timeout_sec = $12, lock_state = $13, voice_detection_enable = $14, vad_rms_threshold = $15, locked_down_at = NULL"
-                                } else {
-                                    "INSERT INTO devices (uuid, user_id, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, voice_detection_enable, vad_rms_threshold, hashed_passphrase) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, NULL)
-                                          ON CONFLICT (uuid) DO UPDATE SET user_id = $2, last_heard = $3, uptime_ms = $4, wifi_ssid = $5, backend_url = $6, mqtt_broker_url = $7, mqtt_heartbeat_enable = $8, mqtt_heartbeat_interval_sec = $9, audio_record_timeout_sec = $10, lock_timeout_ms = $11, pairing_timeout_sec = $12, lock_state = $13, voice_detection_enable = $14, vad_rms_threshold = $15"
-                                };
-
-                                let _ = sqlx::query(update_query)
-                                    .bind(uuid)
-                                    .bind(&heartbeat_msg.user_id)
-                                    .bind(now)
-                                    .bind(heartbeat_msg.uptime_ms as i64)
-                                    .bind(&heartbeat_msg.wifi_ssid)
-                                    .bind(&heartbeat_msg.backend_url)
-                                    .bind(&heartbeat_msg.mqtt_broker_url)
-                                    .bind(heartbeat_msg.mqtt_heartbeat_enable)
-                                    .bind(heartbeat_msg.mqtt_heartbeat_interval_sec)
-                                    .bind(heartbeat_msg.audio_record_timeout_sec)
-                                    .bind(heartbeat_msg.lock_timeout_ms)
-                                    .bind(heartbeat_msg.pairing_timeout_sec)
-                                    .bind(lock_state)
-                                    .bind(heartbeat_msg.voice_detection_enable)
-                                    .bind(heartbeat_msg.vad_rms_threshold)
-                                    .execute(db_pool)
-                                    .await;
-
-                                // Broadcast device online update to owner and invited users
-                                if let Some(user_broadcasts) = super::USER_BROADCASTS.get() {
-                                    let update = serde_json::json!({
-                                        "type": "device_online",
-                                        "device_id": uuid_str,
-                                        "last_heard": now.timestamp_millis(),
-                                        "lock_state": lock_state,
-                                        "locked_down_at": null
-                                    })
-                                    .to_string();
-
-                                    // Get owner
-                                    let owner_row: Option<(String,)> = sqlx::query_as(
-                                        "SELECT user_id FROM devices WHERE uuid = $1",
+                        let authenticated =
+                            match channel::authenticate(db_pool, uuid, &publish.payload).await {
+                                Ok(authenticated) => authenticated,
+                                Err(e) => {
+                                    channel::record_rejection(uuid, &e);
+                                    event::record_rejected_message(
+                                        db_pool,
+                                        uuid,
+                                        "UNAUTHENTICATED",
+                                        &e.to_string(),
                                     )
-                                    .bind(uuid)
-                                    .fetch_optional(db_pool)
-                                    .await
-                                    .unwrap_or(None);
-
-                                    let mut recipients = Vec::new();
-                                    if let Some((owner_id,)) = owner_row {
-                                        recipients.push(owner_id);
-                                    }
-
-                                    // Get invited users with status = 1
-                                    let invited_rows: Vec<(String,)> = sqlx::query_as(
-                                         "SELECT receiver_id FROM invites WHERE device_id = $1 AND status = 1"
-                                     )
-                                     .bind(uuid)
-                                     .fetch_all(db_pool)
-                                     .await
-                                     .unwrap_or_default();
-
-                                    for (receiver_id,) in invited_rows {
-                                        recipients.push(receiver_id);
-                                    }
-
-                                    // Send to each recipient
-                                    let broadcasts = user_broadcasts.lock().unwrap();
-                                    for recipient in recipients {
-                                        if let Some(tx) = broadcasts.get(&recipient) {
-                                            let _ = tx.send(update.clone());
-                                        }
-                                    }
+                                    .await;
+                                    continue;
                                 }
-                            }
-                        } else if let Ok(event_msg) =
-                            serde_cbor::from_slice::<EventMessage>(&publish.payload)
+                            };
+                        // A message signed with a pairing code only counts once the
+                        // code has claimed the device
+                        if authenticated.pairing
+                            && !super::pairing::claim_presented(
+                                db_pool,
+                                mqtt_client,
+                                uuid,
+                                &authenticated.payload,
+                            )
+                            .await
                         {
-                            if event_msg.event == "PONG" {
-                                // Handle PONG
-                                let pings_mutex = super::PENDING_PINGS.get().unwrap();
-                                let mut pings = pings_mutex.lock().unwrap();
-                                if let Some((_, tx)) = pings.remove(&uuid_str.to_string()) {
-                                    tx.send(()).ok();
-                                }
-                            } else if event_msg.event == "CONFIG_UPDATED" {
-                                // Handle CONFIG_UPDATED
-                                let updates_mutex = super::PENDING_CONFIG_UPDATES.get().unwrap();
-                                let mut updates = updates_mutex.lock().unwrap();
-                                if let Some(tx) = updates.remove(&uuid_str.to_string()) {
-                                    tx.send(()).ok();
+                            event::record_rejected_message(
+                                db_pool,
+                                uuid,
+                                "UNAUTHENTICATED",
+                                "pairing code did not claim the device",
+                            )
+                            .await;
+                            continue;
+                        }
+                        let payload = authenticated.payload;
+                        match protocol::decode(&payload) {
+                            Ok(envelope) => {
+                                event::record_device_message(db_pool, uuid, &envelope).await;
+                                handle_device_message(db_pool, mqtt_client, uuid, envelope).await;
+                                if let Err(e) = presence::mark_online(db_pool, uuid).await {
+                                    println!("DEBUG: Failed to mark device {} online: {}", uuid, e);
                                 }
-                            } else if event_msg.event == "LOCKING_DOWN" {
-                                // LOCKING_DOWN event - set locked_down_at
-                                let timestamp = Utc
-                                    .timestamp_millis_opt(event_msg.timestamp as i64 * 1000)
-                                    .unwrap();
-                                let result = sqlx::query(
-                                    "UPDATE devices SET locked_down_at = $1 WHERE uuid = $2",
+                            }
+                            Err(e) => {
+                                protocol::record_failure(uuid, &e);
+                                event::record_rejected_message(
+                                    db_pool,
+                                    uuid,
+                                    "DECODE_FAILED",
+                                    &e.to_string(),
                                 )
-                                .bind(timestamp)
-                                .bind(uuid)
-                                .execute(db_pool)
                                 .await;
-                                if result.is_ok() {
-                                    // Broadcast device update to owner and invited users
-                                    if let Some(user_broadcasts) = super::USER_BROADCASTS.get() {
-                                        let update = serde_json::json!({
-                                            "type": "device_update",
-                                            "device_id": uuid_str,
-                                            "lock_state": "LOCKED",
-                                            "locked_down_at": timestamp.timestamp_millis()
-                                        })
-                                        .to_string();
-
-                                        // Get owner
-                                        let owner_row: Option<(String,)> = sqlx::query_as(
-                                            "SELECT user_id FROM devices WHERE uuid = $1",
-                                        )
-                                        .bind(uuid)
-                                        .fetch_optional(db_pool)
-                                        .await
-                                        .unwrap_or(None);
-
-                                        let mut recipients = Vec::new();
-                                        if let Some((owner_id,)) = owner_row {
-                                            recipients.push(owner_id);
-                                        }
-
-                                        // Get invited users with status = 1
-                                        let invited_rows: Vec<(String,)> = sqlx::query_as(
-                                              "SELECT receiver_id FROM invites WHERE device_id = $1 AND status = 1"
-                                          )
-                                          .bind(uuid)
-                                          .fetch_all(db_pool)
-                                          .await
-                                          .unwrap_or_default();
-
-                                        for (receiver_id,) in invited_rows {
-                                            recipients.push(receiver_id);
-                                        }
-
-                                        // Send to each recipient
-                                        let broadcasts = user_broadcasts.lock().unwrap();
-                                        for recipient in recipients {
-                                            if let Some(tx) = broadcasts.get(&recipient) {
-                                                let _ = tx.send(update.clone());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else if let Ok(lock_msg) =
-                            serde_cbor::from_slice::<LockStatusMessage>(&publish.payload)
-                        {
-                            // LOCK/UNLOCK event
-                            let event_type = if lock_msg.lock == "LOCKED" {
-                                "LOCK"
-                            } else {
-                                "UNLOCK"
-                            };
-                            let reason = &lock_msg.reason;
-                            let timestamp = Utc
-                                .timestamp_millis_opt(lock_msg.timestamp as i64 * 1000)
-                                .unwrap();
-
-                            // Check for recent command
-                            let user_id = {
-                                let commands_mutex = super::RECENT_COMMANDS.get().unwrap();
-                                let mut commands = commands_mutex.lock().unwrap();
-                                if let Some((uid, cmd_time)) = commands.get(&uuid_str.to_string()) {
-                                    let now = Utc::now().timestamp();
-                                    if now - cmd_time < 5 {
-                                        // within 5 seconds
-                                        let uid = uid.clone();
-                                        commands.remove(&uuid_str.to_string());
-                                        Some(uid)
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                }
-                            };
-
-                            // Insert log
-                            let _ = sqlx::query(
-                                        "INSERT INTO logs (device_id, timestamp, event_type, reason, user_id) VALUES ($1, $2, $3, $4, $5)"
-                                    )
-                                    .bind(uuid_str)
-                                    .bind(timestamp)
-                                    .bind(event_type)
-                                    .bind(reason)
-                                    .bind(&user_id)
-                                    .execute(db_pool)
-                                    .await;
-
-                            // Broadcast log update to owner only
-                            if let Some(user_broadcasts) = super::USER_BROADCASTS.get() {
-                                // Get user name if user_id is present
-                                let user_name = if let Some(ref uid) = user_id {
-                                    let row: Option<(String,)> = sqlx::query_as(
-                                        "SELECT name FROM users WHERE firebase_uid = $1",
-                                    )
-                                    .bind(uid)
-                                    .fetch_optional(db_pool)
-                                    .await
-                                    .unwrap_or(None);
-                                    row.map(|(name,)| name)
-                                } else {
-                                    None
-                                };
-
-                                let log_update = serde_json::json!({
-                                    "type": "log_update",
-                                    "device_id": uuid_str,
-                                    "timestamp": timestamp.timestamp_millis(),
-                                    "event_type": event_type,
-                                    "reason": reason,
-                                    "user_id": user_id,
-                                    "user_name": user_name
-                                })
-                                .to_string();
-
-                                // Get owner
-                                let owner_row: Option<(String,)> =
-                                    sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-                                        .bind(uuid)
-                                        .fetch_optional(db_pool)
-                                        .await
-                                        .unwrap_or(None);
-
-                                if let Some((owner_id,)) = owner_row {
-                                    let broadcasts = user_broadcasts.lock().unwrap();
-                                    if let Some(tx) = broadcasts.get(&owner_id) {
-                                        let _ = tx.send(log_update);
-                                    }
-                                }
                             }
+                        }
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                set_connected(false);
+                *CONNECTION.last_error.lock().unwrap() = Some(e.to_string());
+                println!(
+                    "DEBUG: MQTT connection error: {}; reconnecting in {} ms",
+                    e, backoff_ms
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(RECONNECT_MAX_BACKOFF_MS);
+            }
+        }
+    }
+}
 
-                            // Update lock_state
-                            let lock_state = if lock_msg.lock == "LOCKED" {
-                                "LOCKED"
-                            } else {
-                                "UNLOCKED"
-                            };
-                            let _ =
-                                sqlx::query("UPDATE devices SET lock_state = $1 WHERE uuid = $2")
-                                    .bind(lock_state)
-                                    .bind(uuid_str)
-                                    .execute(db_pool)
-                                    .await;
+/// Converte o timestamp informado pelo dispositivo (segundos desde a época Unix). Como o
+/// valor vem do dispositivo, timestamps fora da faixa representável usam o horário atual.
+fn device_time(timestamp: u64) -> DateTime<Utc> {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|seconds| seconds.checked_mul(1000))
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        .unwrap_or_else(Utc::now)
+}
 
-                            // Get locked_down_at
-                            let locked_down_at: Option<i64> = {
-                                let row: Option<(Option<chrono::DateTime<Utc>>,)> = sqlx::query_as(
-                                    "SELECT locked_down_at FROM devices WHERE uuid = $1",
-                                )
-                                .bind(uuid)
-                                .fetch_optional(db_pool)
-                                .await
-                                .unwrap_or(None);
-                                row.and_then(|(dt,)| dt.map(|d| d.timestamp_millis()))
-                            };
+/// Trata uma mensagem decodificada de um dispositivo.
+async fn handle_device_message(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    uuid: Uuid,
+    envelope: Envelope,
+) {
+    let uuid_str = uuid.to_string();
+    let timestamp = envelope.timestamp.unwrap_or(0);
+    let command_id = envelope.command_id;
+    match envelope.message {
+        DeviceMessage::Heartbeat(heartbeat) => {
+            let firmware_version = heartbeat.fw_version.clone();
+            let uptime_ms = heartbeat.uptime_ms;
+            handle_heartbeat(db_pool, mqtt_client, uuid, heartbeat).await;
+            // The heartbeat is the reported state of the device twin
+            if let Err(e) = twin::compare_reported(db_pool, uuid).await {
+                println!("DEBUG: Failed to compare config of device {}: {}", uuid, e);
+            }
+            if let Err(e) = revision::observe(db_pool, uuid).await {
+                println!("DEBUG: Failed to record config of device {}: {}", uuid, e);
+            }
+            // The running version tells whether a pending firmware update took
+            if let Some(version) = firmware_version
+                && let Err(e) = ota::observe(db_pool, uuid, &version, uptime_ms).await
+            {
+                println!("DEBUG: Failed to check firmware of device {}: {}", uuid, e);
+            }
+        }
+        DeviceMessage::Lock(lock_msg) => {
+            handle_lock_event(db_pool, uuid, timestamp, lock_msg).await;
+        }
+        DeviceMessage::Pong => {
+            // Firmware that does not echo the ID answers every pending ping
+            let ping_id = command_id.and_then(|id| Uuid::parse_str(&id).ok());
+            cluster::publish(Signal::Pong {
+                device_id: uuid,
+                ping_id,
+            });
+        }
+        DeviceMessage::ConfigUpdated => {
+            if let Some(command_id) = command_id {
+                twin::acknowledge(db_pool, uuid, &command_id).await;
+            }
+        }
+        ref failure @ (DeviceMessage::InvalidConfigKey
+        | DeviceMessage::InvalidUpdateConfigFormat
+        | DeviceMessage::InvalidUpdateConfigValue
+        | DeviceMessage::UpdateConfigFailed
+        | DeviceMessage::CommitConfigFailed) => {
+            if let Some(command_id) = command_id {
+                twin::reject(db_pool, uuid, &command_id, failure.name()).await;
+            }
+        }
+        ref report @ (DeviceMessage::OtaProgress(_)
+        | DeviceMessage::OtaInstalled
+        | DeviceMessage::OtaFailed(_)) => {
+            if let Some(command_id) = command_id
+                && let Err(e) = ota::handle_report(db_pool, uuid, &command_id, report).await
+            {
+                println!(
+                    "DEBUG: Failed to record firmware update of device {}: {}",
+                    uuid, e
+                );
+            }
+        }
+        DeviceMessage::EnteringPairingMode => {
+            super::pairing::handle_entering_pairing_mode(db_pool, uuid).await;
+        }
+        DeviceMessage::PowerOn | DeviceMessage::Restarting => {
+            // A power-on or restart acknowledges a pending REBOOT
+            let _ = command::acknowledge_oldest(db_pool, uuid, "REBOOT").await;
+        }
+        DeviceMessage::Connected => {
+            // Resend queued commands and pending config without blocking the event loop
+            let db_pool = db_pool.clone();
+            let mqtt_client = mqtt_client.clone();
+            tokio::spawn(async move {
+                let _ = command::retry_pending(&db_pool, &mqtt_client, Some(uuid)).await;
+                let _ = twin::reconcile(&db_pool, &mqtt_client, Some(uuid)).await;
+                if let Err(e) = channel::resume_rekey(&db_pool, &mqtt_client, uuid).await {
+                    println!("DEBUG: Failed to request key of device {}: {}", uuid, e);
+                }
+            });
+        }
+        DeviceMessage::KeyOffer(public_key) => {
+            if let Err(e) = channel::handle_key_offer(db_pool, mqtt_client, uuid, &public_key).await
+            {
+                println!("DEBUG: Failed to replace key of device {}: {}", uuid, e);
+            }
+        }
+        DeviceMessage::LockingDown => {
+            let _ = command::acknowledge_oldest(db_pool, uuid, "LOCKDOWN").await;
+            // LOCKING_DOWN event - set locked_down_at
+            let timestamp = device_time(timestamp);
+            let result = sqlx::query("UPDATE devices SET locked_down_at = $1 WHERE uuid = $2")
+                .bind(timestamp)
+                .bind(uuid)
+                .execute(db_pool)
+                .await;
+            if result.is_ok() {
+                // Broadcast device update to owner, members and invited users
+                let update = serde_json::json!({
+                    "type": "device_update",
+                    "device_id": uuid_str,
+                    "lock_state": "LOCKED",
+                    "locked_down_at": timestamp.timestamp_millis()
+                })
+                .to_string();
+                broadcast_to_viewers(db_pool, uuid, &update).await;
+            }
+        }
+        DeviceMessage::Unknown(event) => {
+            println!("DEBUG: Device {} reported unknown event {}", uuid, event);
+        }
+        other => {
+            println!("DEBUG: Device {} reported {}", uuid, other.name());
+        }
+    }
+}
 
-                            // Broadcast update to owner and invited users
-                            if let Some(user_broadcasts) = super::USER_BROADCASTS.get() {
-                                let update = serde_json::json!({
-                                    "type": "device_update",
-                                    "device_id": uuid_str,
-                                    "lock_state": lock_state,
-                                    "timestamp": timestamp.timestamp_millis(),
-                                    "locked_down_at": locked_down_at
-                                })
-                                .to_string();
-
-                                // Get owner
-                                let owner_row: Option<(String,)> =
-                                    sqlx::query_as("SELECT user_id FROM devices WHERE uuid = $1")
-                                        .bind(uuid)
-                                        .fetch_optional(db_pool)
-                                        .await
-                                        .unwrap_or(None);
-
-                                let mut recipients = Vec::new();
-                                if let Some((owner_id,)) = owner_row {
-                                    recipients.push(owner_id);
-                                }
+/// Trata um heartbeat: atualiza o estado do dispositivo, encerra o bloqueio após ao
+/// menos 10 segundos e trata códigos de pareamento apresentados.
+async fn handle_heartbeat(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    uuid: Uuid,
+    heartbeat_msg: Heartbeat,
+) {
+    let now = Utc::now();
+    let lock_state = heartbeat_msg.lock_state.as_deref().unwrap_or("UNKNOWN");
+
+    // Check if device is in lockdown and heartbeat is at least 10 seconds after lockdown
+    let should_clear_lockdown = {
+        let row: Option<(Option<chrono::DateTime<chrono::Utc>>,)> =
+            sqlx::query_as("SELECT locked_down_at FROM devices WHERE uuid = $1")
+                .bind(uuid)
+                .fetch_optional(db_pool)
+                .await
+                .unwrap_or(None);
+
+        if let Some((Some(locked_down_at),)) = row {
+            let duration_since_lockdown = now - locked_down_at;
+            duration_since_lockdown.num_seconds() >= 10
+        } else {
+            false
+        }
+    };
+
+    // Fields missing from the heartbeat keep their previous values
+    let update_query = if should_clear_lockdown {
+        "INSERT INTO devices (uuid, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, voice_detection_enable, vad_rms_threshold, firmware_version, hashed_passphrase, locked_down_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, NULL, NULL)
+              ON CONFLICT (uuid) DO UPDATE SET last_heard = $2, uptime_ms = $3, wifi_ssid = COALESCE($4, devices.wifi_ssid), backend_url = COALESCE($5, devices.backend_url), mqtt_broker_url = COALESCE($6, devices.mqtt_broker_url), mqtt_heartbeat_enable = COALESCE($7, devices.mqtt_heartbeat_enable), mqtt_heartbeat_interval_sec = COALESCE($8, devices.mqtt_heartbeat_interval_sec), audio_record_timeout_sec = COALESCE($9, devices.audio_record_timeout_sec), lock_timeout_ms = COALESCE($10, devices.lock_timeout_ms), pairing_timeout_sec = COALESCE($11, devices.pairing_timeout_sec), lock_state = $12, voice_detection_enable = COALESCE($13, devices.voice_detection_enable), vad_rms_threshold = COALESCE($14, devices.vad_rms_threshold), firmware_version = COALESCE($15, devices.firmware_version), locked_down_at = NULL"
+    } else {
+        "INSERT INTO devices (uuid, last_heard, uptime_ms, wifi_ssid, backend_url, mqtt_broker_url, mqtt_heartbeat_enable, mqtt_heartbeat_interval_sec, audio_record_timeout_sec, lock_timeout_ms, pairing_timeout_sec, lock_state, voice_detection_enable, vad_rms_threshold, firmware_version, hashed_passphrase) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, NULL)
+              ON CONFLICT (uuid) DO UPDATE SET last_heard = $2, uptime_ms = $3, wifi_ssid = COALESCE($4, devices.wifi_ssid), backend_url = COALESCE($5, devices.backend_url), mqtt_broker_url = COALESCE($6, devices.mqtt_broker_url), mqtt_heartbeat_enable = COALESCE($7, devices.mqtt_heartbeat_enable), mqtt_heartbeat_interval_sec = COALESCE($8, devices.mqtt_heartbeat_interval_sec), audio_record_timeout_sec = COALESCE($9, devices.audio_record_timeout_sec), lock_timeout_ms = COALESCE($10, devices.lock_timeout_ms), pairing_timeout_sec = COALESCE($11, devices.pairing_timeout_sec), lock_state = $12, voice_detection_enable = COALESCE($13, devices.voice_detection_enable), vad_rms_threshold = COALESCE($14, devices.vad_rms_threshold), firmware_version = COALESCE($15, devices.firmware_version)"
+    };
+
+    let _ = sqlx::query(update_query)
+        .bind(uuid)
+        .bind(now)
+        .bind(heartbeat_msg.uptime_ms as i64)
+        .bind(&heartbeat_msg.wifi_ssid)
+        .bind(&heartbeat_msg.backend_url)
+        .bind(&heartbeat_msg.mqtt_broker_url)
+        .bind(heartbeat_msg.mqtt_heartbeat_enable)
+        .bind(heartbeat_msg.mqtt_heartbeat_interval_sec)
+        .bind(heartbeat_msg.audio_record_timeout_sec)
+        .bind(heartbeat_msg.lock_timeout_ms)
+        .bind(heartbeat_msg.pairing_timeout_sec)
+        .bind(lock_state)
+        .bind(heartbeat_msg.voice_detection_enable)
+        .bind(heartbeat_msg.vad_rms_threshold)
+        .bind(&heartbeat_msg.fw_version)
+        .execute(db_pool)
+        .await;
+
+    // The owner is only set through pairing; a device in
+    // pairing presents its code in place of the user ID
+    if let Some(code) = heartbeat_msg
+        .user_id
+        .as_deref()
+        .filter(|id| id.starts_with(PAIRING_CODE_PREFIX))
+    {
+        super::pairing::handle_presented_code(
+            db_pool,
+            mqtt_client,
+            uuid,
+            code,
+            heartbeat_msg.pub_key.as_deref(),
+        )
+        .await;
+    }
 
-                                // Get invited users with status = 1
-                                let invited_rows: Vec<(String,)> = sqlx::query_as(
-                                     "SELECT receiver_id FROM invites WHERE device_id = $1 AND status = 1"
-                                 )
-                                 .bind(uuid)
-                                 .fetch_all(db_pool)
-                                 .await
-                                 .unwrap_or_default();
-
-                                for (receiver_id,) in invited_rows {
-                                    recipients.push(receiver_id);
-                                }
+    // Broadcast device online update to owner, members and invited users
+    let update = serde_json::json!({
+        "type": "device_online",
+        "device_id": uuid.to_string(),
+        "last_heard": now.timestamp_millis(),
+        "lock_state": lock_state,
+        "locked_down_at": null
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, uuid, &update).await;
+}
 
-                                // Send to each recipient
-                                let broadcasts = user_broadcasts.lock().unwrap();
-                                for recipient in recipients {
-                                    if let Some(tx) = broadcasts.get(&recipient) {
-                                        let _ = tx.send(update.clone());
-                                    }
-                                }
-                            }
-                        }
+/// Trata uma mudança de estado da fechadura: registra o log, atribuído ao autor do
+/// comando ecoado ou da última verificação de voz, e avisa os usuários.
+async fn handle_lock_event(db_pool: &PgPool, uuid: Uuid, timestamp: u64, lock_msg: LockEvent) {
+    let uuid_str = uuid.to_string();
+    let event_type = if lock_msg.lock == "LOCKED" {
+        "LOCK"
+    } else {
+        "UNLOCK"
+    };
+    let reason = &lock_msg.reason;
+    let timestamp = device_time(timestamp);
+
+    // Attribute the event to the command it echoes; voice
+    // unlocks go to the user matched by the last verification
+    let user_id = if let Some(ref command_id) = lock_msg.command_id {
+        super::command::acknowledge(db_pool, uuid, command_id, &lock_msg.lock)
+            .await
+            .unwrap_or(None)
+    } else if lock_msg.reason == "VOICE" {
+        let recent = super::RECENT_VOICE_MATCHES
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .remove(&uuid_str);
+        // Other instances hold a copy of the match as well
+        cluster::publish(Signal::VoiceMatchConsumed { device_id: uuid });
+        match recent {
+            Some((uid, match_time, via_invite)) if Utc::now().timestamp() - match_time < 5 => {
+                // The unlock reached the device, so it uses up the invite
+                if via_invite && event_type == "UNLOCK" {
+                    match super::invite::consume_use(db_pool, uuid, &uid).await {
+                        Ok(true) => {}
+                        Ok(false) => println!(
+                            "DEBUG: Invite of user {} had no uses left for voice unlock",
+                            uid
+                        ),
+                        Err(e) => println!("DEBUG: Failed to consume invite use: {}", e),
                     }
                 }
+                Some(uid)
             }
-            Ok(_) => {}
-            Err(_) => {}
+            _ => None,
         }
-    }
+    } else {
+        None
+    };
+
+    // Insert log
+    let _ = sqlx::query(
+        "INSERT INTO logs (device_id, timestamp, event_type, reason, user_id) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(&uuid_str)
+    .bind(timestamp)
+    .bind(event_type)
+    .bind(reason)
+    .bind(&user_id)
+    .execute(db_pool)
+    .await;
+
+    // Broadcast log update to users who can read logs
+    // Get user name if user_id is present
+    let user_name = if let Some(ref uid) = user_id {
+        let row: Option<(String,)> =
+            sqlx::query_as("SELECT name FROM users WHERE firebase_uid = $1")
+                .bind(uid)
+                .fetch_optional(db_pool)
+                .await
+                .unwrap_or(None);
+        row.map(|(name,)| name)
+    } else {
+        None
+    };
+
+    let log_update = serde_json::json!({
+        "type": "log_update",
+        "device_id": uuid_str,
+        "timestamp": timestamp.timestamp_millis(),
+        "event_type": event_type,
+        "reason": reason,
+        "user_id": user_id,
+        "user_name": user_name
+    })
+    .to_string();
+
+    // Get everyone who can read the logs
+    let recipients = super::member::users_with_permission(db_pool, uuid, Action::ViewLogs)
+        .await
+        .unwrap_or_default();
+    notify_users(&recipients, &log_update);
+
+    // Update lock_state
+    let lock_state = if lock_msg.lock == "LOCKED" {
+        "LOCKED"
+    } else {
+        "UNLOCKED"
+    };
+    let _ = sqlx::query("UPDATE devices SET lock_state = $1 WHERE uuid = $2")
+        .bind(lock_state)
+        .bind(uuid)
+        .execute(db_pool)
+        .await;
+
+    // Get locked_down_at
+    let locked_down_at: Option<i64> = {
+        let row: Option<(Option<chrono::DateTime<Utc>>,)> =
+            sqlx::query_as("SELECT locked_down_at FROM devices WHERE uuid = $1")
+                .bind(uuid)
+                .fetch_optional(db_pool)
+                .await
+                .unwrap_or(None);
+        row.and_then(|(dt,)| dt.map(|d| d.timestamp_millis()))
+    };
+
+    // Broadcast update to owner, members and invited users
+    let update = serde_json::json!({
+        "type": "device_update",
+        "device_id": uuid_str,
+        "lock_state": lock_state,
+        "timestamp": timestamp.timestamp_millis(),
+        "locked_down_at": locked_down_at
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, uuid, &update).await;
+}
+
+/// Envia uma atualização via WebSocket a todos que podem ver o dispositivo.
+pub async fn broadcast_to_viewers(db_pool: &PgPool, uuid: Uuid, update: &str) {
+    // Get everyone who can view the device
+    let recipients = super::member::users_with_permission(db_pool, uuid, Action::View)
+        .await
+        .unwrap_or_default();
+    notify_users(&recipients, update);
 }
 
 /// Publica uma mensagem de controle para um dispositivo via MQTT.
 /// Envia um comando para o UUID do dispositivo especificado.
 pub async fn publish_control_message(
+    db_pool: &PgPool,
     client: &AsyncClient,
     uuid: Uuid,
     command: String,
 ) -> Result<()> {
+    publish_command(db_pool, client, uuid, command, None).await
+}
+
+/// Publica um comando para um dispositivo via MQTT, opcionalmente com um ID que o
+/// dispositivo ecoa no evento resultante. O comando é assinado com a chave do dispositivo.
+pub async fn publish_command(
+    db_pool: &PgPool,
+    client: &AsyncClient,
+    uuid: Uuid,
+    command: String,
+    command_id: Option<String>,
+) -> Result<()> {
+    if !is_connected() {
+        bail!("MQTT broker unavailable");
+    }
     let topic = format!("lockwise/{}/control", uuid);
-    let msg = ControlMessage { command };
-    let payload = serde_cbor::to_vec(&msg)?;
+    let msg = ControlMessage {
+        command,
+        command_id,
+    };
+    let payload = channel::control_payload(db_pool, uuid, &msg).await?;
     client
-        .publish(topic, QoS::AtMostOnce, false, payload)
+        .publish(topic, QoS::AtLeastOnce, false, payload)
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_timestamps_out_of_range_fall_back_to_now() {
+        assert_eq!(device_time(1_700_000_000).timestamp(), 1_700_000_000);
+        assert_eq!(device_time(0).timestamp(), 0);
+
+        for timestamp in [u64::MAX, i64::MAX as u64, 1 << 60] {
+            let before = Utc::now();
+            assert!(device_time(timestamp) >= before);
+        }
+    }
+}
@@ -0,0 +1,1217 @@
+//! Módulo de atualização de firmware pelo ar (OTA).
+//!
+//! Administradores de firmware (`FIRMWARE_ADMINS`) publicam versões em
+//! `POST /create_firmware_release`: a imagem ESP-IDF, cuja versão é lida do próprio
+//! binário, e a assinatura ECDSA P-256 do seu SHA-256, verificada com
+//! `FIRMWARE_SIGNING_PUBLIC_KEY` (a mesma chave gravada no firmware, que verifica a imagem
+//! de novo antes de instalá-la). Um rollout leva uma versão a um dispositivo, aos
+//! dispositivos de um perfil ou a uma porcentagem da frota (esta, só para
+//! administradores), com uma atualização por dispositivo.
+//!
+//! O orquestrador ([`orchestrate`]) roda periodicamente em uma instância por vez: envia o
+//! comando `OTA` por `lockwise/<id>/control` aos dispositivos online, no máximo
+//! [`MAX_IN_FLIGHT`] por rollout, e o dispositivo baixa a imagem de
+//! `GET /firmware/<release_id>/<device_id>` com seu token. O progresso chega nos eventos
+//! `OTA_PROGRESS`, `OTA_INSTALLED` e `OTA_FAILED`, e a atualização só é concluída quando
+//! um heartbeat informa a nova versão; se o dispositivo reiniciar na versão anterior, o
+//! bootloader a reverteu (`ROLLED_BACK`). Quando as falhas passam de
+//! `max_failure_percent` das atualizações já enviadas (contando ao menos
+//! [`MIN_FAILURE_SAMPLE`]), o rollout é interrompido (`HALTED`) até ser retomado ou
+//! cancelado.
+use anyhow::{Context, Result};
+use argon2::{Argon2, PasswordVerifier, password_hash::PasswordHash};
+use chrono::{DateTime, Utc};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::{State, get, post};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::env;
+use uuid::Uuid;
+
+use super::channel;
+use super::device::DeviceToken;
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{Action, authorize};
+use super::mqtt::{self, broadcast_to_viewers};
+use super::notify_users;
+use super::protocol::DeviceMessage;
+use super::session::AuthUser;
+
+/// Número máximo de atualizações em andamento por rollout.
+const MAX_IN_FLIGHT: i64 = 5;
+/// Tempo sem notícias do dispositivo após o qual uma atualização falha, em minutos.
+const UPDATE_TIMEOUT_MIN: i32 = 10;
+/// Porcentagem de falhas padrão a partir da qual um rollout é interrompido.
+const DEFAULT_MAX_FAILURE_PERCENT: i16 = 10;
+/// Número máximo de rollouts retornados por consulta.
+const MAX_ROLLOUTS_PER_PAGE: i64 = 100;
+/// Tamanho máximo de uma imagem de firmware (uma partição OTA do dispositivo).
+const MAX_IMAGE_SIZE: usize = 0x1B0000;
+/// Primeiro byte de uma imagem ESP-IDF.
+const IMAGE_MAGIC: u8 = 0xE9;
+/// Posição de `esp_app_desc_t` na imagem, após os cabeçalhos da imagem e do primeiro
+/// segmento.
+const APP_DESC_OFFSET: usize = 32;
+/// Valor de `esp_app_desc_t.magic_word`.
+const APP_DESC_MAGIC: u32 = 0xABCD5432;
+/// Número mínimo de atualizações sobre o qual a taxa de falhas de um rollout é
+/// calculada, para que as primeiras falhas não o interrompam sozinhas.
+const MIN_FAILURE_SAMPLE: i64 = 20;
+/// Estados de uma atualização em andamento no dispositivo.
+const IN_FLIGHT: &[&str] = &["SENT", "DOWNLOADING", "INSTALLED"];
+
+/// Configuração das atualizações de firmware, lida do ambiente.
+pub struct FirmwareConfig {
+    /// Usuários que podem publicar versões e criar rollouts por porcentagem
+    /// (`FIRMWARE_ADMINS`, separados por vírgula).
+    admins: Vec<String>,
+    /// Chave pública que assina as imagens (`FIRMWARE_SIGNING_PUBLIC_KEY`, ponto SEC1 em
+    /// hexadecimal). Sem ela, novas versões são recusadas.
+    signing_key: Option<VerifyingKey>,
+}
+
+impl FirmwareConfig {
+    /// Lê a configuração das variáveis de ambiente.
+    pub fn from_env() -> Result<Self> {
+        let admins = env::var("FIRMWARE_ADMINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|uid| !uid.is_empty())
+            .map(String::from)
+            .collect();
+        let signing_key = env::var("FIRMWARE_SIGNING_PUBLIC_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| {
+                let bytes = hex::decode(key.trim())
+                    .context("FIRMWARE_SIGNING_PUBLIC_KEY is not hexadecimal")?;
+                VerifyingKey::from_sec1_bytes(&bytes).context("invalid FIRMWARE_SIGNING_PUBLIC_KEY")
+            })
+            .transpose()?;
+        Ok(FirmwareConfig {
+            admins,
+            signing_key,
+        })
+    }
+
+    /// Indica se o usuário é administrador de firmware.
+    fn is_admin(&self, user_id: &str) -> bool {
+        self.admins.iter().any(|admin| admin == user_id)
+    }
+}
+
+/// Configuração das atualizações de firmware desta instância.
+fn firmware() -> &'static FirmwareConfig {
+    super::FIRMWARE.get().expect("FIRMWARE not set")
+}
+
+/// Estrutura de requisição para criar um rollout. Exatamente um alvo deve ser informado.
+#[derive(Deserialize)]
+pub struct RolloutRequest {
+    /// Versão a instalar.
+    release_id: Uuid,
+    /// Alvo: um dispositivo.
+    #[serde(default)]
+    device_id: Option<Uuid>,
+    /// Alvo: os dispositivos atribuídos a um perfil do usuário.
+    #[serde(default)]
+    profile_id: Option<Uuid>,
+    /// Alvo: uma porcentagem (1 a 100) dos dispositivos pareados.
+    #[serde(default)]
+    percentage: Option<i16>,
+    /// Porcentagem de falhas a partir da qual o rollout é interrompido.
+    #[serde(default = "default_max_failure_percent")]
+    max_failure_percent: i16,
+    /// Permite instalar uma versão mais antiga que a atual do dispositivo.
+    #[serde(default)]
+    allow_downgrade: bool,
+}
+
+/// Valor padrão de [`RolloutRequest::max_failure_percent`].
+fn default_max_failure_percent() -> i16 {
+    DEFAULT_MAX_FAILURE_PERCENT
+}
+
+/// Linha da tabela `ota_rollouts`, com a versão do firmware.
+#[derive(sqlx::FromRow)]
+struct RolloutRow {
+    /// ID do rollout.
+    id: Uuid,
+    /// ID da versão.
+    release_id: Uuid,
+    /// Versão do firmware.
+    version: String,
+    /// Tipo de alvo (DEVICE, PROFILE ou PERCENTAGE).
+    target: String,
+    /// Dispositivo alvo.
+    device_id: Option<Uuid>,
+    /// Perfil alvo.
+    profile_id: Option<Uuid>,
+    /// Porcentagem alvo.
+    percentage: Option<i16>,
+    /// Porcentagem de falhas a partir da qual o rollout é interrompido.
+    max_failure_percent: i16,
+    /// Estado (RUNNING, HALTED, COMPLETED ou CANCELLED).
+    status: String,
+    /// Motivo da interrupção, se houver.
+    halted_reason: Option<String>,
+    /// Usuário que criou o rollout.
+    created_by: String,
+    /// Momento da criação.
+    created_at: DateTime<Utc>,
+    /// Momento da última mudança de estado.
+    updated_at: DateTime<Utc>,
+}
+
+/// Atualização de um dispositivo, como retornada pela API.
+#[derive(Serialize, sqlx::FromRow)]
+struct UpdateInfo {
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// Estado (QUEUED, SENT, DOWNLOADING, INSTALLED, SUCCEEDED, FAILED ou CANCELLED).
+    status: String,
+    /// Porcentagem baixada.
+    progress: i16,
+    /// Motivo da falha, se houver.
+    error: Option<String>,
+    /// Versão em execução quando o comando foi enviado.
+    previous_version: Option<String>,
+    /// Momento do envio do comando.
+    sent_at: Option<DateTime<Utc>>,
+    /// Momento em que o dispositivo instalou a imagem.
+    installed_at: Option<DateTime<Utc>>,
+    /// Momento da última mudança de estado.
+    updated_at: DateTime<Utc>,
+}
+
+/// Versão de firmware, como retornada pela API.
+#[derive(Serialize, sqlx::FromRow)]
+struct ReleaseInfo {
+    /// ID da versão.
+    id: Uuid,
+    /// Versão do firmware.
+    version: String,
+    /// Tamanho da imagem em bytes.
+    size: i32,
+    /// SHA-256 da imagem, em hexadecimal.
+    sha256: String,
+    /// Momento da publicação.
+    created_at: DateTime<Utc>,
+}
+
+/// Resumo de um rollout, como retornado na listagem.
+#[derive(Serialize, sqlx::FromRow)]
+struct RolloutSummary {
+    /// ID do rollout.
+    id: Uuid,
+    /// Versão do firmware.
+    version: String,
+    /// Tipo de alvo (DEVICE, PROFILE ou PERCENTAGE).
+    target: String,
+    /// Estado (RUNNING, HALTED, COMPLETED ou CANCELLED).
+    status: String,
+    /// Motivo da interrupção, se houver.
+    halted_reason: Option<String>,
+    /// Momento da criação.
+    created_at: DateTime<Utc>,
+    /// Total de dispositivos.
+    devices: i64,
+    /// Atualizações concluídas.
+    succeeded: i64,
+    /// Atualizações com falha.
+    failed: i64,
+}
+
+/// Rollout em execução, com a versão a enviar e a contagem das atualizações.
+#[derive(sqlx::FromRow)]
+struct RunningRollout {
+    /// ID do rollout.
+    id: Uuid,
+    /// ID da versão.
+    release_id: Uuid,
+    /// Versão do firmware.
+    version: String,
+    /// Tamanho da imagem em bytes.
+    size: i32,
+    /// SHA-256 da imagem.
+    sha256: Vec<u8>,
+    /// Assinatura da imagem (r || s).
+    signature: Vec<u8>,
+    /// Tipo de alvo (DEVICE, PROFILE ou PERCENTAGE).
+    target: String,
+    /// Usuário que criou o rollout.
+    created_by: String,
+    /// Porcentagem de falhas a partir da qual o rollout é interrompido.
+    max_failure_percent: i16,
+    /// Total de atualizações.
+    total: i64,
+    /// Atualizações aguardando envio.
+    queued: i64,
+    /// Atualizações já enviadas (em andamento ou concluídas).
+    attempted: i64,
+    /// Atualizações em andamento.
+    in_flight: i64,
+    /// Atualizações com falha.
+    failed: i64,
+}
+
+impl RunningRollout {
+    /// Indica se as falhas passaram de `max_failure_percent` das atualizações já
+    /// enviadas, contando ao menos [`MIN_FAILURE_SAMPLE`] delas (ou todas, em rollouts
+    /// menores).
+    fn exceeds_failure_limit(&self) -> bool {
+        let sample = self.attempted.max(MIN_FAILURE_SAMPLE.min(self.total));
+        self.failed * 100 > i64::from(self.max_failure_percent) * sample
+    }
+}
+
+/// Lê a versão de `esp_app_desc_t` de uma imagem ESP-IDF.
+fn app_version(image: &[u8]) -> Option<String> {
+    if image.first() != Some(&IMAGE_MAGIC) {
+        return None;
+    }
+    let desc = image.get(APP_DESC_OFFSET..APP_DESC_OFFSET + 48)?;
+    if u32::from_le_bytes(desc[..4].try_into().ok()?) != APP_DESC_MAGIC {
+        return None;
+    }
+    // magic_word, secure_version and two reserved words precede the version
+    let version = &desc[16..];
+    let end = version
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(version.len());
+    let version = std::str::from_utf8(&version[..end]).ok()?.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// Converte uma versão como `v1.2.3-rc1` nos seus componentes numéricos (`[1, 2, 3]`),
+/// sem zeros à direita, ou `None` se ela não seguir esse formato.
+fn version_parts(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    while parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(parts)
+}
+
+/// Indica se instalar `target` num dispositivo com `current` seria um downgrade. Versões
+/// fora do formato numérico não são comparadas.
+fn is_downgrade(current: Option<&str>, target: &str) -> bool {
+    match (current.and_then(version_parts), version_parts(target)) {
+        (Some(current), Some(target)) => target < current,
+        _ => false,
+    }
+}
+
+/// Recupera um rollout visível ao usuário (criado por ele, ou qualquer um para
+/// administradores de firmware), ou `404`.
+async fn load(db_pool: &PgPool, rollout_id: Uuid, user_id: &str) -> Result<RolloutRow, Status> {
+    sqlx::query_as(
+        "SELECT r.id, r.release_id, f.version, r.target, r.device_id, r.profile_id, r.percentage,
+                r.max_failure_percent, r.status, r.halted_reason, r.created_by, r.created_at, r.updated_at
+         FROM ota_rollouts r JOIN firmware_releases f ON f.id = r.release_id
+         WHERE r.id = $1 AND (r.created_by = $2 OR $3)",
+    )
+    .bind(rollout_id)
+    .bind(user_id)
+    .bind(firmware().is_admin(user_id))
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)
+}
+
+/// Monta o documento de um rollout, com a atualização de cada dispositivo.
+async fn document(db_pool: &PgPool, rollout: RolloutRow) -> Result<String> {
+    let updates: Vec<UpdateInfo> = sqlx::query_as(
+        "SELECT device_id, status, progress, error, previous_version, sent_at, installed_at, updated_at
+         FROM ota_updates WHERE rollout_id = $1 ORDER BY device_id",
+    )
+    .bind(rollout.id)
+    .fetch_all(db_pool)
+    .await?;
+    let count = |statuses: &[&str]| {
+        updates
+            .iter()
+            .filter(|u| statuses.contains(&u.status.as_str()))
+            .count()
+    };
+    let summary = serde_json::json!({
+        "devices": updates.len(),
+        "queued": count(&["QUEUED"]),
+        "in_progress": count(IN_FLIGHT),
+        "succeeded": count(&["SUCCEEDED"]),
+        "failed": count(&["FAILED"]),
+        "cancelled": count(&["CANCELLED"])
+    });
+
+    Ok(serde_json::json!({
+        "id": rollout.id.to_string(),
+        "release_id": rollout.release_id.to_string(),
+        "version": rollout.version,
+        "target": rollout.target,
+        "device_id": rollout.device_id,
+        "profile_id": rollout.profile_id,
+        "percentage": rollout.percentage,
+        "max_failure_percent": rollout.max_failure_percent,
+        "status": rollout.status,
+        "halted_reason": rollout.halted_reason,
+        "created_by": rollout.created_by,
+        "created_at": rollout.created_at,
+        "updated_at": rollout.updated_at,
+        "devices": updates,
+        "summary": summary
+    })
+    .to_string())
+}
+
+/// Avisa os usuários com acesso ao dispositivo sobre o estado da sua atualização.
+async fn notify_update(db_pool: &PgPool, rollout_id: Uuid, device_id: Uuid) {
+    let update: Option<(String, i16, Option<String>)> = sqlx::query_as(
+        "SELECT status, progress, error FROM ota_updates WHERE rollout_id = $1 AND device_id = $2",
+    )
+    .bind(rollout_id)
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await
+    .unwrap_or(None);
+    let Some((status, progress, error)) = update else {
+        return;
+    };
+    let update = serde_json::json!({
+        "type": "ota_status",
+        "device_id": device_id.to_string(),
+        "rollout_id": rollout_id.to_string(),
+        "status": status,
+        "progress": progress,
+        "error": error
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, device_id, &update).await;
+}
+
+/// Encerra ou interrompe um rollout em execução e avisa o usuário que o criou.
+async fn finish(
+    db_pool: &PgPool,
+    rollout: &RunningRollout,
+    status: &str,
+    reason: Option<&str>,
+) -> Result<()> {
+    let result = sqlx::query(
+        "UPDATE ota_rollouts SET status = $2, halted_reason = $3, updated_at = NOW()
+         WHERE id = $1 AND status = 'RUNNING'",
+    )
+    .bind(rollout.id)
+    .bind(status)
+    .bind(reason)
+    .execute(db_pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(());
+    }
+    println!(
+        "DEBUG: Firmware rollout {} of version {} is {} ({} of {} failed)",
+        rollout.id, rollout.version, status, rollout.failed, rollout.total
+    );
+
+    let update = serde_json::json!({
+        "type": "ota_rollout",
+        "rollout_id": rollout.id.to_string(),
+        "status": status,
+        "halted_reason": reason
+    })
+    .to_string();
+    notify_users(std::slice::from_ref(&rollout.created_by), &update);
+    Ok(())
+}
+
+/// Verifica se quem criou o rollout ainda pode atualizar o dispositivo (ex.: ele pode
+/// ter sido despareado ou transferido desde a criação). Rollouts por porcentagem valem
+/// para qualquer dispositivo pareado enquanto o criador for administrador de firmware.
+async fn still_authorized(db_pool: &PgPool, rollout: &RunningRollout, device_id: Uuid) -> bool {
+    if rollout.target == "PERCENTAGE" {
+        if !firmware().is_admin(&rollout.created_by) {
+            return false;
+        }
+        let paired: Option<bool> =
+            sqlx::query_scalar("SELECT user_id IS NOT NULL FROM devices WHERE uuid = $1")
+                .bind(device_id)
+                .fetch_optional(db_pool)
+                .await
+                .unwrap_or(None);
+        return paired == Some(true);
+    }
+    authorize(
+        db_pool,
+        device_id,
+        &rollout.created_by,
+        Action::UpdateFirmware,
+    )
+    .await
+    .is_ok()
+}
+
+/// Envia o comando `OTA` da versão do rollout a um dispositivo.
+async fn send_update(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    rollout: &RunningRollout,
+    device_id: Uuid,
+    previous_version: Option<String>,
+) -> Result<()> {
+    if previous_version.as_deref() == Some(rollout.version.as_str()) {
+        // Already running the release, e.g. updated by an earlier rollout
+        sqlx::query(
+            "UPDATE ota_updates SET status = 'SUCCEEDED', progress = 100, previous_version = $3, updated_at = NOW()
+             WHERE rollout_id = $1 AND device_id = $2 AND status = 'QUEUED'",
+        )
+        .bind(rollout.id)
+        .bind(device_id)
+        .bind(&previous_version)
+        .execute(db_pool)
+        .await?;
+        notify_update(db_pool, rollout.id, device_id).await;
+        return Ok(());
+    }
+
+    // Mark the update as sent first, so that a fast reply finds its command ID
+    let command_id = Uuid::new_v4();
+    sqlx::query(
+        "UPDATE ota_updates SET status = 'SENT', progress = 0, command_id = $3, previous_version = $4,
+         sent_at = NOW(), updated_at = NOW()
+         WHERE rollout_id = $1 AND device_id = $2 AND status = 'QUEUED'",
+    )
+    .bind(rollout.id)
+    .bind(device_id)
+    .bind(command_id)
+    .bind(&previous_version)
+    .execute(db_pool)
+    .await?;
+
+    let text = |s: &str| Value::Text(s.to_string());
+    let message = Value::Map(BTreeMap::from([
+        (text("command"), text("OTA")),
+        (text("command_id"), text(&command_id.to_string())),
+        (text("release_id"), text(&rollout.release_id.to_string())),
+        (text("version"), text(&rollout.version)),
+        (text("size"), Value::Integer(rollout.size.into())),
+        (text("sha256"), Value::Bytes(rollout.sha256.clone())),
+        (text("signature"), Value::Bytes(rollout.signature.clone())),
+    ]));
+    let topic = format!("lockwise/{}/control", device_id);
+    let sent = match channel::control_payload(db_pool, device_id, &message).await {
+        Ok(payload) => mqtt_client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        // Try again on the next run
+        sqlx::query(
+            "UPDATE ota_updates SET status = 'QUEUED', command_id = NULL, sent_at = NULL
+             WHERE rollout_id = $1 AND device_id = $2 AND command_id = $3",
+        )
+        .bind(rollout.id)
+        .bind(device_id)
+        .bind(command_id)
+        .execute(db_pool)
+        .await?;
+        return Err(e);
+    }
+
+    println!(
+        "DEBUG: Sent firmware {} to device {} (rollout {})",
+        rollout.version, device_id, rollout.id
+    );
+    record_backend_event(
+        db_pool,
+        device_id,
+        BackendEvent::CommandSent,
+        Some(&rollout.created_by),
+        serde_json::json!({
+            "command": "OTA",
+            "command_id": command_id.to_string(),
+            "rollout_id": rollout.id.to_string(),
+            "version": rollout.version
+        }),
+    )
+    .await;
+    notify_update(db_pool, rollout.id, device_id).await;
+    Ok(())
+}
+
+/// Avança os rollouts em execução: falha as atualizações sem notícias há mais de
+/// [`UPDATE_TIMEOUT_MIN`] minutos, interrompe os rollouts que passaram do limite de
+/// falhas, encerra os concluídos e envia o comando `OTA` aos próximos dispositivos
+/// online, até [`MAX_IN_FLIGHT`] atualizações em andamento por rollout.
+pub async fn orchestrate(db_pool: &PgPool, mqtt_client: &AsyncClient) -> Result<()> {
+    let timed_out: Vec<(Uuid, Uuid)> = sqlx::query_as(
+        "UPDATE ota_updates SET status = 'FAILED', error = 'TIMEOUT', updated_at = NOW()
+         WHERE status = ANY($1) AND updated_at < NOW() - make_interval(mins => $2)
+         RETURNING rollout_id, device_id",
+    )
+    .bind(IN_FLIGHT)
+    .bind(UPDATE_TIMEOUT_MIN)
+    .fetch_all(db_pool)
+    .await?;
+    for (rollout_id, device_id) in timed_out {
+        println!("DEBUG: Firmware update of device {} timed out", device_id);
+        notify_update(db_pool, rollout_id, device_id).await;
+    }
+
+    let rollouts: Vec<RunningRollout> = sqlx::query_as(
+        "SELECT r.id, r.release_id, f.version, f.size, f.sha256, f.signature, r.target, r.created_by, r.max_failure_percent,
+                COUNT(u.device_id) AS total,
+                COUNT(u.device_id) FILTER (WHERE u.status = 'QUEUED') AS queued,
+                COUNT(u.device_id) FILTER (WHERE u.status NOT IN ('QUEUED', 'CANCELLED')) AS attempted,
+                COUNT(u.device_id) FILTER (WHERE u.status = ANY($1)) AS in_flight,
+                COUNT(u.device_id) FILTER (WHERE u.status = 'FAILED') AS failed
+         FROM ota_rollouts r JOIN firmware_releases f ON f.id = r.release_id
+         LEFT JOIN ota_updates u ON u.rollout_id = r.id
+         WHERE r.status = 'RUNNING' GROUP BY r.id, f.id ORDER BY r.created_at",
+    )
+    .bind(IN_FLIGHT)
+    .fetch_all(db_pool)
+    .await?;
+
+    for rollout in rollouts {
+        if rollout.exceeds_failure_limit() {
+            finish(db_pool, &rollout, "HALTED", Some("FAILURE_THRESHOLD")).await?;
+            continue;
+        }
+        if rollout.queued == 0 && rollout.in_flight == 0 {
+            finish(db_pool, &rollout, "COMPLETED", None).await?;
+            continue;
+        }
+        let slots = MAX_IN_FLIGHT - rollout.in_flight;
+        if slots <= 0 || !mqtt::is_connected() {
+            continue;
+        }
+
+        let next: Vec<(Uuid, Option<String>)> = sqlx::query_as(
+            "SELECT u.device_id, d.firmware_version FROM ota_updates u JOIN devices d ON d.uuid = u.device_id
+             WHERE u.rollout_id = $1 AND u.status = 'QUEUED' AND d.online
+             ORDER BY u.device_id LIMIT $2",
+        )
+        .bind(rollout.id)
+        .bind(slots)
+        .fetch_all(db_pool)
+        .await?;
+        for (device_id, previous_version) in next {
+            if !still_authorized(db_pool, &rollout, device_id).await {
+                println!(
+                    "DEBUG: Cancelled firmware update of device {}: {} may no longer update it",
+                    device_id, rollout.created_by
+                );
+                sqlx::query(
+                    "UPDATE ota_updates SET status = 'CANCELLED', error = 'FORBIDDEN', updated_at = NOW()
+                     WHERE rollout_id = $1 AND device_id = $2 AND status = 'QUEUED'",
+                )
+                .bind(rollout.id)
+                .bind(device_id)
+                .execute(db_pool)
+                .await?;
+                notify_update(db_pool, rollout.id, device_id).await;
+                continue;
+            }
+            if let Err(e) =
+                send_update(db_pool, mqtt_client, &rollout, device_id, previous_version).await
+            {
+                println!(
+                    "DEBUG: Failed to send firmware to device {}: {}",
+                    device_id, e
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registra um evento de atualização de firmware (`OTA_PROGRESS`, `OTA_INSTALLED` ou
+/// `OTA_FAILED`) na atualização do comando ecoado.
+pub async fn handle_report(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    command_id: &str,
+    report: &DeviceMessage,
+) -> Result<()> {
+    let Ok(command_id) = Uuid::parse_str(command_id) else {
+        return Ok(());
+    };
+    let (status, progress, error, from): (&str, Option<i16>, Option<String>, &[&str]) = match report
+    {
+        DeviceMessage::OtaProgress(progress) => (
+            "DOWNLOADING",
+            Some(i16::from(*progress)),
+            None,
+            &["SENT", "DOWNLOADING"],
+        ),
+        DeviceMessage::OtaInstalled => ("INSTALLED", Some(100), None, &["SENT", "DOWNLOADING"]),
+        DeviceMessage::OtaFailed(error) => (
+            "FAILED",
+            None,
+            Some(error.chars().take(64).collect()),
+            IN_FLIGHT,
+        ),
+        _ => return Ok(()),
+    };
+
+    let rollout_id: Option<Uuid> = sqlx::query_scalar(
+        "UPDATE ota_updates SET status = $3, progress = COALESCE($4, progress), error = $5,
+         installed_at = CASE WHEN $3 = 'INSTALLED' THEN NOW() ELSE installed_at END, updated_at = NOW()
+         WHERE command_id = $1 AND device_id = $2 AND status = ANY($6) RETURNING rollout_id",
+    )
+    .bind(command_id)
+    .bind(device_id)
+    .bind(status)
+    .bind(progress)
+    .bind(&error)
+    .bind(from)
+    .fetch_optional(db_pool)
+    .await?;
+    if let Some(rollout_id) = rollout_id {
+        if let Some(error) = error {
+            println!(
+                "DEBUG: Firmware update of device {} failed: {}",
+                device_id, error
+            );
+        }
+        notify_update(db_pool, rollout_id, device_id).await;
+    }
+    Ok(())
+}
+
+/// Confere a versão informada no heartbeat com a atualização em andamento do
+/// dispositivo: a versão do rollout a conclui; a versão anterior depois de o dispositivo
+/// reiniciar após a instalação indica que o bootloader reverteu a atualização.
+pub async fn observe(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    firmware_version: &str,
+    uptime_ms: u64,
+) -> Result<()> {
+    let active: Option<(Uuid, String, String, Option<DateTime<Utc>>)> = sqlx::query_as(
+        "SELECT u.rollout_id, u.status, f.version, u.installed_at FROM ota_updates u
+         JOIN ota_rollouts r ON r.id = u.rollout_id JOIN firmware_releases f ON f.id = r.release_id
+         WHERE u.device_id = $1 AND u.status = ANY($2) LIMIT 1",
+    )
+    .bind(device_id)
+    .bind(IN_FLIGHT)
+    .fetch_optional(db_pool)
+    .await?;
+    let Some((rollout_id, status, version, installed_at)) = active else {
+        return Ok(());
+    };
+
+    let (status, error) = if firmware_version == version {
+        ("SUCCEEDED", None)
+    } else if status == "INSTALLED"
+        && installed_at.is_some_and(|installed_at| {
+            Utc::now() - installed_at > chrono::Duration::milliseconds(uptime_ms as i64)
+        })
+    {
+        // Restarted since the install, but still on the old version
+        ("FAILED", Some("ROLLED_BACK"))
+    } else {
+        return Ok(());
+    };
+    sqlx::query(
+        "UPDATE ota_updates SET status = $3, error = $4, updated_at = NOW()
+         WHERE rollout_id = $1 AND device_id = $2",
+    )
+    .bind(rollout_id)
+    .bind(device_id)
+    .bind(status)
+    .bind(error)
+    .execute(db_pool)
+    .await?;
+    println!(
+        "DEBUG: Firmware update of device {} to {}: {}",
+        device_id, version, status
+    );
+    notify_update(db_pool, rollout_id, device_id).await;
+    Ok(())
+}
+
+/// Publica uma versão de firmware. O corpo é a imagem ESP-IDF, cuja versão é lida de
+/// `esp_app_desc_t`; `signature` é a assinatura ECDSA P-256 da imagem (SHA-256), em
+/// hexadecimal, nos formatos r || s ou DER. Apenas administradores de firmware.
+#[post("/create_firmware_release?<signature>", data = "<image>")]
+pub async fn create_release(
+    user: AuthUser,
+    signature: &str,
+    image: Data<'_>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    if !firmware().is_admin(&user.firebase_uid) {
+        return Err(Status::Forbidden);
+    }
+    let Some(signing_key) = &firmware().signing_key else {
+        return Err(Status::ServiceUnavailable);
+    };
+
+    let image = image
+        .open(MAX_IMAGE_SIZE.bytes())
+        .into_bytes()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if !image.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    let image = image.into_inner();
+    let version = app_version(&image).ok_or(Status::BadRequest)?;
+    if version.len() > 32 {
+        return Err(Status::BadRequest);
+    }
+
+    let signature = hex::decode(signature.trim()).map_err(|_| Status::BadRequest)?;
+    let signature = Signature::from_slice(&signature)
+        .or_else(|_| Signature::from_der(&signature))
+        .map_err(|_| Status::BadRequest)?;
+    if signing_key.verify(&image, &signature).is_err() {
+        println!(
+            "DEBUG: Rejected firmware {} with an invalid signature",
+            version
+        );
+        return Err(Status::BadRequest);
+    }
+
+    let release_id = Uuid::new_v4();
+    let sha256 = Sha256::digest(&image).to_vec();
+    let result = sqlx::query(
+        "INSERT INTO firmware_releases (id, version, image, size, sha256, signature, uploaded_by)
+         VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (version) DO NOTHING",
+    )
+    .bind(release_id)
+    .bind(&version)
+    .bind(&image)
+    .bind(image.len() as i32)
+    .bind(&sha256)
+    .bind(signature.to_bytes().to_vec())
+    .bind(&user.firebase_uid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::Conflict);
+    }
+    println!(
+        "DEBUG: Firmware {} published by {} ({} bytes)",
+        version,
+        user.firebase_uid,
+        image.len()
+    );
+
+    Ok(serde_json::json!({
+        "id": release_id.to_string(),
+        "version": version,
+        "size": image.len(),
+        "sha256": hex::encode(sha256)
+    })
+    .to_string())
+}
+
+/// Lista as versões de firmware publicadas, da mais recente à mais antiga.
+#[get("/firmware_releases")]
+pub async fn get_releases(_user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let releases: Vec<ReleaseInfo> = sqlx::query_as(
+        "SELECT id, version, size, encode(sha256, 'hex') AS sha256, created_at
+         FROM firmware_releases ORDER BY created_at DESC",
+    )
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::json!({ "releases": releases }).to_string())
+}
+
+/// Serve a imagem de uma versão ao dispositivo, autenticado com seu token, se houver um
+/// rollout dessa versão para ele.
+#[get("/firmware/<release_id>/<device_id>")]
+pub async fn download_firmware(
+    release_id: &str,
+    device_id: &str,
+    device_token: DeviceToken,
+    db_pool: &State<PgPool>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let release_id = Uuid::parse_str(release_id).map_err(|_| Status::BadRequest)?;
+    let device_id = Uuid::parse_str(device_id).map_err(|_| Status::BadRequest)?;
+
+    // Verify the bearer token against the hashed passphrase
+    let hashed_passphrase: Option<Option<String>> =
+        sqlx::query_scalar("SELECT hashed_passphrase FROM devices WHERE uuid = $1")
+            .bind(device_id)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    let Some(Some(hash)) = hashed_passphrase else {
+        return Err(Status::Unauthorized);
+    };
+    let parsed_hash = PasswordHash::new(&hash).map_err(|_| Status::InternalServerError)?;
+    if Argon2::default()
+        .verify_password(device_token.0.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Err(Status::Unauthorized);
+    }
+
+    let image: Option<Vec<u8>> = sqlx::query_scalar(
+        "SELECT f.image FROM firmware_releases f WHERE f.id = $1 AND EXISTS (
+             SELECT 1 FROM ota_updates u JOIN ota_rollouts r ON r.id = u.rollout_id
+             WHERE r.release_id = f.id AND u.device_id = $2)",
+    )
+    .bind(release_id)
+    .bind(device_id)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let image = image.ok_or(Status::NotFound)?;
+
+    println!(
+        "DEBUG: Device {} downloading firmware {}",
+        device_id, release_id
+    );
+    Ok((ContentType::Binary, image))
+}
+
+/// Cria um rollout de uma versão para um dispositivo (exige permissão de atualizar o
+/// firmware), para os dispositivos de um perfil do usuário (apenas aqueles em que ele tem
+/// essa permissão) ou para uma porcentagem dos dispositivos pareados (apenas
+/// administradores de firmware). Dispositivos que já estão em outra atualização ficam de
+/// fora, assim como aqueles com uma versão mais recente que a do rollout, a menos que
+/// `allow_downgrade` seja informado; sem nenhum dispositivo a atualizar, retorna `409`. Os
+/// comandos são enviados pelo orquestrador; retorna o rollout com a atualização de cada
+/// dispositivo.
+#[post("/create_ota_rollout", data = "<request>")]
+pub async fn create_rollout(
+    user: AuthUser,
+    request: rocket::serde::json::Json<RolloutRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    if !(0..=100).contains(&request.max_failure_percent) {
+        return Err(Status::BadRequest);
+    }
+    let version: Option<String> =
+        sqlx::query_scalar("SELECT version FROM firmware_releases WHERE id = $1")
+            .bind(request.release_id)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    let version = version.ok_or(Status::NotFound)?;
+
+    let (target, devices) = match (request.device_id, request.profile_id, request.percentage) {
+        (Some(device_id), None, None) => {
+            authorize(
+                db_pool,
+                device_id,
+                &user.firebase_uid,
+                Action::UpdateFirmware,
+            )
+            .await?;
+            ("DEVICE", vec![device_id])
+        }
+        (None, Some(profile_id), None) => {
+            let exists: Option<Uuid> = sqlx::query_scalar(
+                "SELECT id FROM config_profiles WHERE id = $1 AND owner_id = $2",
+            )
+            .bind(profile_id)
+            .bind(&user.firebase_uid)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+            exists.ok_or(Status::NotFound)?;
+
+            let assigned: Vec<Uuid> = sqlx::query_scalar(
+                "SELECT device_id FROM device_config_profiles WHERE profile_id = $1",
+            )
+            .bind(profile_id)
+            .fetch_all(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+            let mut devices = Vec::new();
+            for device_id in assigned {
+                if authorize(
+                    db_pool,
+                    device_id,
+                    &user.firebase_uid,
+                    Action::UpdateFirmware,
+                )
+                .await
+                .is_ok()
+                {
+                    devices.push(device_id);
+                }
+            }
+            ("PROFILE", devices)
+        }
+        (None, None, Some(percentage)) if (1..=100).contains(&percentage) => {
+            if !firmware().is_admin(&user.firebase_uid) {
+                return Err(Status::Forbidden);
+            }
+            // Stable buckets per release: a larger percentage includes the smaller one
+            let devices: Vec<Uuid> = sqlx::query_scalar(
+                "SELECT uuid FROM devices WHERE user_id IS NOT NULL
+                 AND (hashtext(uuid::text || $1::text)::bigint % 100 + 100) % 100 < $2",
+            )
+            .bind(request.release_id)
+            .bind(i64::from(percentage))
+            .fetch_all(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+            ("PERCENTAGE", devices)
+        }
+        _ => return Err(Status::BadRequest),
+    };
+
+    let devices = if request.allow_downgrade {
+        devices
+    } else {
+        let current: Vec<(Uuid, Option<String>)> =
+            sqlx::query_as("SELECT uuid, firmware_version FROM devices WHERE uuid = ANY($1)")
+                .bind(&devices)
+                .fetch_all(&**db_pool)
+                .await
+                .map_err(|_| Status::InternalServerError)?;
+        current
+            .into_iter()
+            .filter(|(_, current)| !is_downgrade(current.as_deref(), &version))
+            .map(|(device_id, _)| device_id)
+            .collect()
+    };
+
+    let rollout_id = Uuid::new_v4();
+    let mut tx = db_pool
+        .begin()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    sqlx::query(
+        "INSERT INTO ota_rollouts (id, release_id, target, device_id, profile_id, percentage, max_failure_percent, created_by)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(rollout_id)
+    .bind(request.release_id)
+    .bind(target)
+    .bind(request.device_id)
+    .bind(request.profile_id)
+    .bind(request.percentage)
+    .bind(request.max_failure_percent)
+    .bind(&user.firebase_uid)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let queued = sqlx::query(
+        "INSERT INTO ota_updates (rollout_id, device_id)
+         SELECT $1, d.uuid FROM devices d WHERE d.uuid = ANY($2)
+         AND d.firmware_version IS DISTINCT FROM $3
+         ON CONFLICT (device_id) WHERE status IN ('QUEUED', 'SENT', 'DOWNLOADING', 'INSTALLED')
+         DO NOTHING",
+    )
+    .bind(rollout_id)
+    .bind(&devices)
+    .bind(&version)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .rows_affected();
+    if queued == 0 {
+        return Err(Status::Conflict);
+    }
+    tx.commit().await.map_err(|_| Status::InternalServerError)?;
+    println!(
+        "DEBUG: Firmware rollout {} of version {} created by {} for {} devices",
+        rollout_id, version, user.firebase_uid, queued
+    );
+
+    let rollout = load(db_pool, rollout_id, &user.firebase_uid).await?;
+    document(db_pool, rollout)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Lista os rollouts do usuário (todos, para administradores de firmware), do mais
+/// recente ao mais antigo, com a contagem das atualizações.
+#[get("/ota_rollouts")]
+pub async fn get_rollouts(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let rollouts: Vec<RolloutSummary> = sqlx::query_as(
+        "SELECT r.id, f.version, r.target, r.status, r.halted_reason, r.created_at,
+                COUNT(u.device_id) AS devices,
+                COUNT(u.device_id) FILTER (WHERE u.status = 'SUCCEEDED') AS succeeded,
+                COUNT(u.device_id) FILTER (WHERE u.status = 'FAILED') AS failed
+         FROM ota_rollouts r JOIN firmware_releases f ON f.id = r.release_id
+         LEFT JOIN ota_updates u ON u.rollout_id = r.id
+         WHERE r.created_by = $1 OR $2
+         GROUP BY r.id, f.version ORDER BY r.created_at DESC LIMIT $3",
+    )
+    .bind(&user.firebase_uid)
+    .bind(firmware().is_admin(&user.firebase_uid))
+    .bind(MAX_ROLLOUTS_PER_PAGE)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::json!({ "rollouts": rollouts }).to_string())
+}
+
+/// Recupera um rollout com a atualização de cada dispositivo.
+#[get("/ota_rollouts/<rollout_id>")]
+pub async fn get_rollout(
+    user: AuthUser,
+    rollout_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let rollout_id = Uuid::parse_str(rollout_id).map_err(|_| Status::BadRequest)?;
+
+    let rollout = load(db_pool, rollout_id, &user.firebase_uid).await?;
+    document(db_pool, rollout)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Cancela um rollout em execução ou interrompido. Atualizações ainda não enviadas são
+/// canceladas; as que já estão em andamento continuam sendo acompanhadas.
+#[post("/cancel_ota_rollout/<rollout_id>")]
+pub async fn cancel_rollout(
+    user: AuthUser,
+    rollout_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let rollout_id = Uuid::parse_str(rollout_id).map_err(|_| Status::BadRequest)?;
+    load(db_pool, rollout_id, &user.firebase_uid).await?;
+
+    let result = sqlx::query(
+        "UPDATE ota_rollouts SET status = 'CANCELLED', updated_at = NOW()
+         WHERE id = $1 AND status IN ('RUNNING', 'HALTED')",
+    )
+    .bind(rollout_id)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::Conflict);
+    }
+    sqlx::query(
+        "UPDATE ota_updates SET status = 'CANCELLED', updated_at = NOW()
+         WHERE rollout_id = $1 AND status = 'QUEUED'",
+    )
+    .bind(rollout_id)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let rollout = load(db_pool, rollout_id, &user.firebase_uid).await?;
+    document(db_pool, rollout)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Retoma um rollout interrompido, opcionalmente com um novo limite de falhas. Se as
+/// falhas ainda passarem do limite, o rollout é interrompido de novo.
+#[post("/resume_ota_rollout/<rollout_id>?<max_failure_percent>")]
+pub async fn resume_rollout(
+    user: AuthUser,
+    rollout_id: &str,
+    max_failure_percent: Option<i16>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let rollout_id = Uuid::parse_str(rollout_id).map_err(|_| Status::BadRequest)?;
+    if max_failure_percent.is_some_and(|max| !(0..=100).contains(&max)) {
+        return Err(Status::BadRequest);
+    }
+    load(db_pool, rollout_id, &user.firebase_uid).await?;
+
+    let result = sqlx::query(
+        "UPDATE ota_rollouts SET status = 'RUNNING', halted_reason = NULL,
+         max_failure_percent = COALESCE($2, max_failure_percent), updated_at = NOW()
+         WHERE id = $1 AND status = 'HALTED'",
+    )
+    .bind(rollout_id)
+    .bind(max_failure_percent)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::Conflict);
+    }
+
+    let rollout = load(db_pool, rollout_id, &user.firebase_uid).await?;
+    document(db_pool, rollout)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monta o início de uma imagem ESP-IDF com a versão informada em `esp_app_desc_t`.
+    fn image(version: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 256];
+        image[0] = IMAGE_MAGIC;
+        image[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        let start = APP_DESC_OFFSET + 16;
+        image[start..start + version.len()].copy_from_slice(version.as_bytes());
+        image
+    }
+
+    #[test]
+    fn reads_the_version_from_the_app_descriptor() {
+        assert_eq!(app_version(&image("1.4.2")), Some("1.4.2".to_string()));
+        // A version filling the whole 32-byte field has no terminator
+        let long = "1".repeat(32);
+        assert_eq!(app_version(&image(&long)), Some(long));
+    }
+
+    #[test]
+    fn rejects_images_without_a_valid_descriptor() {
+        let mut bad_magic = image("1.4.2");
+        bad_magic[0] = 0;
+        assert_eq!(app_version(&bad_magic), None);
+
+        let mut bad_desc = image("1.4.2");
+        bad_desc[APP_DESC_OFFSET] ^= 0xFF;
+        assert_eq!(app_version(&bad_desc), None);
+
+        assert_eq!(app_version(&image("1.4.2")[..APP_DESC_OFFSET + 40]), None);
+        assert_eq!(app_version(&image("")), None);
+        assert_eq!(app_version(&[]), None);
+    }
+
+    #[test]
+    fn compares_versions_numerically() {
+        assert!(is_downgrade(Some("1.10.0"), "1.9.9"));
+        assert!(is_downgrade(Some("v2.0"), "1.99"));
+        assert!(!is_downgrade(Some("1.9.9"), "1.10.0"));
+        assert!(!is_downgrade(Some("1.2.0"), "1.2.0"));
+    }
+
+    #[test]
+    fn ignores_prefixes_suffixes_and_trailing_zeros() {
+        assert!(!is_downgrade(Some("v1.2"), "1.2.0"));
+        assert!(!is_downgrade(Some("1.2.0"), "v1.2"));
+        assert!(!is_downgrade(Some("1.2.0-rc1"), "1.2.0"));
+        assert!(is_downgrade(Some("1.2.1+build5"), "1.2.0-rc2"));
+        assert_eq!(version_parts("v1.2.0.0-rc1"), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn does_not_compare_unknown_versions() {
+        assert!(!is_downgrade(None, "1.0.0"));
+        assert!(!is_downgrade(Some("dev"), "1.0.0"));
+        assert!(!is_downgrade(Some("2.0.0"), "nightly"));
+        assert!(!is_downgrade(Some("2.0.0"), "1.x"));
+    }
+}
@@ -0,0 +1,412 @@
+//! Módulo para pareamento de dispositivos.
+//!
+//! Um usuário autenticado solicita um código de pareamento de curta duração e o envia ao
+//! dispositivo em modo de pareamento (pelo ponto de acesso do dispositivo, no lugar do ID do
+//! usuário). O dispositivo apresenta o código (ou, com o canal autenticado, o hash dele)
+//! no campo `user_id` do heartbeat e o back-end o reivindica para o usuário, desde que o dispositivo não tenha proprietário (ex.: após
+//! `POST /unpair`) ou já pertença ao próprio usuário. Para repareamento, o back-end envia
+//! o comando `PAIR` e avisa o proprietário quando o dispositivo publica
+//! `ENTERING_PAIRING_MODE`.
+use anyhow::Result;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::{Argon2, PasswordHasher};
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::{State, post};
+use rumqttc::{AsyncClient, QoS};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::channel::{self, DeviceKey, control_payload};
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{self, Action, authorize};
+use super::mqtt::{ensure_connected, publish_control_message};
+use super::notify_users;
+use super::protocol::{self, DeviceMessage, Envelope};
+use super::session::AuthUser;
+
+/// Prefixo que distingue códigos de pareamento de IDs de usuário no heartbeat.
+pub const PAIRING_CODE_PREFIX: &str = "LWP-";
+/// Validade de um código de pareamento, em minutos.
+const PAIRING_CODE_TTL_MINUTES: i64 = 15;
+/// Alfabeto dos códigos, sem caracteres ambíguos.
+const PAIRING_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Estrutura de requisição para solicitar um código de pareamento.
+#[derive(Deserialize)]
+pub struct PairingCodeRequest {
+    /// UUID de um dispositivo do próprio usuário a reparear; recebe o comando `PAIR`.
+    #[serde(default)]
+    device_id: Option<String>,
+    /// Senha do dispositivo, usada na verificação por voz.
+    #[serde(default)]
+    user_key: Option<String>,
+}
+
+/// Código de pareamento pendente, como armazenado no banco de dados.
+#[derive(sqlx::FromRow)]
+struct PairingCodeRow {
+    /// ID do código.
+    id: i32,
+    /// ID do usuário que solicitou o código.
+    user_id: String,
+    /// Hash da senha do dispositivo a aplicar no pareamento.
+    hashed_passphrase: Option<String>,
+}
+
+/// Gera um código de pareamento de uso único para o usuário autenticado.
+/// Se `device_id` for informado, o dispositivo deve pertencer ao usuário e recebe o
+/// comando `PAIR` para entrar em modo de pareamento.
+#[post("/pairing_code", data = "<request>")]
+pub async fn create_pairing_code(
+    user: AuthUser,
+    request: rocket::serde::json::Json<PairingCodeRequest>,
+    db_pool: &State<PgPool>,
+    mqtt_client: &State<AsyncClient>,
+) -> Result<String, Status> {
+    let user_id = user.firebase_uid;
+
+    let device_uuid = match &request.device_id {
+        Some(id) => {
+            let uuid = Uuid::parse_str(id).map_err(|_| Status::BadRequest)?;
+            authorize(db_pool, uuid, &user_id, Action::Unpair).await?;
+            ensure_connected()?;
+            Some(uuid)
+        }
+        None => None,
+    };
+
+    // Hash the user key
+    let hashed_passphrase = match &request.user_key {
+        Some(key) => {
+            let salt = argon2::password_hash::SaltString::generate(&mut OsRng);
+            Some(
+                Argon2::default()
+                    .hash_password(key.as_bytes(), &salt)
+                    .map_err(|_| Status::InternalServerError)?
+                    .to_string(),
+            )
+        }
+        None => None,
+    };
+
+    let code = generate_code();
+    let expires_at = Utc::now() + chrono::Duration::minutes(PAIRING_CODE_TTL_MINUTES);
+    sqlx::query(
+        "INSERT INTO pairing_codes (code_hash, user_id, device_id, hashed_passphrase, expires_at) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(hash_code(&code))
+    .bind(&user_id)
+    .bind(device_uuid)
+    .bind(&hashed_passphrase)
+    .bind(expires_at)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    // Put the device into pairing mode; it answers with ENTERING_PAIRING_MODE
+    if let Some(uuid) = device_uuid {
+        publish_control_message(db_pool, mqtt_client, uuid, "PAIR".to_string())
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    }
+
+    Ok(serde_json::json!({
+        "code": code,
+        "expires_at": expires_at.timestamp_millis()
+    })
+    .to_string())
+}
+
+/// Reivindica um dispositivo com o código apresentado no heartbeat.
+/// Retorna o ID do novo proprietário, ou `None` se o código for inválido, expirado,
+/// já usado, destinado a outro dispositivo, ou se o dispositivo pertencer a outro usuário.
+/// Se o código for apresentado na forma de hash (firmware com canal autenticado), o
+/// dispositivo passa a usar a chave acordada com a chave pública apresentada junto com
+/// ele ([`channel::exchange_key`]); sem uma chave pública válida, o pareamento é recusado.
+/// Um código em texto puro (firmware antigo) não traz chave e é recusado se o dispositivo
+/// já tiver chave, para não rebaixá-lo a um canal sem assinatura.
+pub async fn claim(
+    db_pool: &PgPool,
+    device_uuid: Uuid,
+    code: &str,
+    public_key: Option<&str>,
+) -> Result<Option<String>> {
+    let device_key = if is_hashed(code) {
+        let Some(key) = public_key.and_then(channel::exchange_key) else {
+            println!(
+                "DEBUG: Rejected pairing of device {} without a valid public key",
+                device_uuid
+            );
+            return Ok(None);
+        };
+        Some(key.to_vec())
+    } else {
+        None
+    };
+
+    let mut tx = db_pool.begin().await?;
+
+    // Lock the code so concurrent heartbeats cannot claim it twice
+    let row: Option<PairingCodeRow> = sqlx::query_as(
+        "SELECT id, user_id, hashed_passphrase FROM pairing_codes WHERE code_hash = $1 AND claimed_at IS NULL AND expires_at > NOW() AND (device_id IS NULL OR device_id = $2) FOR UPDATE",
+    )
+    .bind(presented_hash(code))
+    .bind(device_uuid)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let owner: Option<(Option<String>, bool)> = sqlx::query_as(
+        "SELECT user_id, device_key IS NOT NULL FROM devices WHERE uuid = $1 FOR UPDATE",
+    )
+    .bind(device_uuid)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let has_key = owner.as_ref().is_some_and(|(_, has_key)| *has_key);
+    if has_key && device_key.is_none() {
+        println!(
+            "DEBUG: Rejected plaintext pairing code for device {} with an authenticated channel",
+            device_uuid
+        );
+        // A plaintext code carries no key, so accepting it would drop the channel back to unsigned
+        return Ok(None);
+    }
+    let previous_owner = owner.and_then(|(uid, _)| uid);
+    if previous_owner
+        .as_ref()
+        .is_some_and(|owner| *owner != row.user_id)
+    {
+        println!(
+            "DEBUG: Rejected pairing of device {} owned by another user",
+            device_uuid
+        );
+        // Leave the code unclaimed, so its owner can still use it on their own device
+        return Ok(None);
+    }
+
+    // Consume the code only now that the claim succeeds
+    sqlx::query("UPDATE pairing_codes SET claimed_at = NOW(), device_id = $1 WHERE id = $2")
+        .bind(device_uuid)
+        .bind(row.id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO devices (uuid, user_id, hashed_passphrase, device_key, last_heard, uptime_ms) VALUES ($1, $2, $3, $4, NOW(), 0)
+         ON CONFLICT (uuid) DO UPDATE SET user_id = $2, hashed_passphrase = COALESCE($3, devices.hashed_passphrase), device_key = $4, control_seq = 0,
+         pending_device_key = NULL, rekey_requested_at = NULL",
+    )
+    .bind(device_uuid)
+    .bind(&row.user_id)
+    .bind(&row.hashed_passphrase)
+    .bind(device_key)
+    .execute(&mut *tx)
+    .await?;
+
+    if previous_owner.is_none() {
+        // Remove any logs, events, invites, members, pending settings, configuration history,
+        // profile assignments and firmware updates from previous owners
+        sqlx::query("DELETE FROM logs WHERE device_id = $1")
+            .bind(device_uuid.to_string())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM device_events WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM invites WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM device_members WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM device_desired_config WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM device_config_revisions WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM device_config_profiles WHERE device_id = $1")
+            .bind(device_uuid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "UPDATE ota_updates SET status = 'CANCELLED', updated_at = NOW() WHERE device_id = $1 AND status = 'QUEUED'",
+        )
+        .bind(device_uuid)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    println!("DEBUG: Device {} paired to {}", device_uuid, row.user_id);
+    Ok(Some(row.user_id))
+}
+
+/// Trata o código de pareamento apresentado por um dispositivo no heartbeat.
+/// Em caso de sucesso, substitui o código armazenado no dispositivo pelo ID do proprietário
+/// e avisa o proprietário via WebSocket. Retorna `true` se o dispositivo foi reivindicado.
+pub async fn handle_presented_code(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_uuid: Uuid,
+    code: &str,
+    public_key: Option<&str>,
+) -> bool {
+    let owner = match claim(db_pool, device_uuid, code, public_key).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return false,
+        Err(e) => {
+            println!("DEBUG: Failed to pair device {}: {}", device_uuid, e);
+            return false;
+        }
+    };
+
+    record_backend_event(
+        db_pool,
+        device_uuid,
+        BackendEvent::Paired,
+        Some(&owner),
+        serde_json::json!({}),
+    )
+    .await;
+
+    // The event loop is not polled while this runs, so never wait on the request queue
+    let msg = serde_json::json!({
+        "command": "update_config",
+        "key": "user_id",
+        "value": owner
+    });
+    if let Ok(payload) = control_payload(db_pool, device_uuid, &msg).await {
+        let _ = mqtt_client.try_publish(
+            format!("lockwise/{}/control", device_uuid),
+            QoS::AtLeastOnce,
+            false,
+            payload,
+        );
+    }
+
+    notify_users(
+        &[owner],
+        &serde_json::json!({
+            "type": "device_paired",
+            "device_id": device_uuid.to_string()
+        })
+        .to_string(),
+    );
+    true
+}
+
+/// Reivindica o dispositivo com o código do heartbeat assinado com a chave desse código
+/// ([`super::channel::Authenticated::pairing`]). Até lá, a mensagem não prova nada além
+/// da posse do código, então só deve ser processada se o pareamento for concluído.
+pub async fn claim_presented(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_uuid: Uuid,
+    payload: &[u8],
+) -> bool {
+    match presented_code(payload) {
+        Some((code, public_key)) => {
+            handle_presented_code(db_pool, mqtt_client, device_uuid, &code, Some(&public_key)).await
+        }
+        None => false,
+    }
+}
+
+/// Extrai o código de pareamento, na forma de hash, e a chave pública do heartbeat de um
+/// dispositivo com canal autenticado.
+fn presented_code(payload: &[u8]) -> Option<(String, String)> {
+    let Ok(Envelope {
+        message: DeviceMessage::Heartbeat(heartbeat),
+        ..
+    }) = protocol::decode(payload)
+    else {
+        return None;
+    };
+    let code = heartbeat.user_id.filter(|code| is_hashed(code))?;
+    Some((code, heartbeat.pub_key?))
+}
+
+/// Avisa o proprietário de que o dispositivo entrou em modo de pareamento.
+pub async fn handle_entering_pairing_mode(db_pool: &PgPool, device_uuid: Uuid) {
+    let recipients = member::users_with_permission(db_pool, device_uuid, Action::Unpair)
+        .await
+        .unwrap_or_default();
+    notify_users(
+        &recipients,
+        &serde_json::json!({
+            "type": "pairing_mode",
+            "device_id": device_uuid.to_string()
+        })
+        .to_string(),
+    );
+}
+
+/// Deriva a chave apresentada em um heartbeat de pareamento ainda não autenticado (a
+/// chave pública gerada pelo dispositivo junto com o código), para verificar a assinatura
+/// do dispositivo que está sendo pareado. Só há chave se o código for válido: com
+/// `any_code` falso (o dispositivo já tem chave), apenas um código emitido para reparear
+/// esse dispositivo; caso contrário, qualquer um poderia assinar mensagens em nome dele
+/// com um código próprio.
+pub async fn presented_key(
+    db_pool: &PgPool,
+    device_uuid: Uuid,
+    any_code: bool,
+    payload: &[u8],
+) -> Option<DeviceKey> {
+    let (code, public_key) = presented_code(payload)?;
+
+    let row: Option<i32> = sqlx::query_scalar(
+        "SELECT id FROM pairing_codes WHERE code_hash = $1 AND claimed_at IS NULL AND expires_at > NOW() AND (device_id = $2 OR ($3 AND device_id IS NULL))",
+    )
+    .bind(presented_hash(&code))
+    .bind(device_uuid)
+    .bind(any_code)
+    .fetch_optional(db_pool)
+    .await
+    .ok()?;
+    row?;
+    channel::exchange_key(&public_key)
+}
+
+/// Gera um código de pareamento aleatório, com prefixo [`PAIRING_CODE_PREFIX`].
+fn generate_code() -> String {
+    let mut bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut bytes);
+    let body: String = bytes
+        .iter()
+        .map(|b| PAIRING_CODE_ALPHABET[(*b as usize) % PAIRING_CODE_ALPHABET.len()] as char)
+        .collect();
+    format!("{}{}", PAIRING_CODE_PREFIX, body)
+}
+
+/// Indica se o código foi apresentado na forma de hash: prefixo seguido do SHA-256
+/// (hexadecimal) do código, como faz o firmware com canal autenticado.
+fn is_hashed(code: &str) -> bool {
+    code.strip_prefix(PAIRING_CODE_PREFIX)
+        .is_some_and(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Hash do código apresentado no heartbeat, presente diretamente na forma de hash.
+fn presented_hash(code: &str) -> String {
+    if is_hashed(code) {
+        code[PAIRING_CODE_PREFIX.len()..].to_ascii_lowercase()
+    } else {
+        hash_code(code)
+    }
+}
+
+/// Calcula o hash SHA-256 (hexadecimal) de um código de pareamento.
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
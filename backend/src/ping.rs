@@ -0,0 +1,188 @@
+//! Módulo para pings dos dispositivos.
+//!
+//! Cada ping recebe um ID, enviado ao dispositivo como `command_id` e ecoado no `PONG`.
+//! Assim, vários usuários podem fazer ping no mesmo dispositivo ao mesmo tempo, cada um
+//! esperando a própria resposta. O tempo de ida e volta é medido por quem espera, desde a
+//! publicação do `PING` até a chegada do `PONG`, e retornado na resposta. Todo ping,
+//! respondido ou não, é registrado em `device_pings` para diagnóstico de conectividade.
+//! Firmwares que não ecoam o ID respondem a todos os pings pendentes do dispositivo.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::{State, get};
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{Action, authorize};
+use super::mqtt::{ensure_connected, publish_command};
+use super::session::AuthUser;
+
+/// Tempo máximo de espera pelo `PONG`, em segundos.
+const PING_TIMEOUT_SEC: u64 = 10;
+/// Número máximo de pings retornados por consulta.
+const MAX_PINGS_PER_PAGE: i64 = 500;
+
+/// Ping registrado no histórico.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct PingInfo {
+    /// ID do ping.
+    id: Uuid,
+    /// Usuário que fez o ping.
+    user_id: Option<String>,
+    /// Momento do envio do `PING`.
+    sent_at: DateTime<Utc>,
+    /// Tempo de ida e volta em milissegundos, ou `None` se não houve resposta.
+    rtt_ms: Option<i32>,
+}
+
+/// Resumo dos pings retornados no histórico.
+#[derive(Serialize)]
+pub struct PingSummary {
+    /// Número de pings enviados.
+    sent: usize,
+    /// Número de pings sem resposta.
+    lost: usize,
+    /// Menor tempo de ida e volta, em milissegundos.
+    min_rtt_ms: Option<i32>,
+    /// Tempo médio de ida e volta, em milissegundos.
+    avg_rtt_ms: Option<f64>,
+    /// Maior tempo de ida e volta, em milissegundos.
+    max_rtt_ms: Option<i32>,
+}
+
+/// Faz ping em um dispositivo e aguarda o `PONG`. Retorna o ID do ping e o tempo de ida
+/// e volta, ou `408` se o dispositivo não responder a tempo.
+pub async fn ping(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Uuid,
+    user_id: &str,
+) -> Result<String, Status> {
+    ensure_connected()?;
+
+    // Register the waiter before publishing so a fast PONG is not missed
+    let ping_id = Uuid::new_v4();
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    {
+        let pings_mutex = super::PENDING_PINGS.get().unwrap();
+        let mut pings = pings_mutex.lock().unwrap();
+        pings.insert(ping_id, (device_id, tx));
+    }
+
+    let sent_at = Utc::now();
+    let start = Instant::now();
+    if publish_command(
+        db_pool,
+        mqtt_client,
+        device_id,
+        "PING".to_string(),
+        Some(ping_id.to_string()),
+    )
+    .await
+    .is_err()
+    {
+        forget(ping_id);
+        return Err(Status::InternalServerError);
+    }
+    record_backend_event(
+        db_pool,
+        device_id,
+        BackendEvent::CommandSent,
+        Some(user_id),
+        serde_json::json!({ "command": "PING", "ping_id": ping_id.to_string() }),
+    )
+    .await;
+
+    let rtt_ms = match tokio::time::timeout(Duration::from_secs(PING_TIMEOUT_SEC), rx).await {
+        Ok(Ok(())) => Some(start.elapsed().as_millis() as i32),
+        _ => {
+            forget(ping_id);
+            None
+        }
+    };
+
+    if let Err(e) = record(db_pool, ping_id, device_id, user_id, sent_at, rtt_ms).await {
+        println!("DEBUG: Failed to record ping {}: {}", ping_id, e);
+    }
+
+    let Some(rtt_ms) = rtt_ms else {
+        println!(
+            "DEBUG: Ping {} to device {} not answered within {} s",
+            ping_id, device_id, PING_TIMEOUT_SEC
+        );
+        return Err(Status::RequestTimeout);
+    };
+    Ok(serde_json::json!({ "ping_id": ping_id.to_string(), "rtt_ms": rtt_ms }).to_string())
+}
+
+/// Remove a espera de um ping.
+fn forget(ping_id: Uuid) {
+    super::PENDING_PINGS
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .remove(&ping_id);
+}
+
+/// Registra um ping no histórico do dispositivo.
+async fn record(
+    db_pool: &PgPool,
+    ping_id: Uuid,
+    device_id: Uuid,
+    user_id: &str,
+    sent_at: DateTime<Utc>,
+    rtt_ms: Option<i32>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO device_pings (id, device_id, user_id, sent_at, rtt_ms) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(ping_id)
+    .bind(device_id)
+    .bind(user_id)
+    .bind(sent_at)
+    .bind(rtt_ms)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Recupera os pings mais recentes de um dispositivo (`limit` até 500, padrão 50), do
+/// mais recente ao mais antigo, com um resumo de perdas e tempos de ida e volta.
+#[get("/pings/<uuid>?<limit>")]
+pub async fn get_pings(
+    user: AuthUser,
+    uuid: &str,
+    limit: Option<i64>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::Ping).await?;
+
+    let limit = limit.unwrap_or(50).clamp(1, MAX_PINGS_PER_PAGE);
+    let pings: Vec<PingInfo> = sqlx::query_as(
+        "SELECT id, user_id, sent_at, rtt_ms FROM device_pings WHERE device_id = $1 ORDER BY sent_at DESC LIMIT $2",
+    )
+    .bind(uuid)
+    .bind(limit)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let rtts: Vec<i32> = pings.iter().filter_map(|p| p.rtt_ms).collect();
+    let summary = PingSummary {
+        sent: pings.len(),
+        lost: pings.len() - rtts.len(),
+        min_rtt_ms: rtts.iter().min().copied(),
+        avg_rtt_ms: (!rtts.is_empty())
+            .then(|| rtts.iter().map(|&r| r as f64).sum::<f64>() / rtts.len() as f64),
+        max_rtt_ms: rtts.iter().max().copied(),
+    };
+
+    Ok(serde_json::json!({ "pings": pings, "summary": summary }).to_string())
+}
@@ -0,0 +1,236 @@
+//! Módulo para a presença dos dispositivos.
+//!
+//! Um dispositivo passa a offline quando nenhuma mensagem chega dentro de
+//! [`MISSED_HEARTBEATS`] intervalos de heartbeat (verificado periodicamente por
+//! [`check_timeouts`]) ou quando o broker publica a mensagem Last Will do dispositivo em
+//! `lockwise/<id>/presence`, e volta a online com a próxima mensagem autenticada. Cada
+//! transição registra o intervalo de queda em `device_outages`, entra no histórico de
+//! eventos e é enviada via WebSocket (`device_offline` e `device_online`) a todos que
+//! podem ver o dispositivo.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::{State, get};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{Action, authorize};
+use super::mqtt::broadcast_to_viewers;
+use super::session::AuthUser;
+
+/// Tópico em que o broker publica a mensagem Last Will dos dispositivos.
+pub const PRESENCE_TOPIC: &str = "lockwise/+/presence";
+/// Conteúdo da mensagem Last Will.
+pub const LAST_WILL_PAYLOAD: &[u8] = b"offline";
+/// Número de heartbeats perdidos após o qual o dispositivo é considerado offline.
+const MISSED_HEARTBEATS: i32 = 3;
+/// Intervalo de heartbeat assumido quando o dispositivo não informou o seu.
+const DEFAULT_HEARTBEAT_INTERVAL_SEC: i32 = 30;
+/// Número máximo de quedas retornadas por consulta.
+const MAX_OUTAGES_PER_PAGE: i64 = 500;
+
+/// Motivo de uma queda.
+#[derive(Clone, Copy)]
+pub enum OfflineReason {
+    /// Nenhuma mensagem dentro do prazo.
+    HeartbeatTimeout,
+    /// Mensagem Last Will publicada pelo broker.
+    LastWill,
+}
+
+impl OfflineReason {
+    /// Nome do motivo, como armazenado no banco.
+    fn as_str(self) -> &'static str {
+        match self {
+            OfflineReason::HeartbeatTimeout => "HEARTBEAT_TIMEOUT",
+            OfflineReason::LastWill => "LAST_WILL",
+        }
+    }
+}
+
+/// Intervalo em que um dispositivo ficou offline.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct OutageInfo {
+    /// Início da queda (última mensagem recebida ou Last Will).
+    started_at: DateTime<Utc>,
+    /// Fim da queda, ou `None` se ainda está offline.
+    ended_at: Option<DateTime<Utc>>,
+    /// Motivo da queda (HEARTBEAT_TIMEOUT ou LAST_WILL).
+    reason: String,
+}
+
+/// Presença de um dispositivo, como retornada pela API.
+#[derive(Serialize)]
+pub struct PresenceInfo {
+    /// Se o dispositivo está online.
+    online: bool,
+    /// Última mensagem recebida do dispositivo, em milissegundos desde a época Unix.
+    last_heard: i64,
+    /// Quedas mais recentes, da mais recente à mais antiga.
+    outages: Vec<OutageInfo>,
+}
+
+/// Marca o dispositivo como online após uma mensagem autenticada, encerrando a queda em
+/// andamento, se houver.
+pub async fn mark_online(db_pool: &PgPool, device_id: Uuid) -> Result<()> {
+    let now = Utc::now();
+    let was_offline: Option<(bool,)> = sqlx::query_as(
+        "UPDATE devices d SET online = TRUE, last_heard = GREATEST(d.last_heard, $2)
+         FROM (SELECT uuid, online FROM devices WHERE uuid = $1 FOR UPDATE) prev
+         WHERE d.uuid = prev.uuid RETURNING NOT prev.online",
+    )
+    .bind(device_id)
+    .bind(now)
+    .fetch_optional(db_pool)
+    .await?;
+    if !matches!(was_offline, Some((true,))) {
+        return Ok(());
+    }
+
+    let outage: Option<(DateTime<Utc>,)> = sqlx::query_as(
+        "UPDATE device_outages SET ended_at = $2 WHERE device_id = $1 AND ended_at IS NULL RETURNING started_at",
+    )
+    .bind(device_id)
+    .bind(now)
+    .fetch_optional(db_pool)
+    .await?;
+    let offline_for_ms = outage.map(|(started_at,)| (now - started_at).num_milliseconds());
+
+    println!("DEBUG: Device {} is back online", device_id);
+    record_backend_event(
+        db_pool,
+        device_id,
+        BackendEvent::CameOnline,
+        None,
+        serde_json::json!({ "offline_for_ms": offline_for_ms }),
+    )
+    .await;
+
+    let update = serde_json::json!({
+        "type": "device_online",
+        "device_id": device_id.to_string(),
+        "last_heard": now.timestamp_millis(),
+        "offline_for_ms": offline_for_ms
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, device_id, &update).await;
+    Ok(())
+}
+
+/// Marca o dispositivo como offline, abrindo uma queda a partir de `since`. Não faz nada
+/// se o dispositivo já estiver offline.
+pub async fn mark_offline(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    reason: OfflineReason,
+    since: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let result = sqlx::query("UPDATE devices SET online = FALSE WHERE uuid = $1 AND online")
+        .bind(device_id)
+        .execute(db_pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(());
+    }
+    let since = since.unwrap_or_else(Utc::now);
+
+    sqlx::query(
+        "INSERT INTO device_outages (device_id, started_at, reason) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+    )
+    .bind(device_id)
+    .bind(since)
+    .bind(reason.as_str())
+    .execute(db_pool)
+    .await?;
+
+    println!(
+        "DEBUG: Device {} is offline ({})",
+        device_id,
+        reason.as_str()
+    );
+    record_backend_event(
+        db_pool,
+        device_id,
+        BackendEvent::WentOffline,
+        None,
+        serde_json::json!({ "reason": reason.as_str() }),
+    )
+    .await;
+
+    let update = serde_json::json!({
+        "type": "device_offline",
+        "device_id": device_id.to_string(),
+        "since": since.timestamp_millis(),
+        "reason": reason.as_str()
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, device_id, &update).await;
+    Ok(())
+}
+
+/// Marca como offline os dispositivos online sem mensagens há mais de
+/// [`MISSED_HEARTBEATS`] intervalos de heartbeat. Dispositivos com heartbeat desativado
+/// só ficam offline pela mensagem Last Will.
+pub async fn check_timeouts(db_pool: &PgPool) -> Result<()> {
+    let overdue: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(
+        "SELECT uuid, last_heard FROM devices
+         WHERE online AND COALESCE(mqtt_heartbeat_enable, TRUE)
+           AND last_heard < NOW() - make_interval(secs => COALESCE(mqtt_heartbeat_interval_sec, $1) * $2)",
+    )
+    .bind(DEFAULT_HEARTBEAT_INTERVAL_SEC)
+    .bind(MISSED_HEARTBEATS)
+    .fetch_all(db_pool)
+    .await?;
+
+    for (device_id, last_heard) in overdue {
+        mark_offline(
+            db_pool,
+            device_id,
+            OfflineReason::HeartbeatTimeout,
+            Some(last_heard),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Recupera a presença de um dispositivo e suas quedas mais recentes (`limit` até 500,
+/// padrão 50).
+#[get("/presence/<uuid>?<limit>")]
+pub async fn get_presence(
+    user: AuthUser,
+    uuid: &str,
+    limit: Option<i64>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::View).await?;
+
+    let (online, last_heard): (bool, DateTime<Utc>) =
+        sqlx::query_as("SELECT online, last_heard FROM devices WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::NotFound)?;
+
+    let limit = limit.unwrap_or(50).clamp(1, MAX_OUTAGES_PER_PAGE);
+    let outages: Vec<OutageInfo> = sqlx::query_as(
+        "SELECT started_at, ended_at, reason FROM device_outages WHERE device_id = $1 ORDER BY started_at DESC LIMIT $2",
+    )
+    .bind(uuid)
+    .bind(limit)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let presence = PresenceInfo {
+        online,
+        last_heard: last_heard.timestamp_millis(),
+        outages,
+    };
+    Ok(serde_json::to_string(&presence).unwrap())
+}
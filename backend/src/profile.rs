@@ -0,0 +1,558 @@
+//! Módulo de perfis de configuração.
+//!
+//! Um perfil é um conjunto nomeado de chaves de configuração de um usuário, atribuído a
+//! vários dispositivos. Atribuir um perfil aplica a configuração efetiva do dispositivo
+//! (o perfil com as sobreposições do dispositivo) pelo mesmo caminho de
+//! `POST /update_config/<uuid>`; editar o perfil aplica as chaves alteradas a todos os
+//! dispositivos atribuídos, exceto as sobrepostas. O progresso de cada dispositivo vem do
+//! device twin: `APPLIED` quando todas as chaves do dispositivo foram confirmadas,
+//! `PENDING` enquanto alguma aguarda o dispositivo e `FAILED` se alguma foi rejeitada ou
+//! se o perfil não pôde ser aplicado (ex.: o dono do perfil perdeu o acesso).
+//!
+//! Alterações feitas em `update_config` em chaves do perfil viram sobreposições do
+//! dispositivo, para que a próxima edição do perfil não as desfaça. Valores sensíveis
+//! (ex.: `wifi_pass`) não podem fazer parte de um perfil.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::{State, get, post};
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use super::config::{self, ConfigKey};
+use super::device::apply_config;
+use super::member::{Action, authorize};
+use super::revision::Source;
+use super::session::AuthUser;
+
+/// Configuração de um perfil ou sobreposições de um dispositivo, por chave.
+pub type ProfileConfig = BTreeMap<String, String>;
+
+/// Estrutura de requisição para criar ou editar um perfil.
+#[derive(Deserialize)]
+pub struct ProfileRequest {
+    /// Nome do perfil, único entre os perfis do usuário.
+    name: String,
+    /// Valores das chaves de configuração do perfil.
+    config: ProfileConfig,
+}
+
+/// Estrutura de requisição para atribuir um perfil a um dispositivo.
+#[derive(Deserialize)]
+pub struct AssignProfileRequest {
+    /// Valores próprios do dispositivo, que prevalecem sobre os do perfil.
+    #[serde(default)]
+    overrides: ProfileConfig,
+}
+
+/// Linha da tabela `config_profiles`.
+#[derive(sqlx::FromRow)]
+struct ProfileRow {
+    /// ID do perfil.
+    id: Uuid,
+    /// Nome do perfil.
+    name: String,
+    /// Configuração em JSON.
+    config: String,
+    /// Momento da criação.
+    created_at: DateTime<Utc>,
+    /// Momento da última edição.
+    updated_at: DateTime<Utc>,
+}
+
+/// Linha da tabela `device_config_profiles`.
+#[derive(sqlx::FromRow)]
+struct AssignmentRow {
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// Sobreposições do dispositivo em JSON.
+    overrides: String,
+    /// Usuário que atribuiu o perfil.
+    assigned_by: Option<String>,
+    /// Momento da atribuição.
+    assigned_at: DateTime<Utc>,
+    /// Momento da última aplicação do perfil.
+    rollout_at: Option<DateTime<Utc>>,
+    /// Motivo da falha da última aplicação, se houver.
+    rollout_error: Option<String>,
+}
+
+/// Progresso da aplicação do perfil em um dispositivo, como retornado pela API.
+#[derive(Serialize)]
+pub struct DeviceProgress {
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// Sobreposições do dispositivo.
+    overrides: ProfileConfig,
+    /// Usuário que atribuiu o perfil.
+    assigned_by: Option<String>,
+    /// Momento da atribuição.
+    assigned_at: DateTime<Utc>,
+    /// Momento da última aplicação do perfil.
+    rollout_at: Option<DateTime<Utc>>,
+    /// Estado da aplicação (PENDING, APPLIED ou FAILED).
+    status: &'static str,
+    /// Motivo da falha ao aplicar o perfil, se houver.
+    error: Option<String>,
+    /// Chaves rejeitadas pelo dispositivo, com o motivo.
+    failed_keys: BTreeMap<String, String>,
+}
+
+/// Lê a configuração de um perfil ou as sobreposições de um dispositivo.
+fn parse(config: &str) -> Result<ProfileConfig> {
+    Ok(serde_json::from_str(config)?)
+}
+
+/// Valida e normaliza as chaves de um perfil ou de sobreposições. Chaves desconhecidas,
+/// sensíveis ou com valores inválidos resultam em `400`.
+fn validate(config: &ProfileConfig) -> Result<ProfileConfig, Status> {
+    config
+        .iter()
+        .map(|(key, value)| {
+            let key = config::find(key)
+                .filter(|k| !k.sensitive)
+                .ok_or(Status::BadRequest)?;
+            let value = key.validate(value).ok_or(Status::BadRequest)?;
+            Ok((key.key.to_string(), value))
+        })
+        .collect()
+}
+
+/// Recupera um perfil do usuário, ou `404` se não existir ou for de outro usuário.
+async fn load(db_pool: &PgPool, profile_id: Uuid, owner_id: &str) -> Result<ProfileRow, Status> {
+    sqlx::query_as(
+        "SELECT id, name, config::text AS config, created_at, updated_at FROM config_profiles
+         WHERE id = $1 AND owner_id = $2",
+    )
+    .bind(profile_id)
+    .bind(owner_id)
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)
+}
+
+/// Aplica o perfil a um dispositivo e registra o resultado na atribuição. Com `keys`,
+/// aplica apenas essas chaves; sem elas, toda a configuração efetiva do dispositivo.
+/// Chaves sobrepostas pelo dispositivo nunca são substituídas pelo valor do perfil.
+async fn roll_out(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    user_id: &str,
+    device_id: Uuid,
+    profile: &ProfileConfig,
+    overrides: &ProfileConfig,
+    keys: Option<&[String]>,
+) -> Result<()> {
+    let mut effective: BTreeMap<&str, &String> = BTreeMap::new();
+    match keys {
+        Some(keys) => {
+            for key in keys.iter().filter(|key| !overrides.contains_key(*key)) {
+                if let Some(value) = profile.get(key) {
+                    effective.insert(key, value);
+                }
+            }
+        }
+        None => {
+            effective.extend(profile.iter().map(|(key, value)| (key.as_str(), value)));
+            effective.extend(overrides.iter().map(|(key, value)| (key.as_str(), value)));
+        }
+    }
+    let configs: Vec<(&'static ConfigKey, String)> = effective
+        .into_iter()
+        .filter_map(|(key, value)| config::find(key).map(|k| (k, value.clone())))
+        .collect();
+
+    let error = match authorize(db_pool, device_id, user_id, Action::UpdateConfig).await {
+        Err(_) => Some("FORBIDDEN"),
+        Ok(_) if configs.is_empty() => None,
+        Ok(_) => apply_config(
+            db_pool,
+            mqtt_client,
+            device_id,
+            user_id,
+            &configs,
+            Source::Profile,
+        )
+        .await
+        .err()
+        .map(|_| "INTERNAL_ERROR"),
+    };
+    if let Some(error) = error {
+        println!(
+            "DEBUG: Failed to apply profile to device {}: {}",
+            device_id, error
+        );
+    }
+
+    sqlx::query(
+        "UPDATE device_config_profiles SET rollout_at = NOW(), rollout_error = $2 WHERE device_id = $1",
+    )
+    .bind(device_id)
+    .bind(error)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Monta o documento de um perfil, com o progresso da aplicação em cada dispositivo.
+async fn document(db_pool: &PgPool, profile: ProfileRow) -> Result<String> {
+    let config = parse(&profile.config)?;
+    let assignments: Vec<AssignmentRow> = sqlx::query_as(
+        "SELECT device_id, overrides::text AS overrides, assigned_by, assigned_at, rollout_at, rollout_error
+         FROM device_config_profiles WHERE profile_id = $1 ORDER BY assigned_at",
+    )
+    .bind(profile.id)
+    .fetch_all(db_pool)
+    .await?;
+    let device_ids: Vec<Uuid> = assignments.iter().map(|a| a.device_id).collect();
+    let desired: Vec<(Uuid, String, String, Option<String>)> = sqlx::query_as(
+        "SELECT device_id, key, status, error FROM device_desired_config WHERE device_id = ANY($1)",
+    )
+    .bind(&device_ids)
+    .fetch_all(db_pool)
+    .await?;
+
+    let mut devices = Vec::new();
+    for assignment in assignments {
+        let overrides = parse(&assignment.overrides)?;
+        let mut pending = false;
+        let mut failed_keys = BTreeMap::new();
+        for (_, key, status, error) in desired.iter().filter(|(device_id, key, _, _)| {
+            *device_id == assignment.device_id
+                && (config.contains_key(key) || overrides.contains_key(key))
+        }) {
+            match status.as_str() {
+                "PENDING" => pending = true,
+                "FAILED" => {
+                    failed_keys.insert(key.clone(), error.clone().unwrap_or_default());
+                }
+                _ => {}
+            }
+        }
+        let status = if assignment.rollout_error.is_some() || !failed_keys.is_empty() {
+            "FAILED"
+        } else if pending || assignment.rollout_at.is_none() {
+            "PENDING"
+        } else {
+            "APPLIED"
+        };
+        devices.push(DeviceProgress {
+            device_id: assignment.device_id,
+            overrides,
+            assigned_by: assignment.assigned_by,
+            assigned_at: assignment.assigned_at,
+            rollout_at: assignment.rollout_at,
+            status,
+            error: assignment.rollout_error,
+            failed_keys,
+        });
+    }
+    let count = |status: &str| devices.iter().filter(|d| d.status == status).count();
+    let summary = serde_json::json!({
+        "devices": devices.len(),
+        "applied": count("APPLIED"),
+        "pending": count("PENDING"),
+        "failed": count("FAILED")
+    });
+
+    Ok(serde_json::json!({
+        "id": profile.id.to_string(),
+        "name": profile.name,
+        "config": config,
+        "created_at": profile.created_at,
+        "updated_at": profile.updated_at,
+        "devices": devices,
+        "summary": summary
+    })
+    .to_string())
+}
+
+/// Guarda como sobreposições do dispositivo as chaves de seu perfil alteradas
+/// diretamente em `update_config`.
+pub async fn record_overrides(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    configs: &[(&'static ConfigKey, String)],
+) -> Result<()> {
+    let row: Option<(String, String)> = sqlx::query_as(
+        "SELECT p.config::text, a.overrides::text FROM device_config_profiles a
+         JOIN config_profiles p ON p.id = a.profile_id WHERE a.device_id = $1",
+    )
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await?;
+    let Some((profile, overrides)) = row else {
+        return Ok(());
+    };
+    let profile = parse(&profile)?;
+    let mut overrides = parse(&overrides)?;
+
+    let previous = overrides.clone();
+    for (key, value) in configs {
+        if profile.contains_key(key.key) {
+            overrides.insert(key.key.to_string(), value.clone());
+        }
+    }
+    if overrides != previous {
+        sqlx::query("UPDATE device_config_profiles SET overrides = $2::jsonb WHERE device_id = $1")
+            .bind(device_id)
+            .bind(serde_json::to_string(&overrides)?)
+            .execute(db_pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Cria um perfil de configuração.
+#[post("/create_profile", data = "<request>")]
+pub async fn create_profile(
+    user: AuthUser,
+    request: rocket::serde::json::Json<ProfileRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    if request.name.trim().is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let config = validate(&request.config)?;
+
+    let profile_id = Uuid::new_v4();
+    let result = sqlx::query(
+        "INSERT INTO config_profiles (id, owner_id, name, config) VALUES ($1, $2, $3, $4::jsonb)
+         ON CONFLICT (owner_id, name) DO NOTHING",
+    )
+    .bind(profile_id)
+    .bind(&user.firebase_uid)
+    .bind(request.name.trim())
+    .bind(serde_json::to_string(&config).map_err(|_| Status::InternalServerError)?)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::Conflict);
+    }
+
+    let profile = load(db_pool, profile_id, &user.firebase_uid).await?;
+    document(db_pool, profile)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Lista os perfis de configuração do usuário, com o número de dispositivos atribuídos.
+#[get("/profiles")]
+pub async fn get_profiles(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let rows: Vec<(Uuid, String, String, DateTime<Utc>, i64)> = sqlx::query_as(
+        "SELECT p.id, p.name, p.config::text, p.updated_at, COUNT(a.device_id)
+         FROM config_profiles p LEFT JOIN device_config_profiles a ON a.profile_id = p.id
+         WHERE p.owner_id = $1 GROUP BY p.id ORDER BY p.name",
+    )
+    .bind(&user.firebase_uid)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let mut profiles = Vec::new();
+    for (id, name, config, updated_at, devices) in rows {
+        let config = parse(&config).map_err(|_| Status::InternalServerError)?;
+        profiles.push(serde_json::json!({
+            "id": id.to_string(),
+            "name": name,
+            "config": config,
+            "updated_at": updated_at,
+            "devices": devices
+        }));
+    }
+
+    Ok(serde_json::json!({ "profiles": profiles }).to_string())
+}
+
+/// Recupera um perfil de configuração com o progresso da aplicação em cada dispositivo.
+#[get("/profiles/<profile_id>")]
+pub async fn get_profile(
+    user: AuthUser,
+    profile_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let profile_id = Uuid::parse_str(profile_id).map_err(|_| Status::BadRequest)?;
+
+    let profile = load(db_pool, profile_id, &user.firebase_uid).await?;
+    document(db_pool, profile)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Edita um perfil de configuração e aplica as chaves alteradas a todos os dispositivos
+/// atribuídos. Retorna o perfil com o progresso de cada dispositivo.
+#[post("/update_profile/<profile_id>", data = "<request>")]
+pub async fn update_profile(
+    user: AuthUser,
+    profile_id: &str,
+    request: rocket::serde::json::Json<ProfileRequest>,
+    db_pool: &State<PgPool>,
+    mqtt_client: &State<AsyncClient>,
+) -> Result<String, Status> {
+    let profile_id = Uuid::parse_str(profile_id).map_err(|_| Status::BadRequest)?;
+    if request.name.trim().is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let config = validate(&request.config)?;
+
+    let previous = load(db_pool, profile_id, &user.firebase_uid).await?;
+    let previous = parse(&previous.config).map_err(|_| Status::InternalServerError)?;
+    let result = sqlx::query(
+        "UPDATE config_profiles SET name = $3, config = $4::jsonb, updated_at = NOW()
+         WHERE id = $1 AND owner_id = $2
+         AND NOT EXISTS (SELECT 1 FROM config_profiles WHERE owner_id = $2 AND name = $3 AND id <> $1)",
+    )
+    .bind(profile_id)
+    .bind(&user.firebase_uid)
+    .bind(request.name.trim())
+    .bind(serde_json::to_string(&config).map_err(|_| Status::InternalServerError)?)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::Conflict);
+    }
+
+    // Removed keys keep their current value on the devices
+    let changed: Vec<String> = config
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if !changed.is_empty() {
+        let assignments: Vec<(Uuid, String)> = sqlx::query_as(
+            "SELECT device_id, overrides::text FROM device_config_profiles WHERE profile_id = $1",
+        )
+        .bind(profile_id)
+        .fetch_all(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+        println!(
+            "DEBUG: Rolling out {} changed keys of profile {} to {} devices",
+            changed.len(),
+            profile_id,
+            assignments.len()
+        );
+        for (device_id, overrides) in assignments {
+            let overrides = parse(&overrides).map_err(|_| Status::InternalServerError)?;
+            roll_out(
+                db_pool,
+                mqtt_client,
+                &user.firebase_uid,
+                device_id,
+                &config,
+                &overrides,
+                Some(&changed),
+            )
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        }
+    }
+
+    let profile = load(db_pool, profile_id, &user.firebase_uid).await?;
+    document(db_pool, profile)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Exclui um perfil de configuração. Os dispositivos atribuídos mantêm a configuração
+/// atual.
+#[post("/delete_profile/<profile_id>")]
+pub async fn delete_profile(
+    user: AuthUser,
+    profile_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let profile_id = Uuid::parse_str(profile_id).map_err(|_| Status::BadRequest)?;
+
+    let result = sqlx::query("DELETE FROM config_profiles WHERE id = $1 AND owner_id = $2")
+        .bind(profile_id)
+        .bind(&user.firebase_uid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Atribui um perfil a um dispositivo, substituindo o perfil anterior e as
+/// sobreposições, e aplica a configuração efetiva do dispositivo. Retorna o perfil com o
+/// progresso de cada dispositivo.
+#[post("/assign_profile/<profile_id>/<uuid>", data = "<request>")]
+pub async fn assign_profile(
+    user: AuthUser,
+    profile_id: &str,
+    uuid: &str,
+    request: rocket::serde::json::Json<AssignProfileRequest>,
+    db_pool: &State<PgPool>,
+    mqtt_client: &State<AsyncClient>,
+) -> Result<String, Status> {
+    let profile_id = Uuid::parse_str(profile_id).map_err(|_| Status::BadRequest)?;
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::UpdateConfig).await?;
+
+    let profile = load(db_pool, profile_id, &user.firebase_uid).await?;
+    let config = parse(&profile.config).map_err(|_| Status::InternalServerError)?;
+    let overrides = validate(&request.overrides)?;
+
+    sqlx::query(
+        "INSERT INTO device_config_profiles (device_id, profile_id, overrides, assigned_by) VALUES ($1, $2, $3::jsonb, $4)
+         ON CONFLICT (device_id) DO UPDATE SET profile_id = $2, overrides = $3::jsonb, assigned_by = $4,
+         assigned_at = NOW(), rollout_at = NULL, rollout_error = NULL",
+    )
+    .bind(uuid)
+    .bind(profile_id)
+    .bind(serde_json::to_string(&overrides).map_err(|_| Status::InternalServerError)?)
+    .bind(&user.firebase_uid)
+    .execute(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    roll_out(
+        db_pool,
+        mqtt_client,
+        &user.firebase_uid,
+        uuid,
+        &config,
+        &overrides,
+        None,
+    )
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    document(db_pool, profile)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+/// Remove o perfil de um dispositivo. O dispositivo mantém a configuração atual.
+#[post("/unassign_profile/<uuid>")]
+pub async fn unassign_profile(
+    user: AuthUser,
+    uuid: &str,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::UpdateConfig).await?;
+
+    let result = sqlx::query("DELETE FROM device_config_profiles WHERE device_id = $1")
+        .bind(uuid)
+        .execute(&**db_pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if result.rows_affected() == 0 {
+        return Err(Status::NotFound);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,468 @@
+//! Módulo do protocolo CBOR publicado pelos dispositivos em `lockwise/<id>/status`.
+//!
+//! Toda mensagem é decodificada uma única vez para um [`DeviceMessage`], identificado
+//! pelo campo presente no mapa CBOR: `heartbeat` (heartbeat periódico), `lock` (mudança
+//! de estado da fechadura) ou `event` (eventos de status, como `POWER_ON` ou `PONG`). O
+//! campo opcional `v` indica a versão do protocolo; mensagens sem ele são da versão 1.
+//! Campos opcionais ausentes não invalidam a mensagem; falhas de decodificação são
+//! contadas e registradas com o UUID do dispositivo.
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Versão mais recente do protocolo suportada pelo back-end.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Total de mensagens que não puderam ser decodificadas desde a inicialização.
+static DECODE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Mensagem recebida de um dispositivo, com os campos comuns a todas as mensagens.
+pub struct Envelope {
+    /// Versão do protocolo informada pelo dispositivo.
+    #[allow(dead_code)]
+    pub version: u64,
+    /// Tempo de atividade do dispositivo em milissegundos.
+    pub uptime_ms: Option<u64>,
+    /// Timestamp da mensagem (segundos desde a época Unix).
+    pub timestamp: Option<u64>,
+    /// ID do comando ecoado pelo dispositivo em eventos publicados durante um comando
+    /// (ex.: `PONG`, `CONFIG_UPDATED`).
+    pub command_id: Option<String>,
+    /// Conteúdo da mensagem.
+    pub message: DeviceMessage,
+}
+
+/// Mensagens emitidas pelo firmware.
+pub enum DeviceMessage {
+    /// Heartbeat periódico com o estado e a configuração do dispositivo.
+    Heartbeat(Heartbeat),
+    /// Mudança de estado da fechadura.
+    Lock(LockEvent),
+    /// Dispositivo ligado.
+    PowerOn,
+    /// Dispositivo (re)conectado ao broker.
+    Connected,
+    /// Resposta a um `PING`.
+    Pong,
+    /// Configuração atualizada com sucesso.
+    ConfigUpdated,
+    /// Dispositivo entrando em bloqueio (`LOCKDOWN`).
+    LockingDown,
+    /// Dispositivo reiniciando em modo de pareamento.
+    EnteringPairingMode,
+    /// Dispositivo reiniciando.
+    Restarting,
+    /// Início da transmissão de áudio.
+    Streaming,
+    /// Fim da transmissão de áudio.
+    StoppedStreaming,
+    /// Memória não volátil apagada (`FLASH`).
+    NvsErased,
+    /// Falha ao apagar a memória não volátil.
+    NvsEraseFailed,
+    /// Comando desconhecido recebido pelo dispositivo.
+    InvalidCommand,
+    /// Chave de configuração desconhecida.
+    InvalidConfigKey,
+    /// Mensagem `UPDATE_CONFIG` mal formada.
+    InvalidUpdateConfigFormat,
+    /// Valor cifrado de configuração que não pôde ser decifrado.
+    InvalidUpdateConfigValue,
+    /// Falha ao aplicar uma configuração.
+    UpdateConfigFailed,
+    /// Falha ao persistir a configuração.
+    CommitConfigFailed,
+    /// Falha ao abrir a memória não volátil.
+    NvmOpenFailed,
+    /// Progresso do download de uma atualização de firmware, em porcentagem.
+    OtaProgress(u8),
+    /// Atualização de firmware instalada; o dispositivo reinicia na nova versão.
+    OtaInstalled,
+    /// Falha na atualização de firmware, com o motivo informado pelo dispositivo.
+    OtaFailed(String),
+    /// Chave pública (SEC1 hexadecimal) gerada em resposta ao comando `REKEY`.
+    KeyOffer(String),
+    /// Falha ao gerar uma nova chave em resposta ao comando `REKEY`.
+    RekeyFailed,
+    /// Evento não reconhecido por esta versão do back-end.
+    Unknown(String),
+}
+
+/// Heartbeat periódico. Apenas `uptime_ms` é obrigatório; os demais campos ausentes
+/// mantêm os valores já conhecidos do dispositivo.
+#[derive(Deserialize, Serialize)]
+pub struct Heartbeat {
+    /// Tempo de atividade do dispositivo em milissegundos.
+    pub uptime_ms: u64,
+    /// SSID WiFi ao qual o dispositivo está conectado.
+    #[serde(default)]
+    pub wifi_ssid: Option<String>,
+    /// URL do back-end configurada no dispositivo.
+    #[serde(default)]
+    pub backend_url: Option<String>,
+    /// URL do broker MQTT configurada no dispositivo.
+    #[serde(default)]
+    pub mqtt_broker_url: Option<String>,
+    /// Se o heartbeat MQTT está habilitado.
+    #[serde(default)]
+    pub mqtt_heartbeat_enable: Option<bool>,
+    /// Intervalo de heartbeat em segundos.
+    #[serde(default)]
+    pub mqtt_heartbeat_interval_sec: Option<i32>,
+    /// Tempo limite de gravação de áudio em segundos.
+    #[serde(default)]
+    pub audio_record_timeout_sec: Option<i32>,
+    /// Tempo limite de bloqueio em milissegundos.
+    #[serde(default)]
+    pub lock_timeout_ms: Option<i32>,
+    /// Tempo limite de pareamento em segundos.
+    #[serde(default)]
+    pub pairing_timeout_sec: Option<i32>,
+    /// ID do usuário associado ao dispositivo, ou código de pareamento apresentado.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Chave pública (SEC1 hexadecimal) gerada no pareamento, apresentada junto com o
+    /// código para a troca de chaves.
+    #[serde(default)]
+    pub pub_key: Option<String>,
+    /// Estado atual de bloqueio.
+    #[serde(default)]
+    pub lock_state: Option<String>,
+    /// Se a detecção de voz está habilitada.
+    #[serde(default)]
+    pub voice_detection_enable: Option<bool>,
+    /// Limiar RMS para detecção de atividade de voz.
+    #[serde(default)]
+    pub vad_rms_threshold: Option<i32>,
+    /// Versão do firmware em execução.
+    #[serde(default)]
+    pub fw_version: Option<String>,
+}
+
+/// Mudança de estado da fechadura.
+#[derive(Deserialize, Serialize)]
+pub struct LockEvent {
+    /// Estado de bloqueio (LOCKED/UNLOCKED).
+    pub lock: String,
+    /// Motivo da mudança de bloqueio.
+    pub reason: String,
+    /// ID do comando que causou o evento, ecoado pelo dispositivo.
+    #[serde(default)]
+    pub command_id: Option<String>,
+}
+
+/// Erro de decodificação de uma mensagem de dispositivo.
+pub enum DecodeError {
+    /// O conteúdo não é CBOR válido.
+    Cbor(serde_cbor::Error),
+    /// O CBOR não é um mapa.
+    NotAMap,
+    /// Versão do protocolo mais recente que a suportada.
+    UnsupportedVersion(u64),
+    /// O mapa não tem `heartbeat`, `lock` nem `event`.
+    UnknownShape,
+    /// Um campo obrigatório está ausente ou tem tipo inválido.
+    Invalid(&'static str, serde_cbor::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Cbor(e) => write!(f, "invalid CBOR: {}", e),
+            DecodeError::NotAMap => write!(f, "payload is not a CBOR map"),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported protocol version {}", v)
+            }
+            DecodeError::UnknownShape => {
+                write!(f, "map has no heartbeat, lock or event field")
+            }
+            DecodeError::Invalid(kind, e) => write!(f, "invalid {} message: {}", kind, e),
+        }
+    }
+}
+
+impl DeviceMessage {
+    /// Converte o nome de um evento de status na mensagem correspondente.
+    fn from_event(event: &str) -> Self {
+        match event {
+            "POWER_ON" => DeviceMessage::PowerOn,
+            "CONNECTED" => DeviceMessage::Connected,
+            "PONG" => DeviceMessage::Pong,
+            "CONFIG_UPDATED" => DeviceMessage::ConfigUpdated,
+            "LOCKING_DOWN" => DeviceMessage::LockingDown,
+            "ENTERING_PAIRING_MODE" => DeviceMessage::EnteringPairingMode,
+            "RESTARTING" => DeviceMessage::Restarting,
+            "STREAMING" => DeviceMessage::Streaming,
+            "STOPPED_STREAMING" => DeviceMessage::StoppedStreaming,
+            "NVS_ERASED" => DeviceMessage::NvsErased,
+            "NVS_ERASE_FAILED_NO_SUCH" | "NVS_ERASE_FAILED_UNKNOWN_ERROR" => {
+                DeviceMessage::NvsEraseFailed
+            }
+            "INVALID_COMMAND" => DeviceMessage::InvalidCommand,
+            "INVALID_CONFIG_KEY" => DeviceMessage::InvalidConfigKey,
+            "INVALID_UPDATE_CONFIG_FORMAT" => DeviceMessage::InvalidUpdateConfigFormat,
+            "INVALID_UPDATE_CONFIG_VALUE" => DeviceMessage::InvalidUpdateConfigValue,
+            "UPDATE_CONFIG_FAILED" => DeviceMessage::UpdateConfigFailed,
+            "COMMIT_CONFIG_FAILED" => DeviceMessage::CommitConfigFailed,
+            "NVM_OPEN_FAILED" => DeviceMessage::NvmOpenFailed,
+            "OTA_INSTALLED" => DeviceMessage::OtaInstalled,
+            "REKEY_FAILED" => DeviceMessage::RekeyFailed,
+            other => DeviceMessage::Unknown(other.to_string()),
+        }
+    }
+
+    /// Nome da mensagem, como publicado pelo firmware.
+    pub fn name(&self) -> &str {
+        match self {
+            DeviceMessage::Heartbeat(_) => "HEARTBEAT",
+            DeviceMessage::Lock(_) => "LOCK",
+            DeviceMessage::PowerOn => "POWER_ON",
+            DeviceMessage::Connected => "CONNECTED",
+            DeviceMessage::Pong => "PONG",
+            DeviceMessage::ConfigUpdated => "CONFIG_UPDATED",
+            DeviceMessage::LockingDown => "LOCKING_DOWN",
+            DeviceMessage::EnteringPairingMode => "ENTERING_PAIRING_MODE",
+            DeviceMessage::Restarting => "RESTARTING",
+            DeviceMessage::Streaming => "STREAMING",
+            DeviceMessage::StoppedStreaming => "STOPPED_STREAMING",
+            DeviceMessage::NvsErased => "NVS_ERASED",
+            DeviceMessage::NvsEraseFailed => "NVS_ERASE_FAILED",
+            DeviceMessage::InvalidCommand => "INVALID_COMMAND",
+            DeviceMessage::InvalidConfigKey => "INVALID_CONFIG_KEY",
+            DeviceMessage::InvalidUpdateConfigFormat => "INVALID_UPDATE_CONFIG_FORMAT",
+            DeviceMessage::InvalidUpdateConfigValue => "INVALID_UPDATE_CONFIG_VALUE",
+            DeviceMessage::UpdateConfigFailed => "UPDATE_CONFIG_FAILED",
+            DeviceMessage::CommitConfigFailed => "COMMIT_CONFIG_FAILED",
+            DeviceMessage::NvmOpenFailed => "NVM_OPEN_FAILED",
+            DeviceMessage::OtaProgress(_) => "OTA_PROGRESS",
+            DeviceMessage::OtaInstalled => "OTA_INSTALLED",
+            DeviceMessage::OtaFailed(_) => "OTA_FAILED",
+            DeviceMessage::KeyOffer(_) => "KEY_OFFER",
+            DeviceMessage::RekeyFailed => "REKEY_FAILED",
+            DeviceMessage::Unknown(name) => name,
+        }
+    }
+}
+
+/// Decodifica uma mensagem publicada por um dispositivo.
+pub fn decode(payload: &[u8]) -> Result<Envelope, DecodeError> {
+    let value: Value = serde_cbor::from_slice(payload).map_err(DecodeError::Cbor)?;
+    let Value::Map(map) = &value else {
+        return Err(DecodeError::NotAMap);
+    };
+    let field = |name: &str| map.get(&Value::Text(name.to_string()));
+    let unsigned = |name: &str| match field(name) {
+        Some(Value::Integer(n)) => u64::try_from(*n).ok(),
+        _ => None,
+    };
+
+    let version = unsigned("v").unwrap_or(1);
+    if version > PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let uptime_ms = unsigned("uptime_ms");
+    let timestamp = unsigned("timestamp");
+    let command_id = match field("command_id") {
+        Some(Value::Text(id)) => Some(id.clone()),
+        _ => None,
+    };
+
+    let message = if field("heartbeat").is_some() {
+        DeviceMessage::Heartbeat(
+            serde_cbor::value::from_value(value.clone())
+                .map_err(|e| DecodeError::Invalid("heartbeat", e))?,
+        )
+    } else if field("lock").is_some() {
+        DeviceMessage::Lock(
+            serde_cbor::value::from_value(value.clone())
+                .map_err(|e| DecodeError::Invalid("lock", e))?,
+        )
+    } else if let Some(event) = field("event") {
+        let Value::Text(event) = event else {
+            return Err(DecodeError::Invalid(
+                "event",
+                serde::de::Error::custom("event is not a string"),
+            ));
+        };
+        // OTA and key events carry their progress, failure reason or key in extra fields
+        match event.as_str() {
+            "OTA_PROGRESS" => {
+                DeviceMessage::OtaProgress(unsigned("progress").unwrap_or(0).min(100) as u8)
+            }
+            "OTA_FAILED" => DeviceMessage::OtaFailed(match field("error") {
+                Some(Value::Text(error)) => error.clone(),
+                _ => "UNKNOWN".to_string(),
+            }),
+            "KEY_OFFER" => match field("pub_key") {
+                Some(Value::Text(public_key)) => DeviceMessage::KeyOffer(public_key.clone()),
+                _ => {
+                    return Err(DecodeError::Invalid(
+                        "event",
+                        serde::de::Error::custom("KEY_OFFER without pub_key"),
+                    ));
+                }
+            },
+            _ => DeviceMessage::from_event(event),
+        }
+    } else {
+        return Err(DecodeError::UnknownShape);
+    };
+
+    Ok(Envelope {
+        version,
+        uptime_ms,
+        timestamp,
+        command_id,
+        message,
+    })
+}
+
+/// Registra uma falha de decodificação de uma mensagem do dispositivo.
+pub fn record_failure(device_id: Uuid, error: &DecodeError) {
+    let failures = DECODE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    println!(
+        "DEBUG: Failed to decode message from device {}: {} ({} failures so far)",
+        device_id, error, failures
+    );
+}
+
+/// Total de mensagens que não puderam ser decodificadas desde a inicialização.
+pub fn decode_failures() -> u64 {
+    DECODE_FAILURES.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode(value: serde_json::Value) -> Vec<u8> {
+        serde_cbor::to_vec(&value).unwrap()
+    }
+
+    fn message(value: serde_json::Value) -> DeviceMessage {
+        match decode(&encode(value)) {
+            Ok(envelope) => envelope.message,
+            Err(e) => panic!("decode failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn decodes_heartbeat_with_common_fields() {
+        let envelope = match decode(&encode(json!({
+            "v": 1,
+            "heartbeat": true,
+            "uptime_ms": 1234,
+            "timestamp": 1700000000,
+            "wifi_ssid": "casa",
+            "fw_version": "1.2.0",
+        }))) {
+            Ok(envelope) => envelope,
+            Err(e) => panic!("decode failed: {}", e),
+        };
+        assert_eq!(envelope.uptime_ms, Some(1234));
+        assert_eq!(envelope.timestamp, Some(1700000000));
+        let DeviceMessage::Heartbeat(heartbeat) = envelope.message else {
+            panic!("expected a heartbeat");
+        };
+        assert_eq!(heartbeat.uptime_ms, 1234);
+        assert_eq!(heartbeat.wifi_ssid.as_deref(), Some("casa"));
+        assert_eq!(heartbeat.fw_version.as_deref(), Some("1.2.0"));
+        assert!(heartbeat.pub_key.is_none());
+    }
+
+    #[test]
+    fn decodes_lock_events_and_command_ids() {
+        let envelope = match decode(&encode(json!({
+            "lock": "UNLOCKED",
+            "reason": "REMOTE",
+            "command_id": "abc",
+        }))) {
+            Ok(envelope) => envelope,
+            Err(e) => panic!("decode failed: {}", e),
+        };
+        assert_eq!(envelope.version, 1);
+        assert_eq!(envelope.command_id.as_deref(), Some("abc"));
+        let DeviceMessage::Lock(lock) = envelope.message else {
+            panic!("expected a lock event");
+        };
+        assert_eq!(lock.lock, "UNLOCKED");
+        assert_eq!(lock.reason, "REMOTE");
+    }
+
+    #[test]
+    fn decodes_named_events() {
+        assert_eq!(message(json!({ "event": "PONG" })).name(), "PONG");
+        assert_eq!(
+            message(json!({ "event": "NVS_ERASE_FAILED_NO_SUCH" })).name(),
+            "NVS_ERASE_FAILED"
+        );
+        assert!(matches!(
+            message(json!({ "event": "SOMETHING_NEW" })),
+            DeviceMessage::Unknown(name) if name == "SOMETHING_NEW"
+        ));
+    }
+
+    #[test]
+    fn decodes_ota_events() {
+        assert!(matches!(
+            message(json!({ "event": "OTA_PROGRESS", "progress": 42 })),
+            DeviceMessage::OtaProgress(42)
+        ));
+        assert!(matches!(
+            message(json!({ "event": "OTA_PROGRESS", "progress": 250 })),
+            DeviceMessage::OtaProgress(100)
+        ));
+        assert!(matches!(
+            message(json!({ "event": "OTA_FAILED", "error": "IMAGE_INVALID" })),
+            DeviceMessage::OtaFailed(error) if error == "IMAGE_INVALID"
+        ));
+        assert!(matches!(
+            message(json!({ "event": "OTA_FAILED" })),
+            DeviceMessage::OtaFailed(error) if error == "UNKNOWN"
+        ));
+    }
+
+    #[test]
+    fn key_offer_requires_a_public_key() {
+        assert!(matches!(
+            message(json!({ "event": "KEY_OFFER", "pub_key": "04ab" })),
+            DeviceMessage::KeyOffer(key) if key == "04ab"
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "event": "KEY_OFFER" }))),
+            Err(DecodeError::Invalid("event", _))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(decode(b"\xff\xff"), Err(DecodeError::Cbor(_))));
+        assert!(matches!(
+            decode(&encode(json!(["PONG"]))),
+            Err(DecodeError::NotAMap)
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "v": 2, "event": "PONG" }))),
+            Err(DecodeError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "uptime_ms": 1 }))),
+            Err(DecodeError::UnknownShape)
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "event": 7 }))),
+            Err(DecodeError::Invalid("event", _))
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "heartbeat": true }))),
+            Err(DecodeError::Invalid("heartbeat", _))
+        ));
+        assert!(matches!(
+            decode(&encode(json!({ "lock": "LOCKED" }))),
+            Err(DecodeError::Invalid("lock", _))
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+//! Módulo do histórico de configuração dos dispositivos.
+//!
+//! Cada alteração de configuração gera uma revisão numerada em `device_config_revisions`,
+//! com a configuração completa resultante, quem a alterou e a origem: `USER` (via
+//! `POST /update_config/<uuid>`), `HEARTBEAT` (valor observado no dispositivo que não
+//! corresponde a uma alteração pendente), `PROFILE` (aplicação de um perfil, ver
+//! [`super::profile`]) ou `ROLLBACK` (reversão para uma revisão anterior). Revisões que
+//! não alteram nada não são registradas. Valores sensíveis (ex.: `wifi_pass`) ficam fora
+//! do histórico.
+//!
+//! A reversão reaplica as chaves que diferem da revisão atual pelo mesmo caminho de
+//! `update_config`: as do back-end são gravadas diretamente e as do dispositivo viram
+//! configuração desejada do device twin, enviada via MQTT. A rede Wi-Fi (`wifi_ssid`) não é
+//! revertida, pois a senha correspondente não fica no histórico.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::{State, get, post};
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use super::config;
+use super::device::apply_config;
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{Action, authorize};
+use super::session::AuthUser;
+use super::twin;
+
+/// Número máximo de revisões retornadas por consulta.
+const MAX_REVISIONS_PER_PAGE: i64 = 200;
+
+/// Chaves não revertidas: `wifi_ssid` sem a senha correspondente, que não fica no histórico,
+/// desconectaria o dispositivo.
+const NOT_ROLLED_BACK: &[&str] = &["wifi_ssid"];
+
+/// Configuração completa de uma revisão, por chave.
+pub type Snapshot = BTreeMap<String, String>;
+
+/// Origem de uma revisão.
+#[derive(Clone, Copy)]
+pub enum Source {
+    /// Alteração feita por um usuário.
+    User,
+    /// Valor observado no heartbeat do dispositivo.
+    Heartbeat,
+    /// Aplicação de um perfil de configuração.
+    Profile,
+    /// Reversão para a revisão informada.
+    Rollback(i32),
+}
+
+impl Source {
+    /// Nome da origem, como armazenado no banco.
+    fn name(self) -> &'static str {
+        match self {
+            Source::User => "USER",
+            Source::Heartbeat => "HEARTBEAT",
+            Source::Profile => "PROFILE",
+            Source::Rollback(_) => "ROLLBACK",
+        }
+    }
+}
+
+/// Linha da tabela `device_config_revisions`.
+#[derive(sqlx::FromRow)]
+struct RevisionRow {
+    /// Número da revisão.
+    revision: i32,
+    /// Configuração completa em JSON.
+    config: String,
+    /// Origem da revisão (USER, HEARTBEAT, PROFILE ou ROLLBACK).
+    source: String,
+    /// Usuário que fez a alteração, se houver.
+    actor: Option<String>,
+    /// Revisão revertida, quando `ROLLBACK`.
+    rollback_of: Option<i32>,
+    /// Momento da alteração.
+    created_at: DateTime<Utc>,
+}
+
+/// Revisão de configuração, como retornada pela API.
+#[derive(Serialize)]
+pub struct RevisionInfo {
+    /// Número da revisão.
+    revision: i32,
+    /// Origem da revisão (USER, HEARTBEAT, PROFILE ou ROLLBACK).
+    source: String,
+    /// Usuário que fez a alteração, se houver.
+    actor: Option<String>,
+    /// Revisão revertida, quando `ROLLBACK`.
+    rollback_of: Option<i32>,
+    /// Momento da alteração.
+    created_at: DateTime<Utc>,
+    /// Configuração completa após a alteração.
+    config: Snapshot,
+    /// Chaves alteradas em relação à revisão anterior.
+    changes: BTreeMap<String, Change>,
+}
+
+/// Alteração de uma chave entre duas revisões.
+#[derive(Serialize)]
+pub struct Change {
+    /// Valor na revisão de origem, ou `None` se a chave não existia.
+    from: Option<String>,
+    /// Valor na revisão de destino, ou `None` se a chave deixou de existir.
+    to: Option<String>,
+}
+
+/// Compara duas configurações e retorna as chaves alteradas.
+fn diff(from: &Snapshot, to: &Snapshot) -> BTreeMap<String, Change> {
+    from.keys()
+        .chain(to.keys())
+        .filter(|key| from.get(*key) != to.get(*key))
+        .map(|key| {
+            let change = Change {
+                from: from.get(key).cloned(),
+                to: to.get(key).cloned(),
+            };
+            (key.clone(), change)
+        })
+        .collect()
+}
+
+/// Lê a configuração de uma revisão.
+fn parse(config: &str) -> Result<Snapshot> {
+    Ok(serde_json::from_str(config)?)
+}
+
+/// Registra uma revisão com as alterações informadas aplicadas sobre a revisão atual.
+/// Retorna o número da nova revisão, ou `None` se nada mudou.
+pub async fn record(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    changes: &[(&str, &str)],
+    source: Source,
+    actor: Option<&str>,
+) -> Result<Option<i32>> {
+    let mut tx = db_pool.begin().await?;
+
+    // Serialize revisions of the same device
+    sqlx::query("SELECT 1 FROM devices WHERE uuid = $1 FOR UPDATE")
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await?;
+    let latest: Option<(i32, String)> = sqlx::query_as(
+        "SELECT revision, config::text FROM device_config_revisions WHERE device_id = $1
+         ORDER BY revision DESC LIMIT 1",
+    )
+    .bind(device_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (revision, previous) = match latest {
+        Some((revision, config)) => (revision, parse(&config)?),
+        None => (0, Snapshot::new()),
+    };
+
+    let mut snapshot = previous.clone();
+    for (key, value) in changes {
+        if config::find(key).is_some_and(|k| !k.sensitive) {
+            snapshot.insert(key.to_string(), value.to_string());
+        }
+    }
+    if snapshot == previous {
+        return Ok(None);
+    }
+
+    let rollback_of = match source {
+        Source::Rollback(revision) => Some(revision),
+        _ => None,
+    };
+    sqlx::query(
+        "INSERT INTO device_config_revisions (device_id, revision, config, source, actor, rollback_of)
+         VALUES ($1, $2, $3::jsonb, $4, $5, $6)",
+    )
+    .bind(device_id)
+    .bind(revision + 1)
+    .bind(serde_json::to_string(&snapshot)?)
+    .bind(source.name())
+    .bind(actor)
+    .bind(rollback_of)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(revision + 1))
+}
+
+/// Registra a configuração observada no heartbeat. Chaves com alteração pendente são
+/// ignoradas até que o dispositivo as confirme ou rejeite, para que o valor antigo
+/// reportado nesse meio-tempo não apareça como uma nova revisão.
+pub async fn observe(db_pool: &PgPool, device_id: Uuid) -> Result<()> {
+    let pending: Vec<String> = sqlx::query_scalar(
+        "SELECT key FROM device_desired_config WHERE device_id = $1 AND status = 'PENDING'",
+    )
+    .bind(device_id)
+    .fetch_all(db_pool)
+    .await?;
+
+    let reported = twin::reported_state(db_pool, device_id).await?;
+    let backend = config::backend_state(db_pool, device_id).await?;
+    let changes: Vec<(&str, &str)> = reported
+        .iter()
+        .filter(|(key, _)| !pending.iter().any(|p| p == *key))
+        .chain(backend.iter())
+        .filter_map(|(key, value)| value.as_deref().map(|value| (*key, value)))
+        .collect();
+
+    if let Some(revision) = record(db_pool, device_id, &changes, Source::Heartbeat, None).await? {
+        println!(
+            "DEBUG: Recorded config revision {} of device {} from heartbeat",
+            revision, device_id
+        );
+    }
+    Ok(())
+}
+
+/// Recupera uma revisão de um dispositivo.
+async fn fetch(db_pool: &PgPool, device_id: Uuid, revision: i32) -> Result<Option<RevisionRow>> {
+    Ok(sqlx::query_as(
+        "SELECT revision, config::text AS config, source, actor, rollback_of, created_at
+         FROM device_config_revisions WHERE device_id = $1 AND revision = $2",
+    )
+    .bind(device_id)
+    .bind(revision)
+    .fetch_optional(db_pool)
+    .await?)
+}
+
+/// Recupera as revisões de configuração mais recentes de um dispositivo (`limit` até
+/// 200, padrão 50), da mais recente à mais antiga, cada uma com a configuração completa
+/// e as chaves alteradas em relação à anterior.
+#[get("/config_revisions/<uuid>?<limit>")]
+pub async fn get_revisions(
+    user: AuthUser,
+    uuid: &str,
+    limit: Option<i64>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::ViewEvents).await?;
+
+    // One extra row gives the changes of the oldest revision returned
+    let limit = limit.unwrap_or(50).clamp(1, MAX_REVISIONS_PER_PAGE);
+    let rows: Vec<RevisionRow> = sqlx::query_as(
+        "SELECT revision, config::text AS config, source, actor, rollback_of, created_at
+         FROM device_config_revisions WHERE device_id = $1 ORDER BY revision DESC LIMIT $2",
+    )
+    .bind(uuid)
+    .bind(limit + 1)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let snapshots = rows
+        .iter()
+        .map(|row| parse(&row.config))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|_| Status::InternalServerError)?;
+    let mut revisions = Vec::new();
+    for (i, row) in rows.into_iter().enumerate().take(limit as usize) {
+        // Only the first revision of the device has no predecessor
+        let previous = match snapshots.get(i + 1) {
+            Some(previous) => previous.clone(),
+            None if row.revision == 1 => Snapshot::new(),
+            None => continue,
+        };
+        revisions.push(RevisionInfo {
+            revision: row.revision,
+            source: row.source,
+            actor: row.actor,
+            rollback_of: row.rollback_of,
+            created_at: row.created_at,
+            changes: diff(&previous, &snapshots[i]),
+            config: snapshots[i].clone(),
+        });
+    }
+
+    Ok(serde_json::json!({ "revisions": revisions }).to_string())
+}
+
+/// Compara duas revisões de configuração de um dispositivo e retorna as chaves
+/// alteradas de `from` para `to`.
+#[get("/config_revisions/<uuid>/diff?<from>&<to>")]
+pub async fn diff_revisions(
+    user: AuthUser,
+    uuid: &str,
+    from: i32,
+    to: i32,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::ViewEvents).await?;
+
+    let mut snapshots = Vec::new();
+    for revision in [from, to] {
+        let row = fetch(db_pool, uuid, revision)
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::NotFound)?;
+        snapshots.push(parse(&row.config).map_err(|_| Status::InternalServerError)?);
+    }
+
+    Ok(serde_json::json!({
+        "from": from,
+        "to": to,
+        "changes": diff(&snapshots[0], &snapshots[1])
+    })
+    .to_string())
+}
+
+/// Reverte a configuração de um dispositivo para uma revisão anterior. As chaves que
+/// diferem da revisão atual são reaplicadas como em `POST /update_config/<uuid>`, exceto
+/// [`NOT_ROLLED_BACK`], e a reversão é registrada como uma nova revisão. Retorna o documento do device twin.
+#[post("/config_revisions/<uuid>/rollback/<revision>")]
+pub async fn rollback(
+    user: AuthUser,
+    uuid: &str,
+    revision: i32,
+    db_pool: &State<PgPool>,
+    mqtt_client: &State<AsyncClient>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::UpdateConfig).await?;
+
+    let target = fetch(db_pool, uuid, revision)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    let target = parse(&target.config).map_err(|_| Status::InternalServerError)?;
+    let current: Option<(String,)> = sqlx::query_as(
+        "SELECT config::text FROM device_config_revisions WHERE device_id = $1
+         ORDER BY revision DESC LIMIT 1",
+    )
+    .bind(uuid)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let current = match current {
+        Some((config,)) => parse(&config).map_err(|_| Status::InternalServerError)?,
+        None => Snapshot::new(),
+    };
+
+    // Values are revalidated since the registry may have changed since then
+    let mut configs = Vec::new();
+    for (key, value) in &target {
+        if current.get(key) == Some(value) || NOT_ROLLED_BACK.contains(&key.as_str()) {
+            continue;
+        }
+        let Some(value) = config::find(key).and_then(|k| Some((k, k.validate(value)?))) else {
+            println!(
+                "DEBUG: Skipping config {} = {} of revision {} of device {}: no longer valid",
+                key, value, revision, uuid
+            );
+            continue;
+        };
+        configs.push(value);
+    }
+
+    let keys: Vec<&str> = configs.iter().map(|(key, _)| key.key).collect();
+    record_backend_event(
+        db_pool,
+        uuid,
+        BackendEvent::ConfigUpdateRequested,
+        Some(&user.firebase_uid),
+        serde_json::json!({ "keys": keys, "rollback_to": revision }),
+    )
+    .await;
+
+    apply_config(
+        db_pool,
+        mqtt_client,
+        uuid,
+        &user.firebase_uid,
+        &configs,
+        Source::Rollback(revision),
+    )
+    .await
+}
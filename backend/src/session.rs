@@ -0,0 +1,355 @@
+//! Módulo para sessões de usuário.
+//!
+//! Cada login cria uma sessão na tabela `sessions` e emite um par de tokens: um
+//! token de acesso JWT assinado e de curta duração, e um token de atualização opaco
+//! de longa duração, armazenado apenas como hash. O token de atualização é rotacionado
+//! a cada uso; a reapresentação de um token já rotacionado revoga a sessão inteira.
+//! Um usuário pode manter várias sessões simultâneas (uma por aparelho).
+use anyhow::Result;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State, get, post};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{JWT_KEYS, Token};
+
+/// Audiência dos tokens de acesso, distinta da de outros tokens assinados com as mesmas
+/// chaves (como os códigos de convite).
+const ACCESS_AUDIENCE: &str = "access";
+
+/// Chaves e tempos de vida usados para emitir tokens de sessão.
+pub struct JwtKeys {
+    /// Chave de assinatura dos tokens de acesso.
+    encoding: EncodingKey,
+    /// Chave de verificação dos tokens de acesso.
+    decoding: DecodingKey,
+    /// Tempo de vida do token de acesso.
+    access_ttl: chrono::Duration,
+    /// Tempo de vida do token de atualização (e da sessão).
+    refresh_ttl: chrono::Duration,
+}
+
+impl JwtKeys {
+    /// Cria as chaves HS256 a partir de um segredo compartilhado.
+    pub fn new(secret: &[u8], access_ttl_sec: i64, refresh_ttl_sec: i64) -> Self {
+        JwtKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            access_ttl: chrono::Duration::seconds(access_ttl_sec),
+            refresh_ttl: chrono::Duration::seconds(refresh_ttl_sec),
+        }
+    }
+
+    /// Assina claims arbitrárias, que devem incluir uma audiência própria (`aud`).
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        Ok(jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            claims,
+            &self.encoding,
+        )?)
+    }
+
+    /// Verifica assinatura, expiração e audiência de um token assinado com [`JwtKeys::sign`].
+    /// A audiência obrigatória impede que um tipo de token seja aceito no lugar de outro.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str, audience: &str) -> Option<T> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        validation.set_audience(&[audience]);
+        jsonwebtoken::decode::<T>(token, &self.decoding, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+/// Claims carregadas no token de acesso.
+#[derive(Serialize, Deserialize)]
+pub struct Claims {
+    /// Audiência fixa ([`ACCESS_AUDIENCE`]).
+    pub aud: String,
+    /// UID do Firebase do usuário.
+    pub sub: String,
+    /// ID da sessão que emitiu o token.
+    pub sid: Uuid,
+    /// Momento de emissão (segundos desde a época Unix).
+    pub iat: i64,
+    /// Momento de expiração (segundos desde a época Unix).
+    pub exp: i64,
+}
+
+/// Par de tokens retornado no login e na atualização.
+#[derive(Serialize)]
+pub struct TokenPair {
+    /// Token de acesso JWT.
+    access_token: String,
+    /// Token de atualização opaco.
+    refresh_token: String,
+    /// Tipo do token (sempre "Bearer").
+    token_type: &'static str,
+    /// Segundos até a expiração do token de acesso.
+    expires_in: i64,
+    /// ID da sessão.
+    session_id: Uuid,
+}
+
+/// Estrutura de requisição para atualizar o token de acesso.
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    /// Token de atualização emitido no login ou na última atualização.
+    refresh_token: String,
+}
+
+/// Informações sobre uma sessão retornadas pela API.
+#[derive(sqlx::FromRow, Serialize)]
+pub struct SessionInfo {
+    /// ID da sessão.
+    id: Uuid,
+    /// Nome do aparelho informado no login.
+    device_name: Option<String>,
+    /// Timestamp de criação.
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Timestamp do último uso do token de atualização.
+    last_used_at: chrono::DateTime<chrono::Utc>,
+    /// Timestamp de expiração.
+    expires_at: chrono::DateTime<chrono::Utc>,
+    /// Se esta é a sessão que fez a requisição.
+    #[sqlx(default)]
+    current: bool,
+}
+
+/// Gera um token de atualização aleatório (256 bits, base64 URL-safe).
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Calcula o hash SHA-256 (hexadecimal) de um token de atualização.
+fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Assina um token de acesso para a sessão informada.
+fn issue_access_token(keys: &JwtKeys, firebase_uid: &str, session_id: Uuid) -> Result<String> {
+    let now = Utc::now();
+    keys.sign(&Claims {
+        aud: ACCESS_AUDIENCE.to_string(),
+        sub: firebase_uid.to_string(),
+        sid: session_id,
+        iat: now.timestamp(),
+        exp: (now + keys.access_ttl).timestamp(),
+    })
+}
+
+/// Verifica a assinatura, a expiração e a audiência de um token de acesso e retorna suas
+/// claims.
+pub fn decode_access_token(token: &str) -> Option<Claims> {
+    JWT_KEYS.get().unwrap().verify(token, ACCESS_AUDIENCE)
+}
+
+/// Usuário autenticado, resolvido a partir do [`Token`] uma única vez por requisição.
+///
+/// Rejeita com `401 Unauthorized` tokens ausentes, com assinatura inválida ou expirados,
+/// e tokens de sessões revogadas ou expiradas. O resultado fica em cache no estado local
+/// da requisição, de modo que guardas e handlers subsequentes não repetem a consulta.
+#[derive(Clone)]
+pub struct AuthUser {
+    /// UID do Firebase do usuário.
+    pub firebase_uid: String,
+    /// Nome do usuário.
+    #[allow(dead_code)]
+    pub name: String,
+    /// Email do usuário.
+    #[allow(dead_code)]
+    pub email: String,
+    /// ID da sessão que emitiu o token.
+    pub session_id: Uuid,
+}
+
+/// Valida o token de acesso e a sessão correspondente, retornando o usuário autenticado.
+async fn resolve_user(token: &Token, db_pool: &PgPool) -> Result<AuthUser, Status> {
+    let claims = decode_access_token(&token.0).ok_or(Status::Unauthorized)?;
+
+    let row: Option<(String, String, String)> = sqlx::query_as(
+        "SELECT u.firebase_uid, u.name, u.email FROM sessions s JOIN users u ON u.firebase_uid = s.user_id WHERE s.id = $1 AND s.revoked_at IS NULL AND s.expires_at > NOW()",
+    )
+    .bind(claims.sid)
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    match row {
+        Some((firebase_uid, name, email)) if firebase_uid == claims.sub => Ok(AuthUser {
+            firebase_uid,
+            name,
+            email,
+            session_id: claims.sid,
+        }),
+        _ => Err(Status::Unauthorized),
+    }
+}
+
+/// Implementação de FromRequest para AuthUser, construída sobre o guarda [`Token`].
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthUser {
+    type Error = &'static str;
+
+    /// Resolve o usuário do token da requisição, usando o cache local da requisição.
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let result: &Result<AuthUser, Status> = req
+            .local_cache_async(async {
+                let token = match req.guard::<Token>().await {
+                    Outcome::Success(token) => token,
+                    _ => return Err(Status::Unauthorized),
+                };
+                let db_pool = match req.guard::<&State<PgPool>>().await {
+                    Outcome::Success(db_pool) => db_pool,
+                    _ => return Err(Status::InternalServerError),
+                };
+                resolve_user(&token, db_pool).await
+            })
+            .await;
+
+        match result {
+            Ok(user) => Outcome::Success(user.clone()),
+            Err(status) => Outcome::Error((*status, "Invalid, expired or revoked session")),
+        }
+    }
+}
+
+/// Cria uma nova sessão para o usuário e retorna o par de tokens inicial.
+pub async fn create_session(
+    db_pool: &PgPool,
+    firebase_uid: &str,
+    device_name: Option<&str>,
+) -> Result<TokenPair> {
+    let keys = JWT_KEYS.get().unwrap();
+    let session_id = Uuid::new_v4();
+    let refresh_token = generate_refresh_token();
+
+    sqlx::query(
+        "INSERT INTO sessions (id, user_id, refresh_token_hash, device_name, expires_at) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(session_id)
+    .bind(firebase_uid)
+    .bind(hash_refresh_token(&refresh_token))
+    .bind(device_name)
+    .bind(Utc::now() + keys.refresh_ttl)
+    .execute(db_pool)
+    .await?;
+
+    Ok(TokenPair {
+        access_token: issue_access_token(keys, firebase_uid, session_id)?,
+        refresh_token,
+        token_type: "Bearer",
+        expires_in: keys.access_ttl.num_seconds(),
+        session_id,
+    })
+}
+
+/// Revoga uma sessão do usuário. Retorna `false` se a sessão não existir ou já estiver revogada.
+pub async fn revoke(db_pool: &PgPool, firebase_uid: &str, session_id: Uuid) -> Result<bool> {
+    let rows_affected = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .bind(firebase_uid)
+    .execute(db_pool)
+    .await?
+    .rows_affected();
+    Ok(rows_affected > 0)
+}
+
+/// Troca um token de atualização por um novo par de tokens, rotacionando o token de atualização.
+/// A reutilização de um token já rotacionado indica vazamento e revoga a sessão.
+#[post("/refresh", data = "<request>")]
+pub async fn refresh_session(
+    request: rocket::serde::json::Json<RefreshRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let keys = JWT_KEYS.get().unwrap();
+    let presented_hash = hash_refresh_token(&request.refresh_token);
+
+    // Detect reuse of an already rotated refresh token
+    let reused: Option<(Uuid,)> = sqlx::query_as(
+        "UPDATE sessions SET revoked_at = NOW() WHERE previous_refresh_token_hash = $1 AND revoked_at IS NULL RETURNING id",
+    )
+    .bind(&presented_hash)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    if let Some((session_id,)) = reused {
+        println!(
+            "DEBUG: Refresh token reuse detected, revoked session {}",
+            session_id
+        );
+        return Err(Status::Unauthorized);
+    }
+
+    // Rotate the refresh token
+    let new_refresh_token = generate_refresh_token();
+    let row: Option<(Uuid, String)> = sqlx::query_as(
+        "UPDATE sessions SET previous_refresh_token_hash = refresh_token_hash, refresh_token_hash = $1, last_used_at = NOW() WHERE refresh_token_hash = $2 AND revoked_at IS NULL AND expires_at > NOW() RETURNING id, user_id",
+    )
+    .bind(hash_refresh_token(&new_refresh_token))
+    .bind(&presented_hash)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let (session_id, firebase_uid) = row.ok_or(Status::Unauthorized)?;
+
+    let pair = TokenPair {
+        access_token: issue_access_token(keys, &firebase_uid, session_id)
+            .map_err(|_| Status::InternalServerError)?,
+        refresh_token: new_refresh_token,
+        token_type: "Bearer",
+        expires_in: keys.access_ttl.num_seconds(),
+        session_id,
+    };
+
+    Ok(serde_json::to_string(&pair).unwrap())
+}
+
+/// Lista as sessões ativas do usuário.
+#[get("/sessions")]
+pub async fn get_sessions(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let mut sessions: Vec<SessionInfo> = sqlx::query_as(
+        "SELECT id, device_name, created_at, last_used_at, expires_at FROM sessions WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW() ORDER BY last_used_at DESC",
+    )
+    .bind(&user.firebase_uid)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    for session in &mut sessions {
+        session.current = session.id == user.session_id;
+    }
+
+    Ok(serde_json::to_string(&sessions).unwrap())
+}
+
+/// Revoga uma sessão do usuário (ex.: um aparelho perdido).
+#[post("/revoke_session/<session_id>")]
+pub async fn revoke_session(
+    user: AuthUser,
+    session_id: &str,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    let session_id = Uuid::parse_str(session_id).map_err(|_| Status::BadRequest)?;
+
+    if revoke(db_pool, &user.firebase_uid, session_id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+    {
+        Ok(())
+    } else {
+        Err(Status::NotFound)
+    }
+}
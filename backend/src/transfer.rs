@@ -0,0 +1,439 @@
+//! Módulo para transferência de propriedade de dispositivos.
+//!
+//! O proprietário solicita a transferência para outro usuário, que a aceita ou rejeita.
+//! Ao aceitar, o novo proprietário assume o dispositivo sem novo pareamento. Convites e
+//! membros podem ser mantidos (passando a ser geridos pelo novo proprietário) e o
+//! histórico pode ser mantido no dispositivo ou arquivado sob o proprietário anterior.
+//! Ambas as partes são avisadas via WebSocket.
+use rocket::http::Status;
+use rocket::{State, get, post};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::channel::{self, control_payload};
+use super::event::{BackendEvent, record_backend_event};
+use super::member::{Action, authorize};
+use super::notify_users;
+use super::session::AuthUser;
+
+/// Estrutura de requisição para solicitar uma transferência.
+#[derive(Deserialize)]
+pub struct TransferRequest {
+    /// Email do novo proprietário.
+    new_owner_email: String,
+    /// Se convites e membros devem ser mantidos.
+    #[serde(default)]
+    keep_invites: bool,
+    /// Destino do histórico: "keep" (mantido no dispositivo) ou "archive"
+    /// (arquivado sob o proprietário anterior).
+    #[serde(default = "default_history")]
+    history: String,
+}
+
+/// Destino padrão do histórico.
+fn default_history() -> String {
+    "keep".to_string()
+}
+
+/// Informações de uma transferência pendente.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct TransferInfo {
+    /// ID da transferência.
+    id: i32,
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// ID do proprietário atual.
+    from_user_id: String,
+    /// Nome do proprietário atual.
+    from_name: Option<String>,
+    /// ID do novo proprietário.
+    to_user_id: String,
+    /// Nome do novo proprietário.
+    to_name: Option<String>,
+    /// Se convites e membros serão mantidos.
+    keep_invites: bool,
+    /// Destino do histórico ("keep" ou "archive").
+    history: String,
+    /// Momento da solicitação.
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Entrada de log arquivada após uma transferência.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ArchivedLogEntry {
+    /// ID da entrada de log.
+    id: i32,
+    /// UUID do dispositivo como string.
+    device_id: String,
+    /// Timestamp da entrada de log.
+    timestamp: chrono::DateTime<chrono::Utc>,
+    /// Tipo de evento (LOCK/UNLOCK).
+    event_type: String,
+    /// Motivo do evento.
+    reason: String,
+    /// ID do usuário que acionou o evento.
+    user_id: Option<String>,
+    /// Momento do arquivamento.
+    archived_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Linha de transferência pendente usada ao aceitá-la.
+#[derive(sqlx::FromRow)]
+struct PendingTransferRow {
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// ID do proprietário atual.
+    from_user_id: String,
+    /// Se convites e membros serão mantidos.
+    keep_invites: bool,
+    /// Destino do histórico.
+    history: String,
+}
+
+/// Solicita a transferência de um dispositivo para outro usuário.
+#[post("/transfer/<uuid>", data = "<request>")]
+pub async fn request_transfer(
+    user: AuthUser,
+    uuid: &str,
+    request: rocket::serde::json::Json<TransferRequest>,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    let from_user_id = user.firebase_uid;
+
+    authorize(db_pool, uuid, &from_user_id, Action::Unpair).await?;
+
+    if request.history != "keep" && request.history != "archive" {
+        return Err(Status::BadRequest);
+    }
+
+    // Find new owner by email
+    let to_row: Option<(String,)> =
+        sqlx::query_as("SELECT firebase_uid FROM users WHERE email = $1")
+            .bind(&request.new_owner_email)
+            .fetch_optional(&**db_pool)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    let (to_user_id,) = to_row.ok_or(Status::NotFound)?;
+    if to_user_id == from_user_id {
+        return Err(Status::BadRequest);
+    }
+
+    // A device has at most one pending transfer
+    let transfer_id: Option<i32> = sqlx::query_scalar(
+        "INSERT INTO device_transfers (device_id, from_user_id, to_user_id, keep_invites, history) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (device_id) WHERE status = 0 DO NOTHING RETURNING id",
+    )
+    .bind(uuid)
+    .bind(&from_user_id)
+    .bind(&to_user_id)
+    .bind(request.keep_invites)
+    .bind(&request.history)
+    .fetch_optional(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let transfer_id = transfer_id.ok_or(Status::Conflict)?;
+
+    notify_users(
+        &[to_user_id],
+        &serde_json::json!({
+            "type": "transfer_request",
+            "transfer_id": transfer_id,
+            "device_id": uuid.to_string(),
+            "from_user_id": from_user_id
+        })
+        .to_string(),
+    );
+
+    Ok(serde_json::json!({ "transfer_id": transfer_id }).to_string())
+}
+
+/// Lista as transferências pendentes enviadas e recebidas pelo usuário.
+#[get("/transfers")]
+pub async fn get_transfers(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let transfers: Vec<TransferInfo> = sqlx::query_as(
+        "SELECT t.id, t.device_id, t.from_user_id, fu.name AS from_name, t.to_user_id, tu.name AS to_name, t.keep_invites, t.history, t.created_at
+         FROM device_transfers t
+         LEFT JOIN users fu ON t.from_user_id = fu.firebase_uid
+         LEFT JOIN users tu ON t.to_user_id = tu.firebase_uid
+         WHERE t.status = 0 AND (t.from_user_id = $1 OR t.to_user_id = $1)
+         ORDER BY t.created_at DESC",
+    )
+    .bind(&user.firebase_uid)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let (sent, received): (Vec<_>, Vec<_>) = transfers
+        .into_iter()
+        .partition(|t| t.from_user_id == user.firebase_uid);
+
+    Ok(serde_json::json!({
+        "sent": sent,
+        "received": received
+    })
+    .to_string())
+}
+
+/// Aceita uma transferência: o usuário passa a ser o proprietário do dispositivo.
+#[post("/accept_transfer/<transfer_id>")]
+pub async fn accept_transfer(
+    user: AuthUser,
+    transfer_id: i32,
+    db_pool: &State<PgPool>,
+    mqtt_client: &State<AsyncClient>,
+) -> Result<(), Status> {
+    let to_user_id = user.firebase_uid;
+
+    let mut tx = db_pool
+        .begin()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let transfer: Option<PendingTransferRow> = sqlx::query_as(
+        "UPDATE device_transfers SET status = 1, resolved_at = NOW() WHERE id = $1 AND to_user_id = $2 AND status = 0
+         RETURNING device_id, from_user_id, keep_invites, history",
+    )
+    .bind(transfer_id)
+    .bind(&to_user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let transfer = transfer.ok_or(Status::NotFound)?;
+    let device_id = transfer.device_id;
+
+    // The device may have been unpaired since the request
+    let rows_affected =
+        sqlx::query("UPDATE devices SET user_id = $1 WHERE uuid = $2 AND user_id = $3")
+            .bind(&to_user_id)
+            .bind(device_id)
+            .bind(&transfer.from_user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .rows_affected();
+    if rows_affected == 0 {
+        return Err(Status::Conflict);
+    }
+
+    if transfer.keep_invites {
+        // The new owner manages the existing invites and no longer needs their own access
+        sqlx::query("UPDATE invites SET sender_id = $1 WHERE device_id = $2")
+            .bind(&to_user_id)
+            .bind(device_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        sqlx::query("DELETE FROM invites WHERE device_id = $1 AND receiver_id = $2")
+            .bind(device_id)
+            .bind(&to_user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        sqlx::query("DELETE FROM device_members WHERE device_id = $1 AND user_id = $2")
+            .bind(device_id)
+            .bind(&to_user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    } else {
+        sqlx::query("DELETE FROM invites WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        sqlx::query("DELETE FROM device_members WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    }
+
+    // The previous owner's profiles are not visible to the new owner
+    sqlx::query("DELETE FROM device_config_profiles WHERE device_id = $1")
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // Firmware updates not yet sent were created with the previous owner's permission
+    sqlx::query(
+        "UPDATE ota_updates SET status = 'CANCELLED', updated_at = NOW() WHERE device_id = $1 AND status = 'QUEUED'",
+    )
+    .bind(device_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    if transfer.history == "archive" {
+        sqlx::query(
+            "INSERT INTO archived_logs (id, device_id, owner_id, timestamp, event_type, reason, user_id)
+             SELECT id, device_id, $2, timestamp, event_type, reason, user_id FROM logs WHERE device_id = $1",
+        )
+        .bind(device_id.to_string())
+        .bind(&transfer.from_user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+        sqlx::query("DELETE FROM logs WHERE device_id = $1")
+            .bind(device_id.to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    }
+
+    tx.commit().await.map_err(|_| Status::InternalServerError)?;
+
+    record_backend_event(
+        db_pool,
+        device_id,
+        BackendEvent::OwnershipTransferred,
+        Some(&to_user_id),
+        serde_json::json!({
+            "from_user_id": transfer.from_user_id,
+            "keep_invites": transfer.keep_invites,
+            "history": transfer.history
+        }),
+    )
+    .await;
+
+    // Keep the owner stored on the device in sync
+    if let Ok(payload) = control_payload(
+        db_pool,
+        device_id,
+        &serde_json::json!({
+            "command": "update_config",
+            "key": "user_id",
+            "value": to_user_id
+        }),
+    )
+    .await
+    {
+        let _ = mqtt_client
+            .publish(
+                format!("lockwise/{}/control", device_id),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .await;
+    }
+
+    // The previous owner must not keep any way into the channel of the device
+    if let Err(e) = channel::request_rekey(db_pool, mqtt_client, device_id).await {
+        println!(
+            "DEBUG: Failed to request key of device {}: {}",
+            device_id, e
+        );
+    }
+
+    notify_users(
+        &[transfer.from_user_id, to_user_id],
+        &serde_json::json!({
+            "type": "transfer_accepted",
+            "transfer_id": transfer_id,
+            "device_id": device_id.to_string()
+        })
+        .to_string(),
+    );
+
+    Ok(())
+}
+
+/// Rejeita uma transferência recebida.
+#[post("/reject_transfer/<transfer_id>")]
+pub async fn reject_transfer(
+    user: AuthUser,
+    transfer_id: i32,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    resolve(
+        db_pool,
+        transfer_id,
+        &user.firebase_uid,
+        2,
+        "transfer_rejected",
+    )
+    .await
+}
+
+/// Cancela uma transferência enviada.
+#[post("/cancel_transfer/<transfer_id>")]
+pub async fn cancel_transfer(
+    user: AuthUser,
+    transfer_id: i32,
+    db_pool: &State<PgPool>,
+) -> Result<(), Status> {
+    resolve(
+        db_pool,
+        transfer_id,
+        &user.firebase_uid,
+        3,
+        "transfer_cancelled",
+    )
+    .await
+}
+
+/// Encerra uma transferência pendente sem efeito (2: rejeitada pelo destinatário,
+/// 3: cancelada pelo remetente) e avisa ambas as partes.
+async fn resolve(
+    db_pool: &PgPool,
+    transfer_id: i32,
+    user_id: &str,
+    status: i16,
+    event: &str,
+) -> Result<(), Status> {
+    let column = if status == 2 {
+        "to_user_id"
+    } else {
+        "from_user_id"
+    };
+    let row: Option<(Uuid, String, String)> = sqlx::query_as(&format!(
+        "UPDATE device_transfers SET status = $1, resolved_at = NOW() WHERE id = $2 AND {} = $3 AND status = 0
+         RETURNING device_id, from_user_id, to_user_id",
+        column
+    ))
+    .bind(status)
+    .bind(transfer_id)
+    .bind(user_id)
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+    let (device_id, from_user_id, to_user_id) = row.ok_or(Status::NotFound)?;
+
+    notify_users(
+        &[from_user_id, to_user_id],
+        &serde_json::json!({
+            "type": event,
+            "transfer_id": transfer_id,
+            "device_id": device_id.to_string()
+        })
+        .to_string(),
+    );
+
+    Ok(())
+}
+
+/// Recupera o histórico arquivado de um dispositivo transferido pelo usuário.
+#[get("/archived_logs/<uuid>")]
+pub async fn get_archived_logs(
+    user: AuthUser,
+    uuid: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    let logs: Vec<ArchivedLogEntry> = sqlx::query_as(
+        "SELECT id, device_id, timestamp, event_type, reason, user_id, archived_at FROM archived_logs WHERE device_id = $1 AND owner_id = $2 ORDER BY timestamp DESC LIMIT 1000",
+    )
+    .bind(uuid.to_string())
+    .bind(&user.firebase_uid)
+    .fetch_all(&**db_pool)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    Ok(serde_json::to_string(&logs).unwrap())
+}
@@ -0,0 +1,389 @@
+//! Módulo do device twin: configuração desejada e reportada dos dispositivos.
+//!
+//! `POST /update_config/<uuid>` apenas registra a configuração desejada de cada chave em
+//! `device_desired_config`, com estado `PENDING`. O estado reportado é o último heartbeat,
+//! armazenado em `devices`. Um reconciliador reenvia as chaves pendentes, cada envio com
+//! um `command_id` que o dispositivo ecoa em `CONFIG_UPDATED` ou no erro correspondente,
+//! até que a chave seja confirmada (`APPLIED`) ou esgote as tentativas (`FAILED`). Cada
+//! heartbeat também confirma as chaves cujo valor reportado já é o desejado e devolve a
+//! `PENDING` as que divergiram depois de aplicadas. Valores sensíveis (ex.: `wifi_pass`)
+//! não são reportados; são confirmados apenas pelo `CONFIG_UPDATED` e apagados do banco
+//! assim que deixam de estar pendentes.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::{State, get};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use super::channel;
+use super::config::{self, Location};
+use super::member::{Action, authorize};
+use super::mqtt::{broadcast_to_viewers, is_connected};
+use super::session::AuthUser;
+
+/// Intervalo mínimo entre reenvios de uma chave pendente, em segundos.
+const CONFIG_RETRY_INTERVAL_SEC: i64 = 30;
+/// Número de envios sem resposta após o qual a chave é marcada como `FAILED`.
+const MAX_CONFIG_ATTEMPTS: i32 = 5;
+
+/// Configuração desejada de uma chave, como retornada pela API.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct DesiredConfig {
+    /// Chave de configuração.
+    key: String,
+    /// Valor desejado (omitido para valores sensíveis).
+    value: Option<String>,
+    /// Estado da chave (PENDING, APPLIED ou FAILED).
+    status: String,
+    /// Número de envios desde a última alteração.
+    attempts: i32,
+    /// Motivo da falha, quando `FAILED`.
+    error: Option<String>,
+    /// Usuário que definiu o valor.
+    requested_by: Option<String>,
+    /// Momento da última alteração do valor desejado.
+    updated_at: DateTime<Utc>,
+    /// Momento em que o dispositivo confirmou o valor.
+    applied_at: Option<DateTime<Utc>>,
+}
+
+/// Chave pendente a enviar ao dispositivo.
+#[derive(sqlx::FromRow)]
+struct PendingConfigRow {
+    /// UUID do dispositivo.
+    device_id: Uuid,
+    /// Chave de configuração.
+    key: String,
+    /// Valor desejado.
+    value: String,
+}
+
+/// Registra a configuração desejada das chaves informadas, que voltam a `PENDING`. Os
+/// valores devem ter sido validados com [`config::ConfigKey::validate`].
+pub async fn set_desired(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    user_id: &str,
+    configs: &[(&str, &str)],
+) -> Result<()> {
+    let mut tx = db_pool.begin().await?;
+    for (key, value) in configs {
+        sqlx::query(
+            "INSERT INTO device_desired_config (device_id, key, value, requested_by) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (device_id, key) DO UPDATE SET value = $3, requested_by = $4, status = 'PENDING',
+             attempts = 0, command_id = NULL, error = NULL, updated_at = NOW(), last_sent_at = NULL, applied_at = NULL",
+        )
+        .bind(device_id)
+        .bind(key)
+        .bind(value)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Marca como `FAILED` as chaves que esgotaram as tentativas e reenvia as pendentes.
+/// Com `device_id`, reenvia imediatamente todas as chaves pendentes do dispositivo (ex.:
+/// ao se reconectar); sem ele, apenas as de dispositivos online que aguardam há mais de
+/// [`CONFIG_RETRY_INTERVAL_SEC`] segundos.
+pub async fn reconcile(
+    db_pool: &PgPool,
+    mqtt_client: &AsyncClient,
+    device_id: Option<Uuid>,
+) -> Result<()> {
+    let failed: Vec<(Uuid, String)> = sqlx::query_as(
+        "UPDATE device_desired_config SET status = 'FAILED', error = 'TIMEOUT',
+         value = CASE WHEN key = ANY($3) THEN NULL ELSE value END
+         WHERE status = 'PENDING' AND attempts >= $1 AND last_sent_at < NOW() - make_interval(secs => $2)
+         RETURNING device_id, key",
+    )
+    .bind(MAX_CONFIG_ATTEMPTS)
+    .bind(CONFIG_RETRY_INTERVAL_SEC as f64)
+    .bind(config::sensitive_keys())
+    .fetch_all(db_pool)
+    .await?;
+    for (device_id, key) in failed {
+        println!(
+            "DEBUG: Device {} did not apply config {} after {} attempts",
+            device_id, key, MAX_CONFIG_ATTEMPTS
+        );
+        notify_status(db_pool, device_id, &key, "FAILED", Some("TIMEOUT")).await;
+    }
+
+    if !is_connected() {
+        return Ok(());
+    }
+
+    let pending: Vec<PendingConfigRow> = sqlx::query_as(
+        "SELECT c.device_id, c.key, c.value FROM device_desired_config c JOIN devices d ON d.uuid = c.device_id
+         WHERE c.status = 'PENDING' AND c.value IS NOT NULL
+         AND ($1::uuid IS NULL OR c.device_id = $1)
+         AND ($1::uuid IS NOT NULL OR (d.online AND (c.last_sent_at IS NULL OR c.last_sent_at < NOW() - make_interval(secs => $2))))
+         ORDER BY c.device_id, c.updated_at",
+    )
+    .bind(device_id)
+    .bind(CONFIG_RETRY_INTERVAL_SEC as f64)
+    .fetch_all(db_pool)
+    .await?;
+
+    for config in &pending {
+        send(db_pool, mqtt_client, config).await;
+    }
+    Ok(())
+}
+
+/// Envia uma chave pendente ao dispositivo e registra a tentativa.
+async fn send(db_pool: &PgPool, mqtt_client: &AsyncClient, config: &PendingConfigRow) {
+    let device_key = match channel::control_key(db_pool, config.device_id).await {
+        Ok(key) => key,
+        Err(e) => {
+            println!(
+                "DEBUG: Failed to load key of device {}: {}",
+                config.device_id, e
+            );
+            return;
+        }
+    };
+
+    // Record the attempt first so a fast answer finds its command ID
+    let command_id = Uuid::new_v4();
+    let _ = sqlx::query(
+        "UPDATE device_desired_config SET attempts = attempts + 1, last_sent_at = NOW(), command_id = $3
+         WHERE device_id = $1 AND key = $2 AND status = 'PENDING'",
+    )
+    .bind(config.device_id)
+    .bind(&config.key)
+    .bind(command_id)
+    .execute(db_pool)
+    .await;
+
+    let payload = match channel::config_message(
+        device_key.as_ref(),
+        &config.key,
+        &config.value,
+        command_id,
+    ) {
+        Ok(msg) => channel::wrap(db_pool, config.device_id, device_key.as_ref(), &msg).await,
+        Err(e) => Err(e),
+    };
+    let Ok(payload) = payload else {
+        // Sensitive values are never sent in clear to a device without key
+        reject(
+            db_pool,
+            config.device_id,
+            &command_id.to_string(),
+            "NO_DEVICE_KEY",
+        )
+        .await;
+        return;
+    };
+
+    let topic = format!("lockwise/{}/control", config.device_id);
+    if let Err(e) = mqtt_client
+        .publish(topic, QoS::AtLeastOnce, false, payload)
+        .await
+    {
+        println!(
+            "DEBUG: Failed to send config {} to device {}: {}",
+            config.key, config.device_id, e
+        );
+    }
+}
+
+/// Confirma a chave enviada com o `command_id` ecoado em `CONFIG_UPDATED`.
+pub async fn acknowledge(db_pool: &PgPool, device_id: Uuid, command_id: &str) {
+    let Ok(command_id) = Uuid::parse_str(command_id) else {
+        return;
+    };
+    let row: Option<(String,)> = sqlx::query_as(
+        "UPDATE device_desired_config SET status = 'APPLIED', applied_at = NOW(), error = NULL,
+         value = CASE WHEN key = ANY($3) THEN NULL ELSE value END
+         WHERE device_id = $1 AND command_id = $2 AND status <> 'APPLIED' RETURNING key",
+    )
+    .bind(device_id)
+    .bind(command_id)
+    .bind(config::sensitive_keys())
+    .fetch_optional(db_pool)
+    .await
+    .unwrap_or(None);
+    if let Some((key,)) = row {
+        notify_status(db_pool, device_id, &key, "APPLIED", None).await;
+    }
+}
+
+/// Marca como `FAILED` a chave enviada com o `command_id` ecoado em um erro do
+/// dispositivo (ex.: `UPDATE_CONFIG_FAILED`).
+pub async fn reject(db_pool: &PgPool, device_id: Uuid, command_id: &str, reason: &str) {
+    let Ok(command_id) = Uuid::parse_str(command_id) else {
+        return;
+    };
+    let row: Option<(String,)> = sqlx::query_as(
+        "UPDATE device_desired_config SET status = 'FAILED', error = $3,
+         value = CASE WHEN key = ANY($4) THEN NULL ELSE value END
+         WHERE device_id = $1 AND command_id = $2 AND status = 'PENDING' RETURNING key",
+    )
+    .bind(device_id)
+    .bind(command_id)
+    .bind(reason)
+    .bind(config::sensitive_keys())
+    .fetch_optional(db_pool)
+    .await
+    .unwrap_or(None);
+    if let Some((key,)) = row {
+        println!(
+            "DEBUG: Device {} rejected config {}: {}",
+            device_id, key, reason
+        );
+        notify_status(db_pool, device_id, &key, "FAILED", Some(reason)).await;
+    }
+}
+
+/// Compara a configuração desejada com a reportada no último heartbeat: confirma as
+/// chaves que já têm o valor desejado e devolve a `PENDING` as aplicadas que divergiram.
+pub async fn compare_reported(db_pool: &PgPool, device_id: Uuid) -> Result<()> {
+    let reported = reported_state(db_pool, device_id).await?;
+    let desired: Vec<(String, Option<String>, String)> =
+        sqlx::query_as("SELECT key, value, status FROM device_desired_config WHERE device_id = $1")
+            .bind(device_id)
+            .fetch_all(db_pool)
+            .await?;
+
+    for (key, value, status) in desired {
+        let (Some(value), Some(Some(reported))) = (value, reported.get(key.as_str())) else {
+            continue;
+        };
+        let new_status = match (status.as_str(), *reported == value) {
+            ("PENDING" | "FAILED", true) => "APPLIED",
+            ("APPLIED", false) => "PENDING",
+            _ => continue,
+        };
+        if new_status == "PENDING" {
+            println!(
+                "DEBUG: Device {} reports {} = {}, expected {}; resending",
+                device_id, key, reported, value
+            );
+        }
+
+        let updated = sqlx::query(
+            "UPDATE device_desired_config SET status = $3, error = NULL,
+             applied_at = CASE WHEN $3 = 'APPLIED' THEN NOW() ELSE applied_at END,
+             attempts = CASE WHEN $3 = 'PENDING' THEN 0 ELSE attempts END,
+             last_sent_at = CASE WHEN $3 = 'PENDING' THEN NULL ELSE last_sent_at END
+             WHERE device_id = $1 AND key = $2 AND status = $4",
+        )
+        .bind(device_id)
+        .bind(&key)
+        .bind(new_status)
+        .bind(&status)
+        .execute(db_pool)
+        .await?;
+        if updated.rows_affected() > 0 {
+            notify_status(db_pool, device_id, &key, new_status, None).await;
+        }
+    }
+    Ok(())
+}
+
+/// Recupera a configuração reportada no último heartbeat, por chave.
+pub async fn reported_state(
+    db_pool: &PgPool,
+    device_id: Uuid,
+) -> Result<BTreeMap<&'static str, Option<String>>> {
+    let columns: Vec<(&'static str, &'static str)> = config::device_keys()
+        .filter_map(|k| match k.location {
+            Location::Device {
+                reported: Some(expr),
+            } => Some((k.key, expr)),
+            _ => None,
+        })
+        .collect();
+    let select: Vec<String> = columns
+        .iter()
+        .map(|(key, expr)| format!("{} AS {}", expr, key))
+        .collect();
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM devices WHERE uuid = $1",
+        select.join(", ")
+    ))
+    .bind(device_id)
+    .fetch_optional(db_pool)
+    .await?;
+
+    let mut reported = BTreeMap::new();
+    if let Some(row) = row {
+        for (key, _) in columns {
+            reported.insert(key, row.try_get::<Option<String>, _>(key)?);
+        }
+    }
+    Ok(reported)
+}
+
+/// Envia a mudança de estado de uma chave via WebSocket a todos que podem ver o
+/// dispositivo.
+async fn notify_status(
+    db_pool: &PgPool,
+    device_id: Uuid,
+    key: &str,
+    status: &str,
+    error: Option<&str>,
+) {
+    let update = serde_json::json!({
+        "type": "config_status",
+        "device_id": device_id.to_string(),
+        "key": key,
+        "status": status,
+        "error": error
+    })
+    .to_string();
+    broadcast_to_viewers(db_pool, device_id, &update).await;
+}
+
+/// Monta o documento do twin: configuração desejada, com o estado de cada chave, e a
+/// configuração reportada.
+pub async fn document(db_pool: &PgPool, device_id: Uuid) -> Result<String> {
+    let mut desired: Vec<DesiredConfig> = sqlx::query_as(
+        "SELECT key, value, status, attempts, error, requested_by, updated_at, applied_at
+         FROM device_desired_config WHERE device_id = $1 ORDER BY key",
+    )
+    .bind(device_id)
+    .fetch_all(db_pool)
+    .await?;
+    for entry in &mut desired {
+        if config::find(&entry.key).is_some_and(|k| k.sensitive) {
+            entry.value = None;
+        }
+    }
+    let in_sync = desired.iter().all(|c| c.status == "APPLIED");
+    let reported = reported_state(db_pool, device_id).await?;
+
+    Ok(serde_json::json!({
+        "device_id": device_id.to_string(),
+        "desired": desired,
+        "reported": reported,
+        "in_sync": in_sync
+    })
+    .to_string())
+}
+
+/// Recupera o twin de um dispositivo: configuração desejada com o estado de cada chave
+/// (PENDING, APPLIED ou FAILED) e configuração reportada no último heartbeat.
+#[get("/config/<uuid>")]
+pub async fn get_config(
+    user: AuthUser,
+    uuid: &str,
+    db_pool: &State<PgPool>,
+) -> Result<String, Status> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| Status::BadRequest)?;
+
+    authorize(db_pool, uuid, &user.firebase_uid, Action::View).await?;
+
+    document(db_pool, uuid)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
@@ -12,15 +12,16 @@ use rocket::{State, get, post};
 use serde::Deserialize;
 use sqlx::PgPool;
 use tokio::io::AsyncReadExt;
-use uuid::Uuid;
 
-use super::{SpeechbrainUrl, Token};
+use super::SpeechbrainUrl;
+use super::firebase::FirebaseVerifier;
+use super::session::{self, AuthUser};
 
 /// Estrutura de requisição para registro de usuário.
 #[derive(Deserialize)]
 pub struct RegisterRequest {
-    /// UID do Firebase para autenticação.
-    firebase_uid: String,
+    /// ID token do Firebase; o UID é extraído das claims verificadas.
+    id_token: String,
     /// Senha do usuário.
     password: String,
     /// Email do usuário.
@@ -34,9 +35,12 @@ pub struct RegisterRequest {
 /// Estrutura de requisição para login de usuário.
 #[derive(Deserialize)]
 pub struct LoginRequest {
-    /// UID do Firebase.
-    firebase_uid: String,
+    /// ID token do Firebase; o UID é extraído das claims verificadas.
+    id_token: String,
     password: String,
+    /// Nome do aparelho, exibido na lista de sessões.
+    #[serde(default)]
+    device_name: Option<String>,
 }
 
 /// Estrutura de requisição para atualizar número de telefone do usuário.
@@ -59,12 +63,19 @@ pub struct VerifyPasswordRequest {
     password: String,
 }
 
-/// Registra um novo usuário com senha hashada.
+/// Registra um novo usuário com senha hashada, usando o UID do ID token verificado.
 #[post("/register", data = "<request>")]
 pub async fn register_user(
     request: rocket::serde::json::Json<RegisterRequest>,
     db_pool: &State<PgPool>,
+    firebase: &State<FirebaseVerifier>,
 ) -> Result<(), Status> {
+    let firebase_uid = firebase
+        .verify(&request.id_token)
+        .await
+        .ok_or(Status::Unauthorized)?
+        .sub;
+
     // Hash the password
     let salt =
         argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
@@ -78,7 +89,7 @@ pub async fn register_user(
     sqlx::query(
         "INSERT INTO users (firebase_uid, hashed_password, email, phone_number, name, created_at) VALUES ($1, $2, $3, $4, $5, NOW())"
     )
-    .bind(&request.firebase_uid)
+    .bind(&firebase_uid)
     .bind(&hashed_password)
     .bind(&request.email)
     .bind(&request.phone_number)
@@ -90,16 +101,24 @@ pub async fn register_user(
     Ok(())
 }
 
-/// Autentica usuário e retorna token JWT.
+/// Autentica usuário a partir do ID token verificado e da senha, abre uma nova sessão
+/// e retorna os tokens de acesso e atualização.
 #[post("/login", data = "<request>")]
 pub async fn login_user(
     request: rocket::serde::json::Json<LoginRequest>,
     db_pool: &State<PgPool>,
+    firebase: &State<FirebaseVerifier>,
 ) -> Result<String, Status> {
+    let firebase_uid = firebase
+        .verify(&request.id_token)
+        .await
+        .ok_or(Status::Unauthorized)?
+        .sub;
+
     // Fetch user
     let row: Option<(String,)> =
         sqlx::query_as("SELECT hashed_password FROM users WHERE firebase_uid = $1")
-            .bind(&request.firebase_uid)
+            .bind(&firebase_uid)
             .fetch_optional(&**db_pool)
             .await
             .map_err(|_| Status::InternalServerError)?;
@@ -112,28 +131,27 @@ pub async fn login_user(
                 .verify_password(request.password.as_bytes(), &hash)
                 .is_ok()
             {
-                // Generate token
-                let token = Uuid::new_v4().to_string();
-                // Invalidate old token and set new
-                sqlx::query("UPDATE users SET current_token = $1, last_login = NOW() WHERE firebase_uid = $2")
-                    .bind(&token)
-                    .bind(&request.firebase_uid)
+                // Open a new session for this device
+                let pair =
+                    session::create_session(db_pool, &firebase_uid, request.device_name.as_deref())
+                        .await
+                        .map_err(|_| Status::InternalServerError)?;
+                sqlx::query("UPDATE users SET last_login = NOW() WHERE firebase_uid = $1")
+                    .bind(&firebase_uid)
                     .execute(&**db_pool)
                     .await
                     .map_err(|_| Status::InternalServerError)?;
-                return Ok(token);
+                return Ok(serde_json::to_string(&pair).unwrap());
             }
         }
     }
     Err(Status::Unauthorized)
 }
 
-/// Faz logout do usuário invalidando o token.
+/// Faz logout do usuário revogando a sessão atual.
 #[post("/logout")]
-pub async fn logout_user(token: Token, db_pool: &State<PgPool>) -> Result<(), Status> {
-    sqlx::query("UPDATE users SET current_token = NULL WHERE firebase_uid = $1")
-        .bind(token.0)
-        .execute(&**db_pool)
+pub async fn logout_user(user: AuthUser, db_pool: &State<PgPool>) -> Result<(), Status> {
+    session::revoke(db_pool, &user.firebase_uid, user.session_id)
         .await
         .map_err(|_| Status::InternalServerError)?;
     Ok(())
@@ -142,21 +160,11 @@ pub async fn logout_user(token: Token, db_pool: &State<PgPool>) -> Result<(), St
 /// Atualiza número de telefone do usuário.
 #[post("/update_phone", data = "<request>")]
 pub async fn update_phone(
-    token: Token,
+    user: AuthUser,
     request: rocket::serde::json::Json<UpdatePhoneRequest>,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Update phone number
     sqlx::query("UPDATE users SET phone_number = $1 WHERE firebase_uid = $2")
@@ -172,21 +180,11 @@ pub async fn update_phone(
 /// Atualiza senha do usuário.
 #[post("/update_password", data = "<request>")]
 pub async fn update_password(
-    token: Token,
+    user: AuthUser,
     request: rocket::serde::json::Json<UpdatePasswordRequest>,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Hash the new password
     let salt =
@@ -210,21 +208,11 @@ pub async fn update_password(
 
 /// Exclui conta do usuário e dados associados.
 #[post("/delete_account")]
-pub async fn delete_account(token: Token, db_pool: &State<PgPool>) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+pub async fn delete_account(user: AuthUser, db_pool: &State<PgPool>) -> Result<(), Status> {
+    let firebase_uid = user.firebase_uid;
 
     // Unpair all devices owned by this user
-    sqlx::query("UPDATE devices SET user_id = NULL WHERE user_id = $1")
+    sqlx::query("UPDATE devices SET user_id = NULL, device_key = NULL, pending_device_key = NULL, rekey_requested_at = NULL WHERE user_id = $1")
         .bind(&firebase_uid)
         .execute(&**db_pool)
         .await
@@ -257,21 +245,11 @@ pub async fn delete_account(token: Token, db_pool: &State<PgPool>) -> Result<(),
 /// Verifica senha atual do usuário.
 #[post("/verify_password", data = "<request>")]
 pub async fn verify_password(
-    token: Token,
+    user: AuthUser,
     request: rocket::serde::json::Json<VerifyPasswordRequest>,
     db_pool: &State<PgPool>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => return Err(Status::Unauthorized),
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Get hashed password
     let password_row: Option<(String,)> =
@@ -300,24 +278,12 @@ pub async fn verify_password(
 /// Registra embedding de voz do usuário a partir de dados de áudio.
 #[post("/register_voice", data = "<audio_data>")]
 pub async fn register_voice(
-    token: Token,
+    user: AuthUser,
     audio_data: rocket::data::Data<'_>,
     db_pool: &State<PgPool>,
     speechbrain_url: &State<SpeechbrainUrl>,
 ) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+    let firebase_uid = user.firebase_uid;
 
     // Read audio data
     let mut data = Vec::new();
@@ -376,20 +342,8 @@ pub async fn register_voice(
 
 /// Exclui embedding de voz do usuário.
 #[post("/delete_voice")]
-pub async fn delete_voice(token: Token, db_pool: &State<PgPool>) -> Result<(), Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+pub async fn delete_voice(user: AuthUser, db_pool: &State<PgPool>) -> Result<(), Status> {
+    let firebase_uid = user.firebase_uid;
 
     // Delete voice embeddings
     sqlx::query("UPDATE users SET voice_embeddings = NULL WHERE firebase_uid = $1")
@@ -403,20 +357,8 @@ pub async fn delete_voice(token: Token, db_pool: &State<PgPool>) -> Result<(), S
 
 /// Verifica se o usuário tem voz registrada.
 #[get("/voice_status")]
-pub async fn voice_status(token: Token, db_pool: &State<PgPool>) -> Result<String, Status> {
-    // Validate token
-    let user_row: Option<(String,)> =
-        sqlx::query_as("SELECT firebase_uid FROM users WHERE current_token = $1")
-            .bind(&token.0)
-            .fetch_optional(&**db_pool)
-            .await
-            .map_err(|_| Status::InternalServerError)?;
-    let firebase_uid = match user_row {
-        Some((uid,)) => uid,
-        None => {
-            return Err(Status::Unauthorized);
-        }
-    };
+pub async fn voice_status(user: AuthUser, db_pool: &State<PgPool>) -> Result<String, Status> {
+    let firebase_uid = user.firebase_uid;
 
     // Check if user has voice embeddings
     let voice_row: Option<(Option<Vec<u8>>,)> =
@@ -28,6 +28,7 @@ void mqtt_init(void);
  * @param status String descrevendo o status atual (ex.: "CONNECTED", "STREAMING").
  *
  * Publica uma mensagem CBOR contendo o evento, uptime e timestamp no tópico lockwise/{device_id}/status.
+ * Status publicados durante um comando MQTT com `command_id` (ex.: PONG) ecoam esse ID.
  */
 void mqtt_publish_status(const char *status);
 
@@ -38,9 +39,23 @@ void mqtt_publish_status(const char *status);
  * @param reason Razão da mudança de estado.
  *
  * Publica uma mensagem CBOR detalhada incluindo lock state, reason, uptime e timestamp.
+ * Eventos causados por um comando MQTT com `command_id` ecoam esse ID.
  */
 void mqtt_publish_lock_event(lock_state_t state, door_reason_t reason);
 
+/**
+ * @brief Publica um evento de atualização de firmware via MQTT.
+ *
+ * @param event Evento (OTA_PROGRESS, OTA_INSTALLED ou OTA_FAILED).
+ * @param command_id ID do comando OTA, ecoado no evento (ou NULL).
+ * @param progress Porcentagem baixada, ou -1 para omitir.
+ * @param error Motivo da falha, ou NULL para omitir.
+ *
+ * Publica uma mensagem CBOR no tópico lockwise/{device_id}/status. Diferente de
+ * mqtt_publish_status, pode ser chamada fora do tratamento do comando (pela tarefa de OTA).
+ */
+void mqtt_publish_ota_event(const char *event, const char *command_id, int progress, const char *error);
+
 /**
  * @brief Tarefa que publica heartbeats periódicos via MQTT.
  *
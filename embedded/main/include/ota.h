@@ -0,0 +1,51 @@
+/* OTA Update Header */
+
+#pragma once
+#ifndef OTA_H
+#define OTA_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * @brief Pedido de atualização de firmware recebido no comando OTA.
+ */
+typedef struct {
+	/** @brief ID do comando, ecoado nos eventos de progresso e resultado */
+	char command_id[48];
+	/** @brief ID da versão no back-end, usado para montar a URL da imagem */
+	char release_id[48];
+	/** @brief Versão da imagem (ex.: "1.2.0") */
+	char version[32];
+	/** @brief SHA-256 esperado da imagem */
+	uint8_t sha256[32];
+	/** @brief Assinatura ECDSA P-256 (r || s) do SHA-256 da imagem */
+	uint8_t signature[64];
+	/** @brief Tamanho da imagem em bytes */
+	size_t size;
+} ota_request_t;
+
+/**
+ * @brief Inicia uma atualização de firmware em segundo plano.
+ *
+ * @param request Pedido de atualização (copiado).
+ * @return true se a atualização foi iniciada, false se outra já estiver em andamento.
+ *
+ * A imagem é baixada de {backend_url}/firmware/{release_id}/{device_id} com o token do
+ * dispositivo e gravada na partição OTA livre. O progresso é publicado em OTA_PROGRESS.
+ * Se o tamanho, o SHA-256 e a assinatura conferirem, a nova partição é marcada para boot,
+ * OTA_INSTALLED é publicado e o dispositivo reinicia; caso contrário, publica OTA_FAILED
+ * com o motivo.
+ */
+bool ota_start(const ota_request_t *request);
+
+/**
+ * @brief Confirma o firmware atual após uma atualização.
+ *
+ * Chamada na primeira conexão ao broker. Um firmware recém-instalado que não chega a se
+ * conectar é revertido pelo bootloader na próxima reinicialização.
+ */
+void ota_mark_valid(void);
+
+#endif /* OTA_H */
@@ -0,0 +1,105 @@
+/* Secure Channel Header */
+
+#pragma once
+#ifndef SECURE_CHANNEL_H
+#define SECURE_CHANNEL_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * @brief Carrega a chave do dispositivo da NVS.
+ *
+ * A chave é combinada com o back-end por ECDH no pareamento. Sem chave
+ * (dispositivo ainda não pareado com o canal autenticado), as mensagens são enviadas e
+ * aceitas sem assinatura.
+ */
+void secure_channel_init(void);
+
+/**
+ * @brief Indica se o dispositivo tem chave para o canal autenticado.
+ *
+ * @return true se houver chave carregada.
+ */
+bool secure_channel_has_key(void);
+
+/**
+ * @brief Gera e armazena uma nova chave do dispositivo para o pareamento.
+ *
+ * @param code Código de pareamento recebido pelo ponto de acesso (ex.: "LWP-...").
+ * @param presented Buffer que recebe o valor a apresentar no heartbeat: o prefixo do
+ * código seguido do SHA-256 (hexadecimal) do código, que não revela o código.
+ * @param presented_size Tamanho do buffer (ao menos 69 bytes).
+ * @return true em caso de sucesso.
+ *
+ * A chave é derivada por ECDH entre um par de chaves P-256 gerado aqui e a chave pública do
+ * back-end (CONFIG_BACKEND_KEY_EXCHANGE_PUBLIC_KEY), e não depende do código. A chave pública
+ * gerada vai no campo `pub_key` do heartbeat (ver secure_channel_public_key()).
+ */
+bool secure_channel_pair(const char *code, char *presented, size_t presented_size);
+
+/**
+ * @brief Obtém a chave pública gerada no último pareamento.
+ *
+ * @param out Buffer que recebe a chave pública em hexadecimal (ponto não comprimido).
+ * @param out_size Tamanho do buffer (ao menos 131 bytes).
+ * @return true se houver chave pública armazenada.
+ */
+bool secure_channel_public_key(char *out, size_t out_size);
+
+/**
+ * @brief Gera uma nova chave do dispositivo, atendendo ao comando REKEY do back-end.
+ *
+ * @param public_key Buffer que recebe a nova chave pública em hexadecimal, a enviar no evento KEY_OFFER.
+ * @param public_key_size Tamanho do buffer (ao menos 131 bytes).
+ * @return true em caso de sucesso.
+ *
+ * A nova chave fica pendente: as mensagens continuam assinadas com a chave atual até chegar a
+ * primeira mensagem de controle assinada com a nova, quando ela substitui a atual.
+ */
+bool secure_channel_rekey(char *public_key, size_t public_key_size);
+
+/**
+ * @brief Envelopa e assina uma mensagem CBOR de status.
+ *
+ * @param payload Mensagem CBOR original.
+ * @param payload_len Tamanho da mensagem.
+ * @param out Buffer de saída.
+ * @param out_size Tamanho do buffer de saída.
+ * @return Tamanho da mensagem envelopada, ou 0 em caso de erro.
+ *
+ * O envelope é um mapa CBOR com `p` (mensagem), `t` (timestamp), `n` (nonce) e `s`
+ * (HMAC-SHA256), no formato esperado pelo back-end.
+ */
+size_t secure_channel_seal(const uint8_t *payload, size_t payload_len, uint8_t *out, size_t out_size);
+
+/**
+ * @brief Verifica uma mensagem de controle envelopada e extrai a mensagem original.
+ *
+ * @param frame Mensagem recebida.
+ * @param frame_len Tamanho da mensagem recebida.
+ * @param payload Buffer que recebe a mensagem original.
+ * @param payload_len Entrada: tamanho do buffer; saída: tamanho da mensagem original.
+ * @return true se a assinatura for válida, o relógio estiver sincronizado, o timestamp estiver
+ * na janela aceita e o número de sequência (`n`) for maior que o da última mensagem aceita.
+ *
+ * Mensagens assinadas com a chave pendente (ver secure_channel_rekey()) também são aceitas e
+ * tornam-na a chave do dispositivo. O último número de sequência aceito fica na NVS, de modo
+ * que uma mensagem capturada não é aceita de novo nem após uma reinicialização. Mensagens que
+ * chegam fora de ordem são descartadas e reenviadas pelo back-end.
+ */
+bool secure_channel_open(const uint8_t *frame, size_t frame_len, uint8_t *payload, size_t *payload_len);
+
+/**
+ * @brief Decifra um valor de configuração enviado em `value_enc`.
+ *
+ * @param data Nonce de 12 bytes seguido do texto cifrado e da tag (AES-256-GCM).
+ * @param data_len Tamanho dos dados.
+ * @param out Buffer que recebe o valor decifrado, terminado em '\0'.
+ * @param out_size Tamanho do buffer de saída.
+ * @return true se o valor for autêntico e couber no buffer.
+ */
+bool secure_channel_decrypt(const uint8_t *data, size_t data_len, char *out, size_t out_size);
+
+#endif /* SECURE_CHANNEL_H */
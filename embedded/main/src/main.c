@@ -21,6 +21,7 @@
 #include "lock.h"
 #include "mqtt.h"
 #include "nvs_flash.h"
+#include "secure_channel.h"
 #include "serial.h"
 #include "system_utils.h"
 #include "wifi.h"
@@ -144,6 +145,7 @@ void app_main(void)
 
 	// Load configuration
 	load_config_from_nvs();
+	secure_channel_init();
 
 	// Start serial command task early to allow config updates before wifi connects
 	xTaskCreate(serial_command_task, "serial_cmd", 4096, NULL, 4, NULL);
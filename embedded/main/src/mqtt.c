@@ -3,12 +3,15 @@
 #include "audio_stream.h"
 #include "config.h"
 #include "driver/gpio.h"
+#include "esp_app_desc.h"
 #include "esp_crt_bundle.h"
 #include "esp_log.h"
 #include "freertos/FreeRTOS.h"
 #include "lock.h"
 #include "mqtt.h"
 #include "nvs_flash.h"
+#include "ota.h"
+#include "secure_channel.h"
 #include "system_utils.h"
 #include <arpa/inet.h>
 #include <cbor.h>
@@ -56,6 +59,62 @@ static void process_cbor_command(CborValue *value);
  */
 static void handle_update_config_command(CborValue *map_value);
 
+/**
+ * @brief Processa o comando OTA de atualização de firmware.
+ *
+ * @param map_value Ponteiro para o mapa CBOR contendo release_id, version, size, sha256 e signature.
+ *
+ * Inicia a atualização em segundo plano, ou publica OTA_FAILED se o comando for inválido ou
+ * outra atualização já estiver em andamento.
+ */
+static void handle_ota_command(CborValue *map_value);
+
+/**
+ * @brief Publica uma mensagem CBOR no tópico de status.
+ *
+ * @param topic Tópico de status do dispositivo.
+ * @param payload Mensagem CBOR.
+ * @param payload_len Tamanho da mensagem.
+ * @return ID da mensagem publicada, ou -1 em caso de erro.
+ *
+ * Se o dispositivo tiver chave, a mensagem é assinada antes da publicação.
+ */
+static int publish_status_message(const char *topic, const uint8_t *payload, size_t payload_len);
+
+/**
+ * @brief Atende ao comando REKEY: gera uma nova chave do dispositivo e a oferece ao back-end.
+ *
+ * Publica KEY_OFFER com a nova chave pública (`pub_key`), assinado com a chave atual, ou
+ * REKEY_FAILED se não for possível gerar a chave.
+ */
+static void handle_rekey_command(void);
+
+/* ID of the LOCK/UNLOCK command being executed, echoed in the resulting lock event */
+static char current_command_id[48];
+
+/** @brief Número de IDs de comandos executados lembrados para descartar reenvios */
+#define RECENT_COMMAND_IDS 16
+
+/* IDs of recently executed commands, oldest overwritten first */
+static char recent_command_ids[RECENT_COMMAND_IDS][48];
+static size_t recent_command_next;
+
+/**
+ * @brief Registra o ID de um comando prestes a ser executado.
+ *
+ * @param command_id ID do comando.
+ * @return false se o comando já foi executado (reenvio do back-end sem confirmação).
+ */
+static bool remember_command_id(const char *command_id)
+{
+	for (size_t i = 0; i < RECENT_COMMAND_IDS; i++)
+		if (!strcmp(recent_command_ids[i], command_id))
+			return false;
+	strlcpy(recent_command_ids[recent_command_next], command_id, sizeof(recent_command_ids[0]));
+	recent_command_next = (recent_command_next + 1) % RECENT_COMMAND_IDS;
+	return true;
+}
+
 static void process_cbor_command(CborValue *value)
 {
 	CborValue cmd_val;
@@ -67,6 +126,23 @@ static void process_cbor_command(CborValue *value)
 		if (cbor_value_copy_text_string(&cmd_val, command, &cmd_len, NULL) == CborNoError) {
 			ESP_LOGI(TAG, "Command:\033[1m %s", command);
 
+			// Optional command ID, echoed in the lock event
+			CborValue id_val;
+			size_t id_len = sizeof(current_command_id);
+			if (cbor_value_map_find_value(value, "command_id", &id_val) != CborNoError ||
+			    !cbor_value_is_text_string(&id_val) ||
+			    cbor_value_copy_text_string(&id_val, current_command_id, &id_len, NULL) != CborNoError)
+				current_command_id[0] = '\0';
+
+			// A redelivered command whose ack was lost is acknowledged again, not executed twice
+			if (current_command_id[0] && !remember_command_id(current_command_id)) {
+				ESP_LOGW(TAG, "Command %s already executed, ignoring", current_command_id);
+				if (!strcasecmp(command, "UNLOCK") || !strcasecmp(command, "LOCK"))
+					mqtt_publish_lock_event(get_lock_state(), DOOR_REASON_MQTT);
+				current_command_id[0] = '\0';
+				return;
+			}
+
 			if (!strcasecmp(command, "UNLOCK")) {
 				unlock_door(DOOR_REASON_MQTT);
 			} else if (!strcasecmp(command, "LOCK")) {
@@ -95,11 +171,23 @@ static void process_cbor_command(CborValue *value)
 				cleanup_halt();
 			} else if (!strcasecmp(command, "UPDATE_CONFIG")) {
 				handle_update_config_command(value);
+			} else if (!strcasecmp(command, "OTA")) {
+				handle_ota_command(value);
+			} else if (!strcasecmp(command, "REKEY")) {
+				handle_rekey_command();
+			} else if (!strcasecmp(command, "REKEYED")) {
+				// Signed with the new key, so secure_channel_open() has already switched to it
+				ESP_LOGI(TAG, "Backend confirmed the new device key");
 			} else if (!strcasecmp(command, "PAIR")) {
 				update_config("pairing_mode", "1");
 				mqtt_publish_status("ENTERING_PAIRING_MODE");
 				cleanup_restart();
 			}
+
+			// The lock was already in the requested state: acknowledge with the current state
+			if (current_command_id[0] && (!strcasecmp(command, "UNLOCK") || !strcasecmp(command, "LOCK")))
+				mqtt_publish_lock_event(get_lock_state(), DOOR_REASON_MQTT);
+			current_command_id[0] = '\0';
 		}
 	} else {
 		ESP_LOGW(TAG, "No 'command' field or not text");
@@ -114,10 +202,30 @@ static void handle_update_config_command(CborValue *map_value)
 	size_t key_len = sizeof(config_key);
 	size_t value_len = sizeof(config_value);
 
-	if (cbor_value_map_find_value(map_value, "key", &key_val) == CborNoError &&
-	    cbor_value_map_find_value(map_value, "value", &value_val) == CborNoError &&
-	    cbor_value_is_text_string(&key_val) && cbor_value_is_text_string(&value_val) &&
-	    cbor_value_copy_text_string(&key_val, config_key, &key_len, NULL) == CborNoError &&
+	if (cbor_value_map_find_value(map_value, "key", &key_val) != CborNoError || !cbor_value_is_text_string(&key_val) ||
+	    cbor_value_copy_text_string(&key_val, config_key, &key_len, NULL) != CborNoError) {
+		mqtt_publish_status("INVALID_UPDATE_CONFIG_FORMAT");
+		ESP_LOGW(TAG, "Invalid UPDATE_CONFIG CBOR format");
+		return;
+	}
+
+	// Sensitive values (e.g. wifi_pass) arrive encrypted with the device key
+	if (cbor_value_map_find_value(map_value, "value_enc", &value_val) == CborNoError &&
+	    cbor_value_is_byte_string(&value_val)) {
+		uint8_t encrypted[sizeof(config_value) + 28];
+		size_t encrypted_len = sizeof(encrypted);
+		if (cbor_value_copy_byte_string(&value_val, encrypted, &encrypted_len, NULL) == CborNoError &&
+		    secure_channel_decrypt(encrypted, encrypted_len, config_value, sizeof(config_value))) {
+			update_config(config_key, config_value);
+			memset(config_value, 0, sizeof(config_value));
+		} else {
+			mqtt_publish_status("INVALID_UPDATE_CONFIG_VALUE");
+		}
+		return;
+	}
+
+	if (cbor_value_map_find_value(map_value, "value", &value_val) == CborNoError &&
+	    cbor_value_is_text_string(&value_val) &&
 	    cbor_value_copy_text_string(&value_val, config_value, &value_len, NULL) == CborNoError) {
 		update_config(config_key, config_value);
 	} else {
@@ -126,6 +234,92 @@ static void handle_update_config_command(CborValue *map_value)
 	}
 }
 
+static void handle_ota_command(CborValue *map_value)
+{
+	ota_request_t request = { 0 };
+	CborValue val;
+	size_t len;
+	uint64_t size;
+
+	strlcpy(request.command_id, current_command_id, sizeof(request.command_id));
+	bool valid = cbor_value_map_find_value(map_value, "release_id", &val) == CborNoError &&
+		     cbor_value_is_text_string(&val) && (len = sizeof(request.release_id)) &&
+		     cbor_value_copy_text_string(&val, request.release_id, &len, NULL) == CborNoError &&
+		     cbor_value_map_find_value(map_value, "version", &val) == CborNoError &&
+		     cbor_value_is_text_string(&val) && (len = sizeof(request.version)) &&
+		     cbor_value_copy_text_string(&val, request.version, &len, NULL) == CborNoError &&
+		     cbor_value_map_find_value(map_value, "size", &val) == CborNoError &&
+		     cbor_value_is_unsigned_integer(&val) && cbor_value_get_uint64(&val, &size) == CborNoError &&
+		     cbor_value_map_find_value(map_value, "sha256", &val) == CborNoError &&
+		     cbor_value_is_byte_string(&val) && (len = sizeof(request.sha256)) &&
+		     cbor_value_copy_byte_string(&val, request.sha256, &len, NULL) == CborNoError &&
+		     len == sizeof(request.sha256) &&
+		     cbor_value_map_find_value(map_value, "signature", &val) == CborNoError &&
+		     cbor_value_is_byte_string(&val) && (len = sizeof(request.signature)) &&
+		     cbor_value_copy_byte_string(&val, request.signature, &len, NULL) == CborNoError &&
+		     len == sizeof(request.signature);
+	if (!valid || size == 0) {
+		ESP_LOGW(TAG, "Invalid OTA CBOR format");
+		mqtt_publish_ota_event("OTA_FAILED", request.command_id, -1, "INVALID_OTA_FORMAT");
+		return;
+	}
+	request.size = size;
+
+	if (!strcmp(request.version, esp_app_get_description()->version)) {
+		// Already running the requested version: report it as installed
+		mqtt_publish_ota_event("OTA_INSTALLED", request.command_id, 100, NULL);
+		return;
+	}
+	if (!ota_start(&request))
+		mqtt_publish_ota_event("OTA_FAILED", request.command_id, -1, "BUSY");
+}
+
+static void handle_rekey_command(void)
+{
+	char public_key[160];
+	if (!secure_channel_rekey(public_key, sizeof(public_key))) {
+		mqtt_publish_status("REKEY_FAILED");
+		return;
+	}
+
+	char topic[96];
+	snprintf(topic, sizeof(topic), "lockwise/%s/status", config.device_id);
+
+	uint8_t cbor_buffer[256];
+	CborEncoder encoder, map_encoder;
+	cbor_encoder_init(&encoder, cbor_buffer, sizeof(cbor_buffer), 0);
+	cbor_encoder_create_map(&encoder, &map_encoder, 4);
+	cbor_encode_text_stringz(&map_encoder, "event");
+	cbor_encode_text_stringz(&map_encoder, "KEY_OFFER");
+	cbor_encode_text_stringz(&map_encoder, "uptime_ms");
+	cbor_encode_uint(&map_encoder, (uint64_t)xTaskGetTickCount() * portTICK_PERIOD_MS);
+	cbor_encode_text_stringz(&map_encoder, "timestamp");
+	cbor_encode_uint(&map_encoder, (uint64_t)time(NULL));
+	cbor_encode_text_stringz(&map_encoder, "pub_key");
+	cbor_encode_text_stringz(&map_encoder, public_key);
+	cbor_encoder_close_container(&encoder, &map_encoder);
+	size_t cbor_len = cbor_encoder_get_buffer_size(&encoder, cbor_buffer);
+
+	int msg_id = publish_status_message(topic, cbor_buffer, cbor_len);
+	if (msg_id >= 0)
+		ESP_LOGI(TAG, "Published CBOR key offer to %s (msg_id=%d)", topic, msg_id);
+	else
+		ESP_LOGE(TAG, "Failed to publish key offer");
+}
+
+static int publish_status_message(const char *topic, const uint8_t *payload, size_t payload_len)
+{
+	if (!secure_channel_has_key())
+		return esp_mqtt_client_publish(mqtt_client, topic, (const char *)payload, payload_len, 1, 0);
+
+	// Envelope overhead: field names, timestamp, nonce and 32-byte signature
+	uint8_t sealed[768 + 64];
+	size_t sealed_len = secure_channel_seal(payload, payload_len, sealed, sizeof(sealed));
+	if (!sealed_len)
+		return -1;
+	return esp_mqtt_client_publish(mqtt_client, topic, (const char *)sealed, sealed_len, 1, 0);
+}
+
 static void mqtt_event_handler(void *handler_args, esp_event_base_t base, int32_t event_id, void *event_data)
 {
 	static bool have_already_connected;
@@ -137,11 +331,16 @@ static void mqtt_event_handler(void *handler_args, esp_event_base_t base, int32_
 		// Subscribe to device-specific topic
 		char topic[96];
 		snprintf(topic, sizeof(topic), "lockwise/%s/control", config.device_id);
-		esp_mqtt_client_subscribe(mqtt_client, topic, 0);
+		esp_mqtt_client_subscribe(mqtt_client, topic, 1);
 		ESP_LOGI(TAG, "Subscribed to topic:\033[1m %s", topic);
 
+		// Clear a retained Last Will from a previous connection
+		snprintf(topic, sizeof(topic), "lockwise/%s/presence", config.device_id);
+		esp_mqtt_client_publish(mqtt_client, topic, "", 0, 1, 1);
+
 		if (!have_already_connected) {
 			have_already_connected = true;
+			ota_mark_valid();
 			mqtt_publish_status("POWER_ON");
 			gpio_set_level(LOCK_INDICATOR_LED_GPIO, 0);
 
@@ -162,10 +361,24 @@ static void mqtt_event_handler(void *handler_args, esp_event_base_t base, int32_
 		ESP_LOGI(TAG, "MQTT CBOR Data received:\033[1m topic=%.*s", event->topic_len, event->topic);
 		ESP_LOGD(TAG, "payload len=%d", event->data_len);
 
+		// Paired devices only accept commands signed by the backend
+		const uint8_t *data = (const uint8_t *)event->data;
+		size_t data_len = event->data_len;
+		uint8_t command_buffer[512];
+		if (secure_channel_has_key()) {
+			data_len = sizeof(command_buffer);
+			if (!secure_channel_open((const uint8_t *)event->data, event->data_len, command_buffer,
+						 &data_len)) {
+				mqtt_publish_status("UNAUTHENTICATED_COMMAND");
+				break;
+			}
+			data = command_buffer;
+		}
+
 		// Decode CBOR data
 		CborParser parser;
 		CborValue value;
-		CborError err = cbor_parser_init((const uint8_t *)event->data, event->data_len, 0, &parser, &value);
+		CborError err = cbor_parser_init(data, data_len, 0, &parser, &value);
 		if (err != CborNoError) {
 			ESP_LOGW(TAG, "Invalid CBOR data received, error:\033[1m %d", err);
 			break;
@@ -288,6 +501,10 @@ void mqtt_init(void)
 		}
 	}
 
+	// The broker publishes the Last Will when the connection drops without a DISCONNECT
+	static char presence_topic[96];
+	snprintf(presence_topic, sizeof(presence_topic), "lockwise/%s/presence", config.device_id);
+
 	esp_mqtt_client_config_t mqtt_cfg = {
 		.broker.address.uri = config.mqtt_broker_url,
 		.credentials.client_id = config.device_id,
@@ -296,6 +513,10 @@ void mqtt_init(void)
 		.network.timeout_ms = 30000, // Increase timeout to 30 seconds
 		.network.reconnect_timeout_ms = 5000,
 		.session.keepalive = 60,
+		.session.last_will.topic = presence_topic,
+		.session.last_will.msg = "offline",
+		.session.last_will.qos = 1,
+		.session.last_will.retain = 1,
 	};
 
 	// If using mqtts://, configure TLS with certificate bundle
@@ -322,23 +543,72 @@ void mqtt_publish_status(const char *status)
 	uint8_t cbor_buffer[256];
 	CborEncoder encoder, map_encoder;
 	cbor_encoder_init(&encoder, cbor_buffer, sizeof(cbor_buffer), 0);
-	cbor_encoder_create_map(&encoder, &map_encoder, 3);
+	// Statuses published while handling a command (e.g. PONG) echo its ID
+	bool echo_command_id = current_command_id[0];
+	cbor_encoder_create_map(&encoder, &map_encoder, echo_command_id ? 4 : 3);
 	cbor_encode_text_stringz(&map_encoder, "event");
 	cbor_encode_text_stringz(&map_encoder, status);
 	cbor_encode_text_stringz(&map_encoder, "uptime_ms");
 	cbor_encode_uint(&map_encoder, (uint64_t)xTaskGetTickCount() * portTICK_PERIOD_MS);
 	cbor_encode_text_stringz(&map_encoder, "timestamp");
 	cbor_encode_uint(&map_encoder, (uint64_t)time(NULL));
+	if (echo_command_id) {
+		cbor_encode_text_stringz(&map_encoder, "command_id");
+		cbor_encode_text_stringz(&map_encoder, current_command_id);
+	}
 	cbor_encoder_close_container(&encoder, &map_encoder);
 	size_t cbor_len = cbor_encoder_get_buffer_size(&encoder, cbor_buffer);
 
-	int msg_id = esp_mqtt_client_publish(mqtt_client, topic, (const char *)cbor_buffer, cbor_len, 1, 0);
+	int msg_id = publish_status_message(topic, cbor_buffer, cbor_len);
 	if (msg_id >= 0)
 		ESP_LOGI(TAG, "Published CBOR status to %s:\033[1m %s (msg_id=%d)", topic, status, msg_id);
 	else
 		ESP_LOGE(TAG, "Failed to publish status");
 }
 
+void mqtt_publish_ota_event(const char *event, const char *command_id, int progress, const char *error)
+{
+	if (!mqtt_client) {
+		ESP_LOGW(TAG, "MQTT client not initialized, cannot publish OTA event");
+		return;
+	}
+
+	char topic[96];
+	snprintf(topic, sizeof(topic), "lockwise/%s/status", config.device_id);
+
+	uint8_t cbor_buffer[256];
+	CborEncoder encoder, map_encoder;
+	cbor_encoder_init(&encoder, cbor_buffer, sizeof(cbor_buffer), 0);
+	cbor_encoder_create_map(&encoder, &map_encoder,
+				3 + (command_id && command_id[0]) + (progress >= 0) + (error != NULL));
+	cbor_encode_text_stringz(&map_encoder, "event");
+	cbor_encode_text_stringz(&map_encoder, event);
+	cbor_encode_text_stringz(&map_encoder, "uptime_ms");
+	cbor_encode_uint(&map_encoder, (uint64_t)xTaskGetTickCount() * portTICK_PERIOD_MS);
+	cbor_encode_text_stringz(&map_encoder, "timestamp");
+	cbor_encode_uint(&map_encoder, (uint64_t)time(NULL));
+	if (command_id && command_id[0]) {
+		cbor_encode_text_stringz(&map_encoder, "command_id");
+		cbor_encode_text_stringz(&map_encoder, command_id);
+	}
+	if (progress >= 0) {
+		cbor_encode_text_stringz(&map_encoder, "progress");
+		cbor_encode_uint(&map_encoder, progress);
+	}
+	if (error) {
+		cbor_encode_text_stringz(&map_encoder, "error");
+		cbor_encode_text_stringz(&map_encoder, error);
+	}
+	cbor_encoder_close_container(&encoder, &map_encoder);
+	size_t cbor_len = cbor_encoder_get_buffer_size(&encoder, cbor_buffer);
+
+	int msg_id = publish_status_message(topic, cbor_buffer, cbor_len);
+	if (msg_id >= 0)
+		ESP_LOGI(TAG, "Published CBOR OTA event to %s:\033[1m %s (msg_id=%d)", topic, event, msg_id);
+	else
+		ESP_LOGE(TAG, "Failed to publish OTA event");
+}
+
 void mqtt_publish_lock_event(lock_state_t state, door_reason_t reason)
 {
 	if (!mqtt_client) {
@@ -396,7 +666,8 @@ void mqtt_publish_lock_event(lock_state_t state, door_reason_t reason)
 	uint8_t cbor_buffer[256];
 	CborEncoder encoder, map_encoder;
 	cbor_encoder_init(&encoder, cbor_buffer, sizeof(cbor_buffer), 0);
-	cbor_encoder_create_map(&encoder, &map_encoder, 4);
+	bool echo_command_id = reason == DOOR_REASON_MQTT && current_command_id[0];
+	cbor_encoder_create_map(&encoder, &map_encoder, echo_command_id ? 5 : 4);
 	cbor_encode_text_stringz(&map_encoder, "lock");
 	cbor_encode_text_stringz(&map_encoder, status_str);
 	cbor_encode_text_stringz(&map_encoder, "reason");
@@ -405,10 +676,15 @@ void mqtt_publish_lock_event(lock_state_t state, door_reason_t reason)
 	cbor_encode_uint(&map_encoder, (uint64_t)xTaskGetTickCount() * portTICK_PERIOD_MS);
 	cbor_encode_text_stringz(&map_encoder, "timestamp");
 	cbor_encode_uint(&map_encoder, (uint64_t)time(NULL));
+	if (echo_command_id) {
+		cbor_encode_text_stringz(&map_encoder, "command_id");
+		cbor_encode_text_stringz(&map_encoder, current_command_id);
+		current_command_id[0] = '\0';
+	}
 	cbor_encoder_close_container(&encoder, &map_encoder);
 	size_t cbor_len = cbor_encoder_get_buffer_size(&encoder, cbor_buffer);
 
-	int msg_id = esp_mqtt_client_publish(mqtt_client, topic, (const char *)cbor_buffer, cbor_len, 1, 0);
+	int msg_id = publish_status_message(topic, cbor_buffer, cbor_len);
 	if (msg_id >= 0)
 		ESP_LOGI(TAG, "Published CBOR lock event to %s:\033[1m %s (reason: %s, msg_id=%d)", topic, status_str,
 			 reason_str, msg_id);
@@ -448,10 +724,15 @@ static void mqtt_publish_heartbeat(void)
 	char topic[96];
 	snprintf(topic, sizeof(topic), "lockwise/%s/status", config.device_id);
 
-	uint8_t cbor_buffer[512];
+	// While pairing, the public key lets the backend derive the device key
+	char public_key[160];
+	bool send_public_key = !strncmp(config.user_id, "LWP-", 4) &&
+			       secure_channel_public_key(public_key, sizeof(public_key));
+
+	uint8_t cbor_buffer[768];
 	CborEncoder encoder, map_encoder;
 	cbor_encoder_init(&encoder, cbor_buffer, sizeof(cbor_buffer), 0);
-	cbor_encoder_create_map(&encoder, &map_encoder, 15);
+	cbor_encoder_create_map(&encoder, &map_encoder, send_public_key ? 17 : 16);
 
 	cbor_encode_text_stringz(&map_encoder, "heartbeat");
 	cbor_encode_text_stringz(&map_encoder, "HEARTBEAT");
@@ -489,6 +770,11 @@ static void mqtt_publish_heartbeat(void)
 	cbor_encode_text_stringz(&map_encoder, "user_id");
 	cbor_encode_text_stringz(&map_encoder, config.user_id);
 
+	if (send_public_key) {
+		cbor_encode_text_stringz(&map_encoder, "pub_key");
+		cbor_encode_text_stringz(&map_encoder, public_key);
+	}
+
 	cbor_encode_text_stringz(&map_encoder, "lock_state");
 	cbor_encode_text_stringz(&map_encoder, lock_state_str);
 
@@ -498,6 +784,9 @@ static void mqtt_publish_heartbeat(void)
 	cbor_encode_text_stringz(&map_encoder, "vad_rms_threshold");
 	cbor_encode_int(&map_encoder, config.vad_rms_threshold);
 
+	cbor_encode_text_stringz(&map_encoder, "fw_version");
+	cbor_encode_text_stringz(&map_encoder, esp_app_get_description()->version);
+
 	cbor_encoder_close_container(&encoder, &map_encoder);
 
 	size_t cbor_len = cbor_encoder_get_buffer_size(&encoder, cbor_buffer);
@@ -508,7 +797,7 @@ static void mqtt_publish_heartbeat(void)
 		return;
 	}
 
-	int msg_id = esp_mqtt_client_publish(mqtt_client, topic, (const char *)cbor_buffer, cbor_len, 1, 0);
+	int msg_id = publish_status_message(topic, cbor_buffer, cbor_len);
 	if (msg_id >= 0)
 		ESP_LOGD(TAG, "Published heartbeat CBOR to %s (msg_id=%d)", topic, msg_id);
 	else
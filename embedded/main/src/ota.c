@@ -0,0 +1,219 @@
+/* OTA Update Implementation */
+
+#include "ota.h"
+#include "config.h"
+#include "esp_app_desc.h"
+#include "esp_crt_bundle.h"
+#include "esp_http_client.h"
+#include "esp_log.h"
+#include "esp_ota_ops.h"
+#include "freertos/FreeRTOS.h"
+#include "freertos/task.h"
+#include "mbedtls/ecdsa.h"
+#include "mbedtls/sha256.h"
+#include "mqtt.h"
+#include "system_utils.h"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static const char *TAG = "\033[1mLOCKWISE:\033[96mOTA\033[0m\033[96m";
+
+/** @brief Tamanho dos blocos lidos do back-end */
+#define OTA_CHUNK_SIZE 4096
+/** @brief Intervalo entre eventos OTA_PROGRESS, em pontos percentuais */
+#define OTA_PROGRESS_STEP 10
+
+/** @brief Pedido de atualização em andamento */
+static ota_request_t current_request;
+/** @brief Indica se há uma atualização em andamento */
+static volatile bool ota_running;
+
+/**
+ * @brief Verifica a assinatura da imagem com a chave pública de CONFIG_OTA_SIGNING_PUBLIC_KEY.
+ *
+ * @param hash SHA-256 da imagem.
+ * @param signature Assinatura ECDSA P-256 (r || s).
+ * @return true se a assinatura for válida.
+ */
+static bool verify_signature(const uint8_t hash[32], const uint8_t signature[64])
+{
+	const char *key_hex = CONFIG_OTA_SIGNING_PUBLIC_KEY;
+	uint8_t key[65];
+	if (strlen(key_hex) != 2 * sizeof(key))
+		return false;
+	for (size_t i = 0; i < sizeof(key); i++) {
+		unsigned int byte;
+		if (sscanf(key_hex + 2 * i, "%2x", &byte) != 1)
+			return false;
+		key[i] = byte;
+	}
+
+	mbedtls_ecp_group grp;
+	mbedtls_ecp_point q;
+	mbedtls_mpi r, s;
+	mbedtls_ecp_group_init(&grp);
+	mbedtls_ecp_point_init(&q);
+	mbedtls_mpi_init(&r);
+	mbedtls_mpi_init(&s);
+
+	bool valid = mbedtls_ecp_group_load(&grp, MBEDTLS_ECP_DP_SECP256R1) == 0 &&
+		     mbedtls_ecp_point_read_binary(&grp, &q, key, sizeof(key)) == 0 &&
+		     mbedtls_mpi_read_binary(&r, signature, 32) == 0 &&
+		     mbedtls_mpi_read_binary(&s, signature + 32, 32) == 0 &&
+		     mbedtls_ecdsa_verify(&grp, hash, 32, &q, &r, &s) == 0;
+
+	mbedtls_mpi_free(&s);
+	mbedtls_mpi_free(&r);
+	mbedtls_ecp_point_free(&q);
+	mbedtls_ecp_group_free(&grp);
+	return valid;
+}
+
+/**
+ * @brief Baixa, verifica e instala a imagem do pedido atual.
+ *
+ * @return NULL em caso de sucesso, ou o motivo da falha publicado em OTA_FAILED.
+ */
+static const char *download_and_install(void)
+{
+	const ota_request_t *req = &current_request;
+	const esp_partition_t *partition = esp_ota_get_next_update_partition(NULL);
+	if (!partition)
+		return "NO_OTA_PARTITION";
+	if (req->size > partition->size)
+		return "IMAGE_TOO_LARGE";
+
+	char url[512];
+	snprintf(url, sizeof(url), "%s/firmware/%s/%s", config.backend_url, req->release_id, config.device_id);
+	esp_http_client_config_t http_cfg = {
+		.url = url,
+		.crt_bundle_attach = !strncmp(url, "https://", 8) ? esp_crt_bundle_attach : NULL,
+		.timeout_ms = 15000,
+		.buffer_size = OTA_CHUNK_SIZE,
+	};
+	esp_http_client_handle_t http = esp_http_client_init(&http_cfg);
+	if (!http)
+		return "DOWNLOAD_FAILED";
+	if (strlen(config.backend_bearer_token) > 0) {
+		char auth_header[256 + 10];
+		snprintf(auth_header, sizeof(auth_header), "Bearer %s", config.backend_bearer_token);
+		esp_http_client_set_header(http, "Authorization", auth_header);
+	}
+
+	if (esp_http_client_open(http, 0) != ESP_OK) {
+		esp_http_client_cleanup(http);
+		return "DOWNLOAD_FAILED";
+	}
+	int64_t content_length = esp_http_client_fetch_headers(http);
+	if (esp_http_client_get_status_code(http) != 200 || content_length != (int64_t)req->size) {
+		ESP_LOGE(TAG, "Unexpected image response: status %d, %lld bytes",
+			 esp_http_client_get_status_code(http), content_length);
+		esp_http_client_cleanup(http);
+		return "DOWNLOAD_FAILED";
+	}
+
+	uint8_t *buf = malloc(OTA_CHUNK_SIZE);
+	esp_ota_handle_t ota;
+	if (!buf || esp_ota_begin(partition, req->size, &ota) != ESP_OK) {
+		free(buf);
+		esp_http_client_cleanup(http);
+		return "WRITE_FAILED";
+	}
+
+	const char *error = NULL;
+	mbedtls_sha256_context sha;
+	mbedtls_sha256_init(&sha);
+	mbedtls_sha256_starts(&sha, 0);
+	size_t received = 0;
+	int reported = 0;
+	while (received < req->size) {
+		int n = esp_http_client_read(http, (char *)buf, OTA_CHUNK_SIZE);
+		if (n <= 0 || received + n > req->size) {
+			error = "DOWNLOAD_FAILED";
+			break;
+		}
+		mbedtls_sha256_update(&sha, buf, n);
+		if (esp_ota_write(ota, buf, n) != ESP_OK) {
+			error = "WRITE_FAILED";
+			break;
+		}
+		received += n;
+
+		int progress = (int)((uint64_t)received * 100 / req->size);
+		if (progress >= reported + OTA_PROGRESS_STEP && progress < 100) {
+			reported = progress - progress % OTA_PROGRESS_STEP;
+			mqtt_publish_ota_event("OTA_PROGRESS", req->command_id, reported, NULL);
+		}
+	}
+	uint8_t hash[32];
+	mbedtls_sha256_finish(&sha, hash);
+	mbedtls_sha256_free(&sha);
+	free(buf);
+	esp_http_client_cleanup(http);
+
+	if (!error && memcmp(hash, req->sha256, sizeof(hash)))
+		error = "HASH_MISMATCH";
+	if (!error && !verify_signature(hash, req->signature))
+		error = "INVALID_SIGNATURE";
+	if (error) {
+		esp_ota_abort(ota);
+		return error;
+	}
+
+	// esp_ota_end also validates the image header and segments
+	if (esp_ota_end(ota) != ESP_OK)
+		return "INVALID_IMAGE";
+	if (esp_ota_set_boot_partition(partition) != ESP_OK)
+		return "SET_BOOT_FAILED";
+	return NULL;
+}
+
+/**
+ * @brief Tarefa que executa a atualização e reinicia o dispositivo em caso de sucesso.
+ *
+ * @param arg Parâmetros da tarefa (não usado).
+ */
+static void ota_task(void *arg)
+{
+	ESP_LOGI(TAG, "Updating firmware to\033[1m %s\033[0m (%u bytes)", current_request.version,
+		 (unsigned int)current_request.size);
+	mqtt_publish_ota_event("OTA_PROGRESS", current_request.command_id, 0, NULL);
+
+	const char *error = download_and_install();
+	if (error) {
+		ESP_LOGE(TAG, "Firmware update failed:\033[1m %s", error);
+		mqtt_publish_ota_event("OTA_FAILED", current_request.command_id, -1, error);
+		ota_running = false;
+		vTaskDelete(NULL);
+		return;
+	}
+
+	ESP_LOGI(TAG, "Firmware %s installed, restarting", current_request.version);
+	mqtt_publish_ota_event("OTA_INSTALLED", current_request.command_id, 100, NULL);
+	vTaskDelay(pdMS_TO_TICKS(1000)); // Let the event reach the broker
+	cleanup_restart();
+}
+
+bool ota_start(const ota_request_t *request)
+{
+	if (ota_running)
+		return false;
+	ota_running = true;
+	current_request = *request;
+	if (xTaskCreate(ota_task, "ota", 8192, NULL, 5, NULL) != pdPASS) {
+		ota_running = false;
+		return false;
+	}
+	return true;
+}
+
+void ota_mark_valid(void)
+{
+	esp_ota_img_states_t state;
+	const esp_partition_t *running = esp_ota_get_running_partition();
+	if (esp_ota_get_state_partition(running, &state) == ESP_OK && state == ESP_OTA_IMG_PENDING_VERIFY) {
+		ESP_LOGI(TAG, "Confirming firmware %s", esp_app_get_description()->version);
+		esp_ota_mark_app_valid_cancel_rollback();
+	}
+}
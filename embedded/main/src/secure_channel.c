@@ -0,0 +1,483 @@
+/* Secure Channel Implementation */
+
+#include "secure_channel.h"
+#include "esp_log.h"
+#include "esp_random.h"
+#include "mbedtls/ecdh.h"
+#include "mbedtls/gcm.h"
+#include "mbedtls/hkdf.h"
+#include "mbedtls/md.h"
+#include "mbedtls/sha256.h"
+#include "nvs.h"
+#include <cbor.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+static const char *TAG = "\033[1mLOCKWISE:\033[92mCHANNEL\033[0m\033[92m";
+
+/** @brief Sal da derivação da chave do dispositivo a partir do segredo ECDH, igual ao do back-end */
+#define DEVICE_KEY_SALT "lockwise-pairing-v2"
+/** @brief Diferença máxima aceita entre o timestamp de uma mensagem e o relógio local */
+#define MAX_CLOCK_SKEW_SEC 300
+/** @brief Timestamps anteriores a este indicam relógio ainda não sincronizado */
+#define MIN_SYNCED_TIME 1700000000
+/** @brief Prefixo dos códigos de pareamento */
+#define PAIRING_CODE_PREFIX "LWP-"
+/** @brief Chave da NVS com o último número de sequência de controle aceito */
+#define CONTROL_SEQ_NVS_KEY "control_seq"
+/** @brief Chave da NVS com a nova chave gerada por REKEY, ainda não usada pelo back-end */
+#define PENDING_KEY_NVS_KEY "pending_key"
+/** @brief Chave da NVS com a chave pública gerada no pareamento */
+#define PUBLIC_KEY_NVS_KEY "device_pub"
+/** @brief Tamanho de uma chave pública P-256 não comprimida */
+#define PUBLIC_KEY_SIZE 65
+
+/** @brief Chave do dispositivo */
+static uint8_t device_key[32];
+/** @brief Indica se há chave carregada */
+static bool have_key;
+/** @brief Nova chave gerada por REKEY, adotada na primeira mensagem de controle assinada com ela */
+static uint8_t pending_key[32];
+/** @brief Indica se há nova chave pendente */
+static bool have_pending_key;
+/** @brief Último número de sequência de controle aceito com a chave atual */
+static uint64_t last_control_seq;
+
+/**
+ * @brief Grava na NVS o último número de sequência de controle aceito.
+ *
+ * @param seq Número de sequência.
+ * @return true em caso de sucesso.
+ */
+static bool store_control_seq(uint64_t seq)
+{
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READWRITE, &nvs_handle) != ESP_OK)
+		return false;
+	esp_err_t err = nvs_set_u64(nvs_handle, CONTROL_SEQ_NVS_KEY, seq);
+	if (err == ESP_OK)
+		err = nvs_commit(nvs_handle);
+	nvs_close(nvs_handle);
+	if (err != ESP_OK) {
+		ESP_LOGE(TAG, "Failed to store control sequence: %s", esp_err_to_name(err));
+		return false;
+	}
+	last_control_seq = seq;
+	return true;
+}
+
+/**
+ * @brief Gera bytes aleatórios para o mbedTLS.
+ *
+ * @param ctx Contexto (não usado).
+ * @param buf Buffer de saída.
+ * @param len Quantidade de bytes.
+ * @return 0 (sempre).
+ */
+static int random_bytes(void *ctx, unsigned char *buf, size_t len)
+{
+	esp_fill_random(buf, len);
+	return 0;
+}
+
+/**
+ * @brief Converte bytes em hexadecimal.
+ *
+ * @param data Bytes a converter.
+ * @param len Quantidade de bytes.
+ * @param out Buffer com ao menos 2 * len + 1 bytes.
+ */
+static void to_hex(const uint8_t *data, size_t len, char *out)
+{
+	for (size_t i = 0; i < len; i++)
+		sprintf(out + 2 * i, "%02x", data[i]);
+}
+
+/**
+ * @brief Gera um par de chaves P-256 e deriva uma chave com a chave pública do back-end
+ * (CONFIG_BACKEND_KEY_EXCHANGE_PUBLIC_KEY) por ECDH.
+ *
+ * @param key Buffer de 32 bytes que recebe a chave derivada.
+ * @param public_key Buffer que recebe a chave pública gerada (ponto não comprimido), a enviar ao back-end.
+ * @return true em caso de sucesso.
+ *
+ * A chave privada gerada é descartada: só o back-end, com a sua chave privada, deriva a mesma chave.
+ */
+static bool exchange_key(uint8_t key[32], uint8_t public_key[PUBLIC_KEY_SIZE])
+{
+	const char *backend_hex = CONFIG_BACKEND_KEY_EXCHANGE_PUBLIC_KEY;
+	uint8_t backend_key[PUBLIC_KEY_SIZE];
+	if (strlen(backend_hex) != 2 * sizeof(backend_key)) {
+		ESP_LOGE(TAG, "Backend key exchange public key not configured");
+		return false;
+	}
+	for (size_t i = 0; i < sizeof(backend_key); i++) {
+		unsigned int byte;
+		if (sscanf(backend_hex + 2 * i, "%2x", &byte) != 1)
+			return false;
+		backend_key[i] = byte;
+	}
+
+	mbedtls_ecp_group grp;
+	mbedtls_ecp_point q, peer;
+	mbedtls_mpi d, z;
+	mbedtls_ecp_group_init(&grp);
+	mbedtls_ecp_point_init(&q);
+	mbedtls_ecp_point_init(&peer);
+	mbedtls_mpi_init(&d);
+	mbedtls_mpi_init(&z);
+
+	uint8_t shared[32];
+	size_t public_len = 0;
+	bool ok = mbedtls_ecp_group_load(&grp, MBEDTLS_ECP_DP_SECP256R1) == 0 &&
+		  mbedtls_ecp_point_read_binary(&grp, &peer, backend_key, sizeof(backend_key)) == 0 &&
+		  mbedtls_ecdh_gen_public(&grp, &d, &q, random_bytes, NULL) == 0 &&
+		  mbedtls_ecdh_compute_shared(&grp, &z, &peer, &d, random_bytes, NULL) == 0 &&
+		  mbedtls_mpi_write_binary(&z, shared, sizeof(shared)) == 0 &&
+		  mbedtls_ecp_point_write_binary(&grp, &q, MBEDTLS_ECP_PF_UNCOMPRESSED, &public_len, public_key,
+						 PUBLIC_KEY_SIZE) == 0 &&
+		  public_len == PUBLIC_KEY_SIZE &&
+		  mbedtls_hkdf(mbedtls_md_info_from_type(MBEDTLS_MD_SHA256), (const uint8_t *)DEVICE_KEY_SALT,
+			       strlen(DEVICE_KEY_SALT), shared, sizeof(shared), (const uint8_t *)"device-key",
+			       strlen("device-key"), key, 32) == 0;
+
+	memset(shared, 0, sizeof(shared));
+	mbedtls_mpi_free(&z);
+	mbedtls_mpi_free(&d);
+	mbedtls_ecp_point_free(&peer);
+	mbedtls_ecp_point_free(&q);
+	mbedtls_ecp_group_free(&grp);
+	if (!ok)
+		ESP_LOGE(TAG, "Key exchange failed");
+	return ok;
+}
+
+/**
+ * @brief Adota a chave pendente: grava-a na NVS como chave do dispositivo e descarta a anterior.
+ *
+ * @return true em caso de sucesso.
+ */
+static bool commit_pending_key(void)
+{
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READWRITE, &nvs_handle) != ESP_OK)
+		return false;
+	esp_err_t err = nvs_set_blob(nvs_handle, "device_key", pending_key, sizeof(pending_key));
+	if (err == ESP_OK)
+		err = nvs_erase_key(nvs_handle, PENDING_KEY_NVS_KEY);
+	if (err == ESP_OK)
+		err = nvs_commit(nvs_handle);
+	nvs_close(nvs_handle);
+	if (err != ESP_OK) {
+		ESP_LOGE(TAG, "Failed to store new device key: %s", esp_err_to_name(err));
+		return false;
+	}
+
+	memcpy(device_key, pending_key, sizeof(device_key));
+	memset(pending_key, 0, sizeof(pending_key));
+	have_pending_key = false;
+	ESP_LOGI(TAG, "Switched to the new device key");
+	return true;
+}
+
+/**
+ * @brief Deriva uma subchave de uma chave do dispositivo.
+ *
+ * @param key Chave do dispositivo.
+ * @param purpose Propósito da subchave (ex.: "mac", "config-enc").
+ * @param out Buffer de 32 bytes que recebe a subchave.
+ * @return true em caso de sucesso.
+ */
+static bool derive_subkey(const uint8_t key[32], const char *purpose, uint8_t out[32])
+{
+	return mbedtls_hkdf(mbedtls_md_info_from_type(MBEDTLS_MD_SHA256), NULL, 0, key, 32, (const uint8_t *)purpose,
+			    strlen(purpose), out, 32) == 0;
+}
+
+/**
+ * @brief Calcula a assinatura de uma mensagem.
+ *
+ * @param key Chave do dispositivo.
+ * @param direction 'C' para controle ou 'S' para status.
+ * @param timestamp Timestamp da mensagem.
+ * @param nonce Nonce da mensagem.
+ * @param payload Mensagem original.
+ * @param payload_len Tamanho da mensagem.
+ * @param mac Buffer de 32 bytes que recebe a assinatura.
+ * @return true em caso de sucesso.
+ */
+static bool sign(const uint8_t key[32], char direction, uint64_t timestamp, uint64_t nonce, const uint8_t *payload,
+		 size_t payload_len, uint8_t mac[32])
+{
+	uint8_t mac_key[32];
+	uint8_t header[4 + 16] = { 'L', 'W', '1', (uint8_t)direction };
+	for (int i = 0; i < 8; i++) {
+		header[4 + i] = (uint8_t)(timestamp >> (56 - 8 * i));
+		header[12 + i] = (uint8_t)(nonce >> (56 - 8 * i));
+	}
+
+	if (!derive_subkey(key, "mac", mac_key))
+		return false;
+
+	mbedtls_md_context_t ctx;
+	mbedtls_md_init(&ctx);
+	bool ok = mbedtls_md_setup(&ctx, mbedtls_md_info_from_type(MBEDTLS_MD_SHA256), 1) == 0 &&
+		  mbedtls_md_hmac_starts(&ctx, mac_key, sizeof(mac_key)) == 0 &&
+		  mbedtls_md_hmac_update(&ctx, header, sizeof(header)) == 0 &&
+		  mbedtls_md_hmac_update(&ctx, payload, payload_len) == 0 && mbedtls_md_hmac_finish(&ctx, mac) == 0;
+	mbedtls_md_free(&ctx);
+	memset(mac_key, 0, sizeof(mac_key));
+	return ok;
+}
+
+/**
+ * @brief Compara duas assinaturas em tempo constante.
+ *
+ * @param expected Assinatura calculada.
+ * @param signature Assinatura recebida.
+ * @return true se forem iguais.
+ */
+static bool signature_matches(const uint8_t expected[32], const uint8_t signature[32])
+{
+	uint8_t diff = 0;
+	for (size_t i = 0; i < 32; i++)
+		diff |= expected[i] ^ signature[i];
+	return !diff;
+}
+
+void secure_channel_init(void)
+{
+	esp_log_level_set(TAG, ESP_LOG_INFO);
+
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READONLY, &nvs_handle) != ESP_OK)
+		return;
+
+	size_t key_len = sizeof(device_key);
+	have_key = nvs_get_blob(nvs_handle, "device_key", device_key, &key_len) == ESP_OK &&
+		   key_len == sizeof(device_key);
+	if (nvs_get_u64(nvs_handle, CONTROL_SEQ_NVS_KEY, &last_control_seq) != ESP_OK)
+		last_control_seq = 0;
+	key_len = sizeof(pending_key);
+	have_pending_key = have_key &&
+			   nvs_get_blob(nvs_handle, PENDING_KEY_NVS_KEY, pending_key, &key_len) == ESP_OK &&
+			   key_len == sizeof(pending_key);
+	nvs_close(nvs_handle);
+
+	ESP_LOGI(TAG, have_key ? "Device key loaded" : "No device key, messages will not be signed");
+}
+
+bool secure_channel_has_key(void)
+{
+	return have_key;
+}
+
+bool secure_channel_pair(const char *code, char *presented, size_t presented_size)
+{
+	uint8_t key[32];
+	uint8_t public_key[PUBLIC_KEY_SIZE];
+	uint8_t hash[32];
+
+	if (presented_size < strlen(PAIRING_CODE_PREFIX) + 2 * sizeof(hash) + 1)
+		return false;
+
+	if (!exchange_key(key, public_key) || mbedtls_sha256((const uint8_t *)code, strlen(code), hash, 0) != 0) {
+		ESP_LOGE(TAG, "Failed to derive device key");
+		return false;
+	}
+
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READWRITE, &nvs_handle) != ESP_OK)
+		return false;
+	// The back-end restarts the control sequence along with the new key
+	esp_err_t err = nvs_set_blob(nvs_handle, "device_key", key, sizeof(key));
+	if (err == ESP_OK)
+		err = nvs_set_blob(nvs_handle, PUBLIC_KEY_NVS_KEY, public_key, sizeof(public_key));
+	if (err == ESP_OK)
+		err = nvs_set_u64(nvs_handle, CONTROL_SEQ_NVS_KEY, 0);
+	if (err == ESP_OK && have_pending_key)
+		err = nvs_erase_key(nvs_handle, PENDING_KEY_NVS_KEY);
+	if (err == ESP_OK)
+		err = nvs_commit(nvs_handle);
+	nvs_close(nvs_handle);
+	if (err != ESP_OK) {
+		ESP_LOGE(TAG, "Failed to store device key: %s", esp_err_to_name(err));
+		return false;
+	}
+
+	memcpy(device_key, key, sizeof(key));
+	memset(key, 0, sizeof(key));
+	have_key = true;
+	have_pending_key = false;
+	last_control_seq = 0;
+
+	// Present only the hash of the code, which identifies it without revealing it
+	size_t pos = snprintf(presented, presented_size, "%s", PAIRING_CODE_PREFIX);
+	to_hex(hash, sizeof(hash), presented + pos);
+
+	ESP_LOGI(TAG, "Device key agreed with the backend key");
+	return true;
+}
+
+bool secure_channel_public_key(char *out, size_t out_size)
+{
+	uint8_t public_key[PUBLIC_KEY_SIZE];
+	size_t len = sizeof(public_key);
+
+	if (out_size < 2 * sizeof(public_key) + 1)
+		return false;
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READONLY, &nvs_handle) != ESP_OK)
+		return false;
+	bool ok = nvs_get_blob(nvs_handle, PUBLIC_KEY_NVS_KEY, public_key, &len) == ESP_OK && len == sizeof(public_key);
+	nvs_close(nvs_handle);
+	if (ok)
+		to_hex(public_key, sizeof(public_key), out);
+	return ok;
+}
+
+bool secure_channel_rekey(char *public_key_hex, size_t public_key_size)
+{
+	uint8_t key[32];
+	uint8_t public_key[PUBLIC_KEY_SIZE];
+
+	if (!have_key || public_key_size < 2 * sizeof(public_key) + 1 || !exchange_key(key, public_key))
+		return false;
+
+	nvs_handle_t nvs_handle;
+	if (nvs_open("voice_lock", NVS_READWRITE, &nvs_handle) != ESP_OK)
+		return false;
+	esp_err_t err = nvs_set_blob(nvs_handle, PENDING_KEY_NVS_KEY, key, sizeof(key));
+	if (err == ESP_OK)
+		err = nvs_commit(nvs_handle);
+	nvs_close(nvs_handle);
+	if (err != ESP_OK) {
+		ESP_LOGE(TAG, "Failed to store pending device key: %s", esp_err_to_name(err));
+		return false;
+	}
+
+	memcpy(pending_key, key, sizeof(key));
+	memset(key, 0, sizeof(key));
+	have_pending_key = true;
+	to_hex(public_key, sizeof(public_key), public_key_hex);
+	ESP_LOGI(TAG, "New device key generated, waiting for the backend");
+	return true;
+}
+
+size_t secure_channel_seal(const uint8_t *payload, size_t payload_len, uint8_t *out, size_t out_size)
+{
+	uint64_t timestamp = (uint64_t)time(NULL);
+	uint64_t nonce;
+	uint8_t mac[32];
+
+	esp_fill_random(&nonce, sizeof(nonce));
+	if (!sign(device_key, 'S', timestamp, nonce, payload, payload_len, mac))
+		return 0;
+
+	CborEncoder encoder, map_encoder;
+	cbor_encoder_init(&encoder, out, out_size, 0);
+	cbor_encoder_create_map(&encoder, &map_encoder, 4);
+	cbor_encode_text_stringz(&map_encoder, "p");
+	cbor_encode_byte_string(&map_encoder, payload, payload_len);
+	cbor_encode_text_stringz(&map_encoder, "t");
+	cbor_encode_uint(&map_encoder, timestamp);
+	cbor_encode_text_stringz(&map_encoder, "n");
+	cbor_encode_uint(&map_encoder, nonce);
+	cbor_encode_text_stringz(&map_encoder, "s");
+	cbor_encode_byte_string(&map_encoder, mac, sizeof(mac));
+	cbor_encoder_close_container(&encoder, &map_encoder);
+
+	if (cbor_encoder_get_extra_bytes_needed(&encoder) > 0) {
+		ESP_LOGE(TAG, "Sealed message does not fit the buffer");
+		return 0;
+	}
+	return cbor_encoder_get_buffer_size(&encoder, out);
+}
+
+bool secure_channel_open(const uint8_t *frame, size_t frame_len, uint8_t *payload, size_t *payload_len)
+{
+	CborParser parser;
+	CborValue map, p_val, t_val, n_val, s_val;
+	uint64_t timestamp, seq;
+	uint8_t signature[32], expected[32];
+	size_t signature_len = sizeof(signature);
+
+	if (cbor_parser_init(frame, frame_len, 0, &parser, &map) != CborNoError || !cbor_value_is_map(&map) ||
+	    cbor_value_map_find_value(&map, "p", &p_val) != CborNoError || !cbor_value_is_byte_string(&p_val) ||
+	    cbor_value_map_find_value(&map, "t", &t_val) != CborNoError || !cbor_value_is_unsigned_integer(&t_val) ||
+	    cbor_value_map_find_value(&map, "n", &n_val) != CborNoError || !cbor_value_is_unsigned_integer(&n_val) ||
+	    cbor_value_map_find_value(&map, "s", &s_val) != CborNoError || !cbor_value_is_byte_string(&s_val) ||
+	    cbor_value_get_uint64(&t_val, &timestamp) != CborNoError ||
+	    cbor_value_get_uint64(&n_val, &seq) != CborNoError ||
+	    cbor_value_copy_byte_string(&p_val, payload, payload_len, NULL) != CborNoError ||
+	    cbor_value_copy_byte_string(&s_val, signature, &signature_len, NULL) != CborNoError ||
+	    signature_len != sizeof(signature)) {
+		ESP_LOGW(TAG, "Malformed sealed message");
+		return false;
+	}
+
+	// After REKEY, the first message signed with the new key shows the backend has it
+	bool signed_with_pending = false;
+	if (!sign(device_key, 'C', timestamp, seq, payload, *payload_len, expected))
+		return false;
+	if (!signature_matches(expected, signature)) {
+		if (!have_pending_key || !sign(pending_key, 'C', timestamp, seq, payload, *payload_len, expected) ||
+		    !signature_matches(expected, signature)) {
+			ESP_LOGW(TAG, "Rejected message with bad signature");
+			return false;
+		}
+		signed_with_pending = true;
+	}
+
+	// Without a synchronized clock the time window cannot be checked
+	time_t now = time(NULL);
+	if (now < MIN_SYNCED_TIME) {
+		ESP_LOGW(TAG, "Rejected message before clock synchronization");
+		return false;
+	}
+	if (llabs((long long)now - (long long)timestamp) > MAX_CLOCK_SKEW_SEC) {
+		ESP_LOGW(TAG, "Rejected stale message (timestamp %llu)", (unsigned long long)timestamp);
+		return false;
+	}
+
+	// The sequence survives reboots, so a captured message can never be accepted twice
+	if (seq <= last_control_seq) {
+		ESP_LOGW(TAG, "Rejected replayed message (sequence %llu <= %llu)", (unsigned long long)seq,
+			 (unsigned long long)last_control_seq);
+		return false;
+	}
+	if (signed_with_pending && !commit_pending_key())
+		return false;
+	return store_control_seq(seq);
+}
+
+bool secure_channel_decrypt(const uint8_t *data, size_t data_len, char *out, size_t out_size)
+{
+	const size_t nonce_len = 12, tag_len = 16;
+	uint8_t enc_key[32];
+
+	if (!have_key || data_len < nonce_len + tag_len || data_len - nonce_len - tag_len >= out_size)
+		return false;
+	size_t text_len = data_len - nonce_len - tag_len;
+
+	if (!derive_subkey(device_key, "config-enc", enc_key))
+		return false;
+
+	mbedtls_gcm_context gcm;
+	mbedtls_gcm_init(&gcm);
+	bool ok = mbedtls_gcm_setkey(&gcm, MBEDTLS_CIPHER_ID_AES, enc_key, 256) == 0 &&
+		  mbedtls_gcm_auth_decrypt(&gcm, text_len, data, nonce_len, NULL, 0, data + nonce_len + text_len,
+					   tag_len, data + nonce_len, (uint8_t *)out) == 0;
+	mbedtls_gcm_free(&gcm);
+	memset(enc_key, 0, sizeof(enc_key));
+
+	if (!ok) {
+		memset(out, 0, out_size);
+		ESP_LOGW(TAG, "Failed to decrypt config value");
+		return false;
+	}
+	out[text_len] = '\0';
+	return true;
+}
@@ -11,6 +11,7 @@
 #include "freertos/task.h"
 #include "lwip/sockets.h"
 #include "periph_wifi.h"
+#include "secure_channel.h"
 #include "system_utils.h"
 #include "wifi.h"
 #include <ctype.h>
@@ -320,7 +321,12 @@ static void handle_pairing_client(int client_sock)
 			// Store configuration
 			update_config("wifi_ssid", wifi_ssid);
 			update_config("wifi_pass", wifi_pass);
-			update_config("user_id", user_id);
+			// Pairing codes agree a device key with the backend; only their hash is presented to it
+			char presented[80];
+			if (!strncmp(user_id, "LWP-", 4) && secure_channel_pair(user_id, presented, sizeof(presented)))
+				update_config("user_id", presented);
+			else
+				update_config("user_id", user_id);
 			// pairing_mode is already set to 0 at the start of pairing mode
 
 			// Send success response with device UUID
@@ -330,7 +336,7 @@ static void handle_pairing_client(int client_sock)
 			send(client_sock, response, strlen(response), 0);
 
 			paired = true;
-			ESP_LOGI(TAG, "Configuration stored: user_id=%s, ssid=%s, rebooting...", user_id, wifi_ssid);
+			ESP_LOGI(TAG, "Configuration stored: ssid=%s, rebooting...", wifi_ssid);
 			vTaskDelay(pdMS_TO_TICKS(250));
 			cleanup_restart();
 		} else {